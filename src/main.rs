@@ -1,5 +1,5 @@
-use clap::Parser;
-use std::{fs::File, io::Read};
+use clap::{Parser, Subcommand};
+use std::{fs::File, io::Read, path::PathBuf};
 
 #[derive(Parser, Debug)]
 struct LaunchConfig {
@@ -8,6 +8,31 @@ struct LaunchConfig {
 
     #[clap(short, long, default_value = "/etc/vulpes/vulpes.conf")]
     config: String,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage an on-disk proxy cache
+    #[clap(subcommand)]
+    Cache(CacheCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// List the cached keys with their size and expiry
+    Ls {
+        /// Directory of the `proxy_cache_path`
+        path: PathBuf,
+    },
+    /// Remove the entries of a key, or of a key prefix ending with `*`
+    Rm {
+        /// Directory of the `proxy_cache_path`
+        path: PathBuf,
+        key: String,
+    },
 }
 
 #[tokio::main]
@@ -17,6 +42,11 @@ async fn main() {
     let launch_config = LaunchConfig::parse();
     log::debug!("launch_config: {:?}", launch_config);
 
+    if let Some(Command::Cache(command)) = launch_config.command {
+        cache(command);
+        return;
+    }
+
     let mut f = File::open(launch_config.config).unwrap();
     let mut buf = String::new();
     f.read_to_string(&mut buf).unwrap();
@@ -29,3 +59,26 @@ async fn main() {
 
     vulpes_server::run(config).await.unwrap();
 }
+
+fn cache(command: CacheCommand) {
+    match command {
+        CacheCommand::Ls { path } => {
+            let mut entries = vulpes_server::cache::scan(&path).unwrap();
+            entries.sort_by(|a, b| a.key.cmp(&b.key));
+            for e in entries {
+                println!(
+                    "{}\t{}\t{}",
+                    e.key,
+                    e.size,
+                    httpdate::fmt_http_date(e.expires)
+                );
+            }
+        }
+        CacheCommand::Rm { path, key } => {
+            let removed = vulpes_server::cache::purge_dir(&path, &key).unwrap();
+            for e in removed {
+                println!("{}", e.key);
+            }
+        }
+    }
+}
@@ -1,9 +1,18 @@
 use rand::Rng;
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
 use tempfile::NamedTempFile;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
 
-const BASE_CONFIG_FILE: &'static str = "tests/vulpes.conf";
-const HTTP_BASE_PORT: &'static str = "8080";
-const HTTP_BASE_PORT_2: &'static str = "8081";
+const BASE_CONFIG_FILE: &str = "tests/vulpes.conf";
+const HTTP_BASE_PORT: &str = "8080";
+const HTTP_BASE_PORT_2: &str = "8081";
 
 struct TestServer {
     child: std::process::Child,
@@ -14,8 +23,13 @@ struct TestServer {
 
 impl TestServer {
     async fn init() -> TestServer {
+        let contents = std::fs::read_to_string(BASE_CONFIG_FILE).unwrap();
+        Self::init_with_config(&contents).await
+    }
+
+    async fn init_with_config(contents: &str) -> TestServer {
         let port = rand::thread_rng().gen_range(49152..65535);
-        let temp_file = Self::generate_test_config_file(port);
+        let temp_file = Self::generate_test_config_file(contents, port);
         let path = temp_file.as_ref();
 
         let command_path = assert_cmd::cargo::cargo_bin(env!("CARGO_PKG_NAME"));
@@ -27,24 +41,36 @@ impl TestServer {
         tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
 
         TestServer {
-            child: child,
+            child,
             endpoint: format!("http://127.0.0.1:{}", port),
             endpoint_2: format!("http://127.0.0.1:{}", port + 1),
             _temp_file: temp_file,
         }
     }
 
-    fn generate_test_config_file(port: i32) -> NamedTempFile {
-        let mut contents = std::fs::read_to_string(BASE_CONFIG_FILE).unwrap();
-        contents = contents
-            .replace(HTTP_BASE_PORT, &port.to_string())
-            .replace(HTTP_BASE_PORT_2, &(port + 1).to_string());
+    fn generate_test_config_file(contents: &str, port: i32) -> NamedTempFile {
+        // only the listen ports are replaced, backend ports may contain the
+        // same digits
+        let listen = |port| format!("listen {}", port);
+        let contents = contents
+            .replace(
+                &listen(HTTP_BASE_PORT.to_owned()),
+                &listen(port.to_string()),
+            )
+            .replace(
+                &listen(HTTP_BASE_PORT_2.to_owned()),
+                &listen((port + 1).to_string()),
+            )
+            .replace(
+                &listen(format!("127.0.0.1:{}", HTTP_BASE_PORT)),
+                &listen(format!("127.0.0.1:{}", port)),
+            );
 
         let temp_file = NamedTempFile::new().unwrap();
         let path = temp_file.as_ref();
         std::fs::write(path, contents).unwrap();
 
-        return temp_file;
+        temp_file
     }
 }
 
@@ -54,15 +80,202 @@ impl Drop for TestServer {
     }
 }
 
+/// Starts a backend that answers every request with `name` as the body and
+/// returns its port.
+async fn spawn_backend(name: &'static str) -> u16 {
+    spawn_backend_with_status(name, "200 OK").await
+}
+
+/// Starts a backend that answers every request with `status` and its
+/// `name` as the body.
+async fn spawn_backend_with_status(name: &'static str, status: &'static str) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = vec![];
+                let mut tmp = [0u8; 1024];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut tmp).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&tmp[..n]),
+                    }
+                }
+
+                let res = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    name.len(),
+                    name
+                );
+                let _ = stream.write_all(res.as_bytes()).await;
+            });
+        }
+    });
+
+    port
+}
+
+/// Starts a backend that keeps connections open and answers each request
+/// with the number of the connection it arrived on. With `close_idle`, the
+/// backend closes the connection after every response without announcing
+/// it. Returns the port and the number of accepted connections.
+async fn spawn_keepalive_backend(close_idle: bool) -> (u16, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let accepted = Arc::new(AtomicUsize::new(0));
+
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let id = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            tokio::spawn(async move {
+                let mut buf = vec![];
+                let mut tmp = [0u8; 1024];
+                loop {
+                    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut tmp).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => buf.extend_from_slice(&tmp[..n]),
+                        }
+                    }
+                    let end = buf.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+                    buf.drain(..end + 4);
+
+                    let body = id.to_string();
+                    let res = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    if stream.write_all(res.as_bytes()).await.is_err() || close_idle {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+
+    (port, accepted)
+}
+
+/// Starts a backend that answers with the number of requests it received so
+/// far, sending `headers` along. It answers 503 while `failing` is set.
+async fn spawn_counting_backend(headers: &'static str) -> (u16, Arc<AtomicUsize>, Arc<AtomicBool>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let count = Arc::new(AtomicUsize::new(0));
+    let failing = Arc::new(AtomicBool::new(false));
+
+    let (c, f) = (count.clone(), failing.clone());
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (c, f) = (c.clone(), f.clone());
+            tokio::spawn(async move {
+                let mut buf = vec![];
+                let mut tmp = [0u8; 1024];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut tmp).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&tmp[..n]),
+                    }
+                }
+
+                let body = (c.fetch_add(1, Ordering::SeqCst) + 1).to_string();
+                let status = if f.load(Ordering::SeqCst) {
+                    "503 Service Unavailable"
+                } else {
+                    "200 OK"
+                };
+                let res = format!(
+                    "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(res.as_bytes()).await;
+            });
+        }
+    });
+
+    (port, count, failing)
+}
+
+/// Starts a backend that accepts connections but never answers.
+async fn spawn_silent_backend() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        let mut streams = vec![];
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            streams.push(stream);
+        }
+    });
+
+    port
+}
+
+async fn get(url: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(url)
+        .header(reqwest::header::HOST, "example.com")
+        .send()
+        .await
+        .unwrap()
+}
+
+/// Returns a port that nothing listens on.
+async fn closed_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
 #[tokio::test]
 async fn test_run() {
     let t = TestServer::init().await;
 
+    // the host `127.0.0.1` matches no name and falls back to the only server
     let res = reqwest::get(&t.endpoint).await.unwrap();
-    assert_eq!(res.status().as_u16(), 404);
+    assert_eq!(res.status().as_u16(), 400);
 
     let res = reqwest::get(&format!("{}/503", t.endpoint)).await.unwrap();
-    assert_eq!(res.status().as_u16(), 404);
+    assert_eq!(res.status().as_u16(), 503);
+}
+
+#[tokio::test]
+async fn test_request_framing() {
+    let t = TestServer::init().await;
+    let address = t.endpoint.strip_prefix("http://").unwrap();
+
+    // messages that another server could frame differently are refused
+    for headers in [
+        "Transfer-Encoding: gzip\r\n",
+        "Transfer-Encoding: chunked\r\nContent-Length: 3\r\n",
+        "Content-Length: 3\r\nContent-Length: 4\r\n",
+        "Content-Length: +3\r\n",
+    ] {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let req = format!(
+            "POST / HTTP/1.1\r\nHost: example.com\r\n{}\r\n3\r\nabc\r\n0\r\n\r\n",
+            headers
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        let _ = stream.read_to_string(&mut res).await;
+        assert!(
+            res.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            headers
+        );
+    }
 }
 
 #[tokio::test]
@@ -100,3 +313,2901 @@ async fn test_run_with_host() {
         assert_eq!(res.bytes().await.unwrap(), vec![]);
     }
 }
+
+#[tokio::test]
+async fn test_location_longest_prefix() {
+    let t = TestServer::init_with_config(
+        r#"
+        http {
+            server {
+                listen 8080;
+                location / {
+                    return 200 "root";
+                }
+                location /api {
+                    return 200 "api";
+                }
+                location /api/v1 {
+                    return 200 "v1";
+                }
+            }
+        }
+        "#,
+    )
+    .await;
+
+    for (path, body) in [("/api/v1/x", "v1"), ("/api/v2", "api"), ("/other", "root")] {
+        let res = get(&format!("{}{}", t.endpoint, path)).await;
+        assert_eq!(res.text().await.unwrap(), body, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn test_default_server() {
+    let t = TestServer::init_with_config(
+        r#"
+        http {
+            server {
+                listen 8080;
+                listen 8081;
+                server_name example.com www.example.com;
+                return 200 "first";
+            }
+            server {
+                listen 8080;
+                listen 8081 default_server;
+                server_name example.org;
+                return 200 "second";
+            }
+        }
+        "#,
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let get = |endpoint: &str, host: &'static str| {
+        client
+            .get(endpoint)
+            .header(reqwest::header::HOST, host)
+            .send()
+    };
+
+    for (endpoint, host, expected) in [
+        (&t.endpoint, "example.com", "first"),
+        (&t.endpoint, "WWW.Example.com:8080", "first"),
+        (&t.endpoint, "example.org.", "second"),
+        // the first server of the port is its default
+        (&t.endpoint, "unknown.com", "first"),
+        (&t.endpoint_2, "unknown.com:8081", "second"),
+        (&t.endpoint_2, "example.com:8081", "first"),
+    ] {
+        let res = get(endpoint, host).await.unwrap();
+        assert_eq!(res.text().await.unwrap(), expected, "{} {}", endpoint, host);
+    }
+}
+
+#[tokio::test]
+async fn test_listen_shared_port() {
+    let t = TestServer::init_with_config(
+        "
+        http {
+            server {
+                listen 8080;
+                server_name example.com;
+                location / {
+                    return 200 any;
+                }
+            }
+            server {
+                listen 127.0.0.1:8080;
+                server_name example.com;
+                location / {
+                    return 200 local;
+                }
+            }
+        }",
+    )
+    .await;
+
+    // the specific address is served by the socket of the wildcard one
+    let res = get(&t.endpoint).await;
+    assert_eq!(res.text().await.unwrap(), "local");
+    let other = t.endpoint.replace("127.0.0.1", "127.0.0.2");
+    let res = get(&other).await;
+    assert_eq!(res.text().await.unwrap(), "any");
+}
+
+#[tokio::test]
+async fn test_server_name_wildcard() {
+    let t = TestServer::init_with_config(
+        r#"
+        http {
+            server {
+                listen 8080;
+                server_name example.com;
+                return 200 "exact";
+            }
+            server {
+                listen 8080;
+                server_name *.example.com;
+                return 200 "leading";
+            }
+            server {
+                listen 8080;
+                server_name www.example.*;
+                return 200 "trailing";
+            }
+            server {
+                listen 8080;
+                server_name ~^(?<sub>[a-z]+)\.example\.(org|net)$;
+                location / {
+                    add_header X-Sub $sub;
+                    add_header X-Tld $2;
+                    return 200 "regex";
+                }
+            }
+        }
+        "#,
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for (host, expected) in [
+        ("example.com", "exact"),
+        ("mail.example.com", "leading"),
+        ("www.example.com", "leading"),
+        ("www.example.org", "trailing"),
+        ("mail.example.org", "regex"),
+        ("example.net", "exact"),
+    ] {
+        let res = client
+            .get(&t.endpoint)
+            .header(reqwest::header::HOST, host)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.text().await.unwrap(), expected, "{}", host);
+    }
+
+    let res = client
+        .get(&t.endpoint)
+        .header(reqwest::header::HOST, "Mail.Example.net")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["x-sub"], "mail");
+    assert_eq!(res.headers()["x-tld"], "net");
+}
+
+#[tokio::test]
+async fn test_variables() {
+    let t = TestServer::init_with_config(
+        r#"
+        http {
+            server {
+                listen 8080;
+                server_name example.com;
+
+                location / {
+                    add_header X-Request-Id $request_id;
+                    return 200 "$request_method $scheme://$host$uri?${arg_a}x $http_x_test";
+                }
+            }
+        }
+        "#,
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let mut ids = vec![];
+    for _ in 0..2 {
+        let res = client
+            .get(format!("{}/path?a=1", t.endpoint))
+            .header(reqwest::header::HOST, "example.com")
+            .header("X-Test", "value")
+            .send()
+            .await
+            .unwrap();
+        ids.push(res.headers()["x-request-id"].to_str().unwrap().to_owned());
+        assert_eq!(
+            res.text().await.unwrap(),
+            "GET http://example.com/path?1x value"
+        );
+    }
+    assert_eq!(ids[0].len(), 32);
+    assert_ne!(ids[0], ids[1]);
+}
+
+#[tokio::test]
+async fn test_return_redirect() {
+    let t = TestServer::init_with_config(
+        r#"
+        http {
+            server {
+                listen 8080;
+                server_name example.com alias.example.com;
+
+                location /relative {
+                    return 301 /new$uri;
+                }
+                location /absolute {
+                    return 308 https://example.org/;
+                }
+                location /found {
+                    return http://example.org$request_uri;
+                }
+                location /server_name {
+                    server_name_in_redirect on;
+                    return 302 /;
+                }
+                location /no_port {
+                    port_in_redirect off;
+                    return 307 /;
+                }
+                location /no_absolute {
+                    absolute_redirect off;
+                    return 303 /;
+                }
+            }
+        }
+        "#,
+    )
+    .await;
+    let port = t.endpoint.rsplit(':').next().unwrap();
+
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    for (path, status, location) in [
+        (
+            "/relative",
+            301,
+            format!("http://alias.example.com:{}/new/relative", port),
+        ),
+        ("/absolute", 308, "https://example.org/".to_owned()),
+        ("/found?a=1", 302, "http://example.org/found?a=1".to_owned()),
+        ("/server_name", 302, format!("http://example.com:{}/", port)),
+        ("/no_port", 307, "http://alias.example.com/".to_owned()),
+        ("/no_absolute", 303, "/".to_owned()),
+    ] {
+        let res = client
+            .get(format!("{}{}", t.endpoint, path))
+            .header(reqwest::header::HOST, format!("alias.example.com:{}", port))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), status, "{}", path);
+        assert_eq!(res.headers()["location"], location.as_str(), "{}", path);
+        assert_eq!(res.headers()["content-type"], "text/html");
+    }
+
+    let res = client
+        .get(format!("{}/relative", t.endpoint))
+        .send()
+        .await
+        .unwrap();
+    assert!(res
+        .text()
+        .await
+        .unwrap()
+        .contains("<title>301 Moved Permanently</title>"));
+}
+
+#[tokio::test]
+async fn test_rewrite() {
+    let t = TestServer::init_with_config(
+        r#"
+        http {
+            server {
+                listen 8080;
+                server_name example.com;
+                rewrite ^/old/(.*)$ /new/$1 permanent;
+                rewrite ^/moved/(.*)$ /last/$1;
+
+                location /last {
+                    rewrite ^/last/(.*)$ /target/$1 last;
+                    return 200 "last";
+                }
+                location /break {
+                    rewrite ^/break/(.*)$ /dropped/$1? break;
+                    add_header X-Uri "$uri?$args $request_uri";
+                    return 200 "break";
+                }
+                location /target {
+                    add_header X-Uri "$uri?$args $request_uri";
+                    return 200 "target";
+                }
+                location /loop {
+                    rewrite ^ /loop last;
+                }
+            }
+        }
+        "#,
+    )
+    .await;
+
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let get = |path: &str| {
+        client
+            .get(format!("{}{}", t.endpoint, path))
+            .header(reqwest::header::HOST, "example.com")
+            .send()
+    };
+
+    let res = get("/old/a?b=1").await.unwrap();
+    assert_eq!(res.status().as_u16(), 301);
+    assert_eq!(
+        res.headers()["location"],
+        format!(
+            "{}/new/a?b=1",
+            t.endpoint.replace("127.0.0.1", "example.com")
+        )
+        .as_str()
+    );
+
+    let res = get("/moved/a?b=1").await.unwrap();
+    assert_eq!(res.headers()["x-uri"], "/target/a?b=1 /moved/a?b=1");
+    assert_eq!(res.text().await.unwrap(), "target");
+
+    let res = get("/break/a?b=1").await.unwrap();
+    assert_eq!(res.headers()["x-uri"], "/dropped/a? /break/a?b=1");
+    assert_eq!(res.text().await.unwrap(), "break");
+
+    let res = get("/loop").await.unwrap();
+    assert_eq!(res.status().as_u16(), 500);
+}
+
+#[tokio::test]
+async fn test_if() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("files")).unwrap();
+    std::fs::write(dir.path().join("files/file"), "").unwrap();
+    let t = TestServer::init_with_config(&format!(
+        r#"
+        http {{
+            server {{
+                listen 8080;
+                if ($http_user_agent ~* ^(?<bot>[a-z]+)bot) {{
+                    return 403 "$bot";
+                }}
+
+                location /files {{
+                    root {};
+                    if (-f $request_filename) {{
+                        return 200 "file";
+                    }}
+                    if (!-e $request_filename) {{
+                        return 404 "missing";
+                    }}
+                    return 200 "other";
+                }}
+                location /args {{
+                    if ($arg_a = 1) {{
+                        rewrite ^ /one last;
+                    }}
+                    if ($arg_b) {{
+                        break;
+                    }}
+                    rewrite ^ /other last;
+                    return 200 "args";
+                }}
+                location /one {{
+                    return 200 "one";
+                }}
+                location /other {{
+                    return 200 "other";
+                }}
+            }}
+        }}
+        "#,
+        dir.path().display()
+    ))
+    .await;
+
+    let get = |path: &str| reqwest::get(format!("{}{}", t.endpoint, path));
+
+    let res = reqwest::Client::new()
+        .get(format!("{}/one", t.endpoint))
+        .header(reqwest::header::USER_AGENT, "GoogleBot/2.1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 403);
+    assert_eq!(res.text().await.unwrap(), "Google");
+
+    let res = get("/files/file").await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "file");
+    let res = get("/files").await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "other");
+    let res = get("/files/none").await.unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+
+    let res = get("/args?a=1").await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "one");
+    let res = get("/args?b=1").await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "args");
+    let res = get("/args?b=0").await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "other");
+}
+
+#[tokio::test]
+async fn test_map_geo() {
+    let t = TestServer::init_with_config(
+        r#"
+        http {
+            map $http_host $backend {
+                hostnames;
+                default a;
+                *.foo.com b;
+                ~^api(?<n>[0-9]+) c$n;
+            }
+            geo $internal {
+                default 0;
+                127.0.0.0/8 1;
+            }
+            geo $arg_ip $office {
+                default no;
+                10.0.0.0/8 yes;
+            }
+            server {
+                listen 8080;
+                location / {
+                    return 200 "$backend $internal $office";
+                }
+            }
+        }
+        "#,
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let get = |host: &str, query: &str| {
+        client
+            .get(format!("{}/{}", t.endpoint, query))
+            .header(reqwest::header::HOST, host)
+            .send()
+    };
+
+    let res = get("www.foo.com", "").await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "b 1 no");
+    let res = get("api2.example.com", "?ip=10.1.2.3").await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "c2 1 yes");
+    let res = get("example.com", "?ip=11.1.2.3").await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "a 1 no");
+}
+
+#[tokio::test]
+async fn test_headers() {
+    let echo = spawn_echo_backend().await;
+    let (counting, _, _) = spawn_counting_backend("X-Secret: 1\r\nX-Powered-By: php\r\n").await;
+    let t = TestServer::init_with_config(&format!(
+        r#"
+        http {{
+            upstream echo {{
+                server 127.0.0.1:{};
+            }}
+            upstream counting {{
+                server 127.0.0.1:{};
+            }}
+            add_header X-Http http;
+            more_clear_headers "X-Powered-*";
+            server {{
+                listen 8080;
+                location / {{
+                    return 200 "ok";
+                }}
+                location /own {{
+                    add_header X-Own own;
+                    add_header X-Always 1 always;
+                    return 404;
+                }}
+                location /echo {{
+                    root /srv/echo;
+                    more_set_input_headers "X-Real-Uri: $uri" "Cookie:";
+                    add_header X-Upstream "$proxy_host $document_root";
+                    proxy_pass http://echo;
+                }}
+                location /cleared {{
+                    proxy_pass http://counting;
+                }}
+                location /hide {{
+                    proxy_hide_header X-Secret;
+                    more_set_headers "Server: vulpes";
+                    proxy_pass http://counting;
+                }}
+            }}
+            server {{
+                listen 8081;
+                add_header X-Server server;
+                location / {{
+                    return 200 "ok";
+                }}
+            }}
+        }}
+        "#,
+        echo, counting
+    ))
+    .await;
+
+    // the http level applies to the locations without their own
+    let res = get(&t.endpoint).await;
+    assert_eq!(res.headers()["x-http"], "http");
+
+    let res = get(&format!("{}/own", t.endpoint)).await;
+    assert_eq!(res.status().as_u16(), 404);
+    assert_eq!(res.headers()["x-always"], "1");
+    assert!(!res.headers().contains_key("x-own"));
+    assert!(!res.headers().contains_key("x-http"));
+
+    let res = get(&t.endpoint_2).await;
+    assert_eq!(res.headers()["x-server"], "server");
+    assert!(!res.headers().contains_key("x-http"));
+
+    let res = reqwest::Client::new()
+        .get(format!("{}/echo", t.endpoint))
+        .header(reqwest::header::COOKIE, "a=1")
+        .send()
+        .await
+        .unwrap();
+    // the variables of the location are kept for the response headers
+    assert_eq!(res.headers()["x-upstream"], "echo /srv/echo");
+    let head = res.text().await.unwrap().to_ascii_lowercase();
+    assert!(head.contains("x-real-uri: /echo\r\n"), "{}", head);
+    assert!(!head.contains("cookie"), "{}", head);
+
+    let res = get(&format!("{}/hide", t.endpoint)).await;
+    assert_eq!(res.headers()["server"], "vulpes");
+    assert_eq!(res.headers()["x-http"], "http");
+    assert!(!res.headers().contains_key("x-secret"));
+    // `more_set_headers` replaces the `more_clear_headers` of the http level
+    assert_eq!(res.headers()["x-powered-by"], "php");
+
+    let res = get(&format!("{}/cleared", t.endpoint)).await;
+    assert_eq!(res.headers()["x-secret"], "1");
+    assert!(!res.headers().contains_key("x-powered-by"));
+}
+
+#[tokio::test]
+async fn test_upstream_weighted_round_robin() {
+    let a = spawn_backend("a").await;
+    let b = spawn_backend("b").await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            upstream backend {{
+                server 127.0.0.1:{} weight=2;
+                server 127.0.0.1:{};
+            }}
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://backend;
+                }}
+            }}
+        }}",
+        a, b
+    ))
+    .await;
+
+    let mut bodies = vec![];
+    for _ in 0..3 {
+        let res = get(&t.endpoint).await;
+        assert_eq!(res.status().as_u16(), 200);
+        bodies.push(res.text().await.unwrap());
+    }
+    assert_eq!(bodies, ["a", "b", "a"]);
+}
+
+#[tokio::test]
+async fn test_upstream_backup() {
+    let down = closed_port().await;
+    let backup = spawn_backend("backup").await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            upstream backend {{
+                server 127.0.0.1:{} max_fails=1 fail_timeout=30s;
+                server 127.0.0.1:{} backup;
+            }}
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://backend;
+                }}
+            }}
+        }}",
+        down, backup
+    ))
+    .await;
+
+    // the failed primary passes the request on to the backup
+    for _ in 0..2 {
+        let res = get(&t.endpoint).await;
+        assert_eq!(res.status().as_u16(), 200);
+        assert_eq!(res.text().await.unwrap(), "backup");
+    }
+}
+
+#[tokio::test]
+async fn test_upstream_health_check() {
+    let a = spawn_backend("a").await;
+    let b = spawn_backend("b").await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            upstream backend {{
+                server 127.0.0.1:{} max_fails=0;
+                server 127.0.0.1:{} max_fails=0;
+                health_check uri=/health interval=100ms fall=1 body=a;
+            }}
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://backend;
+                }}
+                location /status {{
+                    health_check_status;
+                }}
+            }}
+        }}",
+        a, b
+    ))
+    .await;
+
+    for _ in 0..4 {
+        let res = get(&t.endpoint).await;
+        assert_eq!(res.text().await.unwrap(), "a");
+    }
+
+    let res = get(&format!("{}/status", t.endpoint)).await;
+    assert_eq!(
+        res.text().await.unwrap(),
+        format!(
+            "backend 127.0.0.1:{} up fails=0 conns=0\nbackend 127.0.0.1:{} unhealthy fails=0 conns=0\n",
+            a, b
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_upstream_keepalive() {
+    let (port, accepted) = spawn_keepalive_backend(false).await;
+    let (closing_port, closing_accepted) = spawn_keepalive_backend(true).await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            upstream backend {{
+                server 127.0.0.1:{};
+                keepalive 8;
+            }}
+            upstream closing {{
+                server 127.0.0.1:{};
+                keepalive 8;
+            }}
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://backend;
+                }}
+                location /closing {{
+                    proxy_pass http://closing;
+                }}
+            }}
+        }}",
+        port, closing_port
+    ))
+    .await;
+
+    for _ in 0..3 {
+        let res = get(&t.endpoint).await;
+        assert_eq!(res.text().await.unwrap(), "1");
+    }
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+    for i in 1..=3 {
+        let res = get(&format!("{}/closing", t.endpoint)).await;
+        assert_eq!(res.status().as_u16(), 200);
+        assert_eq!(res.text().await.unwrap(), i.to_string());
+    }
+    assert_eq!(closing_accepted.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_proxy_next_upstream() {
+    let bad = spawn_backend_with_status("bad", "503 Service Unavailable").await;
+    let good = spawn_backend("good").await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            upstream backend {{
+                server 127.0.0.1:{} max_fails=0;
+                server 127.0.0.1:{} max_fails=0;
+            }}
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://backend;
+                    proxy_next_upstream error http_503;
+                }}
+            }}
+        }}",
+        bad, good
+    ))
+    .await;
+
+    for _ in 0..4 {
+        let res = get(&t.endpoint).await;
+        assert_eq!(res.status().as_u16(), 200);
+        assert_eq!(res.text().await.unwrap(), "good");
+    }
+
+    // a POST that reached a peer is not sent again
+    let mut statuses = vec![];
+    for _ in 0..4 {
+        let res = reqwest::Client::new()
+            .post(&t.endpoint)
+            .header(reqwest::header::HOST, "example.com")
+            .send()
+            .await
+            .unwrap();
+        statuses.push(res.status().as_u16());
+    }
+    assert!(statuses.contains(&503));
+    assert!(statuses.contains(&200));
+}
+
+#[tokio::test]
+async fn test_proxy_timeout() {
+    let silent = spawn_silent_backend().await;
+    let good = spawn_backend("good").await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            upstream backend {{
+                server 127.0.0.1:{} max_fails=0;
+                server 127.0.0.1:{} max_fails=0;
+            }}
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://backend;
+                    proxy_read_timeout 200ms;
+                }}
+                location /silent {{
+                    proxy_pass http://127.0.0.1:{};
+                    proxy_read_timeout 200ms;
+                }}
+            }}
+        }}",
+        silent, good, silent
+    ))
+    .await;
+
+    for _ in 0..2 {
+        let res = get(&t.endpoint).await;
+        assert_eq!(res.status().as_u16(), 200);
+        assert_eq!(res.text().await.unwrap(), "good");
+    }
+
+    let res = get(&format!("{}/silent", t.endpoint)).await;
+    assert_eq!(res.status().as_u16(), 504);
+}
+
+/// Starts a backend that accepts a connection upgrading to `protocol`, and
+/// then echoes everything.
+async fn spawn_upgrade_backend(protocol: &'static str) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![];
+        let mut tmp = [0u8; 1024];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut tmp).await.unwrap();
+            buf.extend_from_slice(&tmp[..n]);
+        }
+        let head = String::from_utf8_lossy(&buf).to_ascii_lowercase();
+        assert!(head.contains(&format!("upgrade: {}\r\n", protocol)));
+        assert!(head.contains("connection: upgrade"));
+
+        let res = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: {}\r\nConnection: Upgrade\r\n\r\n",
+            protocol
+        );
+        stream.write_all(res.as_bytes()).await.unwrap();
+        loop {
+            match stream.read(&mut tmp).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => stream.write_all(&tmp[..n]).await.unwrap(),
+            }
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn test_proxy_streaming() {
+    // the backend holds the end of the body until the test has the start
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf).await;
+        let _ = stream
+            .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nfirst\r\n")
+            .await;
+        let _ = done_rx.await;
+        let _ = stream.write_all(b"4\r\nlast\r\n0\r\n\r\n").await;
+    });
+
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://127.0.0.1:{};
+                }}
+            }}
+        }}",
+        port
+    ))
+    .await;
+
+    let mut res = get(&t.endpoint).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.chunk().await.unwrap().unwrap(), "first");
+    done_tx.send(()).unwrap();
+    assert_eq!(res.chunk().await.unwrap().unwrap(), "last");
+    assert!(res.chunk().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_access_log() {
+    let backend = spawn_backend("backend").await;
+    let all = NamedTempFile::new().unwrap();
+    let proxy = NamedTempFile::new().unwrap();
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            log_format main \"$request $status \" \"$body_bytes_sent $http_x_test\";
+            access_log {} main;
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    return 200 ok;
+                }}
+                location /off {{
+                    access_log off;
+                    return 200 off;
+                }}
+                location /proxy {{
+                    access_log {};
+                    proxy_pass http://127.0.0.1:{};
+                }}
+            }}
+        }}",
+        all.path().display(),
+        proxy.path().display(),
+        backend
+    ))
+    .await;
+
+    let res = reqwest::Client::new()
+        .head(format!("{}/a?b", t.endpoint))
+        .header(reqwest::header::HOST, "example.com")
+        .header("X-Test", "\"quoted\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(
+        get(&format!("{}/off", t.endpoint))
+            .await
+            .text()
+            .await
+            .unwrap(),
+        "off"
+    );
+    assert_eq!(
+        get(&format!("{}/", t.endpoint)).await.text().await.unwrap(),
+        "ok"
+    );
+    let res = get(&format!("{}/proxy", t.endpoint)).await;
+    assert_eq!(res.text().await.unwrap(), "backend");
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    // values are escaped, and empty ones written as `-`
+    let lines = std::fs::read_to_string(all.path()).unwrap();
+    assert_eq!(
+        lines,
+        "HEAD /a?b HTTP/1.1 200 0 \\x22quoted\\x22\nGET / HTTP/1.1 200 2 -\n"
+    );
+
+    // a location with its own logs doesn't inherit those of the server
+    let lines = std::fs::read_to_string(proxy.path()).unwrap();
+    let (addr, rest) = lines.split_once(" - - [").unwrap();
+    assert_eq!(addr, "127.0.0.1");
+    let (_, rest) = rest.split_once("] ").unwrap();
+    assert_eq!(rest, "\"GET /proxy HTTP/1.1\" 200 7 \"-\" \"-\"\n");
+}
+
+#[tokio::test]
+async fn test_proxy_upgrade() {
+    let port = spawn_upgrade_backend("echo").await;
+
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://127.0.0.1:{};
+                }}
+            }}
+        }}",
+        port
+    ))
+    .await;
+
+    let mut stream = tokio::net::TcpStream::connect(t.endpoint.trim_start_matches("http://"))
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET /ws HTTP/1.1\r\nHost: example.com\r\nUpgrade: echo\r\nConnection: Upgrade\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut buf = vec![];
+    let mut tmp = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut tmp).await.unwrap();
+        buf.extend_from_slice(&tmp[..n]);
+    }
+    let head = String::from_utf8_lossy(&buf).to_ascii_lowercase();
+    assert!(head.starts_with("http/1.1 101"));
+    assert!(head.contains("upgrade: echo\r\n"));
+    assert!(!head.contains("content-length"));
+
+    for message in ["ping", "pong"] {
+        stream.write_all(message.as_bytes()).await.unwrap();
+        let n = stream.read(&mut tmp).await.unwrap();
+        assert_eq!(&tmp[..n], message.as_bytes());
+    }
+}
+
+async fn get_cached(url: &str) -> (String, String) {
+    let res = get(url).await;
+    let status = res
+        .headers()
+        .get("x-cache-status")
+        .map(|v| v.to_str().unwrap().to_owned())
+        .unwrap_or_default();
+    (status, res.text().await.unwrap())
+}
+
+#[tokio::test]
+async fn test_proxy_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let (port, count, _) = spawn_counting_backend("").await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            proxy_cache_path {} levels=1:2 keys_zone=one:1m;
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://127.0.0.1:{};
+                    proxy_cache one;
+                    proxy_cache_valid 200 1m;
+                    proxy_cache_lock on;
+                    add_header X-Cache-Status $upstream_cache_status;
+                }}
+            }}
+        }}",
+        dir.path().display(),
+        port
+    ))
+    .await;
+
+    let a = format!("{}/a", t.endpoint);
+    assert_eq!(get_cached(&a).await, ("MISS".to_owned(), "1".to_owned()));
+    assert_eq!(get_cached(&a).await, ("HIT".to_owned(), "1".to_owned()));
+    assert_eq!(
+        get_cached(&format!("{}/b", t.endpoint)).await,
+        ("MISS".to_owned(), "2".to_owned())
+    );
+    assert_eq!(get_cached(&a).await, ("HIT".to_owned(), "1".to_owned()));
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    // concurrent misses are collapsed into a single upstream request
+    let c = format!("{}/c", t.endpoint);
+    let handles = (0..4)
+        .map(|_| {
+            let c = c.clone();
+            tokio::spawn(async move { get_cached(&c).await.1 })
+        })
+        .collect::<Vec<_>>();
+    for h in handles {
+        h.await.unwrap();
+    }
+    assert_eq!(count.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_proxy_cache_stale() {
+    let dir = tempfile::tempdir().unwrap();
+    let (port, _, failing) = spawn_counting_backend("Cache-Control: max-age=1\r\n").await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            proxy_cache_path {} keys_zone=one:1m;
+            server {{
+                listen 8080;
+                server_name example.com;
+                location /error {{
+                    proxy_pass http://127.0.0.1:{};
+                    proxy_cache one;
+                    proxy_cache_use_stale error http_503;
+                    add_header X-Cache-Status $upstream_cache_status;
+                }}
+                location /updating {{
+                    proxy_pass http://127.0.0.1:{};
+                    proxy_cache one;
+                    proxy_cache_use_stale updating;
+                    proxy_cache_background_update on;
+                    add_header X-Cache-Status $upstream_cache_status;
+                }}
+            }}
+        }}",
+        dir.path().display(),
+        port,
+        port
+    ))
+    .await;
+
+    let error = format!("{}/error", t.endpoint);
+    let updating = format!("{}/updating", t.endpoint);
+    assert_eq!(
+        get_cached(&error).await,
+        ("MISS".to_owned(), "1".to_owned())
+    );
+    assert_eq!(
+        get_cached(&updating).await,
+        ("MISS".to_owned(), "2".to_owned())
+    );
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+
+    failing.store(true, Ordering::SeqCst);
+    assert_eq!(
+        get_cached(&error).await,
+        ("STALE".to_owned(), "1".to_owned())
+    );
+    failing.store(false, Ordering::SeqCst);
+    assert_eq!(
+        get_cached(&error).await,
+        ("EXPIRED".to_owned(), "4".to_owned())
+    );
+
+    assert_eq!(
+        get_cached(&updating).await,
+        ("UPDATING".to_owned(), "2".to_owned())
+    );
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(
+        get_cached(&updating).await,
+        ("HIT".to_owned(), "5".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn test_proxy_cache_too_large() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let count = Arc::new(AtomicUsize::new(0));
+    let c = count.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            c.fetch_add(1, Ordering::SeqCst);
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            // without a length, the entry is only found too large while
+            // it is stored
+            let chunk = format!("400\r\n{}\r\n", "a".repeat(1024));
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n{}{}0\r\n\r\n",
+                chunk, chunk
+            );
+            let _ = stream.write_all(res.as_bytes()).await;
+        }
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            proxy_cache_path {} keys_zone=one:1m max_size=1k;
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://127.0.0.1:{};
+                    proxy_cache one;
+                    proxy_cache_valid 200 1m;
+                    add_header X-Cache-Status $upstream_cache_status;
+                }}
+            }}
+        }}",
+        dir.path().display(),
+        port
+    ))
+    .await;
+
+    // a response larger than max_size is passed on without being stored
+    for _ in 0..2 {
+        assert_eq!(
+            get_cached(&t.endpoint).await,
+            ("MISS".to_owned(), "a".repeat(2048))
+        );
+    }
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_proxy_cache_truncated() {
+    // the backend closes the connection in the middle of the body
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let count = Arc::new(AtomicUsize::new(0));
+    let c = count.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            c.fetch_add(1, Ordering::SeqCst);
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort")
+                .await;
+        }
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            proxy_cache_path {} keys_zone=one:1m;
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://127.0.0.1:{};
+                    proxy_cache one;
+                    proxy_cache_valid 200 1m;
+                    add_header X-Cache-Status $upstream_cache_status;
+                }}
+            }}
+        }}",
+        dir.path().display(),
+        port
+    ))
+    .await;
+
+    // the head is passed on and the body fails, the entry is not stored
+    for _ in 0..2 {
+        let res = get(&t.endpoint).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["x-cache-status"], "MISS");
+        assert!(res.text().await.is_err());
+    }
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_proxy_cache_purge() {
+    let dir = tempfile::tempdir().unwrap();
+    let (port, _, _) = spawn_counting_backend("").await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            proxy_cache_path {} levels=2 keys_zone=one:1m;
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    proxy_pass http://127.0.0.1:{};
+                    proxy_cache one;
+                    proxy_cache_key $request_uri;
+                    proxy_cache_valid 1m;
+                    proxy_cache_purge on from 127.0.0.1 ::1;
+                    add_header X-Cache-Status $upstream_cache_status;
+                }}
+                location /denied {{
+                    proxy_pass http://127.0.0.1:{};
+                    proxy_cache one;
+                    proxy_cache_purge on from 10.0.0.0/8;
+                }}
+            }}
+        }}",
+        dir.path().display(),
+        port,
+        port
+    ))
+    .await;
+
+    let purge = |path: &str| {
+        reqwest::Client::new()
+            .request(
+                reqwest::Method::from_bytes(b"PURGE").unwrap(),
+                format!("{}{}", t.endpoint, path),
+            )
+            .header(reqwest::header::HOST, "example.com")
+            .send()
+    };
+
+    for path in ["/a", "/img/b", "/img/c"] {
+        get(&format!("{}{}", t.endpoint, path)).await;
+    }
+
+    let ls = |dir: &std::path::Path| {
+        let output = assert_cmd::Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args(["cache", "ls", dir.to_str().unwrap()])
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|v| v.split('\t').next().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ls(dir.path()), vec!["/a", "/img/b", "/img/c"]);
+
+    // only the clients of `from` may purge
+    assert_eq!(purge("/denied").await.unwrap().status().as_u16(), 403);
+
+    assert_eq!(purge("/a").await.unwrap().status().as_u16(), 200);
+    assert_eq!(purge("/a").await.unwrap().status().as_u16(), 404);
+    assert_eq!(
+        get_cached(&format!("{}/a", t.endpoint)).await,
+        ("MISS".to_owned(), "4".to_owned())
+    );
+
+    assert_eq!(purge("/img/*").await.unwrap().status().as_u16(), 200);
+    assert_eq!(ls(dir.path()), vec!["/a"]);
+
+    assert_cmd::Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .args(["cache", "rm", dir.path().to_str().unwrap(), "/a"])
+        .assert()
+        .success()
+        .stdout("/a\n");
+    assert!(ls(dir.path()).is_empty());
+}
+
+/// Answers one FastCGI request with `Status: 201`, a body listing some of
+/// the received parameters and the request body, and a line on stderr.
+async fn respond_fastcgi<S>(mut stream: S)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut params = vec![];
+    let mut stdin = vec![];
+    loop {
+        let mut head = [0u8; 8];
+        if stream.read_exact(&mut head).await.is_err() {
+            return;
+        }
+        let length = u16::from_be_bytes([head[4], head[5]]) as usize;
+        let mut content = vec![0u8; length + head[6] as usize];
+        stream.read_exact(&mut content).await.unwrap();
+        content.truncate(length);
+
+        match head[1] {
+            4 => params.extend(content),
+            5 if content.is_empty() => break,
+            5 => stdin.extend(content),
+            _ => {}
+        }
+    }
+
+    let mut decoded = std::collections::HashMap::new();
+    let mut rest = params.as_slice();
+    while !rest.is_empty() {
+        let mut length = || {
+            if rest[0] < 0x80 {
+                let n = rest[0] as usize;
+                rest = &rest[1..];
+                n
+            } else {
+                let n = u32::from_be_bytes([rest[0] & 0x7f, rest[1], rest[2], rest[3]]) as usize;
+                rest = &rest[4..];
+                n
+            }
+        };
+        let (name, value) = (length(), length());
+        decoded.insert(
+            String::from_utf8(rest[..name].to_vec()).unwrap(),
+            String::from_utf8(rest[name..name + value].to_vec()).unwrap(),
+        );
+        rest = &rest[name + value..];
+    }
+    let param = |name: &str| decoded.get(name).cloned().unwrap_or_default();
+
+    let record = |kind: u8, content: &[u8]| {
+        let mut record = vec![1, kind, 0, 1];
+        record.extend((content.len() as u16).to_be_bytes());
+        record.extend([0, 0]);
+        record.extend(content);
+        record
+    };
+    let body = format!(
+        "{}|{}|{}|{}|{}|{}",
+        param("SCRIPT_FILENAME"),
+        param("PATH_INFO"),
+        param("QUERY_STRING"),
+        param("HTTP_X_TEST"),
+        param("CONTENT_LENGTH"),
+        String::from_utf8_lossy(&stdin),
+    );
+
+    let mut res = record(
+        6,
+        b"Status: 201 Created\r\nContent-Type: text/plain\r\nContent-Length: 1\r\n\r\n",
+    );
+    res.extend(record(6, body.as_bytes()));
+    res.extend(record(7, b"PHP Notice: test\n"));
+    res.extend(record(6, b"|end"));
+    res.extend(record(3, &[0; 8]));
+    stream.write_all(&res).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_fastcgi_pass() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(respond_fastcgi(stream));
+        }
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("fcgi.sock");
+    let unix_listener = tokio::net::UnixListener::bind(&socket).unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = unix_listener.accept().await.unwrap();
+            tokio::spawn(respond_fastcgi(stream));
+        }
+    });
+
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    root /var/www;
+                    fastcgi_pass 127.0.0.1:{};
+                    fastcgi_index index.php;
+                    fastcgi_split_path_info ^(.+\\.php)(/.*)$;
+                    fastcgi_param SCRIPT_FILENAME $document_root$fastcgi_script_name;
+                    fastcgi_param PATH_INFO $fastcgi_path_info;
+                    fastcgi_param QUERY_STRING $query_string;
+                    fastcgi_param CONTENT_LENGTH $content_length;
+                }}
+                location /unix/ {{
+                    fastcgi_pass unix:{};
+                    fastcgi_param SCRIPT_FILENAME /srv$fastcgi_script_name;
+                    fastcgi_buffering off;
+                }}
+            }}
+        }}",
+        port,
+        socket.display()
+    ))
+    .await;
+
+    let res = reqwest::Client::new()
+        .post(format!("{}/app/test.php/extra?a=1", t.endpoint))
+        .header(reqwest::header::HOST, "example.com")
+        .header("X-Test", "header")
+        .body("request body")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 201);
+    assert_eq!(res.headers()["content-type"], "text/plain");
+    assert_eq!(
+        res.text().await.unwrap(),
+        "/var/www/app/test.php|/extra|a=1|header|12|request body|end"
+    );
+
+    let res = get(&format!("{}/app/", t.endpoint)).await;
+    assert_eq!(res.text().await.unwrap(), "/var/www/app/index.php||||||end");
+
+    let res = get(&format!("{}/unix/index.php", t.endpoint)).await;
+    assert_eq!(res.status().as_u16(), 201);
+    assert_eq!(res.text().await.unwrap(), "/srv/unix/index.php||||||end");
+
+    // the length of a chunked body is passed as well
+    let address = t.endpoint.strip_prefix("http://").unwrap();
+    let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(
+            b"POST /app/test.php HTTP/1.1\r\nHost: example.com\r\n\
+              Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+              5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await.unwrap();
+    assert!(res.starts_with("HTTP/1.1 201 Created\r\n"));
+    assert!(res.ends_with("/var/www/app/test.php||||11|hello world|end"));
+}
+
+#[tokio::test]
+async fn test_fastcgi_pass_error() {
+    let closed = closed_port().await;
+    let silent = spawn_silent_backend().await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080;
+                server_name example.com;
+                location /closed {{
+                    fastcgi_pass 127.0.0.1:{};
+                }}
+                location /silent {{
+                    fastcgi_pass 127.0.0.1:{};
+                    fastcgi_read_timeout 500ms;
+                }}
+            }}
+        }}",
+        closed, silent
+    ))
+    .await;
+
+    let res = get(&format!("{}/closed", t.endpoint)).await;
+    assert_eq!(res.status().as_u16(), 502);
+    let res = get(&format!("{}/silent", t.endpoint)).await;
+    assert_eq!(res.status().as_u16(), 504);
+}
+
+/// Variables and body of a request received by an application server.
+type GatewayRequest = (std::collections::HashMap<String, String>, Vec<u8>);
+
+/// Starts an application server that reads one request with `read_request`,
+/// which returns the request variables and body, and answers with `head`
+/// followed by the values of `REQUEST_URI` and `HTTP_X_TEST` and the body.
+async fn spawn_gateway_backend(
+    read_request: fn(&[u8]) -> Option<GatewayRequest>,
+    head: &'static str,
+) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = vec![];
+                let mut tmp = [0u8; 1024];
+                let (vars, body) = loop {
+                    if let Some(request) = read_request(&buf) {
+                        break request;
+                    }
+                    match stream.read(&mut tmp).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&tmp[..n]),
+                    }
+                };
+
+                let var = |name: &str| vars.get(name).cloned().unwrap_or_default();
+                let res = format!(
+                    "{}{}|{}|{}",
+                    head,
+                    var("REQUEST_URI"),
+                    var("HTTP_X_TEST"),
+                    String::from_utf8_lossy(&body)
+                );
+                let _ = stream.write_all(res.as_bytes()).await;
+            });
+        }
+    });
+
+    port
+}
+
+/// Returns the variables and body of a complete request in `buf`, whose
+/// `CONTENT_LENGTH` tells the length of the body following `vars_end`.
+fn split_gateway_request(
+    vars: Vec<(String, String)>,
+    buf: &[u8],
+    vars_end: usize,
+) -> Option<GatewayRequest> {
+    let vars: std::collections::HashMap<_, _> = vars.into_iter().collect();
+    let length: usize = vars.get("CONTENT_LENGTH")?.parse().ok()?;
+    let body = buf.get(vars_end..vars_end + length)?.to_vec();
+    Some((vars, body))
+}
+
+#[tokio::test]
+async fn test_uwsgi_pass() {
+    let port = spawn_gateway_backend(
+        |buf| {
+            let size = u16::from_le_bytes([*buf.get(1)?, *buf.get(2)?]) as usize;
+            let mut rest = buf.get(4..4 + size)?;
+            let mut vars = vec![];
+            let take = |rest: &mut &[u8]| {
+                let n = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+                let v = String::from_utf8(rest[2..2 + n].to_vec()).unwrap();
+                *rest = &rest[2 + n..];
+                v
+            };
+            while !rest.is_empty() {
+                vars.push((take(&mut rest), take(&mut rest)));
+            }
+            split_gateway_request(vars, buf, 4 + size)
+        },
+        "HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\n\r\n",
+    )
+    .await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    uwsgi_pass 127.0.0.1:{};
+                    uwsgi_param REQUEST_URI $request_uri;
+                    uwsgi_buffering off;
+                }}
+            }}
+        }}",
+        port
+    ))
+    .await;
+
+    let res = reqwest::Client::new()
+        .post(format!("{}/app?a=1", t.endpoint))
+        .header(reqwest::header::HOST, "example.com")
+        .header("X-Test", "header")
+        .body("request body")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 201);
+    assert_eq!(res.headers()["content-type"], "text/plain");
+    assert_eq!(res.text().await.unwrap(), "/app?a=1|header|request body");
+}
+
+#[tokio::test]
+async fn test_scgi_pass() {
+    let port = spawn_gateway_backend(
+        |buf| {
+            let colon = buf.iter().position(|v| *v == b':')?;
+            let size: usize = std::str::from_utf8(&buf[..colon]).ok()?.parse().ok()?;
+            let headers = buf.get(colon + 1..colon + 1 + size)?;
+            let mut fields = headers
+                .split(|v| *v == 0)
+                .map(|v| String::from_utf8_lossy(v).into_owned());
+            let mut vars = vec![];
+            while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+                vars.push((name, value));
+            }
+            assert_eq!(vars[0].0, "CONTENT_LENGTH");
+            assert_eq!(vars[1], ("SCGI".to_owned(), "1".to_owned()));
+            split_gateway_request(vars, buf, colon + size + 2)
+        },
+        "Status: 202 Accepted\r\nContent-Type: text/plain\r\n\r\n",
+    )
+    .await;
+    let closed = closed_port().await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            upstream app {{
+                server 127.0.0.1:{};
+                server 127.0.0.1:{};
+            }}
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    scgi_pass app;
+                    scgi_param REQUEST_URI $request_uri;
+                    scgi_read_timeout 5s;
+                }}
+            }}
+        }}",
+        closed, port
+    ))
+    .await;
+
+    // the peer that can't be connected to is passed over
+    for _ in 0..2 {
+        let res = reqwest::Client::new()
+            .post(format!("{}/scgi", t.endpoint))
+            .header(reqwest::header::HOST, "example.com")
+            .header("X-Test", "header")
+            .body("request body")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), 202);
+        assert_eq!(res.headers()["content-length"], "25");
+        assert_eq!(res.text().await.unwrap(), "/scgi|header|request body");
+    }
+}
+
+/// Starts an HTTP/2 backend that answers gRPC calls with the request body,
+/// some request headers echoed as `x-echo-*` headers, and `grpc-status: 0`
+/// in the trailers.
+async fn spawn_grpc_backend() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut conn = h2::server::handshake(stream).await.unwrap();
+                while let Some(Ok((req, mut respond))) = conn.accept().await {
+                    tokio::spawn(async move {
+                        let (parts, mut body) = req.into_parts();
+                        let mut data = vec![];
+                        while let Some(chunk) = body.data().await {
+                            let chunk = chunk.unwrap();
+                            let _ = body.flow_control().release_capacity(chunk.len());
+                            data.extend(chunk);
+                        }
+
+                        let mut res = http::Response::builder()
+                            .header("content-type", "application/grpc")
+                            .header("x-echo-path", parts.uri.path());
+                        for name in ["te", "x-custom", "user-agent"] {
+                            if let Some(v) = parts.headers.get(name) {
+                                res = res.header(format!("x-echo-{}", name), v);
+                            }
+                        }
+                        let mut send = respond.send_response(res.body(()).unwrap(), false).unwrap();
+                        send.send_data(bytes::Bytes::from(data), false).unwrap();
+
+                        let mut trailers = http::HeaderMap::new();
+                        trailers.insert("grpc-status", "0".parse().unwrap());
+                        trailers.insert("grpc-message", "done".parse().unwrap());
+                        send.send_trailers(trailers).unwrap();
+                    });
+                }
+            });
+        }
+    });
+
+    port
+}
+
+#[tokio::test]
+async fn test_grpc_pass() {
+    let port = spawn_grpc_backend().await;
+    let closed = closed_port().await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080;
+                server_name example.com;
+                location / {{
+                    grpc_pass grpc://127.0.0.1:{};
+                    grpc_set_header X-Custom $request_method;
+                    grpc_set_header User-Agent \"\";
+                }}
+                location /down/ {{
+                    grpc_pass grpc://127.0.0.1:{};
+                }}
+            }}
+        }}",
+        port, closed
+    ))
+    .await;
+
+    let address = t.endpoint.strip_prefix("http://").unwrap();
+    let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    for _ in 0..2 {
+        stream
+            .write_all(
+                b"POST /pkg.Echo/Call HTTP/1.1\r\nHost: example.com\r\n\
+                  Content-Type: application/grpc\r\nTE: trailers\r\nUser-Agent: test\r\n\
+                  Content-Length: 7\r\n\r\nmessage",
+            )
+            .await
+            .unwrap();
+
+        // the trailers follow the last chunk of the response
+        let mut buf = vec![];
+        let mut tmp = [0u8; 1024];
+        while !(buf.ends_with(b"\r\n\r\n") && buf.windows(5).any(|w| w == b"\r\n0\r\n")) {
+            let n = stream.read(&mut tmp).await.unwrap();
+            assert_ne!(n, 0);
+            buf.extend_from_slice(&tmp[..n]);
+        }
+
+        let res = String::from_utf8(buf).unwrap().to_ascii_lowercase();
+        let (head, body) = res.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("http/1.1 200 ok\r\n"));
+        assert!(head.contains("transfer-encoding: chunked"));
+        assert!(head.contains("x-echo-path: /pkg.echo/call\r\n"));
+        assert!(head.contains("x-echo-te: trailers\r\n"));
+        assert!(head.contains("x-echo-x-custom: post\r\n"));
+        assert!(!head.contains("x-echo-user-agent"));
+        assert_eq!(
+            body,
+            "7\r\nmessage\r\n0\r\ngrpc-status: 0\r\ngrpc-message: done\r\n\r\n"
+        );
+    }
+
+    let res = reqwest::Client::new()
+        .post(format!("{}/down/pkg.Echo/Call", t.endpoint))
+        .header(reqwest::header::HOST, "example.com")
+        .header(reqwest::header::CONTENT_TYPE, "application/grpc")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers()["content-type"], "application/grpc");
+    assert_eq!(res.headers()["grpc-status"], "14");
+}
+
+#[tokio::test]
+async fn test_http2_prior_knowledge() {
+    let backend = spawn_backend("backend").await;
+    let grpc = spawn_grpc_backend().await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080;
+                server_name example.com;
+                http2 on;
+                http2_max_concurrent_streams 10;
+                location / {{
+                    proxy_pass http://127.0.0.1:{};
+                }}
+                location /pkg.Echo/ {{
+                    grpc_pass grpc://127.0.0.1:{};
+                }}
+            }}
+        }}",
+        backend, grpc
+    ))
+    .await;
+
+    let address = t.endpoint.strip_prefix("http://").unwrap();
+    let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    let (client, mut conn) = h2::client::handshake(stream).await.unwrap();
+    let max_streams = Arc::new(AtomicUsize::new(0));
+    let max = max_streams.clone();
+    tokio::spawn(std::future::poll_fn(move |cx| {
+        let polled = std::pin::Pin::new(&mut conn).poll(cx);
+        max.store(conn.max_concurrent_send_streams(), Ordering::SeqCst);
+        polled
+    }));
+    let mut client = client.ready().await.unwrap();
+
+    // the host comes from :authority and selects the server
+    let req = http::Request::get("http://example.com/").body(()).unwrap();
+    let (res, _) = client.send_request(req, true).unwrap();
+    let res = res.await.unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers()["content-length"], "7");
+    let mut body = res.into_body();
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        data.extend(chunk.unwrap());
+    }
+    assert_eq!(data, b"backend");
+    assert_eq!(max_streams.load(Ordering::SeqCst), 10);
+
+    // request bodies and trailers pass through in both directions
+    let mut client = client.ready().await.unwrap();
+    let req = http::Request::post("http://example.com/pkg.Echo/Call")
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(())
+        .unwrap();
+    let (res, mut send) = client.send_request(req, false).unwrap();
+    send.send_data(bytes::Bytes::from_static(b"message"), true)
+        .unwrap();
+    let res = res.await.unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.headers()["x-echo-path"], "/pkg.Echo/Call");
+    let mut body = res.into_body();
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        data.extend(chunk.unwrap());
+    }
+    assert_eq!(data, b"message");
+    let trailers = body.trailers().await.unwrap().unwrap();
+    assert_eq!(trailers["grpc-status"], "0");
+}
+
+#[tokio::test]
+async fn test_http2_idle_timeout() {
+    // a backend taking longer than the idle timeout to answer
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                tokio::time::sleep(std::time::Duration::from_millis(2200)).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\nslow",
+                    )
+                    .await;
+            });
+        }
+    });
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080;
+                server_name example.com;
+                http2 on;
+                http2_idle_timeout 1s;
+                location / {{
+                    proxy_pass http://127.0.0.1:{};
+                }}
+            }}
+        }}",
+        backend
+    ))
+    .await;
+
+    let address = t.endpoint.strip_prefix("http://").unwrap();
+    let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    let (client, conn) = h2::client::handshake(stream).await.unwrap();
+    let conn = tokio::spawn(conn);
+    let mut client = client.ready().await.unwrap();
+
+    // the idle timeout doesn't apply while a stream is open
+    let req = http::Request::get("http://example.com/").body(()).unwrap();
+    let (res, _) = client.send_request(req, true).unwrap();
+    let res = res.await.unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let mut body = res.into_body();
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        data.extend(chunk.unwrap());
+    }
+    assert_eq!(data, b"slow");
+
+    // and then closes the connection once no stream is open
+    let closed = tokio::time::timeout(std::time::Duration::from_secs(3), conn).await;
+    assert!(closed.is_ok());
+}
+
+#[tokio::test]
+async fn test_http2_upgrade() {
+    let backend = spawn_backend("backend").await;
+    let upgrade = spawn_upgrade_backend("h2c").await;
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080;
+                server_name example.com;
+                http2 on;
+                location / {{
+                    proxy_pass http://127.0.0.1:{};
+                }}
+            }}
+            server {{
+                listen 8080;
+                server_name other.com;
+                location / {{
+                    proxy_pass http://127.0.0.1:{};
+                }}
+            }}
+        }}",
+        backend, upgrade
+    ))
+    .await;
+
+    // HTTP2-Settings sets an initial window of 4 bytes
+    let request = |host: &str| {
+        format!(
+            "GET / HTTP/1.1\r\nHost: {}\r\n\
+             Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\n\
+             HTTP2-Settings: AAQAAAAE\r\n\r\n",
+            host
+        )
+    };
+
+    let address = t.endpoint.strip_prefix("http://").unwrap();
+    let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(request("example.com").as_bytes())
+        .await
+        .unwrap();
+
+    let mut buf = vec![];
+    let mut tmp = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut tmp).await.unwrap();
+        assert_ne!(n, 0);
+        buf.extend_from_slice(&tmp[..n]);
+    }
+    let head = String::from_utf8_lossy(&buf).to_ascii_lowercase();
+    assert!(head.starts_with("http/1.1 101 switching protocols\r\n"));
+    assert!(head.contains("upgrade: h2c\r\n"));
+
+    // the response to the upgraded request is sent on stream 1, within the
+    // window of the settings until it's updated
+    stream
+        .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x00\x00\x00\x00\x00")
+        .await
+        .unwrap();
+    let mut data = vec![];
+    let mut end = false;
+    while data.len() < 4 {
+        let (kind, flags, stream_id, payload) = read_frame(&mut stream).await;
+        if kind == 0x0 && stream_id == 1 {
+            data.extend(payload);
+            end = flags & 0x1 != 0;
+        }
+    }
+    assert_eq!(data, b"back");
+    assert!(!end);
+
+    stream
+        .write_all(b"\x00\x00\x04\x08\x00\x00\x00\x00\x01\x00\x00\x00\x64")
+        .await
+        .unwrap();
+    while !end {
+        let (kind, flags, stream_id, payload) = read_frame(&mut stream).await;
+        if kind == 0x0 && stream_id == 1 {
+            data.extend(payload);
+            end = flags & 0x1 != 0;
+        }
+    }
+    assert_eq!(data, b"backend");
+
+    // a server without http2 passes the upgrade to its backend
+    let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(request("other.com").as_bytes())
+        .await
+        .unwrap();
+    let mut buf = vec![];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut tmp).await.unwrap();
+        assert_ne!(n, 0);
+        buf.extend_from_slice(&tmp[..n]);
+    }
+    let head = String::from_utf8_lossy(&buf).to_ascii_lowercase();
+    assert!(head.starts_with("http/1.1 101"));
+    assert!(head.contains("upgrade: h2c\r\n"));
+    stream.write_all(b"ping").await.unwrap();
+    let n = stream.read(&mut tmp).await.unwrap();
+    assert_eq!(&tmp[..n], b"ping");
+}
+
+/// Reads an HTTP/2 frame, returning its type, flags, stream and payload.
+async fn read_frame(stream: &mut tokio::net::TcpStream) -> (u8, u8, u32, Vec<u8>) {
+    let mut header = [0u8; 9];
+    stream.read_exact(&mut header).await.unwrap();
+    let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await.unwrap();
+    let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+    (header[3], header[4], stream_id, payload)
+}
+
+/// Self-signed certificate and its key, written to PEM files.
+struct TestCert {
+    der: Vec<u8>,
+    key_der: Vec<u8>,
+    cert: NamedTempFile,
+    key: NamedTempFile,
+    signer: rcgen::Certificate,
+}
+
+impl TestCert {
+    fn new(name: &str) -> TestCert {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+        let pem = cert.serialize_pem().unwrap();
+        TestCert::write(cert, pem)
+    }
+
+    /// Creates a CA whose certificate can be used with `ssl_client_certificate`.
+    fn ca(name: &str) -> TestCert {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let pem = cert.serialize_pem().unwrap();
+        TestCert::write(cert, pem)
+    }
+
+    /// Creates a client certificate for `name` issued by `ca`.
+    fn client(name: &str, ca: &TestCert) -> TestCert {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        params.serial_number = Some(rcgen::SerialNumber::from_slice(&[0x1a, 0x2b]));
+        params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let pem = cert.serialize_pem_with_signer(&ca.signer).unwrap();
+        TestCert::write(cert, pem)
+    }
+
+    /// Writes `pem` and the key of `cert`. The DER form is read back from
+    /// `pem`, as each serialization signs the certificate again.
+    fn write(cert: rcgen::Certificate, pem: String) -> TestCert {
+        let der = rustls_pemfile::certs(&mut pem.as_bytes())
+            .unwrap()
+            .remove(0);
+        let cert_file = NamedTempFile::new().unwrap();
+        std::fs::write(cert_file.path(), pem).unwrap();
+        let key_file = NamedTempFile::new().unwrap();
+        std::fs::write(key_file.path(), cert.serialize_private_key_pem()).unwrap();
+
+        TestCert {
+            der,
+            key_der: cert.serialize_private_key_der(),
+            cert: cert_file,
+            key: key_file,
+            signer: cert,
+        }
+    }
+
+    fn directives(&self) -> String {
+        format!(
+            "ssl_certificate {}; ssl_certificate_key {};",
+            self.cert.path().display(),
+            self.key.path().display()
+        )
+    }
+}
+
+/// Connects to `endpoint` with TLS, trusting `certs` and offering `alpn`.
+async fn connect_tls(
+    endpoint: &str,
+    server_name: &str,
+    certs: &[&TestCert],
+    versions: &[&'static rustls::SupportedProtocolVersion],
+    alpn: &[&[u8]],
+) -> std::io::Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+    connect_tls_with_client(endpoint, server_name, certs, versions, alpn, None).await
+}
+
+/// Same as `connect_tls`, presenting `client` as the client certificate.
+async fn connect_tls_with_client(
+    endpoint: &str,
+    server_name: &str,
+    certs: &[&TestCert],
+    versions: &[&'static rustls::SupportedProtocolVersion],
+    alpn: &[&[u8]],
+    client: Option<&TestCert>,
+) -> std::io::Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in certs {
+        roots.add(&rustls::Certificate(cert.der.clone())).unwrap();
+    }
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .unwrap()
+        .with_root_certificates(roots);
+    let mut config = match client {
+        Some(client) => builder
+            .with_client_auth_cert(
+                vec![rustls::Certificate(client.der.clone())],
+                rustls::PrivateKey(client.key_der.clone()),
+            )
+            .unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn.iter().map(|v| v.to_vec()).collect();
+
+    let address = endpoint.strip_prefix("http://").unwrap();
+    let stream = tokio::net::TcpStream::connect(address).await?;
+    tokio_rustls::TlsConnector::from(Arc::new(config))
+        .connect(server_name.try_into().unwrap(), stream)
+        .await
+}
+
+/// Sends a request on `stream` and returns the response, which is read until
+/// the connection is closed.
+async fn request_tls<S>(mut stream: S, host: &str, path: &str) -> String
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let req = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(req.as_bytes()).await.unwrap();
+    stream.flush().await.unwrap();
+    let mut buf = vec![];
+    let _ = stream.read_to_end(&mut buf).await;
+    String::from_utf8(buf).unwrap()
+}
+
+#[tokio::test]
+async fn test_tls() {
+    let example = TestCert::new("example.com");
+    let other = TestCert::new("other.com");
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080 ssl;
+                server_name example.com;
+                {}
+                http2 on;
+                location / {{
+                    add_header X-Tls \"$scheme $https\";
+                    return 200 example;
+                }}
+            }}
+            server {{
+                listen 8080 ssl;
+                server_name other.com;
+                {}
+                location / {{
+                    add_header X-Tls \"$ssl_protocol $ssl_server_name\";
+                    return 200 other;
+                }}
+            }}
+            server {{
+                listen 8081 ssl;
+                server_name example.com;
+                {}
+                ssl_protocols TLSv1.2;
+                ssl_ciphers TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256;
+                http2 off;
+                location / {{
+                    add_header X-Tls \"$ssl_protocol $ssl_cipher\";
+                    return 200 ok;
+                }}
+            }}
+        }}",
+        example.directives(),
+        other.directives(),
+        example.directives()
+    ))
+    .await;
+    let all = [&rustls::version::TLS12, &rustls::version::TLS13];
+
+    // the certificate is selected by SNI
+    for (name, cert, header) in [
+        ("example.com", &example, "x-tls: https on\r\n"),
+        ("other.com", &other, "x-tls: TLSv1.3 other.com\r\n"),
+    ] {
+        let stream = connect_tls(&t.endpoint, name, &[cert], &all, &[b"http/1.1"])
+            .await
+            .unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+        let res = request_tls(stream, name, "/").await;
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.contains(header));
+        assert!(res.ends_with(name.split('.').next().unwrap()));
+    }
+    assert!(
+        connect_tls(&t.endpoint, "other.com", &[&example], &all, &[])
+            .await
+            .is_err()
+    );
+
+    // h2 is negotiated with ALPN
+    let stream = connect_tls(&t.endpoint, "example.com", &[&example], &all, &[b"h2"])
+        .await
+        .unwrap();
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    let (client, conn) = h2::client::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    let req = http::Request::get("https://example.com/").body(()).unwrap();
+    let (res, _) = client
+        .ready()
+        .await
+        .unwrap()
+        .send_request(req, true)
+        .unwrap();
+    let res = res.await.unwrap();
+    assert_eq!(res.headers()["x-tls"], "https on");
+    let mut body = res.into_body();
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        data.extend(chunk.unwrap());
+    }
+    assert_eq!(data, b"example");
+
+    // protocol versions and cipher suites are restricted
+    assert!(connect_tls(
+        &t.endpoint_2,
+        "example.com",
+        &[&example],
+        &[&rustls::version::TLS13],
+        &[]
+    )
+    .await
+    .is_err());
+    let stream = connect_tls(
+        &t.endpoint_2,
+        "example.com",
+        &[&example],
+        &all,
+        &[b"h2", b"http/1.1"],
+    )
+    .await
+    .unwrap();
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+    let res = request_tls(stream, "example.com", "/").await;
+    assert!(res.contains("x-tls: TLSv1.2 TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256\r\n"));
+}
+
+#[tokio::test]
+async fn test_tls_default_server() {
+    let example = TestCert::new("example.com");
+    let other = TestCert::new("other.com");
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080 ssl;
+                server_name example.com;
+                {}
+                http2 off;
+                location / {{
+                    return 200 example;
+                }}
+            }}
+            server {{
+                listen 8080 ssl default_server;
+                server_name other.com;
+                {}
+                http2 on;
+                location / {{
+                    return 200 other;
+                }}
+            }}
+        }}",
+        example.directives(),
+        other.directives()
+    ))
+    .await;
+    let all = [&rustls::version::TLS12, &rustls::version::TLS13];
+
+    // ALPN follows the HTTP/2 settings of the default server
+    let stream = connect_tls(&t.endpoint, "example.com", &[&example], &all, &[b"h2"])
+        .await
+        .unwrap();
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+    // an unknown name gets the certificate of the default server
+    let (_, der, _) = connect_stapled(&t.endpoint, "example.com").await;
+    assert_eq!(der, example.der);
+    let (_, der, _) = connect_stapled(&t.endpoint, "unknown.com").await;
+    assert_eq!(der, other.der);
+}
+
+/// Starts a backend that answers every request with the request head as the
+/// body.
+async fn spawn_echo_backend() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = vec![];
+                let mut tmp = [0u8; 1024];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut tmp).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&tmp[..n]),
+                    }
+                }
+
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    buf.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&buf).await;
+            });
+        }
+    });
+
+    port
+}
+
+#[tokio::test]
+async fn test_tls_client_verify() {
+    let server = TestCert::new("example.com");
+    let ca = TestCert::ca("Test CA");
+    let client = TestCert::client("client", &ca);
+    let untrusted = TestCert::client("intruder", &TestCert::ca("Other CA"));
+    let backend = spawn_echo_backend().await;
+    let log = NamedTempFile::new().unwrap();
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            log_format tls \"$status $ssl_client_verify $ssl_client_s_dn\";
+            server {{
+                listen 8080 ssl;
+                access_log {} tls;
+                server_name example.com;
+                {}
+                ssl_client_certificate {};
+                ssl_verify_client on;
+                location / {{
+                    proxy_set_header X-Client-Verify $ssl_client_verify;
+                    proxy_set_header X-Client-DN $ssl_client_s_dn;
+                    proxy_set_header X-Client-Issuer $ssl_client_i_dn;
+                    proxy_set_header X-Client-Serial $ssl_client_serial;
+                    proxy_pass http://127.0.0.1:{};
+                }}
+            }}
+            server {{
+                listen 8081 ssl;
+                server_name example.com;
+                {}
+                ssl_verify_client optional_no_ca;
+                location / {{
+                    add_header X-Client-Verify \"$ssl_client_verify\";
+                    return 200 ok;
+                }}
+            }}
+        }}",
+        log.path().display(),
+        server.directives(),
+        ca.cert.path().display(),
+        backend,
+        server.directives(),
+    ))
+    .await;
+    let all = [&rustls::version::TLS12, &rustls::version::TLS13];
+
+    // a trusted certificate is passed to the upstream
+    let stream = connect_tls_with_client(
+        &t.endpoint,
+        "example.com",
+        &[&server],
+        &all,
+        &[],
+        Some(&client),
+    )
+    .await
+    .unwrap();
+    let res = request_tls(stream, "example.com", "/").await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(res.contains("x-client-verify: SUCCESS\r\n"));
+    assert!(res.contains("x-client-dn: CN=client\r\n"));
+    assert!(res.contains("x-client-issuer: CN=Test CA\r\n"));
+    assert!(res.contains("x-client-serial: 1A2B\r\n"));
+
+    // a missing or untrusted certificate is rejected
+    let stream = connect_tls(&t.endpoint, "example.com", &[&server], &all, &[])
+        .await
+        .unwrap();
+    let res = request_tls(stream, "example.com", "/").await;
+    assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(res.ends_with("No required SSL certificate was sent"));
+    let stream = connect_tls_with_client(
+        &t.endpoint,
+        "example.com",
+        &[&server],
+        &all,
+        &[],
+        Some(&untrusted),
+    )
+    .await
+    .unwrap();
+    let res = request_tls(stream, "example.com", "/").await;
+    assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(res.ends_with("The SSL certificate error"));
+
+    // the client certificate variables are usable in access logs
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let lines = std::fs::read_to_string(log.path()).unwrap();
+    let lines: Vec<_> = lines.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "200 SUCCESS CN=client");
+    assert_eq!(lines[1], "400 NONE -");
+    assert!(lines[2].starts_with("400 FAILED:"));
+    assert!(lines[2].ends_with(" CN=intruder"));
+
+    // optional_no_ca only reports the result
+    let stream = connect_tls_with_client(
+        &t.endpoint_2,
+        "example.com",
+        &[&server],
+        &all,
+        &[],
+        Some(&untrusted),
+    )
+    .await
+    .unwrap();
+    let res = request_tls(stream, "example.com", "/").await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(res.contains("x-client-verify: FAILED:"));
+}
+
+#[tokio::test]
+async fn test_tls_client_verify_sni() {
+    let example = TestCert::new("example.com");
+    let secure = TestCert::new("secure.com");
+    let ca = TestCert::ca("Test CA");
+    let client = TestCert::client("client", &ca);
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080 ssl;
+                server_name example.com;
+                {}
+                location / {{
+                    return 200 open;
+                }}
+            }}
+            server {{
+                listen 8080 ssl;
+                server_name secure.com;
+                {}
+                ssl_client_certificate {};
+                ssl_verify_client on;
+                location / {{
+                    return 200 \"secure $ssl_client_verify\";
+                }}
+            }}
+        }}",
+        example.directives(),
+        secure.directives(),
+        ca.cert.path().display(),
+    ))
+    .await;
+    let all = [&rustls::version::TLS12, &rustls::version::TLS13];
+    let roots = [&example, &secure];
+
+    // the server selected by SNI verifies the client certificate
+    let stream = connect_tls(&t.endpoint, "secure.com", &roots, &all, &[])
+        .await
+        .unwrap();
+    let res = request_tls(stream, "secure.com", "/").await;
+    assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(res.ends_with("No required SSL certificate was sent"));
+    let stream =
+        connect_tls_with_client(&t.endpoint, "secure.com", &roots, &all, &[], Some(&client))
+            .await
+            .unwrap();
+    let res = request_tls(stream, "secure.com", "/").await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(res.ends_with("secure SUCCESS"));
+
+    // a host whose server verifies client certificates differently from
+    // that of the SNI name is rejected
+    let stream = connect_tls(&t.endpoint, "example.com", &roots, &all, &[])
+        .await
+        .unwrap();
+    let res = request_tls(stream, "secure.com", "/").await;
+    assert!(res.starts_with("HTTP/1.1 421 Misdirected Request\r\n"));
+    let stream = connect_tls(&t.endpoint, "example.com", &roots, &all, &[])
+        .await
+        .unwrap();
+    let res = request_tls(stream, "example.com", "/").await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(res.ends_with("open"));
+    drop(t);
+
+    // QUIC can't request a client certificate by SNI
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080 quic;
+                server_name example.com;
+                {}
+            }}
+            server {{
+                listen 8080 quic;
+                server_name secure.com;
+                {}
+                ssl_client_certificate {};
+                ssl_verify_client on;
+            }}
+            server {{
+                listen 8081;
+                server_name example.com;
+            }}
+        }}",
+        example.directives(),
+        secure.directives(),
+        ca.cert.path().display(),
+    ))
+    .await;
+    let address = t.endpoint_2.strip_prefix("http://").unwrap();
+    assert!(tokio::net::TcpStream::connect(address).await.is_err());
+}
+
+/// Accepts any server certificate, keeping the stapled OCSP response.
+struct OcspRecorder(std::sync::Mutex<Vec<u8>>);
+
+impl rustls::client::ServerCertVerifier for OcspRecorder {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        *self.0.lock().unwrap() = ocsp_response.to_vec();
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// Connects to `endpoint` with TLS for `server_name`, and returns the
+/// certificate and the OCSP response the server sent.
+async fn connect_stapled(
+    endpoint: &str,
+    server_name: &str,
+) -> (
+    tokio_rustls::client::TlsStream<tokio::net::TcpStream>,
+    Vec<u8>,
+    Vec<u8>,
+) {
+    let recorder = Arc::new(OcspRecorder(Default::default()));
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(recorder.clone())
+        .with_no_client_auth();
+
+    let address = endpoint.strip_prefix("http://").unwrap();
+    let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+        .connect(server_name.try_into().unwrap(), stream)
+        .await
+        .unwrap();
+    let cert = stream.get_ref().1.peer_certificates().unwrap()[0].0.clone();
+    let ocsp = recorder.0.lock().unwrap().clone();
+    (stream, cert, ocsp)
+}
+
+#[tokio::test]
+async fn test_tls_reload() {
+    let cert = TestCert::new("example.com");
+    let ocsp = NamedTempFile::new().unwrap();
+    std::fs::write(ocsp.path(), b"ocsp response 1").unwrap();
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080 ssl;
+                server_name example.com;
+                {}
+                ssl_stapling on;
+                ssl_stapling_file {};
+                location / {{
+                    return 200 ok;
+                }}
+            }}
+        }}",
+        cert.directives(),
+        ocsp.path().display(),
+    ))
+    .await;
+
+    let (old_stream, der, response) = connect_stapled(&t.endpoint, "example.com").await;
+    assert_eq!(der, cert.der);
+    assert_eq!(response, b"ocsp response 1");
+
+    // new handshakes use the replaced files
+    let renewed = TestCert::new("example.com");
+    std::fs::copy(renewed.cert.path(), cert.cert.path()).unwrap();
+    std::fs::copy(renewed.key.path(), cert.key.path()).unwrap();
+    std::fs::write(ocsp.path(), b"ocsp response 2").unwrap();
+    tokio::time::sleep(tokio::time::Duration::from_millis(3000)).await;
+
+    let (stream, der, response) = connect_stapled(&t.endpoint, "example.com").await;
+    assert_eq!(der, renewed.der);
+    assert_eq!(response, b"ocsp response 2");
+    let res = request_tls(stream, "example.com", "/").await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // established connections are kept
+    let res = request_tls(old_stream, "example.com", "/").await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // an invalid certificate keeps the previous one
+    std::fs::write(cert.cert.path(), "invalid").unwrap();
+    tokio::time::sleep(tokio::time::Duration::from_millis(3000)).await;
+    let (_, der, _) = connect_stapled(&t.endpoint, "example.com").await;
+    assert_eq!(der, renewed.der);
+}
+
+/// Accepts any server certificate, counting the full handshakes.
+struct HandshakeCounter(AtomicUsize);
+
+impl rustls::client::ServerCertVerifier for HandshakeCounter {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// Returns a client configuration that keeps sessions, and its counter of
+/// full handshakes.
+fn resuming_client(early_data: bool) -> (Arc<rustls::ClientConfig>, Arc<HandshakeCounter>) {
+    let counter = Arc::new(HandshakeCounter(AtomicUsize::new(0)));
+    let mut config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(counter.clone())
+        .with_no_client_auth();
+    config.enable_early_data = early_data;
+    (Arc::new(config), counter)
+}
+
+/// Sends a request over TLS with `config`, returning the response and
+/// whether the request was accepted as early data.
+async fn request_resuming(config: &Arc<rustls::ClientConfig>, endpoint: &str) -> (String, bool) {
+    let address = endpoint.strip_prefix("http://").unwrap();
+    let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    let mut stream = tokio_rustls::TlsConnector::from(config.clone())
+        .early_data(config.enable_early_data)
+        .connect("example.com".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+    let res = request_tls(&mut stream, "example.com", "/").await;
+    (res, stream.get_ref().1.is_early_data_accepted())
+}
+
+fn random_ticket_key(file: &NamedTempFile) {
+    let key: Vec<u8> = (0..80).map(|_| rand::thread_rng().gen()).collect();
+    std::fs::write(file.path(), key).unwrap();
+}
+
+#[tokio::test]
+async fn test_tls_session_tickets() {
+    let cert = TestCert::new("example.com");
+    let current = NamedTempFile::new().unwrap();
+    let previous = NamedTempFile::new().unwrap();
+    random_ticket_key(&current);
+    random_ticket_key(&previous);
+    let server = format!(
+        "
+            server {{
+                listen {{}} ssl;
+                server_name example.com;
+                {}
+                ssl_session_ticket_key {};
+                ssl_session_ticket_key {};
+                location / {{
+                    return 200 ok;
+                }}
+            }}",
+        cert.directives(),
+        current.path().display(),
+        previous.path().display(),
+    );
+    let t = TestServer::init_with_config(&format!(
+        "http {{ {} {} }}",
+        server.replace("{}", "8080"),
+        server.replace("{}", "8081"),
+    ))
+    .await;
+    let (config, handshakes) = resuming_client(false);
+
+    // the ports share the keys of the tickets
+    let (res, _) = request_resuming(&config, &t.endpoint).await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    let (res, _) = request_resuming(&config, &t.endpoint_2).await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert_eq!(handshakes.0.load(Ordering::SeqCst), 1);
+
+    // tickets of the previous key are accepted after a rotation
+    std::fs::copy(current.path(), previous.path()).unwrap();
+    random_ticket_key(&current);
+    tokio::time::sleep(tokio::time::Duration::from_millis(3000)).await;
+    request_resuming(&config, &t.endpoint).await;
+    assert_eq!(handshakes.0.load(Ordering::SeqCst), 1);
+
+    random_ticket_key(&current);
+    random_ticket_key(&previous);
+    tokio::time::sleep(tokio::time::Duration::from_millis(3000)).await;
+    request_resuming(&config, &t.endpoint).await;
+    assert_eq!(handshakes.0.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_tls_early_data() {
+    let cert = TestCert::new("example.com");
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080 ssl;
+                server_name example.com;
+                {}
+                ssl_session_tickets off;
+                ssl_session_cache shared:SSL:1m;
+                ssl_session_timeout 10m;
+                ssl_early_data on;
+                location / {{
+                    add_header X-Early-Data \"[$ssl_early_data]\";
+                    return 200 ok;
+                }}
+            }}
+        }}",
+        cert.directives(),
+    ))
+    .await;
+    let (config, handshakes) = resuming_client(true);
+
+    let (res, early_data) = request_resuming(&config, &t.endpoint).await;
+    assert!(res.contains("x-early-data: []\r\n"));
+    assert!(!early_data);
+
+    // the resumed session sends the request as early data
+    let (res, early_data) = request_resuming(&config, &t.endpoint).await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(res.contains("x-early-data: [1]\r\n"));
+    assert!(early_data);
+    assert_eq!(handshakes.0.load(Ordering::SeqCst), 1);
+
+    // a later request of the connection is sent after the handshake
+    let address = t.endpoint.strip_prefix("http://").unwrap();
+    let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    let mut stream = tokio_rustls::TlsConnector::from(config.clone())
+        .early_data(true)
+        .connect("example.com".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    stream.flush().await.unwrap();
+    let mut buf = vec![];
+    while !buf.ends_with(b"\r\n\r\nok") {
+        let mut tmp = [0u8; 1024];
+        let n = stream.read(&mut tmp).await.unwrap();
+        assert!(n > 0);
+        buf.extend_from_slice(&tmp[..n]);
+    }
+    assert!(stream.get_ref().1.is_early_data_accepted());
+    assert!(String::from_utf8_lossy(&buf).contains("x-early-data: [1]\r\n"));
+    let res = request_tls(&mut stream, "example.com", "/").await;
+    assert!(res.contains("x-early-data: []\r\n"));
+}
+
+/// Sends a GET request over HTTP/3, returning the response and its body.
+async fn request_h3(endpoint: &str, cert: &TestCert, path: &str) -> (http::Response<()>, String) {
+    use bytes::Buf;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&rustls::Certificate(cert.der.clone())).unwrap();
+    let mut config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h3".to_vec()];
+
+    let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    client.set_default_client_config(quinn::ClientConfig::new(Arc::new(config)));
+    let address = endpoint.strip_prefix("http://").unwrap().parse().unwrap();
+    let conn = client
+        .connect(address, "example.com")
+        .unwrap()
+        .await
+        .unwrap();
+
+    let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(conn))
+        .await
+        .unwrap();
+    tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+    let req = http::Request::get(format!("https://example.com{}", path))
+        .body(())
+        .unwrap();
+    let mut stream = send_request.send_request(req).await.unwrap();
+    stream.finish().await.unwrap();
+    let res = stream.recv_response().await.unwrap();
+    let mut body = vec![];
+    while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+        body.extend(chunk.copy_to_bytes(chunk.remaining()));
+    }
+    (res, String::from_utf8(body).unwrap())
+}
+
+#[tokio::test]
+async fn test_http3() {
+    let cert = TestCert::new("example.com");
+    let t = TestServer::init_with_config(&format!(
+        "
+        http {{
+            server {{
+                listen 8080 ssl;
+                listen 8080 quic;
+                server_name example.com;
+                {}
+                location / {{
+                    add_header X-Protocol \"$server_protocol $ssl_protocol $ssl_server_name\";
+                    return 200 ok;
+                }}
+            }}
+            server {{
+                listen 8081;
+                server_name example.com;
+                location / {{
+                    return 200 plain;
+                }}
+            }}
+        }}",
+        cert.directives(),
+    ))
+    .await;
+    let port = t.endpoint.rsplit(':').next().unwrap();
+    let all = [&rustls::version::TLS12, &rustls::version::TLS13];
+
+    let (res, body) = request_h3(&t.endpoint, &cert, "/").await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-protocol"], "HTTP/3.0 TLSv1.3 example.com");
+    assert!(!res.headers().contains_key("alt-svc"));
+    assert_eq!(body, "ok");
+
+    // HTTP/3 is advertised on the TLS port of the same server
+    let stream = connect_tls(&t.endpoint, "example.com", &[&cert], &all, &[])
+        .await
+        .unwrap();
+    let res = request_tls(stream, "example.com", "/").await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(res.contains(&format!("alt-svc: h3=\":{}\"; ma=86400\r\n", port)));
+
+    let res = get(&t.endpoint_2).await;
+    assert!(!res.headers().contains_key("alt-svc"));
+    assert_eq!(res.text().await.unwrap(), "plain");
+}
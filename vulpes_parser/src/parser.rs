@@ -80,7 +80,7 @@ impl TryInto<Vec<String>> for ParsedValue {
                     }
                 }
 
-                return Ok(result);
+                Ok(result)
             }
             _ => Err(ParserError {
                 kind: ErrorKind::UnexpectedType { value: self },
@@ -106,11 +106,11 @@ impl<'a> std::fmt::Display for ParsedValueWrapper<'a> {
         let prefix = "    ".repeat(self.nest);
         match self.value {
             ParsedValue::Block(v) => {
-                write!(f, "{{\n").unwrap();
+                writeln!(f, "{{").unwrap();
                 for v in v {
-                    write!(
+                    writeln!(
                         f,
-                        "{}\n",
+                        "{}",
                         ParsedConfigWrapper {
                             config: v,
                             nest: self.nest + 1,
@@ -144,7 +144,7 @@ impl<'a> std::fmt::Display for ParsedValueWrapper<'a> {
                 Ok(())
             }
             ParsedValue::String(v) => {
-                if v.contains(" ") {
+                if v.contains(' ') {
                     write!(f, "\"{}\"", v)
                 } else {
                     write!(f, "{}", v)
@@ -157,7 +157,7 @@ impl<'a> std::fmt::Display for ParsedValueWrapper<'a> {
 pub fn parse(data: &[u8]) -> IResult<&[u8], Vec<ParsedConfig>> {
     let (data, v) = many0(permutation((multispace0, parse_label, parse_value)))(data)?;
 
-    return Ok((
+    Ok((
         data,
         v.into_iter()
             .map(|v| ParsedConfig {
@@ -165,19 +165,19 @@ pub fn parse(data: &[u8]) -> IResult<&[u8], Vec<ParsedConfig>> {
                 value: v.2,
             })
             .collect(),
-    ));
+    ))
 }
 
 fn parse_label(data: &[u8]) -> IResult<&[u8], &[u8]> {
     let (_data, label) = take_while(is_allowed_string)(data)?;
-    if label.len() == 0 {
+    if label.is_empty() {
         return Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
             data,
             nom::error::ErrorKind::Eof,
         )));
     }
 
-    return Ok((_data, label));
+    Ok((_data, label))
 }
 
 fn parse_value(data: &[u8]) -> IResult<&[u8], ParsedValue> {
@@ -226,7 +226,8 @@ fn parse_quote_string(data: &[u8]) -> IResult<&[u8], &[u8]> {
     delimited(
         permutation((multispace0, char('"'))),
         take_while(|v| v != b'"'),
-        permutation((char('"'), multispace0)),
+        // the whitespace after the quote separates the next value
+        char('"'),
     )(data)
 }
 
@@ -355,6 +356,19 @@ mod tests {
                 ParsedValue::String("index.htm".to_owned())
             ])
         );
+
+        let (data, result) =
+            parse_inline_multi_value("\"X-A: a\" \"X-B: b\" c;".as_bytes()).unwrap();
+
+        assert_eq!(data, vec![]);
+        assert_eq!(
+            result,
+            ParsedValue::Value(vec![
+                ParsedValue::String("X-A: a".to_owned()),
+                ParsedValue::String("X-B: b".to_owned()),
+                ParsedValue::String("c".to_owned())
+            ])
+        );
     }
 
     #[test]
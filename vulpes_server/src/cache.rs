@@ -0,0 +1,733 @@
+use crate::config::cache::CachePathConfig;
+use http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::AsyncWriteExt;
+
+/// First line of every cache file, changed whenever the format changes.
+const MAGIC: &str = "VULPES-CACHE 1";
+
+/// Memory accounted for each key of a `keys_zone`. As in nginx, a one
+/// megabyte zone holds about 8000 keys.
+const ZONE_ENTRY_SIZE: u64 = 128;
+
+const MAX_HEADERS: usize = 100;
+
+/// Largest entry stored whatever the `max_size`, so that a single response
+/// can't fill the disk.
+const MAX_ENTRY_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Metadata of a cache file, read without loading the response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryInfo {
+    pub key: String,
+    pub expires: SystemTime,
+    /// Size of the file in bytes.
+    pub size: u64,
+    pub path: PathBuf,
+}
+
+/// Cached response together with what is needed to decide whether it may be
+/// served for a request.
+#[derive(Debug)]
+pub struct Entry {
+    pub key: String,
+    pub expires: SystemTime,
+    /// Request headers named by the `Vary` header of the response.
+    vary: Vec<String>,
+    /// Hash of the values of the `vary` headers in the request the response
+    /// was stored for.
+    variant: String,
+    pub response: http::Response<Vec<u8>>,
+}
+
+impl Entry {
+    pub fn is_fresh(&self) -> bool {
+        self.expires > SystemTime::now()
+    }
+}
+
+struct Indexed {
+    key: String,
+    size: u64,
+    accessed: Instant,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Indexed>,
+    size: u64,
+}
+
+/// Response cache stored under the directory of a `proxy_cache_path`. Each
+/// entry is a file named after the MD5 hash of its key, spread over
+/// subdirectories according to `levels`. The keys are indexed in memory to
+/// enforce `max_size`, the zone size and `inactive`.
+pub struct Cache {
+    path: PathBuf,
+    levels: Vec<usize>,
+    max_size: Option<u64>,
+    max_entries: usize,
+    inactive: Duration,
+    index: Mutex<Index>,
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    temp: AtomicUsize,
+}
+
+impl Cache {
+    /// Opens the cache directory, creating it when needed, and indexes the
+    /// entries already stored there.
+    pub fn open(config: &CachePathConfig) -> io::Result<Cache> {
+        fs::create_dir_all(&config.path)?;
+
+        let cache = Cache {
+            path: config.path.clone(),
+            levels: config.levels.clone(),
+            max_size: config.max_size,
+            max_entries: (config.zone_size / ZONE_ENTRY_SIZE).max(1) as usize,
+            inactive: config.inactive,
+            index: Mutex::new(Index::default()),
+            locks: Mutex::new(HashMap::new()),
+            temp: AtomicUsize::new(0),
+        };
+
+        {
+            let mut index = cache.index.lock().unwrap();
+            let now = Instant::now();
+            for info in scan(&config.path)? {
+                index.size += info.size;
+                index.entries.insert(
+                    hash(&info.key),
+                    Indexed {
+                        key: info.key,
+                        size: info.size,
+                        accessed: now,
+                    },
+                );
+            }
+        }
+        // nothing is served yet, so the files are removed right away
+        while let Some(hash) = cache.evicted() {
+            log::debug!("evict cache entry {}", hash);
+            cache.remove_indexed(&hash);
+            if let Err(e) = fs::remove_file(cache.file_path(&hash)) {
+                log::warn!("failed to remove cache entry {}: {:?}", hash, e);
+            }
+        }
+
+        Ok(cache)
+    }
+
+    /// Returns the entry stored for `key` if it matches the `Vary` headers of
+    /// the request. The entry may be expired.
+    pub async fn lookup(&self, key: &str, headers: &HeaderMap) -> Option<Entry> {
+        let hash = hash(key);
+        if !self.index.lock().unwrap().entries.contains_key(&hash) {
+            return None;
+        }
+
+        let entry = match read_entry(&self.file_path(&hash)).await {
+            Ok(entry) if entry.key == key => entry,
+            Ok(_) => return None,
+            Err(e) => {
+                // the file may have been removed by `vulpes cache rm`
+                if e.kind() != io::ErrorKind::NotFound {
+                    log::warn!("failed to read cache entry {}: {:?}", key, e);
+                }
+                self.remove_hashed(&hash).await;
+                return None;
+            }
+        };
+
+        if let Some(v) = self.index.lock().unwrap().entries.get_mut(&hash) {
+            v.accessed = Instant::now();
+        }
+
+        if entry.variant != variant(&entry.vary, headers) {
+            return None;
+        }
+
+        Some(entry)
+    }
+
+    /// Stores `res` for `key` until `expires`, replacing any previous entry.
+    /// `headers` are the request headers, used to select the variant of a
+    /// response with `Vary`.
+    pub async fn store(
+        self: &Arc<Self>,
+        key: &str,
+        headers: &HeaderMap,
+        res: &http::Response<Vec<u8>>,
+        expires: SystemTime,
+    ) -> io::Result<()> {
+        let mut writer = self
+            .writer(key, headers, res.status(), res.headers(), expires)
+            .await?;
+        if let Err(e) = writer.write(res.body()).await {
+            writer.abort().await;
+            return Err(e);
+        }
+        writer.commit().await
+    }
+
+    /// Starts storing a response with `status` and `res_headers` for `key`
+    /// until `expires`. The body is written as it is received and the entry
+    /// replaces any previous one once committed.
+    pub async fn writer(
+        self: &Arc<Self>,
+        key: &str,
+        headers: &HeaderMap,
+        status: StatusCode,
+        res_headers: &HeaderMap,
+        expires: SystemTime,
+    ) -> io::Result<EntryWriter> {
+        if key.contains('\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid key"));
+        }
+
+        let vary = res_headers
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_ascii_lowercase())
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>();
+
+        // the expiry is stored in milliseconds since the epoch
+        let mut data = format!(
+            "{}\nkey: {}\nexpires: {}\nvary: {}\nvariant: {}\n\n",
+            MAGIC,
+            key,
+            expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            vary.join(","),
+            variant(&vary, headers),
+        )
+        .into_bytes();
+        data.extend(format!("HTTP/1.1 {}\r\n", status).as_bytes());
+        for (name, value) in res_headers {
+            data.extend(name.as_str().as_bytes());
+            data.extend(b": ");
+            data.extend(value.as_bytes());
+            data.extend(b"\r\n");
+        }
+        data.extend(b"\r\n");
+
+        let hash = hash(key);
+        let path = self.file_path(&hash);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+
+        // write to a temporary file first so that readers never see a
+        // partially written entry
+        let temp =
+            path.with_extension(format!("{}.tmp", self.temp.fetch_add(1, Ordering::Relaxed)));
+        let mut file = tokio::fs::File::create(&temp).await?;
+        if let Err(e) = file.write_all(&data).await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e);
+        }
+
+        Ok(EntryWriter {
+            cache: self.clone(),
+            key: key.to_owned(),
+            hash,
+            temp,
+            file,
+            size: data.len() as u64,
+        })
+    }
+
+    /// Largest entry stored. An entry larger than `max_size` would evict
+    /// everything else anyway.
+    pub fn max_entry_size(&self) -> u64 {
+        self.max_size.unwrap_or(MAX_ENTRY_SIZE).min(MAX_ENTRY_SIZE)
+    }
+
+    /// Indexes the entry written to `temp` after moving it in place.
+    async fn commit(&self, key: &str, hash: String, temp: &Path, size: u64) -> io::Result<()> {
+        tokio::fs::rename(temp, self.file_path(&hash)).await?;
+
+        {
+            let mut index = self.index.lock().unwrap();
+            let old = index.entries.insert(
+                hash,
+                Indexed {
+                    key: key.to_owned(),
+                    size,
+                    accessed: Instant::now(),
+                },
+            );
+            index.size = index.size - old.map(|v| v.size).unwrap_or(0) + size;
+        }
+        self.evict().await;
+
+        Ok(())
+    }
+
+    /// Removes the entry stored for `key`. Returns whether there was one.
+    pub async fn remove(&self, key: &str) -> bool {
+        self.remove_hashed(&hash(key)).await
+    }
+
+    /// Removes the entries whose key matches `pattern`, see [`matches`].
+    /// Returns the number of removed entries.
+    pub async fn purge(&self, pattern: &str) -> usize {
+        let hashes = {
+            let index = self.index.lock().unwrap();
+            index
+                .entries
+                .iter()
+                .filter(|(_, v)| matches(&v.key, pattern))
+                .map(|(k, _)| k.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let mut removed = 0;
+        for hash in hashes {
+            if self.remove_hashed(&hash).await {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Removes the entries that were not accessed for `inactive`.
+    pub async fn expire_inactive(&self) {
+        let expired = {
+            let index = self.index.lock().unwrap();
+            index
+                .entries
+                .iter()
+                .filter(|(_, v)| v.accessed.elapsed() >= self.inactive)
+                .map(|(k, _)| k.clone())
+                .collect::<Vec<_>>()
+        };
+
+        for hash in expired {
+            log::debug!("remove inactive cache entry {}", hash);
+            self.remove_hashed(&hash).await;
+        }
+    }
+
+    /// Returns the lock that serializes the requests populating `key`. It
+    /// must be handed back with [`Cache::release`].
+    pub fn lock(&self, key: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    pub fn release(&self, key: &str, lock: &Arc<tokio::sync::Mutex<()>>) {
+        let mut locks = self.locks.lock().unwrap();
+        // the map and `lock` are the only owners left
+        if Arc::strong_count(lock) <= 2 {
+            locks.remove(key);
+        }
+    }
+
+    /// Removes the least recently used entries until the cache fits in
+    /// `max_size` and the zone.
+    async fn evict(&self) {
+        while let Some(hash) = self.evicted() {
+            log::debug!("evict cache entry {}", hash);
+            self.remove_hashed(&hash).await;
+        }
+    }
+
+    /// Returns the least recently used entry while the cache doesn't fit in
+    /// `max_size` and the zone.
+    fn evicted(&self) -> Option<String> {
+        let index = self.index.lock().unwrap();
+        let over_size = self.max_size.map(|v| index.size > v).unwrap_or(false);
+        if !over_size && index.entries.len() <= self.max_entries {
+            return None;
+        }
+        index
+            .entries
+            .iter()
+            .min_by_key(|(_, v)| v.accessed)
+            .map(|(k, _)| k.clone())
+    }
+
+    fn remove_indexed(&self, hash: &str) -> bool {
+        let mut index = self.index.lock().unwrap();
+        match index.entries.remove(hash) {
+            Some(v) => {
+                index.size -= v.size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn remove_hashed(&self, hash: &str) -> bool {
+        let removed = self.remove_indexed(hash);
+        match tokio::fs::remove_file(self.file_path(hash)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                log::warn!("failed to remove cache entry {}: {:?}", hash, e);
+            }
+            _ => {}
+        }
+
+        removed
+    }
+
+    /// `levels=1:2` stores the hash `...0bd29c` as `c/29/...0bd29c`.
+    fn file_path(&self, hash: &str) -> PathBuf {
+        let mut path = self.path.clone();
+        let mut end = hash.len();
+        for level in &self.levels {
+            path.push(&hash[end - level..end]);
+            end -= level;
+        }
+        path.push(hash);
+        path
+    }
+}
+
+/// Entry being stored by [`Cache::writer`]. It is discarded unless
+/// [`EntryWriter::commit`] is called.
+pub struct EntryWriter {
+    cache: Arc<Cache>,
+    key: String,
+    hash: String,
+    temp: PathBuf,
+    file: tokio::fs::File,
+    size: u64,
+}
+
+impl EntryWriter {
+    /// Appends `data` to the body. Fails once the entry grows over
+    /// [`Cache::max_entry_size`].
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.size += data.len() as u64;
+        if self.size > self.cache.max_entry_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cache entry too large",
+            ));
+        }
+        self.file.write_all(data).await
+    }
+
+    /// Replaces the entry stored for the key with this one.
+    pub async fn commit(mut self) -> io::Result<()> {
+        let result = match self.file.flush().await {
+            Ok(()) => {
+                self.cache
+                    .commit(&self.key, self.hash, &self.temp, self.size)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&self.temp).await;
+        }
+        result
+    }
+
+    /// Discards the entry.
+    pub async fn abort(self) {
+        drop(self.file);
+        if let Err(e) = tokio::fs::remove_file(&self.temp).await {
+            log::warn!("failed to remove {:?}: {:?}", self.temp, e);
+        }
+    }
+}
+
+/// Lists the entries stored under a cache directory.
+pub fn scan(path: &Path) -> io::Result<Vec<EntryInfo>> {
+    let mut entries = vec![];
+    let mut dirs = vec![path.to_owned()];
+
+    while let Some(dir) = dirs.pop() {
+        for v in fs::read_dir(dir)? {
+            let path = v?.path();
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if path.extension().map(|v| v == "tmp").unwrap_or(false) {
+                continue;
+            }
+
+            match read_info(&path) {
+                Ok(info) => entries.push(info),
+                Err(e) => log::warn!("skip cache file {:?}: {:?}", path, e),
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Removes the entries matching `pattern` from a cache directory without
+/// going through a running server, and returns them.
+pub fn purge_dir(path: &Path, pattern: &str) -> io::Result<Vec<EntryInfo>> {
+    let mut removed = vec![];
+    for info in scan(path)? {
+        if matches(&info.key, pattern) {
+            fs::remove_file(&info.path)?;
+            removed.push(info);
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Whether `key` is selected by `pattern`, which is either an exact key or a
+/// prefix followed by `*`.
+pub fn matches(key: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == pattern,
+    }
+}
+
+fn hash(key: &str) -> String {
+    format!("{:x}", md5::compute(key))
+}
+
+fn variant(vary: &[String], headers: &HeaderMap) -> String {
+    let mut ctx = md5::Context::new();
+    for name in vary {
+        for value in headers.get_all(name.as_str()) {
+            ctx.consume(value.as_bytes());
+            ctx.consume(b"\n");
+        }
+        ctx.consume(b"\0");
+    }
+    format!("{:x}", ctx.compute())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Parses the metadata lines at the start of a cache file.
+fn read_meta(reader: &mut impl BufRead) -> io::Result<HashMap<String, String>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim_end() != MAGIC {
+        return Err(invalid("not a cache file"));
+    }
+
+    let mut meta = HashMap::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("truncated cache file"));
+        }
+        match line.trim_end_matches('\n').split_once(": ") {
+            Some((name, value)) => {
+                meta.insert(name.to_owned(), value.to_owned());
+            }
+            None if line == "\n" => return Ok(meta),
+            None => return Err(invalid("invalid cache metadata")),
+        }
+    }
+}
+
+fn parse_expires(meta: &HashMap<String, String>) -> io::Result<SystemTime> {
+    meta.get("expires")
+        .and_then(|v| v.parse().ok())
+        .map(|v| UNIX_EPOCH + Duration::from_millis(v))
+        .ok_or_else(|| invalid("invalid expires"))
+}
+
+fn read_info(path: &Path) -> io::Result<EntryInfo> {
+    let f = fs::File::open(path)?;
+    let size = f.metadata()?.len();
+    let meta = read_meta(&mut BufReader::new(f))?;
+
+    Ok(EntryInfo {
+        key: meta.get("key").cloned().unwrap_or_default(),
+        expires: parse_expires(&meta)?,
+        size,
+        path: path.to_owned(),
+    })
+}
+
+async fn read_entry(path: &Path) -> io::Result<Entry> {
+    let data = tokio::fs::read(path).await?;
+    let mut reader = &data[..];
+    let meta = read_meta(&mut reader)?;
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    let n = match res.parse(reader) {
+        Ok(httparse::Status::Complete(n)) => n,
+        _ => return Err(invalid("invalid cached response")),
+    };
+
+    let mut response = http::Response::new(reader[n..].to_vec());
+    *response.status_mut() = StatusCode::from_u16(res.code.unwrap_or_default())
+        .map_err(|_| invalid("invalid cached status"))?;
+    for h in res.headers.iter() {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(h.name.as_bytes()),
+            HeaderValue::from_bytes(h.value),
+        ) {
+            response.headers_mut().append(name, value);
+        }
+    }
+
+    Ok(Entry {
+        key: meta.get("key").cloned().unwrap_or_default(),
+        expires: parse_expires(&meta)?,
+        vary: meta
+            .get("vary")
+            .map(|v| {
+                v.split(',')
+                    .filter(|v| !v.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        variant: meta.get("variant").cloned().unwrap_or_default(),
+        response,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cache::Cache, config::cache::CachePathConfig};
+    use http::HeaderMap;
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    fn open(dir: &std::path::Path, max_size: Option<u64>) -> Arc<Cache> {
+        Arc::new(
+            Cache::open(&CachePathConfig {
+                path: dir.to_owned(),
+                levels: vec![1, 2],
+                zone: "test".to_owned(),
+                zone_size: 1024 * 1024,
+                max_size,
+                inactive: Duration::from_secs(60),
+            })
+            .unwrap(),
+        )
+    }
+
+    fn response(body: &str, vary: Option<&str>) -> http::Response<Vec<u8>> {
+        let mut res = http::Response::builder().status(201).header("x-a", "1");
+        if let Some(v) = vary {
+            res = res.header("vary", v);
+        }
+        res.body(body.as_bytes().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_store_and_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(dir.path(), None);
+        let expires = SystemTime::now() + Duration::from_secs(60);
+
+        let mut gzip = HeaderMap::new();
+        gzip.insert("accept-encoding", "gzip".parse().unwrap());
+
+        cache
+            .store(
+                "a",
+                &gzip,
+                &response("body", Some("Accept-Encoding")),
+                expires,
+            )
+            .await
+            .unwrap();
+        cache
+            .store("b", &HeaderMap::new(), &response("b", None), expires)
+            .await
+            .unwrap();
+
+        let entry = cache.lookup("a", &gzip).await.unwrap();
+        assert!(entry.is_fresh());
+        assert_eq!(entry.response.status(), 201);
+        assert_eq!(entry.response.headers()["x-a"], "1");
+        assert_eq!(entry.response.body(), b"body");
+        assert!(cache.lookup("a", &HeaderMap::new()).await.is_none());
+
+        // the index is rebuilt from the files on disk
+        let cache = open(dir.path(), None);
+        let mut keys = super::scan(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|v| v.key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert!(cache.lookup("b", &HeaderMap::new()).await.is_some());
+
+        assert!(cache.remove("b").await);
+        assert!(!cache.remove("b").await);
+        assert!(cache.lookup("b", &HeaderMap::new()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_purge() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(dir.path(), None);
+        let expires = SystemTime::now() + Duration::from_secs(60);
+
+        for key in ["/img/a", "/img/b", "/index"] {
+            cache
+                .store(key, &HeaderMap::new(), &response(key, None), expires)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(cache.purge("/index").await, 1);
+        assert_eq!(cache.purge("/index").await, 0);
+        assert_eq!(cache.purge("/img/*").await, 2);
+        assert!(cache.lookup("/img/a", &HeaderMap::new()).await.is_none());
+
+        cache
+            .store("/img/c", &HeaderMap::new(), &response("c", None), expires)
+            .await
+            .unwrap();
+        let removed = super::purge_dir(dir.path(), "*").unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].key, "/img/c");
+        assert!(super::scan(dir.path()).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_evict() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(dir.path(), Some(800));
+        let expires = SystemTime::now() + Duration::from_secs(60);
+        let body = "x".repeat(200);
+
+        for key in ["a", "b", "c"] {
+            cache
+                .store(key, &HeaderMap::new(), &response(&body, None), expires)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(cache.lookup("a", &HeaderMap::new()).await.is_none());
+        assert!(cache.lookup("b", &HeaderMap::new()).await.is_some());
+        assert!(cache.lookup("c", &HeaderMap::new()).await.is_some());
+    }
+}
@@ -0,0 +1,124 @@
+use crate::config::{
+    error::{ConfigError, ErrorKind},
+    types::Template,
+};
+use vulpes_parser::ParsedValue;
+
+/// Format of the `combined` log, which is available without `log_format`.
+pub const COMBINED: &str = "$remote_addr - $remote_user [$time_local] \"$request\" $status \
+                            $body_bytes_sent \"$http_referer\" \"$http_user_agent\"";
+
+/// `log_format name string ...`, whose strings are joined into the line
+/// written for a request.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LogFormat {
+    pub name: String,
+    pub format: Template,
+}
+
+impl TryFrom<ParsedValue> for LogFormat {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<LogFormat, ConfigError> {
+        let values: Vec<String> = data.try_into()?;
+        match values.as_slice() {
+            [name, format @ ..] if !format.is_empty() => Ok(LogFormat {
+                name: name.clone(),
+                format: format.concat().into(),
+            }),
+            _ => Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue {
+                    value: values.join(" "),
+                },
+            }),
+        }
+    }
+}
+
+/// `access_log path [format]`, appending a line per request to the file at
+/// `path` in the format named by `log_format`, `combined` by default.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AccessLog {
+    pub path: String,
+    pub format: String,
+}
+
+/// Applies `access_log` to the logs of a level, where `off` leaves none and
+/// `None` stands for the logs of the enclosing level.
+pub fn set_access_log(
+    logs: &mut Option<Vec<AccessLog>>,
+    data: ParsedValue,
+) -> Result<(), ConfigError> {
+    let values: Vec<String> = data.try_into()?;
+    let (path, format) = match values.as_slice() {
+        [off] if off == "off" => {
+            *logs = Some(vec![]);
+            return Ok(());
+        }
+        [path] => (path, "combined"),
+        [path, format] => (path, format.as_str()),
+        _ => {
+            return Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue {
+                    value: values.join(" "),
+                },
+            })
+        }
+    };
+
+    logs.get_or_insert_with(Vec::new).push(AccessLog {
+        path: path.clone(),
+        format: format.to_owned(),
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::access_log::{set_access_log, AccessLog, LogFormat};
+    use vulpes_parser::ParsedValue;
+
+    fn value(v: &[&str]) -> ParsedValue {
+        ParsedValue::Value(
+            v.iter()
+                .map(|v| ParsedValue::String(v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_log_format_try_from() {
+        assert_eq!(
+            LogFormat::try_from(value(&["main", "$remote_addr ", "$status"])).unwrap(),
+            LogFormat {
+                name: "main".to_owned(),
+                format: "$remote_addr $status".into(),
+            }
+        );
+        assert!(LogFormat::try_from(value(&["main"])).is_err());
+    }
+
+    #[test]
+    fn test_set_access_log() {
+        let log = |path: &str, format: &str| AccessLog {
+            path: path.to_owned(),
+            format: format.to_owned(),
+        };
+
+        let mut logs = None;
+        set_access_log(&mut logs, value(&["/tmp/a.log"])).unwrap();
+        set_access_log(&mut logs, value(&["/tmp/b.log", "main"])).unwrap();
+        assert_eq!(
+            logs,
+            Some(vec![
+                log("/tmp/a.log", "combined"),
+                log("/tmp/b.log", "main")
+            ])
+        );
+
+        set_access_log(&mut logs, value(&["off"])).unwrap();
+        assert_eq!(logs, Some(vec![]));
+        assert!(set_access_log(&mut logs, value(&[])).is_err());
+        assert!(set_access_log(&mut logs, value(&["/tmp/a.log", "main", "x"])).is_err());
+    }
+}
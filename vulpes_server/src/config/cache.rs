@@ -0,0 +1,360 @@
+use crate::config::{
+    error::{ConfigError, ErrorKind},
+    network::IpNetwork,
+    types::{parse_duration, parse_size, Template},
+};
+use http::StatusCode;
+use std::{net::IpAddr, path::PathBuf, time::Duration};
+use vulpes_parser::ParsedValue;
+
+/// On-disk cache declared by `proxy_cache_path`, referenced from locations
+/// by the name of its `keys_zone`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CachePathConfig {
+    pub path: PathBuf,
+    /// Number of hash characters used by each directory level, e.g. `1:2`.
+    pub levels: Vec<usize>,
+    pub zone: String,
+    /// Memory reserved for keys, which limits the number of entries.
+    pub zone_size: u64,
+    pub max_size: Option<u64>,
+    /// Entries not accessed for this long are removed.
+    pub inactive: Duration,
+}
+
+impl TryFrom<ParsedValue> for CachePathConfig {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<CachePathConfig, ConfigError> {
+        let mut values: Vec<String> = data.try_into()?;
+        values.reverse();
+
+        let mut c = CachePathConfig {
+            path: values.pop().unwrap_or_default().into(),
+            levels: vec![],
+            zone: String::new(),
+            zone_size: 0,
+            max_size: None,
+            inactive: Duration::from_secs(10 * 60),
+        };
+
+        while let Some(v) = values.pop() {
+            match v.split_once('=') {
+                Some(("levels", l)) => {
+                    c.levels = l.split(':').map(|v| v.parse()).collect::<Result<_, _>>()?;
+                    if c.levels.len() > 3 || c.levels.iter().any(|v| *v == 0 || *v > 2) {
+                        return Err(ConfigError {
+                            kind: ErrorKind::UnexpectedValue { value: v },
+                        });
+                    }
+                }
+                Some(("keys_zone", z)) => match z.split_once(':') {
+                    Some((name, size)) => {
+                        c.zone = name.to_owned();
+                        c.zone_size = parse_size(size)?;
+                    }
+                    None => {
+                        return Err(ConfigError {
+                            kind: ErrorKind::UnexpectedValue { value: v },
+                        })
+                    }
+                },
+                Some(("max_size", size)) => c.max_size = Some(parse_size(size)?),
+                Some(("inactive", t)) => c.inactive = parse_duration(t)?,
+                _ => {
+                    return Err(ConfigError {
+                        kind: ErrorKind::UnexpectedValue { value: v },
+                    })
+                }
+            }
+        }
+
+        if c.path.as_os_str().is_empty() || c.zone.is_empty() {
+            return Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue {
+                    value: "proxy_cache_path".to_owned(),
+                },
+            });
+        }
+
+        Ok(c)
+    }
+}
+
+/// Caching settings of a location, set by the `proxy_cache*` directives.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CacheConfig {
+    /// Name of the `keys_zone` to cache into, `None` disables caching.
+    pub zone: Option<String>,
+    pub key: Template,
+    pub valid: Vec<CacheValid>,
+    /// Only one request at a time populates an entry, the others wait for
+    /// it up to `lock_timeout`.
+    pub lock: bool,
+    pub lock_timeout: Duration,
+    pub use_stale: UseStale,
+    /// Refresh an expired entry in the background while the stale one is
+    /// served, requires `updating` in `use_stale`.
+    pub background_update: bool,
+    pub purge: Purge,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            zone: None,
+            key: "$scheme$proxy_host$request_uri".into(),
+            valid: vec![],
+            lock: false,
+            lock_timeout: Duration::from_secs(5),
+            use_stale: UseStale::default(),
+            background_update: false,
+            purge: Purge::default(),
+        }
+    }
+}
+
+/// `proxy_cache_purge on|off [from all|address ...]`, accepting `PURGE`
+/// requests that remove the entry of their key, or the entries starting with
+/// it when it ends with `*`. Without `from`, only loopback clients may purge.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Purge {
+    pub enabled: bool,
+    /// Networks of the clients allowed to purge, all of them when `None`.
+    pub from: Option<Vec<IpNetwork>>,
+}
+
+impl TryFrom<ParsedValue> for Purge {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<Purge, ConfigError> {
+        let values: Vec<String> = data.try_into()?;
+        let invalid = || ConfigError {
+            kind: ErrorKind::UnexpectedValue {
+                value: values.join(" "),
+            },
+        };
+
+        let enabled = match values.first().map(|v| v.as_str()) {
+            Some("on") => true,
+            Some("off") => false,
+            _ => return Err(invalid()),
+        };
+        let from = match &values[1..] {
+            [] => Some(
+                ["127.0.0.0/8", "::1"]
+                    .into_iter()
+                    .map(IpNetwork::try_from)
+                    .collect::<Result<_, _>>()?,
+            ),
+            [from, all] if from == "from" && all == "all" => None,
+            [from, networks @ ..] if from == "from" && !networks.is_empty() => Some(
+                networks
+                    .iter()
+                    .map(|v| IpNetwork::try_from(v.as_str()))
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Purge { enabled, from })
+    }
+}
+
+impl Purge {
+    /// Returns whether the client at `addr` may purge, `None` being a client
+    /// of a UNIX-domain socket which is only allowed `from all`.
+    pub fn allows(&self, addr: Option<IpAddr>) -> bool {
+        match (&self.from, addr) {
+            (None, _) => true,
+            (Some(from), Some(addr)) => from.iter().any(|v| v.contains(addr)),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Caching time for responses with one of `status`, or any status when
+/// `status` is empty.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CacheValid {
+    pub status: Vec<StatusCode>,
+    pub time: Duration,
+}
+
+impl TryFrom<ParsedValue> for CacheValid {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<CacheValid, ConfigError> {
+        let mut values: Vec<String> = data.try_into()?;
+        let time = match values.pop() {
+            Some(t) => parse_duration(&t)?,
+            None => {
+                return Err(ConfigError {
+                    kind: ErrorKind::UnexpectedValue {
+                        value: "proxy_cache_valid".to_owned(),
+                    },
+                })
+            }
+        };
+
+        let status = match values.as_slice() {
+            [] => vec![
+                StatusCode::OK,
+                StatusCode::MOVED_PERMANENTLY,
+                StatusCode::FOUND,
+            ],
+            [any] if any == "any" => vec![],
+            _ => values
+                .iter()
+                .map(|v| StatusCode::from_bytes(v.as_bytes()))
+                .collect::<Result<_, _>>()?,
+        };
+
+        Ok(CacheValid { status, time })
+    }
+}
+
+/// Cases in which an expired entry is served instead of the upstream
+/// response, as listed by `proxy_cache_use_stale`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct UseStale {
+    pub error: bool,
+    pub timeout: bool,
+    /// The entry is being refreshed by another request.
+    pub updating: bool,
+    pub status: Vec<StatusCode>,
+}
+
+impl TryFrom<ParsedValue> for UseStale {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<UseStale, ConfigError> {
+        let mut c = UseStale::default();
+
+        let values: Vec<String> = data.try_into()?;
+        for v in values.into_iter().filter(|v| !v.is_empty()) {
+            match v.as_str() {
+                "off" => c = UseStale::default(),
+                "error" | "invalid_header" => c.error = true,
+                "timeout" => c.timeout = true,
+                "updating" => c.updating = true,
+                "http_500" | "http_502" | "http_503" | "http_504" | "http_403" | "http_404"
+                | "http_429" => c.status.push(v[5..].parse::<u16>()?.try_into()?),
+                _ => {
+                    return Err(ConfigError {
+                        kind: ErrorKind::UnexpectedValue { value: v },
+                    })
+                }
+            }
+        }
+
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::cache::{CachePathConfig, CacheValid, Purge};
+    use http::StatusCode;
+    use std::time::Duration;
+    use vulpes_parser::ParsedValue;
+
+    fn value(v: &[&str]) -> ParsedValue {
+        ParsedValue::Value(
+            v.iter()
+                .map(|v| ParsedValue::String(v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_cache_path_try_from() {
+        assert_eq!(
+            CachePathConfig::try_from(value(&[
+                "/var/cache/vulpes",
+                "levels=1:2",
+                "keys_zone=one:10m",
+                "max_size=1g",
+                "inactive=1h",
+            ]))
+            .unwrap(),
+            CachePathConfig {
+                path: "/var/cache/vulpes".into(),
+                levels: vec![1, 2],
+                zone: "one".to_owned(),
+                zone_size: 10 * 1024 * 1024,
+                max_size: Some(1024 * 1024 * 1024),
+                inactive: Duration::from_secs(60 * 60),
+            }
+        );
+        assert!(CachePathConfig::try_from(value(&["/tmp", "levels=3"])).is_err());
+        assert!(
+            CachePathConfig::try_from(value(&["/tmp", "keys_zone=one:99999999999999g"])).is_err()
+        );
+        assert!(CachePathConfig::try_from(value(&[
+            "/tmp",
+            "keys_zone=one:1m",
+            "max_size=99999999999999g"
+        ]))
+        .is_err());
+        assert!(CachePathConfig::try_from(value(&["/tmp"])).is_err());
+    }
+
+    #[test]
+    fn test_cache_valid_try_from() {
+        assert_eq!(
+            CacheValid::try_from(value(&["10m"])).unwrap(),
+            CacheValid {
+                status: vec![
+                    StatusCode::OK,
+                    StatusCode::MOVED_PERMANENTLY,
+                    StatusCode::FOUND
+                ],
+                time: Duration::from_secs(600),
+            }
+        );
+        assert_eq!(
+            CacheValid::try_from(value(&["404", "1m"])).unwrap(),
+            CacheValid {
+                status: vec![StatusCode::NOT_FOUND],
+                time: Duration::from_secs(60),
+            }
+        );
+        assert!(CacheValid::try_from(value(&["any", "5s"]))
+            .unwrap()
+            .status
+            .is_empty());
+    }
+
+    #[test]
+    fn test_purge_try_from() {
+        let purge = Purge::try_from(value(&["on"])).unwrap();
+        assert!(purge.enabled);
+        assert!(purge.allows(Some("127.0.0.1".parse().unwrap())));
+        assert!(purge.allows(Some("::1".parse().unwrap())));
+        assert!(!purge.allows(Some("192.168.0.1".parse().unwrap())));
+        assert!(!purge.allows(None));
+
+        let purge = Purge::try_from(value(&["on", "from", "all"])).unwrap();
+        assert_eq!(purge.from, None);
+        assert!(purge.allows(Some("192.168.0.1".parse().unwrap())));
+        assert!(purge.allows(None));
+
+        let purge = Purge::try_from(value(&["on", "from", "127.0.0.1", "10.0.0.0/8"])).unwrap();
+        assert_eq!(purge.from.as_ref().map(|v| v.len()), Some(2));
+        assert!(purge.allows(Some("127.0.0.1".parse().unwrap())));
+        assert!(purge.allows(Some("10.1.2.3".parse().unwrap())));
+        assert!(purge.allows(Some("::ffff:10.1.2.3".parse().unwrap())));
+        assert!(!purge.allows(Some("192.168.0.1".parse().unwrap())));
+        assert!(!purge.allows(None));
+
+        for v in [
+            &["maybe"][..],
+            &["on", "127.0.0.1"],
+            &["on", "from"],
+            &["on", "from", "example.com"],
+        ] {
+            assert!(Purge::try_from(value(v)).is_err(), "{:?}", v);
+        }
+    }
+}
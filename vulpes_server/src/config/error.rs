@@ -12,6 +12,7 @@ pub enum ErrorKind {
     ParserError(ParserError),
     ParseIntError(std::num::ParseIntError),
     InvalidStatusCode(http::status::InvalidStatusCode),
+    Regex(regex::Error),
 }
 
 impl From<ParserError> for ConfigError {
@@ -37,3 +38,11 @@ impl From<http::status::InvalidStatusCode> for ConfigError {
         }
     }
 }
+
+impl From<regex::Error> for ConfigError {
+    fn from(value: regex::Error) -> Self {
+        ConfigError {
+            kind: ErrorKind::Regex(value),
+        }
+    }
+}
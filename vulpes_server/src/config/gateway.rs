@@ -0,0 +1,157 @@
+use crate::config::{
+    error::{ConfigError, ErrorKind},
+    types::{parse_duration, parse_flag, parse_single, Pattern, Template},
+};
+use std::time::Duration;
+use vulpes_parser::ParsedValue;
+
+/// Settings shared by the locations that pass requests to an application
+/// server over FastCGI, uwsgi or SCGI, set by the `<protocol>_*` directives.
+#[derive(Debug, PartialEq, Clone)]
+pub struct GatewayConfig {
+    /// `host:port` address, `unix:` socket path or name of an `upstream`
+    /// block.
+    pub pass: Option<String>,
+    pub param: Vec<Param>,
+    pub connect_timeout: Duration,
+    /// Maximum time between two successive reads from the server.
+    pub read_timeout: Duration,
+    /// Maximum time between two successive writes to the server.
+    pub send_timeout: Duration,
+    /// Read the whole response before sending it to the client, instead of
+    /// streaming it as it arrives.
+    pub buffering: bool,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        GatewayConfig {
+            pass: None,
+            param: vec![],
+            connect_timeout: Duration::from_secs(60),
+            read_timeout: Duration::from_secs(60),
+            send_timeout: Duration::from_secs(60),
+            buffering: true,
+        }
+    }
+}
+
+impl GatewayConfig {
+    /// Applies the directive `<protocol>_<name>`, returning `false` when
+    /// `name` is not a shared setting.
+    pub fn set(&mut self, name: &str, value: ParsedValue) -> Result<bool, ConfigError> {
+        match name {
+            "pass" => self.pass = Some(parse_single(value)?),
+            "param" => self.param.push(value.try_into()?),
+            "connect_timeout" => self.connect_timeout = parse_duration(&parse_single(value)?)?,
+            "read_timeout" => self.read_timeout = parse_duration(&parse_single(value)?)?,
+            "send_timeout" => self.send_timeout = parse_duration(&parse_single(value)?)?,
+            "buffering" => self.buffering = parse_flag(value)?,
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
+
+/// FastCGI settings, on top of the shared ones.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FastcgiConfig {
+    pub gateway: GatewayConfig,
+    /// Appended to `$fastcgi_script_name` when the URI ends with a slash.
+    pub index: Option<String>,
+    /// Splits the URI into `$fastcgi_script_name` and `$fastcgi_path_info`
+    /// with its two captures.
+    pub split_path_info: Option<Pattern>,
+}
+
+/// Parameter sent to the application server, `value` may contain variables.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Param {
+    pub name: String,
+    pub value: Template,
+    /// Skip the parameter when its value evaluates to an empty string.
+    pub if_not_empty: bool,
+}
+
+impl TryFrom<ParsedValue> for Param {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<Param, ConfigError> {
+        let values: Vec<String> = data.try_into()?;
+        match values.as_slice() {
+            [name, value] => Ok(Param {
+                name: name.clone(),
+                value: value.as_str().into(),
+                if_not_empty: false,
+            }),
+            [name, value, flag] if flag == "if_not_empty" => Ok(Param {
+                name: name.clone(),
+                value: value.as_str().into(),
+                if_not_empty: true,
+            }),
+            _ => Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue {
+                    value: values.join(" "),
+                },
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::gateway::{GatewayConfig, Param};
+    use std::time::Duration;
+    use vulpes_parser::ParsedValue;
+
+    fn value(v: &[&str]) -> ParsedValue {
+        ParsedValue::Value(
+            v.iter()
+                .map(|v| ParsedValue::String(v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_param_try_from() {
+        assert_eq!(
+            Param::try_from(value(&["QUERY_STRING", "$query_string"])).unwrap(),
+            Param {
+                name: "QUERY_STRING".to_owned(),
+                value: "$query_string".into(),
+                if_not_empty: false,
+            }
+        );
+        assert!(
+            Param::try_from(value(&["HTTPS", "$https", "if_not_empty"]))
+                .unwrap()
+                .if_not_empty
+        );
+        assert!(Param::try_from(value(&["HTTPS"])).is_err());
+        assert!(Param::try_from(value(&["HTTPS", "on", "always"])).is_err());
+    }
+
+    #[test]
+    fn test_gateway_set() {
+        let mut c = GatewayConfig::default();
+        assert!(c.set("pass", value(&["unix:/run/app.sock"])).unwrap());
+        assert!(c.set("read_timeout", value(&["5s"])).unwrap());
+        assert!(c.set("buffering", value(&["off"])).unwrap());
+        assert!(!c.set("index", value(&["index.php"])).unwrap());
+        assert!(c.set("buffering", value(&["maybe"])).is_err());
+        assert!(c
+            .set("send_timeout", value(&["99999999999999999d"]))
+            .is_err());
+
+        assert_eq!(
+            c,
+            GatewayConfig {
+                pass: Some("unix:/run/app.sock".to_owned()),
+                read_timeout: Duration::from_secs(5),
+                buffering: false,
+                ..Default::default()
+            }
+        );
+    }
+}
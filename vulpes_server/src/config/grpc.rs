@@ -0,0 +1,71 @@
+use crate::config::{
+    error::{ConfigError, ErrorKind},
+    types::Template,
+};
+use std::time::Duration;
+use vulpes_parser::ParsedValue;
+
+/// Settings of a location that passes requests to a gRPC server over
+/// HTTP/2, set by the `grpc_*` directives.
+#[derive(Debug, PartialEq, Clone)]
+pub struct GrpcConfig {
+    /// `host:port` address or name of an `upstream` block, given as
+    /// `grpc://<target>`.
+    pub pass: Option<String>,
+    /// Request headers to set, values may contain variables and an empty
+    /// value removes the header.
+    pub set_header: Vec<(String, Template)>,
+    pub connect_timeout: Duration,
+    /// Maximum time between two successive reads from the server.
+    pub read_timeout: Duration,
+    /// Maximum time between two successive writes to the server.
+    pub send_timeout: Duration,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            pass: None,
+            set_header: vec![],
+            connect_timeout: Duration::from_secs(60),
+            read_timeout: Duration::from_secs(60),
+            send_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Parses the `grpc://host:port` argument of `grpc_pass`.
+pub fn parse_pass(data: ParsedValue) -> Result<String, ConfigError> {
+    let mut values: Vec<String> = data.try_into()?;
+    let value = values.pop().unwrap_or_default();
+
+    match value.strip_prefix("grpc://") {
+        Some(target) if !target.is_empty() && !target.contains('/') && values.is_empty() => {
+            Ok(target.to_owned())
+        }
+        _ => Err(ConfigError {
+            kind: ErrorKind::UnexpectedValue { value },
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::grpc::parse_pass;
+    use vulpes_parser::ParsedValue;
+
+    fn value(v: &str) -> ParsedValue {
+        ParsedValue::Value(vec![ParsedValue::String(v.to_owned())])
+    }
+
+    #[test]
+    fn test_parse_pass() {
+        assert_eq!(
+            parse_pass(value("grpc://127.0.0.1:50051")).unwrap(),
+            "127.0.0.1:50051"
+        );
+        assert_eq!(parse_pass(value("grpc://backend")).unwrap(), "backend");
+        assert!(parse_pass(value("http://127.0.0.1:50051")).is_err());
+        assert!(parse_pass(value("grpc://backend/path")).is_err());
+    }
+}
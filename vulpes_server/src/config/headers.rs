@@ -0,0 +1,278 @@
+use crate::config::{
+    error::{ConfigError, ErrorKind},
+    types::Template,
+};
+use vulpes_parser::ParsedValue;
+
+/// Headers changed by `add_header` and the `more_*_headers` directives of
+/// the http, server or location level. A level without any of a directive
+/// inherits those of the enclosing level, as `add_header` does in nginx.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct HeadersConfig {
+    pub add_header: Vec<AddHeader>,
+    /// `more_set_headers` and `more_clear_headers` in their order, applied
+    /// to the responses.
+    pub output: Vec<HeaderRule>,
+    /// `more_set_input_headers` and `more_clear_input_headers` in their
+    /// order, applied to the requests before they are handled.
+    pub input: Vec<HeaderRule>,
+}
+
+/// `add_header name value [always]`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AddHeader {
+    pub name: String,
+    pub value: Template,
+    /// Adds the header to the responses of any status, not only the
+    /// successful ones and the redirects.
+    pub always: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct HeaderRule {
+    pub action: HeaderAction,
+    /// `-s`, the status codes of the responses the rule applies to, all when
+    /// empty.
+    pub status: Vec<u16>,
+    /// `-t`, the media types the rule applies to, all when empty.
+    pub types: Vec<String>,
+    /// `-r`, only replaces the request headers that are present.
+    pub replace: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum HeaderAction {
+    /// Replaces the headers by `Name: value`, an empty value removes them.
+    Set(Vec<(String, Template)>),
+    /// Removes the headers, a name ending with `*` removes those starting
+    /// with the rest of it.
+    Clear(Vec<String>),
+}
+
+impl HeadersConfig {
+    /// Applies the directive `label`, returning `false` when it's not a
+    /// header directive.
+    pub fn set(&mut self, label: &str, value: ParsedValue) -> Result<bool, ConfigError> {
+        match label {
+            "add_header" => self.add_header.push(value.try_into()?),
+            "more_set_headers" => self.output.push(HeaderRule::parse(value, true, false)?),
+            "more_clear_headers" => self.output.push(HeaderRule::parse(value, false, false)?),
+            "more_set_input_headers" => self.input.push(HeaderRule::parse(value, true, true)?),
+            "more_clear_input_headers" => self.input.push(HeaderRule::parse(value, false, true)?),
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    pub fn merge(&self, parent: &HeadersConfig) -> HeadersConfig {
+        fn inherit<T: Clone>(v: &[T], parent: &[T]) -> Vec<T> {
+            match v.is_empty() {
+                true => parent.to_vec(),
+                false => v.to_vec(),
+            }
+        }
+
+        HeadersConfig {
+            add_header: inherit(&self.add_header, &parent.add_header),
+            output: inherit(&self.output, &parent.output),
+            input: inherit(&self.input, &parent.input),
+        }
+    }
+}
+
+impl TryFrom<ParsedValue> for AddHeader {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<AddHeader, ConfigError> {
+        let values: Vec<String> = data.try_into()?;
+        match values.as_slice() {
+            [name, value] => Ok(AddHeader {
+                name: name.clone(),
+                value: value.as_str().into(),
+                always: false,
+            }),
+            [name, value, always] if always == "always" => Ok(AddHeader {
+                name: name.clone(),
+                value: value.as_str().into(),
+                always: true,
+            }),
+            _ => Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue {
+                    value: values.join(" "),
+                },
+            }),
+        }
+    }
+}
+
+impl HeaderRule {
+    /// Parses the arguments of a `more_*_headers` directive, which are the
+    /// options and the quoted headers, e.g. `-s "404 500" "X-Foo: bar"`.
+    fn parse(data: ParsedValue, set: bool, input: bool) -> Result<HeaderRule, ConfigError> {
+        let values: Vec<String> = data.try_into()?;
+        let invalid = || ConfigError {
+            kind: ErrorKind::UnexpectedValue {
+                value: values.join(" "),
+            },
+        };
+
+        let mut rule = HeaderRule {
+            action: HeaderAction::Clear(vec![]),
+            status: vec![],
+            types: vec![],
+            replace: false,
+        };
+        let mut headers = vec![];
+        let mut args = values.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                // the status of a request is unknown
+                "-s" if !input => {
+                    let status = args.next().ok_or_else(invalid)?;
+                    for v in status.split_whitespace() {
+                        rule.status.push(v.parse()?);
+                    }
+                }
+                "-t" => {
+                    let types = args.next().ok_or_else(invalid)?;
+                    rule.types
+                        .extend(types.split_whitespace().map(|v| v.to_ascii_lowercase()));
+                }
+                "-r" if input && set => rule.replace = true,
+                v if v.starts_with('-') => return Err(invalid()),
+                v => headers.push(v),
+            }
+        }
+        if headers.is_empty() {
+            return Err(invalid());
+        }
+
+        rule.action = match set {
+            true => HeaderAction::Set(
+                headers
+                    .into_iter()
+                    .map(|v| match v.split_once(':') {
+                        Some((name, value)) => (name.trim().to_owned(), value.trim().into()),
+                        None => (v.trim().to_owned(), Template::default()),
+                    })
+                    .collect(),
+            ),
+            false => HeaderAction::Clear(
+                headers
+                    .into_iter()
+                    // a trailing colon is allowed as in `more_set_headers`
+                    .map(|v| v.trim_end_matches(':').trim().to_owned())
+                    .collect(),
+            ),
+        };
+        Ok(rule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::headers::{AddHeader, HeaderAction, HeaderRule, HeadersConfig};
+    use vulpes_parser::ParsedValue;
+
+    fn headers(config: &str) -> Result<HeadersConfig, crate::config::error::ConfigError> {
+        let (_, parsed) = vulpes_parser::parse(config.as_bytes()).unwrap();
+        let mut c = HeadersConfig::default();
+        for v in parsed {
+            assert!(c.set(&v.label, v.value)?, "{}", v.label);
+        }
+        Ok(c)
+    }
+
+    #[test]
+    fn test_headers() {
+        let c = headers(
+            r#"
+            add_header X-A $uri;
+            add_header X-B b always;
+            more_set_headers -s "404 500" -t text/html "X-C: c $uri" "X-D:";
+            more_clear_headers Server "X-Powered-*";
+            more_set_input_headers -r "X-E: e";
+            more_clear_input_headers Cookie;
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            c.add_header,
+            vec![
+                AddHeader {
+                    name: "X-A".to_owned(),
+                    value: "$uri".into(),
+                    always: false,
+                },
+                AddHeader {
+                    name: "X-B".to_owned(),
+                    value: "b".into(),
+                    always: true,
+                },
+            ]
+        );
+        assert_eq!(
+            c.output,
+            vec![
+                HeaderRule {
+                    action: HeaderAction::Set(vec![
+                        ("X-C".to_owned(), "c $uri".into()),
+                        ("X-D".to_owned(), "".into()),
+                    ]),
+                    status: vec![404, 500],
+                    types: vec!["text/html".to_owned()],
+                    replace: false,
+                },
+                HeaderRule {
+                    action: HeaderAction::Clear(vec![
+                        "Server".to_owned(),
+                        "X-Powered-*".to_owned()
+                    ]),
+                    status: vec![],
+                    types: vec![],
+                    replace: false,
+                },
+            ]
+        );
+        assert_eq!(c.input.len(), 2);
+        assert!(c.input[0].replace);
+
+        for v in [
+            "add_header X-A;",
+            "add_header X-A a never;",
+            r#"more_set_headers -s;"#,
+            r#"more_set_headers -s "abc" "X-A: a";"#,
+            r#"more_set_headers -r "X-A: a";"#,
+            r#"more_set_input_headers -s 200 "X-A: a";"#,
+            r#"more_clear_headers -t text/html;"#,
+        ] {
+            assert!(headers(v).is_err(), "{}", v);
+        }
+
+        let mut c = HeadersConfig::default();
+        assert!(!c
+            .set("proxy_set_header", ParsedValue::Value(vec![]))
+            .unwrap());
+    }
+
+    #[test]
+    fn test_merge() {
+        let http = headers(
+            r#"
+            add_header X-A a;
+            more_clear_headers Server;
+            "#,
+        )
+        .unwrap();
+        let server = headers("add_header X-B b;").unwrap();
+
+        // a level defining `add_header` doesn't inherit any of its parent
+        let merged = server.merge(&http);
+        assert_eq!(merged.add_header, server.add_header);
+        assert_eq!(merged.output, http.output);
+        assert!(merged.input.is_empty());
+        assert_eq!(HeadersConfig::default().merge(&merged), merged);
+    }
+}
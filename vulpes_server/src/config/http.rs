@@ -1,12 +1,27 @@
 use crate::config::{
+    access_log::{set_access_log, AccessLog, LogFormat, COMBINED},
+    cache::CachePathConfig,
     error::{ConfigError, ErrorKind},
+    headers::HeadersConfig,
+    map::{GeoConfig, MapConfig},
     server::ServerConfig,
+    types::Template,
+    upstream::UpstreamConfig,
 };
+use std::collections::HashMap;
 use vulpes_parser::ParsedValue;
 
 #[derive(Debug, PartialEq, Default)]
 pub struct HttpConfig {
     pub server: Vec<ServerConfig>,
+    pub upstream: HashMap<String, UpstreamConfig>,
+    pub proxy_cache_path: Vec<CachePathConfig>,
+    pub map: Vec<MapConfig>,
+    pub geo: Vec<GeoConfig>,
+    pub headers: HeadersConfig,
+    /// Formats of `log_format` by name, along with `combined`.
+    pub log_format: HashMap<String, Template>,
+    pub access_log: Option<Vec<AccessLog>>,
 }
 
 impl TryFrom<ParsedValue> for HttpConfig {
@@ -23,8 +38,30 @@ impl TryFrom<ParsedValue> for HttpConfig {
                     "server" => {
                         c.server.push(ServerConfig::try_from(v.value)?);
                     }
-                    _ => {
-                        log::warn!("unknown label in http: {:?}", v.label);
+                    "upstream" => {
+                        let upstream = UpstreamConfig::try_from(v.value)?;
+                        c.upstream.insert(upstream.name.clone(), upstream);
+                    }
+                    "proxy_cache_path" => {
+                        c.proxy_cache_path.push(v.value.try_into()?);
+                    }
+                    "map" => {
+                        c.map.push(v.value.try_into()?);
+                    }
+                    "geo" => {
+                        c.geo.push(v.value.try_into()?);
+                    }
+                    "log_format" => {
+                        let format: LogFormat = v.value.try_into()?;
+                        c.log_format.insert(format.name, format.format);
+                    }
+                    "access_log" => {
+                        set_access_log(&mut c.access_log, v.value)?;
+                    }
+                    label => {
+                        if !c.headers.set(label, v.value.clone())? {
+                            log::warn!("unknown label in http: {:?}", v.label);
+                        }
                     }
                 }
             }
@@ -34,6 +71,39 @@ impl TryFrom<ParsedValue> for HttpConfig {
             });
         }
 
-        return Ok(c);
+        // the header directives of a level apply unless a nested level has
+        // its own, as do the access logs
+        for server in c.server.iter_mut() {
+            server.headers = server.headers.merge(&c.headers);
+            server.access_log = server.access_log.take().or_else(|| c.access_log.clone());
+            for location in server.location.values_mut() {
+                location.headers = location.headers.merge(&server.headers);
+                location.access_log = location
+                    .access_log
+                    .take()
+                    .or_else(|| server.access_log.clone());
+            }
+        }
+
+        c.log_format
+            .entry("combined".to_owned())
+            .or_insert_with(|| COMBINED.into());
+        let logs = c.server.iter().flat_map(|s| {
+            s.location
+                .values()
+                .flat_map(|l| l.access_log.iter().flatten())
+                .chain(s.access_log.iter().flatten())
+        });
+        for log in logs.chain(c.access_log.iter().flatten()) {
+            if !c.log_format.contains_key(&log.format) {
+                return Err(ConfigError {
+                    kind: ErrorKind::UnexpectedValue {
+                        value: log.format.clone(),
+                    },
+                });
+            }
+        }
+
+        Ok(c)
     }
 }
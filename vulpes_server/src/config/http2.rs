@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+/// HTTP/2 settings of a server, set by the `http2*` directives. A listener
+/// uses the settings of its default server, except for `Upgrade: h2c`
+/// requests which are taken over when their server enables HTTP/2.
+///
+/// The settings sent in `HTTP2-Settings` apply to an upgraded connection.
+/// Responses are sent by the urgency of the `priority` header (RFC 9218) of
+/// the response or else of its request. The PRIORITY frames and the
+/// priorities of HEADERS, deprecated by RFC 9113, are ignored.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Http2Config {
+    /// `http2 on`, negotiating h2 with ALPN and accepting prior knowledge
+    /// h2c connections and `Upgrade: h2c` requests. Off by default.
+    pub enabled: bool,
+    pub max_concurrent_streams: u32,
+    /// Maximum size of the request headers after decompression.
+    pub max_header_size: u32,
+    /// Connections without new streams for this long are closed gracefully.
+    pub idle_timeout: Duration,
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Http2Config {
+            enabled: false,
+            max_concurrent_streams: 128,
+            max_header_size: 16 * 1024,
+            idle_timeout: Duration::from_secs(3 * 60),
+        }
+    }
+}
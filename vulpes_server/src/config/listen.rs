@@ -0,0 +1,306 @@
+use crate::config::{
+    error::{ConfigError, ErrorKind},
+    types::parse_duration,
+};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    time::Duration,
+};
+use vulpes_parser::ParsedValue;
+
+/// Address and socket parameters of a `listen` directive.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListenConfig {
+    pub address: ListenAddress,
+    /// The server handles the requests whose host matches no server name.
+    pub default_server: bool,
+    pub ssl: bool,
+    /// Listens on the UDP port for HTTP/3.
+    pub quic: bool,
+    /// Maximum length of the queue of pending connections.
+    pub backlog: i32,
+    /// Sets `SO_REUSEPORT`, letting several sockets bind the same address.
+    pub reuseport: bool,
+    /// Sets `IPV6_V6ONLY` on IPv6 sockets, so that `[::]` only accepts IPv6
+    /// connections.
+    pub ipv6only: bool,
+    /// Sets `SO_KEEPALIVE` on the TCP sockets, the system settings are kept
+    /// when unset.
+    pub so_keepalive: Option<SoKeepalive>,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum ListenAddress {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// TCP keepalive probes, set by `so_keepalive=on|off|[idle]:[interval]:[count]`.
+/// The parts left out keep the system settings.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SoKeepalive {
+    pub enabled: bool,
+    pub idle: Option<Duration>,
+    pub interval: Option<Duration>,
+    pub count: Option<u32>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        ListenConfig {
+            address: ListenAddress::Inet(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 80)),
+            default_server: false,
+            ssl: false,
+            quic: false,
+            backlog: 511,
+            reuseport: false,
+            ipv6only: true,
+            so_keepalive: None,
+        }
+    }
+}
+
+impl ListenConfig {
+    /// Returns the port of a TCP or UDP address.
+    pub fn port(&self) -> Option<u16> {
+        match &self.address {
+            ListenAddress::Inet(addr) => Some(addr.port()),
+            ListenAddress::Unix(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Inet(addr) => write!(f, "{}", addr),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl TryFrom<&str> for ListenAddress {
+    type Error = ConfigError;
+
+    /// Parses `port`, `address[:port]`, `[ipv6][:port]`, `*:port` or
+    /// `unix:path`, where the port defaults to 80 and a host name is
+    /// resolved to its first address.
+    fn try_from(value: &str) -> Result<ListenAddress, ConfigError> {
+        let invalid = || ConfigError {
+            kind: ErrorKind::UnexpectedValue {
+                value: value.to_owned(),
+            },
+        };
+
+        if let Some(path) = value.strip_prefix("unix:") {
+            return match path.is_empty() {
+                true => Err(invalid()),
+                false => Ok(ListenAddress::Unix(PathBuf::from(path))),
+            };
+        }
+
+        if !value.is_empty() && value.bytes().all(|v| v.is_ascii_digit()) {
+            let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), value.parse()?);
+            return Ok(ListenAddress::Inet(addr));
+        }
+
+        let (host, port) = match value.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => (host, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+                None => return Err(invalid()),
+            },
+            None => match value.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (value, None),
+            },
+        };
+        let port: u16 = match port {
+            Some(port) => port.parse()?,
+            None => 80,
+        };
+
+        let ip = match host {
+            "*" => Ipv4Addr::UNSPECIFIED.into(),
+            _ => match host.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => (host, port)
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut v| v.next())
+                    .map(|v| v.ip())
+                    .ok_or_else(invalid)?,
+            },
+        };
+        Ok(ListenAddress::Inet(SocketAddr::new(ip, port)))
+    }
+}
+
+impl TryFrom<&str> for SoKeepalive {
+    type Error = ConfigError;
+
+    fn try_from(value: &str) -> Result<SoKeepalive, ConfigError> {
+        let mut c = SoKeepalive {
+            enabled: true,
+            ..Default::default()
+        };
+
+        match value {
+            "on" => {}
+            "off" => c.enabled = false,
+            _ => {
+                let parts: Vec<&str> = value.split(':').collect();
+                if parts.len() != 3 {
+                    return Err(ConfigError {
+                        kind: ErrorKind::UnexpectedValue {
+                            value: value.to_owned(),
+                        },
+                    });
+                }
+                let duration = |v: &str| match v {
+                    "" => Ok(None),
+                    // a bare number is in seconds as in nginx
+                    v if v.bytes().all(|b| b.is_ascii_digit()) => {
+                        Ok(Some(Duration::from_secs(v.parse()?)))
+                    }
+                    v => parse_duration(v).map(Some),
+                };
+                c.idle = duration(parts[0])?;
+                c.interval = duration(parts[1])?;
+                if !parts[2].is_empty() {
+                    c.count = Some(parts[2].parse()?);
+                }
+            }
+        }
+
+        Ok(c)
+    }
+}
+
+impl TryFrom<ParsedValue> for ListenConfig {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<ListenConfig, ConfigError> {
+        let mut c = Self::default();
+
+        let mut values: Vec<String> = data.try_into()?;
+        values.reverse();
+
+        match values.pop() {
+            Some(address) => c.address = address.as_str().try_into()?,
+            None => {
+                return Err(ConfigError {
+                    kind: ErrorKind::UnexpectedValue {
+                        value: "listen".to_owned(),
+                    },
+                })
+            }
+        }
+
+        while let Some(v) = values.pop() {
+            match v.split_once('=') {
+                Some(("backlog", n)) => c.backlog = n.parse()?,
+                Some(("ipv6only", "on")) => c.ipv6only = true,
+                Some(("ipv6only", "off")) => c.ipv6only = false,
+                Some(("so_keepalive", k)) => c.so_keepalive = Some(k.try_into()?),
+                None if v == "default_server" => c.default_server = true,
+                None if v == "ssl" => c.ssl = true,
+                None if v == "quic" => c.quic = true,
+                None if v == "reuseport" => c.reuseport = true,
+                // HTTP/2 is enabled with the `http2` directive
+                None if v == "http2" => {}
+                _ => {
+                    return Err(ConfigError {
+                        kind: ErrorKind::UnexpectedValue { value: v },
+                    })
+                }
+            }
+        }
+
+        if c.quic && c.ssl {
+            return Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue {
+                    value: "ssl quic".to_owned(),
+                },
+            });
+        }
+
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::listen::{ListenAddress, ListenConfig, SoKeepalive};
+    use std::time::Duration;
+    use vulpes_parser::ParsedValue;
+
+    fn listen(v: &str) -> ListenConfig {
+        ParsedValue::Value(
+            v.split(' ')
+                .map(|v| ParsedValue::String(v.to_owned()))
+                .collect(),
+        )
+        .try_into()
+        .unwrap()
+    }
+
+    fn address(v: &str) -> ListenAddress {
+        match v.strip_prefix("unix:") {
+            Some(path) => ListenAddress::Unix(path.into()),
+            None => ListenAddress::Inet(v.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_listen_address() {
+        for (value, expected) in [
+            ("8080", "0.0.0.0:8080"),
+            ("127.0.0.1:8080", "127.0.0.1:8080"),
+            ("127.0.0.1", "127.0.0.1:80"),
+            ("*:443", "0.0.0.0:443"),
+            ("[::]:443", "[::]:443"),
+            ("[::1]", "[::1]:80"),
+            ("unix:/run/vulpes.sock", "unix:/run/vulpes.sock"),
+        ] {
+            assert_eq!(listen(value).address, address(expected), "{}", value);
+        }
+
+        for value in ["[::1", "[::1]8080", "unix:", "127.0.0.1:http"] {
+            assert!(ListenAddress::try_from(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_listen_parameters() {
+        assert_eq!(
+            listen("[::]:443 ssl default_server backlog=128 reuseport ipv6only=off so_keepalive=30m::10"),
+            ListenConfig {
+                address: address("[::]:443"),
+                default_server: true,
+                ssl: true,
+                backlog: 128,
+                reuseport: true,
+                ipv6only: false,
+                so_keepalive: Some(SoKeepalive {
+                    enabled: true,
+                    idle: Some(Duration::from_secs(30 * 60)),
+                    interval: None,
+                    count: Some(10),
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            listen("80 so_keepalive=off").so_keepalive,
+            Some(SoKeepalive::default())
+        );
+
+        let values = ParsedValue::Value(vec![
+            ParsedValue::String("80".to_owned()),
+            ParsedValue::String("fastopen=3".to_owned()),
+        ]);
+        assert!(ListenConfig::try_from(values).is_err());
+    }
+}
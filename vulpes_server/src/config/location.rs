@@ -1,6 +1,16 @@
 use crate::config::{
+    access_log::{set_access_log, AccessLog},
+    cache::CacheConfig,
     error::{ConfigError, ErrorKind},
-    types::Return,
+    gateway::{FastcgiConfig, GatewayConfig},
+    grpc::{self, GrpcConfig},
+    headers::HeadersConfig,
+    proxy::ProxyConfig,
+    rewrite::RewriteRule,
+    types::{
+        parse_duration, parse_flag, parse_header, parse_single, Pattern, ProxyPass, RedirectConfig,
+        Return,
+    },
 };
 use vulpes_parser::ParsedValue;
 
@@ -9,6 +19,24 @@ pub struct LocationConfig {
     pub path: String,
     pub exp: LocationExp,
     pub ret: Return,
+    pub redirect: RedirectConfig,
+    /// `rewrite`, `if` and `break` in their order.
+    pub rewrite: Vec<RewriteRule>,
+    pub proxy_pass: Option<ProxyPass>,
+    pub proxy: ProxyConfig,
+    pub proxy_cache: CacheConfig,
+    pub fastcgi: FastcgiConfig,
+    pub uwsgi: GatewayConfig,
+    pub scgi: GatewayConfig,
+    pub grpc: GrpcConfig,
+    /// Directory exposed as `$document_root`.
+    pub root: Option<String>,
+    /// Headers of the location, merged with those of the enclosing levels.
+    pub headers: HeadersConfig,
+    /// Logs of the requests of the location, merged with those of the
+    /// enclosing levels.
+    pub access_log: Option<Vec<AccessLog>>,
+    pub health_check_status: bool,
 }
 
 #[derive(Debug, PartialEq, Default, Clone)]
@@ -26,7 +54,7 @@ impl TryFrom<String> for LocationExp {
             "" => Ok(LocationExp::Empty),
             "=" => Ok(LocationExp::Exact),
             _ => Err(ConfigError {
-                kind: ErrorKind::UnexpectedValue { value: value },
+                kind: ErrorKind::UnexpectedValue { value },
             }),
         }
     }
@@ -74,8 +102,122 @@ impl TryFrom<ParsedValue> for LocationConfig {
                         "return" => {
                             c.ret = v.value.try_into()?;
                         }
-                        _ => {
-                            log::warn!("unknown config in location: {}", v);
+                        "rewrite" => {
+                            c.rewrite.push(RewriteRule::Rewrite(v.value.try_into()?));
+                        }
+                        "if" => {
+                            c.rewrite.push(RewriteRule::If(v.value.try_into()?));
+                        }
+                        "break" => {
+                            c.rewrite.push(RewriteRule::Break);
+                        }
+                        "absolute_redirect" => {
+                            c.redirect.absolute = Some(parse_flag(v.value)?);
+                        }
+                        "server_name_in_redirect" => {
+                            c.redirect.server_name = Some(parse_flag(v.value)?);
+                        }
+                        "port_in_redirect" => {
+                            c.redirect.port = Some(parse_flag(v.value)?);
+                        }
+                        "health_check_status" => {
+                            c.health_check_status = true;
+                        }
+                        "proxy_pass" => {
+                            c.proxy_pass = Some(v.value.try_into()?);
+                        }
+                        "proxy_set_header" => {
+                            c.proxy.set_header.push(parse_header(v.value)?);
+                        }
+                        "proxy_hide_header" => {
+                            c.proxy.hide_header.push(parse_single(v.value)?);
+                        }
+                        "proxy_connect_timeout" => {
+                            c.proxy.connect_timeout = parse_duration(&parse_single(v.value)?)?;
+                        }
+                        "proxy_read_timeout" => {
+                            c.proxy.read_timeout = parse_duration(&parse_single(v.value)?)?;
+                        }
+                        "proxy_send_timeout" => {
+                            c.proxy.send_timeout = parse_duration(&parse_single(v.value)?)?;
+                        }
+                        "proxy_next_upstream" => {
+                            c.proxy.next_upstream = v.value.try_into()?;
+                        }
+                        "proxy_next_upstream_tries" => {
+                            c.proxy.next_upstream_tries = parse_single(v.value)?.parse()?;
+                        }
+                        "proxy_cache" => {
+                            c.proxy_cache.zone = match parse_single(v.value)?.as_str() {
+                                "off" => None,
+                                zone => Some(zone.to_owned()),
+                            };
+                        }
+                        "proxy_cache_key" => {
+                            c.proxy_cache.key = parse_single(v.value)?.into();
+                        }
+                        "proxy_cache_valid" => {
+                            c.proxy_cache.valid.push(v.value.try_into()?);
+                        }
+                        "proxy_cache_lock" => {
+                            c.proxy_cache.lock = parse_flag(v.value)?;
+                        }
+                        "proxy_cache_lock_timeout" => {
+                            c.proxy_cache.lock_timeout = parse_duration(&parse_single(v.value)?)?;
+                        }
+                        "proxy_cache_use_stale" => {
+                            c.proxy_cache.use_stale = v.value.try_into()?;
+                        }
+                        "proxy_cache_background_update" => {
+                            c.proxy_cache.background_update = parse_flag(v.value)?;
+                        }
+                        "proxy_cache_purge" => {
+                            c.proxy_cache.purge = v.value.try_into()?;
+                        }
+                        "proxy_next_upstream_timeout" => {
+                            c.proxy.next_upstream_timeout =
+                                parse_duration(&parse_single(v.value)?)?;
+                        }
+                        "access_log" => {
+                            set_access_log(&mut c.access_log, v.value)?;
+                        }
+                        "root" => {
+                            c.root = Some(parse_single(v.value)?);
+                        }
+                        "grpc_pass" => {
+                            c.grpc.pass = Some(grpc::parse_pass(v.value)?);
+                        }
+                        "grpc_set_header" => {
+                            c.grpc.set_header.push(parse_header(v.value)?);
+                        }
+                        "grpc_connect_timeout" => {
+                            c.grpc.connect_timeout = parse_duration(&parse_single(v.value)?)?;
+                        }
+                        "grpc_read_timeout" => {
+                            c.grpc.read_timeout = parse_duration(&parse_single(v.value)?)?;
+                        }
+                        "grpc_send_timeout" => {
+                            c.grpc.send_timeout = parse_duration(&parse_single(v.value)?)?;
+                        }
+                        "fastcgi_index" => {
+                            c.fastcgi.index = Some(parse_single(v.value)?);
+                        }
+                        "fastcgi_split_path_info" => {
+                            c.fastcgi.split_path_info =
+                                Some(Pattern::new(&parse_single(v.value)?)?);
+                        }
+                        label => {
+                            let known = match label.split_once('_') {
+                                Some(("fastcgi", name)) => {
+                                    c.fastcgi.gateway.set(name, v.value.clone())?
+                                }
+                                Some(("uwsgi", name)) => c.uwsgi.set(name, v.value.clone())?,
+                                Some(("scgi", name)) => c.scgi.set(name, v.value.clone())?,
+                                _ => c.headers.set(label, v.value.clone())?,
+                            };
+                            if !known {
+                                log::warn!("unknown config in location: {}", v);
+                            }
                         }
                     }
                 }
@@ -86,6 +228,6 @@ impl TryFrom<ParsedValue> for LocationConfig {
             });
         }
 
-        return Ok(c);
+        Ok(c)
     }
 }
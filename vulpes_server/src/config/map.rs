@@ -0,0 +1,287 @@
+use crate::config::{
+    error::{ConfigError, ErrorKind},
+    network::IpNetwork,
+    server::ServerName,
+    types::{Pattern, Template},
+};
+use vulpes_parser::{ParsedConfig, ParsedValue};
+
+/// `map source $variable { ... }`, which sets the variable to the value of
+/// the first key matching the evaluated source.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct MapConfig {
+    pub source: Template,
+    /// Name of the variable without its `$`.
+    pub variable: String,
+    /// `hostnames`, allowing the keys with wildcards of `server_name`.
+    pub hostnames: bool,
+    /// `volatile`, evaluating the variable again each time it's used.
+    pub volatile: bool,
+    pub default: Template,
+    /// Keys compared without case, except `~regex` which is, in the order
+    /// of the block.
+    pub entries: Vec<(ServerName, Template)>,
+}
+
+/// `geo [address] $variable { ... }`, which sets the variable to the value
+/// of the longest network containing the address.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct GeoConfig {
+    /// Address the networks are matched against, `$remote_addr` when unset.
+    pub source: Option<Template>,
+    /// Name of the variable without its `$`.
+    pub variable: String,
+    pub default: String,
+    pub networks: Vec<(IpNetwork, String)>,
+}
+
+/// Splits the arguments of `map` and `geo` into the sources, the variable
+/// and the block.
+fn parse_block(data: ParsedValue) -> Result<(Vec<String>, String, Vec<ParsedConfig>), ConfigError> {
+    let mut values = match data {
+        ParsedValue::Value(v) => v,
+        _ => {
+            return Err(ConfigError {
+                kind: ErrorKind::UnexpectedType { value: data },
+            })
+        }
+    };
+    let block = match values.pop() {
+        Some(ParsedValue::Block(v)) => v,
+        v => {
+            return Err(ConfigError {
+                kind: ErrorKind::UnexpectedType {
+                    value: v.unwrap_or(ParsedValue::Value(vec![])),
+                },
+            })
+        }
+    };
+
+    let mut args: Vec<String> = ParsedValue::Value(values).try_into()?;
+    let variable = match args.pop().as_deref().and_then(|v| v.strip_prefix('$')) {
+        Some(v) if !v.is_empty() => v.to_owned(),
+        _ => {
+            return Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue {
+                    value: args.join(" "),
+                },
+            })
+        }
+    };
+    Ok((args, variable, block))
+}
+
+/// Returns the single value of an entry of a `map` or `geo` block.
+fn entry_value(label: &str, data: ParsedValue) -> Result<String, ConfigError> {
+    let values: Vec<String> = data.try_into()?;
+    match <[String; 1]>::try_from(values) {
+        Ok([value]) => Ok(value),
+        Err(values) => Err(ConfigError {
+            kind: ErrorKind::UnexpectedValue {
+                value: format!("{} {}", label, values.join(" ")),
+            },
+        }),
+    }
+}
+
+impl TryFrom<ParsedValue> for MapConfig {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<MapConfig, ConfigError> {
+        let (args, variable, block) = parse_block(data)?;
+        let source = match <[String; 1]>::try_from(args) {
+            Ok([source]) => source,
+            Err(args) => {
+                return Err(ConfigError {
+                    kind: ErrorKind::UnexpectedValue {
+                        value: args.join(" "),
+                    },
+                })
+            }
+        };
+
+        let mut c = MapConfig {
+            source: source.into(),
+            variable,
+            ..Default::default()
+        };
+
+        // the keys are kept until `hostnames`, which may come after them
+        let mut keys = vec![];
+        for v in block {
+            match v.label.as_ref() {
+                "hostnames" => c.hostnames = true,
+                "volatile" => c.volatile = true,
+                "default" => c.default = entry_value(&v.label, v.value)?.into(),
+                _ => {
+                    let value = entry_value(&v.label, v.value)?;
+                    keys.push((v.label, value));
+                }
+            }
+        }
+
+        for (key, value) in keys {
+            let name = if let Some(regex) = key.strip_prefix("~*") {
+                ServerName::Regex(Pattern::new(&format!("(?i){}", regex))?)
+            } else if let Some(regex) = key.strip_prefix('~') {
+                ServerName::Regex(Pattern::new(regex)?)
+            } else {
+                // `\` escapes a key that would be a parameter or a regex
+                let key = key.strip_prefix('\\').unwrap_or(&key);
+                match c.hostnames {
+                    true => ServerName::try_from(key.to_owned())?,
+                    false => ServerName::Exact(key.to_ascii_lowercase()),
+                }
+            };
+            c.entries.push((name, value.into()));
+        }
+
+        Ok(c)
+    }
+}
+
+impl TryFrom<ParsedValue> for GeoConfig {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<GeoConfig, ConfigError> {
+        let (args, variable, block) = parse_block(data)?;
+        let source = match <[String; 1]>::try_from(args) {
+            Ok([source]) => Some(source.into()),
+            Err(args) if args.is_empty() => None,
+            Err(args) => {
+                return Err(ConfigError {
+                    kind: ErrorKind::UnexpectedValue {
+                        value: args.join(" "),
+                    },
+                })
+            }
+        };
+
+        let mut c = GeoConfig {
+            source,
+            variable,
+            ..Default::default()
+        };
+        for v in block {
+            let value = entry_value(&v.label, v.value)?;
+            match v.label.as_ref() {
+                "default" => c.default = value,
+                _ => c.networks.push((v.label.as_str().try_into()?, value)),
+            }
+        }
+
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{
+        map::{GeoConfig, MapConfig},
+        network::IpNetwork,
+        server::ServerName,
+        types::Pattern,
+    };
+    use vulpes_parser::ParsedValue;
+
+    fn parse<T: TryFrom<ParsedValue>>(config: &str) -> Result<T, T::Error> {
+        let (_, mut parsed) = vulpes_parser::parse(config.as_bytes()).unwrap();
+        parsed.remove(0).value.try_into()
+    }
+
+    #[test]
+    fn test_map() {
+        let c: MapConfig = parse(
+            r"map $http_host $backend {
+                default a;
+                *.Foo.com b;
+                ~^api c$1;
+                ~*^web d;
+                \default e;
+                hostnames;
+            }",
+        )
+        .unwrap();
+        assert_eq!(c.source, "$http_host".into());
+        assert_eq!(c.variable, "backend");
+        assert!(c.hostnames);
+        assert!(!c.volatile);
+        assert_eq!(c.default, "a".into());
+        assert_eq!(
+            c.entries,
+            vec![
+                (ServerName::Subdomains("foo.com".to_owned()), "b".into()),
+                (
+                    ServerName::Regex(Pattern::new("^api").unwrap()),
+                    "c$1".into()
+                ),
+                (
+                    ServerName::Regex(Pattern::new("(?i)^web").unwrap()),
+                    "d".into()
+                ),
+                (ServerName::Exact("default".to_owned()), "e".into()),
+            ]
+        );
+
+        // without `hostnames` a wildcard is compared as is
+        let c: MapConfig = parse("map $a $b { *.Foo.com b; }").unwrap();
+        assert_eq!(
+            c.entries,
+            vec![(ServerName::Exact("*.foo.com".to_owned()), "b".into())]
+        );
+
+        assert!(parse::<MapConfig>("map $a b { x y; }").is_err());
+        assert!(parse::<MapConfig>("map $a $b { x y z; }").is_err());
+        assert!(parse::<MapConfig>("map $a $b { ~( y; }").is_err());
+        assert!(parse::<MapConfig>("map $a $b $c { x y; }").is_err());
+    }
+
+    #[test]
+    fn test_geo() {
+        let c: GeoConfig = parse(
+            "geo $geo {
+                default none;
+                10.0.0.0/8 internal;
+                127.0.0.1 local;
+                2001:db8::/32 doc;
+            }",
+        )
+        .unwrap();
+        assert_eq!(c.source, None);
+        assert_eq!(c.variable, "geo");
+        assert_eq!(c.default, "none");
+        assert_eq!(
+            c.networks,
+            vec![
+                (
+                    IpNetwork {
+                        addr: "10.0.0.0".parse().unwrap(),
+                        prefix: 8
+                    },
+                    "internal".to_owned()
+                ),
+                (
+                    IpNetwork {
+                        addr: "127.0.0.1".parse().unwrap(),
+                        prefix: 32
+                    },
+                    "local".to_owned()
+                ),
+                (
+                    IpNetwork {
+                        addr: "2001:db8::".parse().unwrap(),
+                        prefix: 32
+                    },
+                    "doc".to_owned()
+                ),
+            ]
+        );
+
+        let c: GeoConfig = parse("geo $arg_ip $geo { }").unwrap();
+        assert_eq!(c.source, Some("$arg_ip".into()));
+
+        assert!(parse::<GeoConfig>("geo $geo { 10.0.0.0/33 a; }").is_err());
+        assert!(parse::<GeoConfig>("geo $geo { example.com a; }").is_err());
+        assert!(parse::<GeoConfig>("geo $geo { 10.0.0.1 a b; }").is_err());
+    }
+}
@@ -1,8 +1,21 @@
+pub mod access_log;
+pub mod cache;
 pub mod error;
+pub mod gateway;
+pub mod grpc;
+pub mod headers;
 pub mod http;
+pub mod http2;
+pub mod listen;
 pub mod location;
+pub mod map;
+pub mod network;
+pub mod proxy;
+pub mod rewrite;
 pub mod server;
+pub mod ssl;
 pub mod types;
+pub mod upstream;
 
 use error::ConfigError;
 use vulpes_parser::ParsedConfig;
@@ -31,15 +44,21 @@ impl TryFrom<Vec<ParsedConfig>> for Config {
             }
         }
 
-        return Ok(c);
+        Ok(c)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::config::{
-        http::HttpConfig, location::LocationConfig, location::LocationExp, server::ServerConfig,
-        types::Return, Config,
+        access_log::COMBINED,
+        http::HttpConfig,
+        listen::{ListenAddress, ListenConfig},
+        location::LocationConfig,
+        location::LocationExp,
+        server::{ServerConfig, ServerName},
+        types::Return,
+        Config,
     };
     use vulpes_parser::{ParsedConfig, ParsedValue};
 
@@ -92,8 +111,14 @@ mod tests {
             Config {
                 http: vec![HttpConfig {
                     server: vec![ServerConfig {
-                        listen: vec![vec!["80".to_owned()], vec!["8080".to_owned()]],
-                        server_name: vec!["example.com".to_owned()],
+                        listen: vec![
+                            ListenConfig::default(),
+                            ListenConfig {
+                                address: ListenAddress::Inet("0.0.0.0:8080".parse().unwrap()),
+                                ..Default::default()
+                            },
+                        ],
+                        server_name: vec![ServerName::Exact("example.com".to_owned())],
                         location: std::collections::HashMap::from([(
                             "/".to_owned(),
                             LocationConfig {
@@ -103,13 +128,20 @@ mod tests {
                                     code: http::StatusCode::OK,
                                     text: None,
                                 },
+                                ..Default::default()
                             }
                         ),]),
                         ret: Return {
                             code: http::StatusCode::NOT_FOUND,
                             text: None,
                         },
-                    }]
+                        ..Default::default()
+                    }],
+                    log_format: std::collections::HashMap::from([(
+                        "combined".to_owned(),
+                        COMBINED.into()
+                    )]),
+                    ..Default::default()
                 },]
             }
         )
@@ -0,0 +1,112 @@
+use crate::config::error::{ConfigError, ErrorKind};
+use std::net::IpAddr;
+
+/// `address/prefix`, or a single address.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IpNetwork {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+/// Address family of [`key`], IPv4-mapped IPv6 addresses being IPv4.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl TryFrom<&str> for IpNetwork {
+    type Error = ConfigError;
+
+    fn try_from(value: &str) -> Result<IpNetwork, ConfigError> {
+        let invalid = || ConfigError {
+            kind: ErrorKind::UnexpectedValue {
+                value: value.to_owned(),
+            },
+        };
+
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(v) => v.parse::<u8>().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+impl IpNetwork {
+    /// Returns the [`key`] of the network address and the number of its
+    /// bits that count. The prefix of an IPv4-mapped network counts the
+    /// IPv6 bits.
+    pub fn key(&self) -> (Family, u128, u8) {
+        match key(self.addr) {
+            (Family::V4, bits) if self.addr.is_ipv6() => {
+                (Family::V4, bits, self.prefix.saturating_sub(96))
+            }
+            (family, bits) => (family, bits, self.prefix),
+        }
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (family, bits, prefix) = self.key();
+        let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+        match key(addr) {
+            (f, v) if f == family => v & mask == bits & mask,
+            _ => false,
+        }
+    }
+}
+
+/// Returns the bits of an address aligned on the most significant bit, with
+/// an IPv4-mapped IPv6 address treated as IPv4.
+pub fn key(addr: IpAddr) -> (Family, u128) {
+    let addr = match addr {
+        IpAddr::V6(v) => match v.to_ipv4_mapped() {
+            Some(v) => IpAddr::V4(v),
+            None => IpAddr::V6(v),
+        },
+        v => v,
+    };
+    match addr {
+        IpAddr::V4(v) => (Family::V4, (u32::from(v) as u128) << 96),
+        IpAddr::V6(v) => (Family::V6, u128::from(v)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::network::IpNetwork;
+
+    #[test]
+    fn test_ip_network() {
+        let network = |v| IpNetwork::try_from(v).unwrap();
+        let contains = |n, a: &str| network(n).contains(a.parse().unwrap());
+
+        assert_eq!(network("10.0.0.0/8").prefix, 8);
+        assert_eq!(network("::1").prefix, 128);
+        assert!(IpNetwork::try_from("10.0.0.0/33").is_err());
+        assert!(IpNetwork::try_from("example.com").is_err());
+
+        assert!(contains("10.0.0.0/8", "10.1.2.3"));
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("::ffff:10.0.0.0/104", "10.1.2.3"));
+        assert!(contains("2001:db8::/32", "2001:db8::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(contains("0.0.0.0/0", "192.168.0.1"));
+        assert!(!contains("0.0.0.0/0", "::1"));
+        assert!(contains("::/0", "::1"));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::config::{
+    error::{ConfigError, ErrorKind},
+    types::Template,
+};
+use http::StatusCode;
+use std::time::Duration;
+use vulpes_parser::ParsedValue;
+
+/// Timeouts, retry policy and headers used when passing a request to an
+/// upstream.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProxyConfig {
+    pub connect_timeout: Duration,
+    /// Maximum time between two successive reads from the upstream.
+    pub read_timeout: Duration,
+    /// Maximum time between two successive writes to the upstream.
+    pub send_timeout: Duration,
+    pub next_upstream: NextUpstream,
+    /// Maximum number of peers tried for a request, 0 means no limit.
+    pub next_upstream_tries: usize,
+    /// Time after which no other peer is tried, zero means no limit.
+    pub next_upstream_timeout: Duration,
+    /// Headers set on the request passed to the upstream, the values may
+    /// contain variables and an empty value removes the header.
+    pub set_header: Vec<(String, Template)>,
+    /// Headers of the upstream response not passed to the client.
+    pub hide_header: Vec<String>,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            connect_timeout: Duration::from_secs(60),
+            read_timeout: Duration::from_secs(60),
+            send_timeout: Duration::from_secs(60),
+            next_upstream: NextUpstream::default(),
+            next_upstream_tries: 0,
+            next_upstream_timeout: Duration::ZERO,
+            set_header: vec![],
+            hide_header: vec![],
+        }
+    }
+}
+
+/// Cases in which a request is passed to the next peer, as listed by
+/// `proxy_next_upstream`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct NextUpstream {
+    pub error: bool,
+    pub timeout: bool,
+    pub invalid_header: bool,
+    pub status: Vec<StatusCode>,
+    /// Also retry requests with a non-idempotent method after they were
+    /// sent to a peer.
+    pub non_idempotent: bool,
+}
+
+impl NextUpstream {
+    pub fn off() -> NextUpstream {
+        NextUpstream {
+            error: false,
+            timeout: false,
+            invalid_header: false,
+            status: vec![],
+            non_idempotent: false,
+        }
+    }
+}
+
+impl Default for NextUpstream {
+    fn default() -> Self {
+        NextUpstream {
+            error: true,
+            timeout: true,
+            ..NextUpstream::off()
+        }
+    }
+}
+
+impl TryFrom<ParsedValue> for NextUpstream {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<NextUpstream, ConfigError> {
+        let mut c = NextUpstream::off();
+
+        let values: Vec<String> = data.try_into()?;
+        for v in values.into_iter().filter(|v| !v.is_empty()) {
+            match v.as_str() {
+                "off" => c = NextUpstream::off(),
+                "error" => c.error = true,
+                "timeout" => c.timeout = true,
+                "invalid_header" => c.invalid_header = true,
+                "non_idempotent" => c.non_idempotent = true,
+                "http_500" | "http_502" | "http_503" | "http_504" | "http_403" | "http_404"
+                | "http_429" => c.status.push(v[5..].parse::<u16>()?.try_into()?),
+                _ => {
+                    return Err(ConfigError {
+                        kind: ErrorKind::UnexpectedValue { value: v },
+                    })
+                }
+            }
+        }
+
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::proxy::NextUpstream;
+    use http::StatusCode;
+    use vulpes_parser::ParsedValue;
+
+    #[test]
+    fn test_next_upstream_try_from() {
+        let value = |v: &[&str]| {
+            ParsedValue::Value(
+                v.iter()
+                    .map(|v| ParsedValue::String(v.to_string()))
+                    .collect(),
+            )
+        };
+
+        assert_eq!(
+            NextUpstream::try_from(value(&["error", "http_502", "http_503", "non_idempotent"]))
+                .unwrap(),
+            NextUpstream {
+                error: true,
+                status: vec![StatusCode::BAD_GATEWAY, StatusCode::SERVICE_UNAVAILABLE],
+                non_idempotent: true,
+                ..NextUpstream::off()
+            }
+        );
+        assert_eq!(
+            NextUpstream::try_from(value(&["off"])).unwrap(),
+            NextUpstream::off()
+        );
+        assert!(NextUpstream::try_from(value(&["http_418"])).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use crate::config::{
+    error::{ConfigError, ErrorKind},
+    types::{Pattern, Return, Template},
+};
+use vulpes_parser::ParsedValue;
+
+/// Directive of the rewrite phase of a server or a location, which runs in
+/// the order of the configuration.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RewriteRule {
+    Rewrite(Rewrite),
+    If(IfBlock),
+    /// `return` inside an `if` block.
+    Return(Return),
+    /// `break`, which stops the rewrite phase and keeps the current location.
+    Break,
+}
+
+/// `if (condition) { ... }`, whose block only allows `rewrite`, `return` and
+/// `break`, and no nested `if`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IfBlock {
+    pub condition: Condition,
+    pub rules: Vec<RewriteRule>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Condition {
+    /// `$var`, true unless the value is empty or `0`.
+    Variable(Template),
+    /// `$var = value` or `$var != value`.
+    Equal {
+        value: Template,
+        other: Template,
+        negate: bool,
+    },
+    /// `$var ~ regex`, `~*` ignoring the case, or `!~` and `!~*`. The
+    /// captures of a match are available as variables.
+    Match {
+        value: Template,
+        pattern: Pattern,
+        negate: bool,
+    },
+    /// `-f`, `-d`, `-e` or `-x` testing a path, or `!-f` etc.
+    File {
+        test: FileTest,
+        path: Template,
+        negate: bool,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FileTest {
+    /// `-f`, a regular file.
+    File,
+    /// `-d`, a directory.
+    Directory,
+    /// `-e`, a file, directory or symbolic link.
+    Exists,
+    /// `-x`, an executable file.
+    Executable,
+}
+
+/// `rewrite regex replacement [flag]`, which changes the URI of the requests
+/// whose path matches `regex`. The replacement may refer to the captures as
+/// `$1` or by name.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Rewrite {
+    pub pattern: Pattern,
+    pub replacement: Template,
+    pub flag: RewriteFlag,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum RewriteFlag {
+    /// Goes on with the next `rewrite`, and then searches the location of
+    /// the new URI.
+    #[default]
+    None,
+    /// Stops the rewrites of the block and searches the location of the new
+    /// URI.
+    Last,
+    /// Stops the rewrites and handles the new URI in the current location.
+    Break,
+    /// Redirects with 302.
+    Redirect,
+    /// Redirects with 301.
+    Permanent,
+}
+
+impl TryFrom<ParsedValue> for Rewrite {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<Rewrite, ConfigError> {
+        let values: Vec<String> = data.try_into()?;
+        let (pattern, replacement, flag) = match values.as_slice() {
+            [pattern, replacement] => (pattern, replacement, RewriteFlag::None),
+            [pattern, replacement, flag] => {
+                let flag = match flag.as_str() {
+                    "last" => RewriteFlag::Last,
+                    "break" => RewriteFlag::Break,
+                    "redirect" => RewriteFlag::Redirect,
+                    "permanent" => RewriteFlag::Permanent,
+                    _ => {
+                        return Err(ConfigError {
+                            kind: ErrorKind::UnexpectedValue {
+                                value: flag.clone(),
+                            },
+                        })
+                    }
+                };
+                (pattern, replacement, flag)
+            }
+            _ => {
+                return Err(ConfigError {
+                    kind: ErrorKind::UnexpectedValue {
+                        value: values.join(" "),
+                    },
+                })
+            }
+        };
+
+        Ok(Rewrite {
+            pattern: Pattern::new(pattern)?,
+            replacement: replacement.as_str().into(),
+            flag,
+        })
+    }
+}
+
+impl TryFrom<Vec<String>> for Condition {
+    type Error = ConfigError;
+
+    /// Parses the words of `(condition)`, whose parentheses may be attached
+    /// to the first and last words.
+    fn try_from(values: Vec<String>) -> Result<Condition, ConfigError> {
+        let invalid = || ConfigError {
+            kind: ErrorKind::UnexpectedValue {
+                value: values.join(" "),
+            },
+        };
+
+        let mut words: Vec<&str> = values.iter().map(String::as_str).collect();
+        match words.first_mut().and_then(|v| v.strip_prefix('(')) {
+            Some(v) => words[0] = v,
+            None => return Err(invalid()),
+        }
+        match words.last_mut().and_then(|v| v.strip_suffix(')')) {
+            Some(v) => *words.last_mut().unwrap() = v,
+            None => return Err(invalid()),
+        }
+        words.retain(|v| !v.is_empty());
+
+        // quotes are kept by the parser around an empty string
+        let operand = |v: &str| -> Template {
+            match v.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+                Some(v) => v.into(),
+                None => v.into(),
+            }
+        };
+
+        let condition = match words.as_slice() {
+            [value] if value.starts_with('$') => Condition::Variable(operand(value)),
+            [test, path] => {
+                let (negate, test) = match test.strip_prefix('!') {
+                    Some(v) => (true, v),
+                    None => (false, *test),
+                };
+                let test = match test {
+                    "-f" => FileTest::File,
+                    "-d" => FileTest::Directory,
+                    "-e" => FileTest::Exists,
+                    "-x" => FileTest::Executable,
+                    _ => return Err(invalid()),
+                };
+                Condition::File {
+                    test,
+                    path: operand(path),
+                    negate,
+                }
+            }
+            [value, op, other] if value.starts_with('$') => match *op {
+                "=" | "!=" => Condition::Equal {
+                    value: operand(value),
+                    other: operand(other),
+                    negate: *op == "!=",
+                },
+                "~" | "~*" | "!~" | "!~*" => {
+                    let regex = match op.ends_with('*') {
+                        true => format!("(?i){}", other),
+                        false => other.to_string(),
+                    };
+                    Condition::Match {
+                        value: operand(value),
+                        pattern: Pattern::new(&regex)?,
+                        negate: op.starts_with('!'),
+                    }
+                }
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        };
+        Ok(condition)
+    }
+}
+
+impl TryFrom<ParsedValue> for IfBlock {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<IfBlock, ConfigError> {
+        let mut values = match data {
+            ParsedValue::Value(v) => v,
+            _ => {
+                return Err(ConfigError {
+                    kind: ErrorKind::UnexpectedType { value: data },
+                })
+            }
+        };
+        let block = match values.pop() {
+            Some(ParsedValue::Block(v)) => v,
+            v => {
+                return Err(ConfigError {
+                    kind: ErrorKind::UnexpectedType {
+                        value: v.unwrap_or(ParsedValue::Value(vec![])),
+                    },
+                })
+            }
+        };
+        let condition: Vec<String> = ParsedValue::Value(values).try_into()?;
+
+        let mut rules = vec![];
+        for v in block {
+            match v.label.as_ref() {
+                "rewrite" => rules.push(RewriteRule::Rewrite(v.value.try_into()?)),
+                "return" => rules.push(RewriteRule::Return(v.value.try_into()?)),
+                "break" => rules.push(RewriteRule::Break),
+                _ => {
+                    return Err(ConfigError {
+                        kind: ErrorKind::UnexpectedValue {
+                            value: format!("{} in if", v.label),
+                        },
+                    })
+                }
+            }
+        }
+
+        Ok(IfBlock {
+            condition: condition.try_into()?,
+            rules,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{
+        rewrite::{Condition, FileTest, IfBlock, Rewrite, RewriteFlag, RewriteRule},
+        types::Pattern,
+    };
+    use vulpes_parser::ParsedConfig;
+    use vulpes_parser::ParsedValue;
+
+    fn rewrite(values: &[&str]) -> Result<Rewrite, crate::config::error::ConfigError> {
+        ParsedValue::Value(
+            values
+                .iter()
+                .map(|v| ParsedValue::String(v.to_string()))
+                .collect(),
+        )
+        .try_into()
+    }
+
+    #[test]
+    fn test_rewrite() {
+        assert_eq!(
+            rewrite(&["^/old/(.*)$", "/new/$1", "permanent"]).unwrap(),
+            Rewrite {
+                pattern: Pattern::new("^/old/(.*)$").unwrap(),
+                replacement: "/new/$1".into(),
+                flag: RewriteFlag::Permanent,
+            }
+        );
+        assert_eq!(rewrite(&["^/a", "/b?"]).unwrap().flag, RewriteFlag::None);
+
+        assert!(rewrite(&["^/a", "/b", "other"]).is_err());
+        assert!(rewrite(&["^/a"]).is_err());
+        assert!(rewrite(&["(", "/b"]).is_err());
+    }
+
+    fn condition(v: &str) -> Result<Condition, crate::config::error::ConfigError> {
+        v.split(' ')
+            .map(str::to_owned)
+            .collect::<Vec<_>>()
+            .try_into()
+    }
+
+    #[test]
+    fn test_condition() {
+        assert_eq!(
+            condition("($http_user_agent ~* bot)").unwrap(),
+            Condition::Match {
+                value: "$http_user_agent".into(),
+                pattern: Pattern::new("(?i)bot").unwrap(),
+                negate: false,
+            }
+        );
+        assert_eq!(
+            condition("(!-f $request_filename)").unwrap(),
+            Condition::File {
+                test: FileTest::File,
+                path: "$request_filename".into(),
+                negate: true,
+            }
+        );
+        assert_eq!(
+            condition("( $a != '' )").unwrap(),
+            Condition::Equal {
+                value: "$a".into(),
+                other: "".into(),
+                negate: true,
+            }
+        );
+        assert_eq!(condition("($a)").unwrap(), Condition::Variable("$a".into()));
+
+        for v in ["$a", "(a)", "(-z $a)", "($a < 1)", "($a ~ ()"] {
+            assert!(condition(v).is_err(), "{}", v);
+        }
+    }
+
+    #[test]
+    fn test_if_block() {
+        let block = |directives: &[(&str, &str)]| -> Result<IfBlock, _> {
+            ParsedValue::Value(vec![
+                ParsedValue::String("(-d".to_owned()),
+                ParsedValue::String("$request_filename)".to_owned()),
+                ParsedValue::Block(
+                    directives
+                        .iter()
+                        .map(|(label, value)| ParsedConfig {
+                            label: label.to_string(),
+                            value: ParsedValue::Value(
+                                value
+                                    .split(' ')
+                                    .filter(|v| !v.is_empty())
+                                    .map(|v| ParsedValue::String(v.to_owned()))
+                                    .collect(),
+                            ),
+                        })
+                        .collect(),
+                ),
+            ])
+            .try_into()
+        };
+
+        let rules = block(&[("rewrite", "^ /dir last"), ("break", ""), ("return", "403")])
+            .unwrap()
+            .rules;
+        assert!(matches!(
+            rules.as_slice(),
+            [
+                RewriteRule::Rewrite(_),
+                RewriteRule::Break,
+                RewriteRule::Return(_)
+            ]
+        ));
+
+        // only the directives of the rewrite phase are allowed
+        assert!(block(&[("proxy_pass", "http://backend")]).is_err());
+        assert!(block(&[("if", "($a)")]).is_err());
+    }
+}
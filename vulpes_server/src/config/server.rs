@@ -1,17 +1,85 @@
 use crate::config::{
+    access_log::{set_access_log, AccessLog},
     error::{ConfigError, ErrorKind},
+    headers::HeadersConfig,
+    http2::Http2Config,
+    listen::ListenConfig,
     location::LocationConfig,
-    types::Return,
+    rewrite::RewriteRule,
+    ssl::SslConfig,
+    types::{
+        parse_duration, parse_flag, parse_single, parse_size, Pattern, RedirectConfig, Return,
+    },
 };
 use std::collections::HashMap;
 use vulpes_parser::ParsedValue;
 
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct ServerConfig {
-    pub listen: Vec<Vec<String>>,
-    pub server_name: Vec<String>,
+    pub listen: Vec<ListenConfig>,
+    pub server_name: Vec<ServerName>,
     pub location: HashMap<String, LocationConfig>,
     pub ret: Return,
+    pub redirect: RedirectConfig,
+    /// `rewrite`, `if` and `break` applied before the location of a request
+    /// is searched.
+    pub rewrite: Vec<RewriteRule>,
+    pub http2: Http2Config,
+    pub ssl: SslConfig,
+    pub headers: HeadersConfig,
+    /// Logs of the requests that match no location, and of the locations
+    /// without their own.
+    pub access_log: Option<Vec<AccessLog>>,
+}
+
+/// Name of a `server_name` directive, which is matched case-insensitively.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerName {
+    Exact(String),
+    /// `*.example.com`, matching the subdomains of `example.com`.
+    Subdomains(String),
+    /// `.example.com`, matching `example.com` and its subdomains.
+    Domain(String),
+    /// `www.example.*`, stored with its trailing dot as `www.example.`.
+    Prefix(String),
+    /// `~regex`, whose captures are available as variables.
+    Regex(Pattern),
+}
+
+impl TryFrom<String> for ServerName {
+    type Error = ConfigError;
+
+    fn try_from(value: String) -> Result<ServerName, ConfigError> {
+        if let Some(regex) = value.strip_prefix('~') {
+            return Ok(ServerName::Regex(Pattern::new(&format!("(?i){}", regex))?));
+        }
+
+        let name = value.to_ascii_lowercase();
+        let name = if let Some(domain) = name.strip_prefix("*.") {
+            ServerName::Subdomains(domain.to_owned())
+        } else if let Some(prefix) = name.strip_suffix(".*") {
+            ServerName::Prefix(format!("{}.", prefix))
+        } else if let Some(domain) = name.strip_prefix('.') {
+            ServerName::Domain(domain.to_owned())
+        } else {
+            ServerName::Exact(name)
+        };
+
+        // a wildcard may only replace the first or the last label
+        match &name {
+            ServerName::Subdomains(v) | ServerName::Prefix(v) | ServerName::Domain(v)
+                if v.is_empty() || v.contains('*') || v == "." =>
+            {
+                Err(ConfigError {
+                    kind: ErrorKind::UnexpectedValue { value },
+                })
+            }
+            ServerName::Exact(v) if v.contains('*') => Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue { value },
+            }),
+            _ => Ok(name),
+        }
+    }
 }
 
 impl TryFrom<ParsedValue> for ServerConfig {
@@ -29,7 +97,11 @@ impl TryFrom<ParsedValue> for ServerConfig {
                         c.listen.push(v.value.try_into()?);
                     }
                     "server_name" => {
-                        c.server_name = v.value.try_into()?;
+                        let names: Vec<String> = v.value.try_into()?;
+                        c.server_name = names
+                            .into_iter()
+                            .map(ServerName::try_from)
+                            .collect::<Result<_, _>>()?;
                     }
                     "location" => {
                         let location: LocationConfig = v.value.try_into()?;
@@ -38,8 +110,53 @@ impl TryFrom<ParsedValue> for ServerConfig {
                     "return" => {
                         c.ret = v.value.try_into()?;
                     }
-                    _ => {
-                        log::warn!("unknown config in server: {}", v);
+                    "rewrite" => {
+                        c.rewrite.push(RewriteRule::Rewrite(v.value.try_into()?));
+                    }
+                    "if" => {
+                        c.rewrite.push(RewriteRule::If(v.value.try_into()?));
+                    }
+                    "break" => {
+                        c.rewrite.push(RewriteRule::Break);
+                    }
+                    "absolute_redirect" => {
+                        c.redirect.absolute = Some(parse_flag(v.value)?);
+                    }
+                    "server_name_in_redirect" => {
+                        c.redirect.server_name = Some(parse_flag(v.value)?);
+                    }
+                    "port_in_redirect" => {
+                        c.redirect.port = Some(parse_flag(v.value)?);
+                    }
+                    "http2" => {
+                        c.http2.enabled = parse_flag(v.value)?;
+                    }
+                    "http2_max_concurrent_streams" => {
+                        c.http2.max_concurrent_streams = parse_single(v.value)?.parse()?;
+                    }
+                    "http2_max_header_size" => {
+                        c.http2.max_header_size = parse_size(&parse_single(v.value)?)?
+                            .try_into()
+                            .map_err(|_| ConfigError {
+                            kind: ErrorKind::UnexpectedValue {
+                                value: "http2_max_header_size".to_owned(),
+                            },
+                        })?;
+                    }
+                    "http2_idle_timeout" => {
+                        c.http2.idle_timeout = parse_duration(&parse_single(v.value)?)?;
+                    }
+                    "access_log" => {
+                        set_access_log(&mut c.access_log, v.value)?;
+                    }
+                    label => {
+                        let known = match label.split_once('_') {
+                            Some(("ssl", name)) => c.ssl.set(name, v.value.clone())?,
+                            _ => c.headers.set(label, v.value.clone())?,
+                        };
+                        if !known {
+                            log::warn!("unknown config in server: {}", v);
+                        }
                     }
                 }
             }
@@ -49,6 +166,38 @@ impl TryFrom<ParsedValue> for ServerConfig {
             });
         }
 
-        return Ok(c);
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{server::ServerName, types::Pattern};
+
+    #[test]
+    fn test_server_name() {
+        for (value, expected) in [
+            ("Example.com", ServerName::Exact("example.com".to_owned())),
+            ("", ServerName::Exact("".to_owned())),
+            (
+                "*.example.com",
+                ServerName::Subdomains("example.com".to_owned()),
+            ),
+            (".example.com", ServerName::Domain("example.com".to_owned())),
+            (
+                "www.example.*",
+                ServerName::Prefix("www.example.".to_owned()),
+            ),
+            (
+                r"~^(?<sub>.+)\.example\.com$",
+                ServerName::Regex(Pattern::new(r"(?i)^(?<sub>.+)\.example\.com$").unwrap()),
+            ),
+        ] {
+            assert_eq!(ServerName::try_from(value.to_owned()).unwrap(), expected);
+        }
+
+        for value in ["*", "www.*.com", "*.example.*", ".", "~("] {
+            assert!(ServerName::try_from(value.to_owned()).is_err(), "{}", value);
+        }
     }
 }
@@ -0,0 +1,242 @@
+use crate::config::{
+    error::{ConfigError, ErrorKind},
+    types::{parse_duration, parse_flag, parse_single, parse_size},
+};
+use std::time::Duration;
+use vulpes_parser::ParsedValue;
+
+/// TLS settings of a server, set by the `ssl_*` directives and used on the
+/// ports it listens on with `ssl`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SslConfig {
+    /// PEM file with the certificate chain, leaf first.
+    pub certificate: Option<String>,
+    /// PEM file with the PKCS#8, PKCS#1 or SEC1 private key.
+    pub certificate_key: Option<String>,
+    /// Enabled protocol versions, `TLSv1.2` and `TLSv1.3`.
+    pub protocols: Vec<String>,
+    /// Enabled cipher suites by their IANA, OpenSSL or rustls names. OpenSSL
+    /// keywords such as `HIGH` or `!aNULL` are ignored with a warning. Empty
+    /// enables the default suites of each protocol version.
+    pub ciphers: Vec<String>,
+    /// PEM file with the CAs trusted to sign client certificates.
+    pub client_certificate: Option<String>,
+    pub verify_client: VerifyClient,
+    /// Maximum number of certificates between a client certificate and the
+    /// trusted CA, the default 1 accepts only certificates issued by the CA.
+    pub verify_depth: usize,
+    /// Staples the OCSP response of `stapling_file` to the certificate.
+    pub stapling: bool,
+    /// DER encoded OCSP response, reloaded along with the certificate.
+    pub stapling_file: Option<String>,
+    pub session_tickets: bool,
+    /// Files of 48 or 80 bytes with the keys of the session tickets. The
+    /// first key encrypts new tickets and the others only decrypt tickets
+    /// issued before a rotation. A random key is used when empty.
+    pub session_ticket_key: Vec<String>,
+    pub session_cache: SessionCache,
+    /// Lifetime of the sessions in the cache and of the tickets.
+    pub session_timeout: Duration,
+    /// Accepts TLS 1.3 early data on resumed sessions, which requires the
+    /// session cache as rustls only accepts it for stateful resumption.
+    pub early_data: bool,
+}
+
+/// Storage of TLS sessions on the server, set by `ssl_session_cache`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum SessionCache {
+    Off,
+    /// Sessions are not stored, but may still be resumed with tickets.
+    #[default]
+    None,
+    /// Cache shared by the servers that use the same name, holding about
+    /// 4000 sessions per megabyte of `size`.
+    Shared {
+        name: String,
+        size: u64,
+    },
+}
+
+impl TryFrom<String> for SessionCache {
+    type Error = ConfigError;
+
+    fn try_from(value: String) -> Result<SessionCache, ConfigError> {
+        match value.as_str() {
+            "off" => return Ok(SessionCache::Off),
+            "none" => return Ok(SessionCache::None),
+            _ => {}
+        }
+
+        match value.split(':').collect::<Vec<_>>()[..] {
+            ["shared", name, size] if !name.is_empty() => Ok(SessionCache::Shared {
+                name: name.to_owned(),
+                size: parse_size(size)?,
+            }),
+            _ => Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue { value },
+            }),
+        }
+    }
+}
+
+/// Whether client certificates are requested, set by `ssl_verify_client`.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum VerifyClient {
+    #[default]
+    Off,
+    /// A certificate signed by a trusted CA is required.
+    On,
+    /// A certificate is verified when the client sends one.
+    Optional,
+    /// A certificate is requested but not required to be trusted, the
+    /// result is only available in `$ssl_client_verify`.
+    OptionalNoCa,
+}
+
+impl Default for SslConfig {
+    fn default() -> Self {
+        SslConfig {
+            certificate: None,
+            certificate_key: None,
+            protocols: vec!["TLSv1.2".to_owned(), "TLSv1.3".to_owned()],
+            ciphers: vec![],
+            client_certificate: None,
+            verify_client: VerifyClient::Off,
+            verify_depth: 1,
+            stapling: false,
+            stapling_file: None,
+            session_tickets: true,
+            session_ticket_key: vec![],
+            session_cache: SessionCache::None,
+            session_timeout: Duration::from_secs(300),
+            early_data: false,
+        }
+    }
+}
+
+const PROTOCOLS: [&str; 2] = ["TLSv1.2", "TLSv1.3"];
+
+impl SslConfig {
+    /// Applies the directive `ssl_<name>`, returning `false` when `name` is
+    /// not a TLS setting.
+    pub fn set(&mut self, name: &str, value: ParsedValue) -> Result<bool, ConfigError> {
+        match name {
+            "certificate" => self.certificate = Some(parse_single(value)?),
+            "certificate_key" => self.certificate_key = Some(parse_single(value)?),
+            "protocols" => {
+                let protocols: Vec<String> = value.try_into()?;
+                if let Some(v) = protocols.iter().find(|v| !PROTOCOLS.contains(&v.as_str())) {
+                    return Err(ConfigError {
+                        kind: ErrorKind::UnexpectedValue { value: v.clone() },
+                    });
+                }
+                self.protocols = protocols;
+            }
+            // OpenSSL style `A:B:C` lists are accepted as well as separate
+            // values
+            "ciphers" => {
+                let ciphers: Vec<String> = value.try_into()?;
+                self.ciphers = ciphers
+                    .iter()
+                    .flat_map(|v| v.split(':'))
+                    .filter(|v| !v.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+            }
+            "client_certificate" => self.client_certificate = Some(parse_single(value)?),
+            "verify_client" => {
+                self.verify_client = match parse_single(value)?.as_str() {
+                    "off" => VerifyClient::Off,
+                    "on" => VerifyClient::On,
+                    "optional" => VerifyClient::Optional,
+                    "optional_no_ca" => VerifyClient::OptionalNoCa,
+                    v => {
+                        return Err(ConfigError {
+                            kind: ErrorKind::UnexpectedValue {
+                                value: v.to_owned(),
+                            },
+                        })
+                    }
+                }
+            }
+            "verify_depth" => self.verify_depth = parse_single(value)?.parse()?,
+            "stapling" => self.stapling = parse_flag(value)?,
+            "stapling_file" => self.stapling_file = Some(parse_single(value)?),
+            "session_tickets" => self.session_tickets = parse_flag(value)?,
+            "session_ticket_key" => self.session_ticket_key.push(parse_single(value)?),
+            "session_cache" => self.session_cache = parse_single(value)?.try_into()?,
+            "session_timeout" => self.session_timeout = parse_duration(&parse_single(value)?)?,
+            "early_data" => self.early_data = parse_flag(value)?,
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ssl::{SessionCache, SslConfig, VerifyClient};
+    use std::time::Duration;
+    use vulpes_parser::ParsedValue;
+
+    fn value(v: &[&str]) -> ParsedValue {
+        ParsedValue::Value(
+            v.iter()
+                .map(|v| ParsedValue::String(v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_ssl_set() {
+        let mut c = SslConfig::default();
+        assert!(c.set("certificate", value(&["cert.pem"])).unwrap());
+        assert!(c.set("protocols", value(&["TLSv1.3"])).unwrap());
+        assert!(c
+            .set(
+                "ciphers",
+                value(&["TLS13_AES_256_GCM_SHA384:TLS13_AES_128_GCM_SHA256"])
+            )
+            .unwrap());
+        assert!(c.set("verify_client", value(&["optional_no_ca"])).unwrap());
+        assert!(c.set("verify_depth", value(&["2"])).unwrap());
+        assert!(c.set("stapling", value(&["on"])).unwrap());
+        assert!(c.set("stapling_file", value(&["ocsp.der"])).unwrap());
+        assert!(c
+            .set("session_ticket_key", value(&["current.key"]))
+            .unwrap());
+        assert!(c
+            .set("session_ticket_key", value(&["previous.key"]))
+            .unwrap());
+        assert!(c.set("session_cache", value(&["shared:SSL:1m"])).unwrap());
+        assert!(c.set("session_timeout", value(&["10m"])).unwrap());
+        assert!(c.set("session_cache", value(&["shared:SSL"])).is_err());
+        assert!(!c.set("prefer_server_ciphers", value(&["on"])).unwrap());
+        assert!(c.set("protocols", value(&["TLSv1"])).is_err());
+        assert!(c.set("verify_client", value(&["maybe"])).is_err());
+
+        assert_eq!(
+            c,
+            SslConfig {
+                certificate: Some("cert.pem".to_owned()),
+                protocols: vec!["TLSv1.3".to_owned()],
+                ciphers: vec![
+                    "TLS13_AES_256_GCM_SHA384".to_owned(),
+                    "TLS13_AES_128_GCM_SHA256".to_owned()
+                ],
+                verify_client: VerifyClient::OptionalNoCa,
+                verify_depth: 2,
+                stapling: true,
+                stapling_file: Some("ocsp.der".to_owned()),
+                session_ticket_key: vec!["current.key".to_owned(), "previous.key".to_owned()],
+                session_cache: SessionCache::Shared {
+                    name: "SSL".to_owned(),
+                    size: 1024 * 1024,
+                },
+                session_timeout: Duration::from_secs(600),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -1,11 +1,11 @@
-use super::error::ConfigError;
-use std::str::FromStr;
+use super::error::{ConfigError, ErrorKind};
+use std::{ops::Deref, str::FromStr};
 use vulpes_parser::ParsedValue;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Return {
     pub code: http::StatusCode,
-    pub text: Option<String>,
+    pub text: Option<Template>,
 }
 
 impl Default for Return {
@@ -27,13 +27,325 @@ impl TryFrom<ParsedValue> for Return {
         ret.reverse();
 
         if let Some(code) = ret.pop() {
+            // `return URL` redirects with 302
+            if ["http://", "https://", "$scheme"]
+                .iter()
+                .any(|v| code.starts_with(v))
+                && ret.is_empty()
+            {
+                c.code = http::StatusCode::FOUND;
+                c.text = Some(code.into());
+                return Ok(c);
+            }
             c.code = http::StatusCode::from_str(&code)?;
         }
 
         if let Some(text) = ret.pop() {
-            c.text = Some(text);
+            c.text = Some(text.into());
         }
 
-        return Ok(c);
+        Ok(c)
+    }
+}
+
+impl Return {
+    /// Returns whether the text is the URL of a redirect rather than a body.
+    pub fn is_redirect(&self) -> bool {
+        [301, 302, 303, 307, 308].contains(&self.code.as_u16())
+    }
+}
+
+/// Settings of the `Location` of redirects, set by `absolute_redirect`,
+/// `server_name_in_redirect` and `port_in_redirect`. A location inherits
+/// the settings left unset from its server.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RedirectConfig {
+    /// Prefixes a relative URL with the scheme, host and port, on when unset.
+    pub absolute: Option<bool>,
+    /// Uses the primary server name instead of the `Host` of the request,
+    /// off when unset.
+    pub server_name: Option<bool>,
+    /// Keeps the port of an absolute URL unless it's the default one of the
+    /// scheme, on when unset.
+    pub port: Option<bool>,
+}
+
+impl RedirectConfig {
+    pub fn merge(&self, parent: &RedirectConfig) -> RedirectConfig {
+        RedirectConfig {
+            absolute: self.absolute.or(parent.absolute),
+            server_name: self.server_name.or(parent.server_name),
+            port: self.port.or(parent.port),
+        }
+    }
+}
+
+/// Compiled regular expression of a directive. Patterns compare equal when
+/// their sources do.
+#[derive(Debug, Clone)]
+pub struct Pattern(regex::Regex);
+
+impl Pattern {
+    pub fn new(value: &str) -> Result<Pattern, ConfigError> {
+        Ok(Pattern(regex::Regex::new(value)?))
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Deref for Pattern {
+    type Target = regex::Regex;
+
+    fn deref(&self) -> &regex::Regex {
+        &self.0
+    }
+}
+
+/// Value of a directive with `$name` and `${name}` variable references,
+/// split into its parts once when the configuration is loaded.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Template(Vec<TemplatePart>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplatePart {
+    Literal(String),
+    Variable(String),
+}
+
+impl Template {
+    pub fn parts(&self) -> &[TemplatePart] {
+        &self.0
+    }
+}
+
+impl From<&str> for Template {
+    /// A `$` that starts no name, or an unclosed `${`, is kept literally. A
+    /// name starting with a digit, a capture, is that single digit.
+    fn from(value: &str) -> Template {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut rest = value;
+
+        while let Some(i) = rest.find('$') {
+            literal.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+
+            let (name, next) = match rest.strip_prefix('{') {
+                Some(r) => match r.find('}') {
+                    Some(j) => (&r[..j], &r[j + 1..]),
+                    None => ("", rest),
+                },
+                None if rest.starts_with(|c: char| c.is_ascii_digit()) => (&rest[..1], &rest[1..]),
+                None => {
+                    let j = rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len());
+                    (&rest[..j], &rest[j..])
+                }
+            };
+
+            if name.is_empty() {
+                literal.push('$');
+            } else {
+                if !literal.is_empty() {
+                    parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(TemplatePart::Variable(name.to_owned()));
+            }
+            rest = next;
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+
+        Template(parts)
+    }
+}
+
+impl From<String> for Template {
+    fn from(value: String) -> Template {
+        value.as_str().into()
+    }
+}
+
+/// Returns the argument of a directive that takes exactly one value.
+pub fn parse_single(data: ParsedValue) -> Result<String, ConfigError> {
+    let values: Vec<String> = data.try_into()?;
+    match values.as_slice() {
+        [v] if !v.is_empty() => Ok(v.clone()),
+        _ => Err(ConfigError {
+            kind: ErrorKind::UnexpectedValue {
+                value: values.join(" "),
+            },
+        }),
+    }
+}
+
+/// Returns the name and value of a directive that sets a header.
+pub fn parse_header(data: ParsedValue) -> Result<(String, Template), ConfigError> {
+    let values: Vec<String> = data.try_into()?;
+    match <[String; 2]>::try_from(values) {
+        Ok([name, value]) => Ok((name, value.into())),
+        Err(values) => Err(ConfigError {
+            kind: ErrorKind::UnexpectedValue {
+                value: values.join(" "),
+            },
+        }),
+    }
+}
+
+/// Parses the `on`/`off` argument of a flag directive.
+pub fn parse_flag(data: ParsedValue) -> Result<bool, ConfigError> {
+    match parse_single(data)?.as_str() {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        value => Err(ConfigError {
+            kind: ErrorKind::UnexpectedValue {
+                value: value.to_owned(),
+            },
+        }),
+    }
+}
+
+/// Parses a time value such as `500ms`, `10s` or `1m30s`. A value without
+/// a unit is treated as seconds.
+pub fn parse_duration(value: &str) -> Result<std::time::Duration, ConfigError> {
+    let invalid = || ConfigError {
+        kind: ErrorKind::UnexpectedValue {
+            value: value.to_owned(),
+        },
+    };
+
+    let mut total = std::time::Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(invalid());
+        }
+        let n: u64 = rest[..digits].parse()?;
+        rest = &rest[digits..];
+
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let millis = match &rest[..unit] {
+            "ms" => 1,
+            "" | "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            "d" => 24 * 60 * 60 * 1000,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit..];
+
+        let millis = n.checked_mul(millis).ok_or_else(invalid)?;
+        total = total
+            .checked_add(std::time::Duration::from_millis(millis))
+            .ok_or_else(invalid)?;
+    }
+
+    if value.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(total)
+}
+
+/// Parses a size such as `512k`, `10m` or `1g`. A value without a unit is
+/// treated as bytes.
+pub fn parse_size(value: &str) -> Result<u64, ConfigError> {
+    let (n, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, ""),
+    };
+
+    let invalid = || ConfigError {
+        kind: ErrorKind::UnexpectedValue {
+            value: value.to_owned(),
+        },
+    };
+
+    let multiplier = match unit {
+        "" => 1,
+        "k" | "K" => 1024,
+        "m" | "M" => 1024 * 1024,
+        "g" | "G" => 1024 * 1024 * 1024,
+        _ => return Err(invalid()),
+    };
+
+    n.parse::<u64>()?
+        .checked_mul(multiplier)
+        .ok_or_else(invalid)
+}
+
+/// Target of `proxy_pass`. `host` is either the name of an `upstream` block
+/// or a `host:port` address, and `uri` replaces the matched location prefix
+/// when present.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ProxyPass {
+    pub host: String,
+    pub uri: Option<String>,
+}
+
+impl TryFrom<ParsedValue> for ProxyPass {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<ProxyPass, ConfigError> {
+        let mut values: Vec<String> = data.try_into()?;
+        let value = values.pop().unwrap_or_default();
+
+        let rest = match value.strip_prefix("http://") {
+            Some(rest) if !rest.is_empty() && values.is_empty() => rest,
+            _ => {
+                return Err(ConfigError {
+                    kind: ErrorKind::UnexpectedValue { value },
+                })
+            }
+        };
+
+        Ok(match rest.find('/') {
+            Some(i) => ProxyPass {
+                host: rest[..i].to_owned(),
+                uri: Some(rest[i..].to_owned()),
+            },
+            None => ProxyPass {
+                host: rest.to_owned(),
+                uri: None,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{error::ErrorKind, types::parse_single};
+    use vulpes_parser::ParsedValue;
+
+    #[test]
+    fn test_parse_single() {
+        let value = |v: &[&str]| {
+            ParsedValue::Value(
+                v.iter()
+                    .map(|v| ParsedValue::String(v.to_string()))
+                    .collect(),
+            )
+        };
+        let error = |v: &[&str]| match parse_single(value(v)).unwrap_err().kind {
+            ErrorKind::UnexpectedValue { value } => value,
+            kind => panic!("{:?}", kind),
+        };
+
+        assert_eq!(parse_single(value(&["on"])).unwrap(), "on");
+        assert_eq!(error(&["a", "b"]), "a b");
+        assert_eq!(error(&[""]), "");
+        assert_eq!(error(&[]), "");
     }
 }
@@ -0,0 +1,348 @@
+use crate::config::{
+    error::{ConfigError, ErrorKind},
+    types::{parse_duration, parse_single, Template},
+};
+use std::time::Duration;
+use vulpes_parser::ParsedValue;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct UpstreamConfig {
+    pub name: String,
+    pub method: LoadBalance,
+    pub server: Vec<UpstreamServerConfig>,
+    pub health_check: Option<HealthCheckConfig>,
+    /// Maximum number of idle connections kept open to the servers, 0
+    /// disables connection reuse.
+    pub keepalive: usize,
+    pub keepalive_timeout: Duration,
+    pub keepalive_requests: usize,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            name: String::new(),
+            method: LoadBalance::default(),
+            server: vec![],
+            health_check: None,
+            keepalive: 0,
+            keepalive_timeout: Duration::from_secs(60),
+            keepalive_requests: 1000,
+        }
+    }
+}
+
+/// Peer selection algorithm of an upstream. Round robin honors the `weight`
+/// of each server, so plain round robin is the case where all weights are 1.
+#[derive(Debug, PartialEq, Default, Clone)]
+pub enum LoadBalance {
+    #[default]
+    RoundRobin,
+    LeastConn,
+    IpHash,
+    Hash {
+        key: Template,
+        consistent: bool,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct UpstreamServerConfig {
+    pub address: String,
+    pub weight: usize,
+    pub max_fails: usize,
+    pub fail_timeout: Duration,
+    pub backup: bool,
+    pub down: bool,
+}
+
+impl Default for UpstreamServerConfig {
+    fn default() -> Self {
+        UpstreamServerConfig {
+            address: String::new(),
+            weight: 1,
+            max_fails: 1,
+            fail_timeout: Duration::from_secs(10),
+            backup: false,
+            down: false,
+        }
+    }
+}
+
+/// Active health check run periodically against every server of an
+/// upstream. A server is marked down after `fall` consecutive failures and
+/// up again after `rise` consecutive successes.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HealthCheckConfig {
+    pub kind: HealthCheckType,
+    pub uri: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub rise: usize,
+    pub fall: usize,
+    /// Accepted status codes, either exact (`200`) or a class (`2xx`).
+    pub status: Vec<String>,
+    /// Substring the response body must contain.
+    pub body: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Default, Clone)]
+pub enum HealthCheckType {
+    #[default]
+    Http,
+    Tcp,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            kind: HealthCheckType::Http,
+            uri: "/".to_owned(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(1),
+            rise: 2,
+            fall: 3,
+            status: vec!["2xx".to_owned(), "3xx".to_owned()],
+            body: None,
+        }
+    }
+}
+
+impl TryFrom<ParsedValue> for HealthCheckConfig {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<HealthCheckConfig, ConfigError> {
+        let mut c = Self::default();
+
+        let values: Vec<String> = data.try_into()?;
+        for v in values.into_iter().filter(|v| !v.is_empty()) {
+            match v.split_once('=') {
+                Some(("type", "http")) => c.kind = HealthCheckType::Http,
+                Some(("type", "tcp")) => c.kind = HealthCheckType::Tcp,
+                Some(("uri", uri)) => c.uri = uri.to_owned(),
+                Some(("interval", t)) => c.interval = parse_duration(t)?,
+                Some(("timeout", t)) => c.timeout = parse_duration(t)?,
+                Some(("rise", n)) => c.rise = n.parse()?,
+                Some(("fall", n)) => c.fall = n.parse()?,
+                Some(("status", s)) => c.status = s.split(',').map(String::from).collect(),
+                Some(("body", b)) => c.body = Some(b.to_owned()),
+                _ => {
+                    return Err(ConfigError {
+                        kind: ErrorKind::UnexpectedValue { value: v },
+                    })
+                }
+            }
+        }
+
+        if c.rise == 0 || c.fall == 0 || c.interval.is_zero() {
+            return Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue {
+                    value: "health_check".to_owned(),
+                },
+            });
+        }
+
+        Ok(c)
+    }
+}
+
+impl TryFrom<ParsedValue> for UpstreamServerConfig {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<UpstreamServerConfig, ConfigError> {
+        let mut c = Self::default();
+
+        let mut values: Vec<String> = data.try_into()?;
+        values.reverse();
+
+        match values.pop() {
+            Some(address) => c.address = address,
+            None => {
+                return Err(ConfigError {
+                    kind: ErrorKind::UnexpectedValue {
+                        value: "server".to_owned(),
+                    },
+                })
+            }
+        }
+
+        while let Some(v) = values.pop() {
+            match v.split_once('=') {
+                Some(("weight", n)) => c.weight = n.parse()?,
+                Some(("max_fails", n)) => c.max_fails = n.parse()?,
+                Some(("fail_timeout", t)) => c.fail_timeout = parse_duration(t)?,
+                None if v == "backup" => c.backup = true,
+                None if v == "down" => c.down = true,
+                _ => {
+                    return Err(ConfigError {
+                        kind: ErrorKind::UnexpectedValue { value: v },
+                    })
+                }
+            }
+        }
+
+        if c.weight == 0 {
+            return Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue {
+                    value: "weight=0".to_owned(),
+                },
+            });
+        }
+
+        Ok(c)
+    }
+}
+
+impl TryFrom<ParsedValue> for UpstreamConfig {
+    type Error = ConfigError;
+
+    fn try_from(data: ParsedValue) -> Result<UpstreamConfig, ConfigError> {
+        let mut c = Self::default();
+
+        if let ParsedValue::Value(mut v) = data {
+            log::debug!("parse value in upstream: {:?}", v);
+
+            v.reverse();
+
+            match v.pop() {
+                Some(ParsedValue::String(name)) => c.name = name,
+                Some(value) => {
+                    return Err(ConfigError {
+                        kind: ErrorKind::UnexpectedType { value },
+                    })
+                }
+                None => {}
+            }
+
+            if let Some(ParsedValue::Block(v)) = v.pop() {
+                for v in v {
+                    match v.label.as_ref() {
+                        "server" => {
+                            c.server.push(v.value.try_into()?);
+                        }
+                        "health_check" => {
+                            c.health_check = Some(v.value.try_into()?);
+                        }
+                        "keepalive" => {
+                            c.keepalive = parse_single(v.value)?.parse()?;
+                        }
+                        "keepalive_timeout" => {
+                            c.keepalive_timeout = parse_duration(&parse_single(v.value)?)?;
+                        }
+                        "keepalive_requests" => {
+                            c.keepalive_requests = parse_single(v.value)?.parse()?;
+                        }
+                        "least_conn" => {
+                            c.method = LoadBalance::LeastConn;
+                        }
+                        "ip_hash" => {
+                            c.method = LoadBalance::IpHash;
+                        }
+                        "hash" => {
+                            let mut values: Vec<String> = v.value.try_into()?;
+                            values.reverse();
+
+                            let key = values.pop().unwrap_or_default().into();
+                            let consistent = match values.pop().as_deref() {
+                                Some("consistent") => true,
+                                None => false,
+                                Some(value) => {
+                                    return Err(ConfigError {
+                                        kind: ErrorKind::UnexpectedValue {
+                                            value: value.to_owned(),
+                                        },
+                                    })
+                                }
+                            };
+                            c.method = LoadBalance::Hash { key, consistent };
+                        }
+                        _ => {
+                            log::warn!("unknown config in upstream: {}", v);
+                        }
+                    }
+                }
+            }
+        } else {
+            return Err(ConfigError {
+                kind: ErrorKind::UnexpectedType { value: data },
+            });
+        }
+
+        if c.name.is_empty() || c.server.is_empty() {
+            return Err(ConfigError {
+                kind: ErrorKind::UnexpectedValue {
+                    value: format!("upstream {}", c.name),
+                },
+            });
+        }
+
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::upstream::{
+        HealthCheckConfig, HealthCheckType, LoadBalance, UpstreamConfig, UpstreamServerConfig,
+    };
+    use std::time::Duration;
+    use vulpes_parser::parse;
+
+    #[test]
+    fn test_try_from() {
+        let (_, parsed) = parse(
+            "
+            upstream backend {
+                hash $request_uri consistent;
+                server 127.0.0.1:8000 weight=3 max_fails=2 fail_timeout=30s;
+                server 127.0.0.1:8001;
+                server 127.0.0.1:8002 backup;
+                health_check type=tcp interval=2s fall=1 status=200,3xx;
+                keepalive 16;
+                keepalive_timeout 30s;
+            }"
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let result = UpstreamConfig::try_from(parsed[0].value.clone()).unwrap();
+        assert_eq!(
+            result,
+            UpstreamConfig {
+                name: "backend".to_owned(),
+                method: LoadBalance::Hash {
+                    key: "$request_uri".into(),
+                    consistent: true,
+                },
+                server: vec![
+                    UpstreamServerConfig {
+                        address: "127.0.0.1:8000".to_owned(),
+                        weight: 3,
+                        max_fails: 2,
+                        fail_timeout: Duration::from_secs(30),
+                        ..Default::default()
+                    },
+                    UpstreamServerConfig {
+                        address: "127.0.0.1:8001".to_owned(),
+                        ..Default::default()
+                    },
+                    UpstreamServerConfig {
+                        address: "127.0.0.1:8002".to_owned(),
+                        backup: true,
+                        ..Default::default()
+                    },
+                ],
+                health_check: Some(HealthCheckConfig {
+                    kind: HealthCheckType::Tcp,
+                    interval: Duration::from_secs(2),
+                    fall: 1,
+                    status: vec!["200".to_owned(), "3xx".to_owned()],
+                    ..Default::default()
+                }),
+                keepalive: 16,
+                keepalive_timeout: Duration::from_secs(30),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -1,3 +1,4 @@
+pub mod cache;
 mod config;
 mod processor;
 mod server;
@@ -0,0 +1,94 @@
+use crate::{
+    config::{access_log::AccessLog, http::HttpConfig, types::Template},
+    processor::{variable, variable::Captures, Request, Response},
+};
+use http::Method;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Files of the `access_log` directives of an `http` block by path, opened
+/// once and shared by its servers, and the formats of its `log_format`.
+#[derive(Default)]
+pub struct Logger {
+    formats: HashMap<String, Template>,
+    files: HashMap<String, Mutex<File>>,
+}
+
+pub fn build(http: &HttpConfig) -> io::Result<Logger> {
+    let logs = http.server.iter().flat_map(|s| {
+        s.location
+            .values()
+            .flat_map(|l| l.access_log.iter().flatten())
+            .chain(s.access_log.iter().flatten())
+    });
+
+    let mut files = HashMap::new();
+    for log in logs {
+        if files.contains_key(&log.path) {
+            continue;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log.path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", log.path, e)))?;
+        files.insert(log.path.clone(), Mutex::new(file));
+    }
+
+    Ok(Logger {
+        formats: http.log_format.clone(),
+        files,
+    })
+}
+
+impl Logger {
+    /// Writes a line to each of `logs` for `req` once the body of `res` was
+    /// sent, `req` being a copy of the request that keeps its variables.
+    pub fn log(
+        self: &Arc<Self>,
+        logs: Vec<AccessLog>,
+        req: Request,
+        res: Response,
+        start: Instant,
+    ) -> Response {
+        let logger = self.clone();
+        let status = res.status();
+        let (parts, body) = res.into_parts();
+        let body = body.on_end(move |sent| {
+            // the body of a response to HEAD isn't sent
+            let sent = match req.method() == Method::HEAD {
+                true => 0,
+                false => sent,
+            };
+            let values = Captures(vec![
+                ("status".to_owned(), status.as_str().to_owned()),
+                ("body_bytes_sent".to_owned(), sent.to_string()),
+                (
+                    "request_time".to_owned(),
+                    format!("{:.3}", start.elapsed().as_secs_f64()),
+                ),
+            ]);
+            for log in &logs {
+                logger.write(log, &req, &values);
+            }
+        });
+        Response::from_parts(parts, body)
+    }
+
+    fn write(&self, log: &AccessLog, req: &Request, values: &Captures) {
+        let (format, file) = match (self.formats.get(&log.format), self.files.get(&log.path)) {
+            (Some(format), Some(file)) => (format, file),
+            _ => return,
+        };
+        let mut line = variable::evaluate_log(format, req, values);
+        line.push('\n');
+        if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+            log::error!("failed to write access log {}: {:?}", log.path, e);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use http::HeaderMap;
+use std::io;
+use tokio::sync::{mpsc, oneshot};
+
+/// Number of chunks a streamed body buffers before the producer waits.
+const CHANNEL_SIZE: usize = 8;
+
+pub type BodySender = mpsc::Sender<io::Result<Vec<u8>>>;
+
+pub type TrailersSender = oneshot::Sender<HeaderMap>;
+
+/// Body of a request or response. A streamed body is produced chunk by chunk
+/// by another task and ends when the sender is dropped, optionally followed
+/// by trailers.
+#[derive(Debug)]
+pub enum Body {
+    Full(Vec<u8>),
+    Stream {
+        data: mpsc::Receiver<io::Result<Vec<u8>>>,
+        trailers: Option<oneshot::Receiver<HeaderMap>>,
+    },
+}
+
+impl Body {
+    pub fn channel() -> (BodySender, Body) {
+        let (tx, data) = mpsc::channel(CHANNEL_SIZE);
+        (
+            tx,
+            Body::Stream {
+                data,
+                trailers: None,
+            },
+        )
+    }
+
+    /// Like [`Body::channel`], with trailers sent once the data is done.
+    pub fn channel_with_trailers() -> (BodySender, TrailersSender, Body) {
+        let (tx, data) = mpsc::channel(CHANNEL_SIZE);
+        let (trailers_tx, trailers) = oneshot::channel();
+        (
+            tx,
+            trailers_tx,
+            Body::Stream {
+                data,
+                trailers: Some(trailers),
+            },
+        )
+    }
+
+    /// Returns the next chunk, or `None` at the end of the body.
+    pub async fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        match self {
+            Body::Full(v) if v.is_empty() => None,
+            Body::Full(v) => Some(Ok(std::mem::take(v))),
+            Body::Stream { data, .. } => data.recv().await,
+        }
+    }
+
+    /// Returns the trailers, to be called once [`Body::next`] returned
+    /// `None`.
+    pub async fn trailers(&mut self) -> Option<HeaderMap> {
+        match self {
+            Body::Stream {
+                trailers: Some(rx), ..
+            } => rx.await.ok(),
+            _ => None,
+        }
+    }
+
+    /// Reads the rest of the body into memory.
+    pub async fn collect(mut self) -> io::Result<Vec<u8>> {
+        if let Body::Full(v) = self {
+            return Ok(v);
+        }
+
+        let mut body = vec![];
+        while let Some(chunk) = self.next().await {
+            body.extend(chunk?);
+        }
+        Ok(body)
+    }
+
+    /// Calls `done` with the number of bytes of the body once it was read
+    /// to its end or dropped. A body in memory is counted at once, and a
+    /// streamed one is forwarded by another task.
+    pub fn on_end(self, done: impl FnOnce(usize) + Send + 'static) -> Body {
+        let (mut data, trailers) = match self {
+            Body::Full(v) => {
+                done(v.len());
+                return Body::Full(v);
+            }
+            Body::Stream { data, trailers } => (data, trailers),
+        };
+        let has_trailers = trailers.is_some();
+
+        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+        let (trailers_tx, trailers_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut sent = 0;
+            while let Some(chunk) = data.recv().await {
+                let len = chunk.as_ref().map(|v| v.len()).unwrap_or(0);
+                if tx.send(chunk).await.is_err() {
+                    break;
+                }
+                sent += len;
+            }
+            if let Some(Ok(v)) = match trailers {
+                Some(rx) => Some(rx.await),
+                None => None,
+            } {
+                let _ = trailers_tx.send(v);
+            }
+            done(sent);
+        });
+
+        Body::Stream {
+            data: rx,
+            trailers: has_trailers.then_some(trailers_rx),
+        }
+    }
+
+    /// The content of a body that is fully in memory.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Full(v) => Some(v),
+            Body::Stream { .. } => None,
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Full(vec![])
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(value: Vec<u8>) -> Self {
+        Body::Full(value)
+    }
+}
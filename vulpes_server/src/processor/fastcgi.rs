@@ -0,0 +1,299 @@
+use crate::{
+    config::gateway::{FastcgiConfig, GatewayConfig},
+    processor::{
+        body::{Body, BodySender},
+        gateway::{self, Stream},
+        Request, Response,
+    },
+};
+use http::header;
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::timeout,
+};
+
+const VERSION: u8 = 1;
+
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+const RESPONDER: u16 = 1;
+
+/// Every request uses its own connection, so the id is always the same.
+const REQUEST_ID: u16 = 1;
+
+/// Largest content of a single record.
+const MAX_CONTENT: usize = 0xffff;
+
+/// Parts of the URI split by `fastcgi_split_path_info`, stored in the request
+/// extensions for `$fastcgi_script_name` and `$fastcgi_path_info`.
+#[derive(Debug, Clone)]
+pub struct ScriptPath {
+    pub script_name: String,
+    pub path_info: String,
+}
+
+/// Splits the URI with `fastcgi_split_path_info` and appends `fastcgi_index`
+/// to a script name ending with a slash.
+pub fn split_path(path: &str, config: &FastcgiConfig) -> ScriptPath {
+    let captures = config
+        .split_path_info
+        .as_ref()
+        .and_then(|p| p.captures(path));
+    let capture = |i| {
+        captures
+            .as_ref()
+            .and_then(|c| c.get(i))
+            .map(|v| v.as_str().to_owned())
+    };
+
+    let (mut script_name, path_info) = match captures {
+        Some(_) => (
+            capture(1).unwrap_or_default(),
+            capture(2).unwrap_or_default(),
+        ),
+        None => (path.to_owned(), String::new()),
+    };
+
+    if script_name.ends_with('/') {
+        if let Some(index) = &config.index {
+            script_name.push_str(index);
+        }
+    }
+
+    ScriptPath {
+        script_name,
+        path_info,
+    }
+}
+
+/// Sends `req` with the responder role and reads the response, whose body is
+/// streamed from the server as it arrives.
+pub async fn send(
+    mut stream: Box<dyn Stream>,
+    config: &GatewayConfig,
+    req: Request,
+) -> io::Result<Response> {
+    // the length of a chunked or HTTP/2 body is only known once it is read,
+    // but CONTENT_LENGTH precedes it
+    let streamed = req.body().as_bytes().is_none();
+    let (params, mut body) = match streamed && !req.headers().contains_key(header::CONTENT_LENGTH) {
+        true => {
+            let (params, body) = gateway::read_request(config, req).await?;
+            (params, Body::from(body))
+        }
+        false => (gateway::params(config, &req), req.into_body()),
+    };
+
+    let mut buf = vec![];
+    let [role_hi, role_lo] = RESPONDER.to_be_bytes();
+    // the connection is closed by the server after the request
+    write_record(
+        &mut buf,
+        BEGIN_REQUEST,
+        &[role_hi, role_lo, 0, 0, 0, 0, 0, 0],
+    );
+    write_records(&mut buf, PARAMS, &encode_params(&params));
+    write_record(&mut buf, PARAMS, &[]);
+    gateway::write(&mut stream, &buf, config.send_timeout).await?;
+
+    while let Some(chunk) = body.next().await {
+        let mut buf = vec![];
+        write_records(&mut buf, STDIN, &chunk?);
+        gateway::write(&mut stream, &buf, config.send_timeout).await?;
+    }
+    let mut buf = vec![];
+    write_record(&mut buf, STDIN, &[]);
+    gateway::write(&mut stream, &buf, config.send_timeout).await?;
+
+    let mut stdout = vec![];
+    let (status, headers, n) = loop {
+        match read_record(&mut stream, config.read_timeout).await? {
+            (STDOUT, content) => {
+                stdout.extend(content);
+                if let Some(head) = gateway::parse_cgi_head(&stdout)? {
+                    break head;
+                }
+            }
+            (STDERR, content) => log_stderr(&content),
+            (END_REQUEST, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request ended before the response headers",
+                ))
+            }
+            _ => {}
+        }
+    };
+    stdout.drain(..n);
+
+    let (tx, body) = Body::channel();
+    let read_timeout = config.read_timeout;
+    tokio::spawn(async move {
+        if let Err(e) = read_stdout(stream, stdout, &tx, read_timeout).await {
+            log::error!("failed to read fastcgi response: {:?}", e);
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+
+    Ok(gateway::response(status, headers, body))
+}
+
+/// Forwards the rest of the response body until the server ends the
+/// request, or until the client is gone.
+async fn read_stdout<S>(
+    mut stream: S,
+    buffered: Vec<u8>,
+    tx: &BodySender,
+    read_timeout: Duration,
+) -> io::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    if !buffered.is_empty() && tx.send(Ok(buffered)).await.is_err() {
+        return Ok(());
+    }
+
+    loop {
+        match read_record(&mut stream, read_timeout).await? {
+            (STDOUT, content) if !content.is_empty() => {
+                if tx.send(Ok(content)).await.is_err() {
+                    return Ok(());
+                }
+            }
+            (STDERR, content) => log_stderr(&content),
+            (END_REQUEST, _) => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+fn log_stderr(content: &[u8]) {
+    for line in String::from_utf8_lossy(content).lines() {
+        if !line.is_empty() {
+            log::error!("fastcgi stderr: {}", line);
+        }
+    }
+}
+
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+    let mut buf = vec![];
+    for (name, value) in params {
+        encode_length(&mut buf, name.len());
+        encode_length(&mut buf, value.len());
+        buf.extend(name.as_bytes());
+        buf.extend(value.as_bytes());
+    }
+    buf
+}
+
+fn encode_length(buf: &mut Vec<u8>, length: usize) {
+    if length < 0x80 {
+        buf.push(length as u8);
+    } else {
+        buf.extend((length as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// Appends `content` as one record, padded to a multiple of 8 bytes.
+fn write_record(buf: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    let padding = (8 - content.len() % 8) % 8;
+    buf.extend([VERSION, kind]);
+    buf.extend(REQUEST_ID.to_be_bytes());
+    buf.extend((content.len() as u16).to_be_bytes());
+    buf.extend([padding as u8, 0]);
+    buf.extend(content);
+    buf.extend(std::iter::repeat(0).take(padding));
+}
+
+/// Appends `content` as records of at most [`MAX_CONTENT`] bytes.
+fn write_records(buf: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    for chunk in content.chunks(MAX_CONTENT) {
+        write_record(buf, kind, chunk);
+    }
+}
+
+/// Reads the next record and returns its type and content.
+async fn read_record<S>(stream: &mut S, read_timeout: Duration) -> io::Result<(u8, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let f = async {
+        let mut head = [0u8; 8];
+        stream.read_exact(&mut head).await?;
+        if head[0] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported fastcgi version {}", head[0]),
+            ));
+        }
+
+        let length = u16::from_be_bytes([head[4], head[5]]) as usize;
+        let mut content = vec![0; length + head[6] as usize];
+        stream.read_exact(&mut content).await?;
+        content.truncate(length);
+
+        Ok((head[1], content))
+    };
+
+    timeout(read_timeout, f)
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{gateway::FastcgiConfig, types::Pattern},
+        processor::fastcgi::{encode_params, split_path, write_records},
+    };
+
+    #[test]
+    fn test_encode_params() {
+        let long = "v".repeat(200);
+        let encoded = encode_params(&[
+            ("A".to_owned(), "bc".to_owned()),
+            ("LONG".to_owned(), long.clone()),
+        ]);
+
+        let mut expected = vec![1, 2];
+        expected.extend(b"Abc");
+        expected.extend([4, 0x80, 0, 0, 200]);
+        expected.extend(b"LONG");
+        expected.extend(long.as_bytes());
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn test_write_records() {
+        let mut buf = vec![];
+        write_records(&mut buf, 5, &[0xab; 0x10005]);
+
+        assert_eq!(&buf[..8], &[1, 5, 0, 1, 0xff, 0xff, 1, 0]);
+        let second = 8 + 0xffff + 1;
+        assert_eq!(&buf[second..second + 8], &[1, 5, 0, 1, 0, 6, 2, 0]);
+        assert_eq!(buf.len(), second + 8 + 8);
+    }
+
+    #[test]
+    fn test_split_path() {
+        let config = FastcgiConfig {
+            index: Some("index.php".to_owned()),
+            split_path_info: Some(Pattern::new(r"^(.+\.php)(/.*)$").unwrap()),
+            ..Default::default()
+        };
+
+        let path = split_path("/app/test.php/foo/bar", &config);
+        assert_eq!(path.script_name, "/app/test.php");
+        assert_eq!(path.path_info, "/foo/bar");
+
+        let path = split_path("/app/", &config);
+        assert_eq!(path.script_name, "/app/index.php");
+        assert_eq!(path.path_info, "");
+    }
+}
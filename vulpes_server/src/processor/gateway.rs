@@ -0,0 +1,367 @@
+use crate::{
+    config::{gateway::GatewayConfig, location::LocationConfig},
+    processor::{
+        body::Body, fastcgi, http1, proxy, scgi, upstream::Upstream, uwsgi, variable, Request,
+        Response,
+    },
+};
+use http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+    time::timeout,
+};
+
+const MAX_HEADERS: usize = 100;
+
+/// Connection to an application server, over TCP or a Unix socket.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Protocol spoken with an application server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Fastcgi,
+    Uwsgi,
+    Scgi,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Fastcgi => "fastcgi",
+            Protocol::Uwsgi => "uwsgi",
+            Protocol::Scgi => "scgi",
+        }
+    }
+}
+
+/// Returns the protocol and settings of a location that passes requests to
+/// an application server.
+pub fn target(location: &LocationConfig) -> Option<(Protocol, &GatewayConfig)> {
+    [
+        (Protocol::Fastcgi, &location.fastcgi.gateway),
+        (Protocol::Uwsgi, &location.uwsgi),
+        (Protocol::Scgi, &location.scgi),
+    ]
+    .into_iter()
+    .find(|(_, c)| c.pass.is_some())
+}
+
+/// Passes `req` to a peer of `upstream` with `protocol`, where `config` is
+/// the setting of the location for that protocol. Like `proxy_next_upstream
+/// error timeout`, a peer that can't be connected to is passed over for the
+/// next one. Once connected, the request body is streamed to the peer and
+/// a failure isn't retried.
+pub async fn pass(
+    protocol: Protocol,
+    config: &GatewayConfig,
+    upstream: &Upstream,
+    req: Request,
+) -> Response {
+    let error_response = |address: &str, e: io::Error| {
+        log::error!(
+            "{} {} ({}) error: {:?}",
+            protocol.as_str(),
+            upstream.name,
+            address,
+            e
+        );
+        match e.kind() {
+            io::ErrorKind::TimedOut => proxy::error_response(StatusCode::GATEWAY_TIMEOUT),
+            _ => proxy::error_response(StatusCode::BAD_GATEWAY),
+        }
+    };
+
+    let mut tried = vec![];
+    let mut last = None;
+    let (id, stream) = loop {
+        let id = match upstream.get_peer(&req, &tried) {
+            Some(id) => id,
+            None => return last.unwrap_or_else(|| proxy::error_response(StatusCode::BAD_GATEWAY)),
+        };
+        tried.push(id);
+
+        match connect(&upstream.peer(id).address, config).await {
+            Ok(stream) => break (id, stream),
+            Err(e) => {
+                upstream.free_peer(id, true);
+                last = Some(error_response(&upstream.peer(id).address, e));
+            }
+        }
+    };
+
+    let res = match protocol {
+        Protocol::Fastcgi => fastcgi::send(stream, config, req).await,
+        Protocol::Uwsgi => uwsgi::send(stream, config, req).await,
+        Protocol::Scgi => scgi::send(stream, config, req).await,
+    };
+    let res = match res {
+        Ok(res) if config.buffering => buffer(res).await,
+        res => res,
+    };
+    upstream.free_peer(id, res.is_err());
+
+    res.unwrap_or_else(|e| error_response(&upstream.peer(id).address, e))
+}
+
+async fn connect(address: &str, config: &GatewayConfig) -> io::Result<Box<dyn Stream>> {
+    let f = async {
+        let stream: Box<dyn Stream> = match address.strip_prefix("unix:") {
+            Some(path) => Box::new(UnixStream::connect(path).await?),
+            None => Box::new(TcpStream::connect(address).await?),
+        };
+        Ok(stream)
+    };
+
+    timeout(config.connect_timeout, f)
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+async fn buffer(res: Response) -> io::Result<Response> {
+    let (parts, body) = res.into_parts();
+    let body = body.collect().await?;
+    Ok(Response::from_parts(parts, body.into()))
+}
+
+/// Builds the parameters from the `<protocol>_param` directives and the
+/// request headers, which are passed as `HTTP_*` parameters.
+pub fn params(config: &GatewayConfig, req: &Request) -> Vec<(String, String)> {
+    let mut params: Vec<(String, String)> = config
+        .param
+        .iter()
+        .map(|p| (p.name.clone(), variable::evaluate(&p.value, req), p))
+        .filter(|(_, value, p)| !(p.if_not_empty && value.is_empty()))
+        .map(|(name, value, _)| (name, value))
+        .collect();
+
+    for name in req.headers().keys() {
+        let param = format!(
+            "HTTP_{}",
+            name.as_str().to_ascii_uppercase().replace('-', "_")
+        );
+        if params.iter().any(|(n, _)| *n == param) {
+            continue;
+        }
+
+        let value = req
+            .headers()
+            .get_all(name)
+            .iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        params.push((param, value));
+    }
+
+    params
+}
+
+/// Reads the whole request body and sets `CONTENT_LENGTH` from it, for the
+/// protocols that need the length before the body.
+pub async fn read_request(
+    config: &GatewayConfig,
+    req: Request,
+) -> io::Result<(Vec<(String, String)>, Vec<u8>)> {
+    let mut params = params(config, &req);
+    let body = req.into_body().collect().await?;
+
+    params.retain(|(name, _)| name != "CONTENT_LENGTH");
+    params.insert(0, ("CONTENT_LENGTH".to_owned(), body.len().to_string()));
+
+    Ok((params, body))
+}
+
+pub async fn write<S>(stream: &mut S, buf: &[u8], send_timeout: Duration) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    timeout(send_timeout, stream.write_all(buf))
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+async fn read<S>(stream: &mut S, buf: &mut [u8], read_timeout: Duration) -> io::Result<usize>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    timeout(read_timeout, stream.read(buf))
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+/// Head of a response: its status, headers, and the length of the head.
+pub type Head = (StatusCode, HeaderMap, usize);
+
+/// Reads a response whose head is parsed by `parse_head` and whose body
+/// lasts until the server closes the connection. The body is streamed from
+/// a separate task.
+pub async fn read_response(
+    mut stream: Box<dyn Stream>,
+    config: &GatewayConfig,
+    parse_head: fn(&[u8]) -> io::Result<Option<Head>>,
+) -> io::Result<Response> {
+    let read_timeout = config.read_timeout;
+    let mut buf = vec![];
+    let mut tmp = vec![0u8; 16 * 1024];
+
+    let (status, headers, n) = loop {
+        let n = read(&mut stream, &mut tmp, read_timeout).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before the response headers",
+            ));
+        }
+        buf.extend(&tmp[..n]);
+
+        if let Some(head) = parse_head(&buf)? {
+            break head;
+        }
+    };
+    buf.drain(..n);
+
+    let (tx, body) = Body::channel();
+    tokio::spawn(async move {
+        let result = async {
+            if !buf.is_empty() && tx.send(Ok(buf)).await.is_err() {
+                return Ok(());
+            }
+            loop {
+                let n = read(&mut stream, &mut tmp, read_timeout).await?;
+                if n == 0 || tx.send(Ok(tmp[..n].to_vec())).await.is_err() {
+                    return Ok(());
+                }
+            }
+        };
+
+        if let Err(e) = result.await {
+            log::error!("failed to read response: {:?}", e);
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+
+    Ok(response(status, headers, body))
+}
+
+pub fn response(status: StatusCode, headers: HeaderMap, body: Body) -> Response {
+    let mut res = Response::new(body);
+    *res.status_mut() = status;
+    *res.headers_mut() = headers;
+    res
+}
+
+/// Parses CGI response headers, where the status comes from the `Status`
+/// header, or is 302 when only `Location` is set.
+pub fn parse_cgi_head(data: &[u8]) -> io::Result<Option<Head>> {
+    let mut parsed = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let (n, parsed) = match httparse::parse_headers(data, &mut parsed) {
+        Ok(httparse::Status::Complete(v)) => v,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    };
+
+    let mut status = None;
+    let mut headers = HeaderMap::new();
+    for h in parsed {
+        if h.name.eq_ignore_ascii_case("status") {
+            let code = h.value.get(..3).unwrap_or_default();
+            status = Some(StatusCode::from_bytes(code).map_err(invalid_data)?);
+            continue;
+        }
+        append_header(&mut headers, h)?;
+    }
+
+    let status = status.unwrap_or(match headers.contains_key(header::LOCATION) {
+        true => StatusCode::FOUND,
+        false => StatusCode::OK,
+    });
+
+    clean_headers(&mut headers);
+    Ok(Some((status, headers, n)))
+}
+
+/// Parses the head of an HTTP response, as sent by uwsgi applications.
+pub fn parse_http_head(data: &[u8]) -> io::Result<Option<Head>> {
+    let mut parsed = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut parsed);
+    let n = match res.parse(data) {
+        Ok(httparse::Status::Complete(n)) => n,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(e) => return Err(invalid_data(e)),
+    };
+
+    let status = StatusCode::from_u16(res.code.unwrap_or_default()).map_err(invalid_data)?;
+    let mut headers = HeaderMap::new();
+    for h in res.headers.iter() {
+        append_header(&mut headers, h)?;
+    }
+
+    clean_headers(&mut headers);
+    Ok(Some((status, headers, n)))
+}
+
+fn append_header(headers: &mut HeaderMap, h: &httparse::Header) -> io::Result<()> {
+    headers.append(
+        HeaderName::from_bytes(h.name.as_bytes()).map_err(invalid_data)?,
+        HeaderValue::from_bytes(h.value).map_err(invalid_data)?,
+    );
+    Ok(())
+}
+
+/// Removes the headers that describe the connection to the server, since
+/// the body is sent to the client with its own framing.
+fn clean_headers(headers: &mut HeaderMap) {
+    http1::remove_hop_by_hop_headers(headers, false);
+    headers.remove(header::CONTENT_LENGTH);
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processor::gateway::{parse_cgi_head, parse_http_head};
+    use http::StatusCode;
+
+    #[test]
+    fn test_parse_cgi_head() {
+        assert!(parse_cgi_head(b"Content-Type: text/html\r\n")
+            .unwrap()
+            .is_none());
+
+        let (status, headers, n) = parse_cgi_head(
+            b"Status: 404 Not Found\r\nContent-Type: text/html\r\nContent-Length: 4\r\n\r\nbody",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(headers.get("content-type").unwrap(), "text/html");
+        assert!(headers.get("content-length").is_none());
+        assert_eq!(n, 69);
+
+        let (status, _, _) = parse_cgi_head(b"Location: /login\n\n").unwrap().unwrap();
+        assert_eq!(status, StatusCode::FOUND);
+    }
+
+    #[test]
+    fn test_parse_http_head() {
+        assert!(parse_http_head(b"HTTP/1.1 200 OK\r\n").unwrap().is_none());
+
+        let (status, headers, n) =
+            parse_http_head(b"HTTP/1.1 201 Created\r\nConnection: close\r\nX-App: a\r\n\r\nbody")
+                .unwrap()
+                .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(headers.get("x-app").unwrap(), "a");
+        assert!(headers.get("connection").is_none());
+        assert_eq!(n, 53);
+    }
+}
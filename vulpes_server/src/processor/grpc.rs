@@ -0,0 +1,197 @@
+use crate::{
+    config::{grpc::GrpcConfig, location::LocationConfig},
+    processor::{
+        body::Body,
+        http1,
+        http2::{from_h2, receive_body, send_body},
+        proxy,
+        upstream::Upstream,
+        Request, Response,
+    },
+};
+use bytes::Bytes;
+use h2::client::{self, SendRequest};
+use http::{header, HeaderValue, Version};
+use std::io;
+use tokio::{net::TcpStream, time::timeout};
+
+/// Status codes of the gRPC protocol used for the errors of vulpes.
+const DEADLINE_EXCEEDED: u32 = 4;
+const INTERNAL: u32 = 13;
+const UNAVAILABLE: u32 = 14;
+
+/// Passes `req` to a peer of `upstream` over HTTP/2. Errors are answered
+/// with a gRPC status rather than an HTTP error, so that clients see why
+/// the call failed.
+pub async fn pass(upstream: &Upstream, location: &LocationConfig, req: Request) -> Response {
+    let config = &location.grpc;
+    let id = match upstream.get_peer(&req, &[]) {
+        Some(id) => id,
+        None => return error_response(UNAVAILABLE, "no live upstreams"),
+    };
+    let address = upstream.peer(id).address.as_str();
+
+    let res = send(upstream, address, config, req).await;
+    upstream.free_peer(id, res.is_err());
+
+    res.unwrap_or_else(|e| {
+        log::error!("grpc {} ({}) error: {:?}", upstream.name, address, e);
+        let status = match e.kind() {
+            io::ErrorKind::TimedOut => DEADLINE_EXCEEDED,
+            io::ErrorKind::InvalidData => INTERNAL,
+            _ => UNAVAILABLE,
+        };
+        error_response(status, &e.to_string())
+    })
+}
+
+/// Builds a trailers-only response carrying `status`.
+pub fn error_response(status: u32, message: &str) -> Response {
+    let mut res = Response::new(Body::default());
+    let headers = res.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    headers.insert("grpc-status", HeaderValue::from(status));
+    if let Ok(v) = HeaderValue::from_str(&percent_encode(message)) {
+        headers.insert("grpc-message", v);
+    }
+    res
+}
+
+/// Percent-encodes `grpc-message` as required by the gRPC protocol.
+fn percent_encode(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for b in message.bytes() {
+        if (0x20..0x7f).contains(&b) && b != b'%' {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+fn build_request(config: &GrpcConfig, req: Request) -> Request {
+    let set_header = proxy::evaluate_headers(&config.set_header, &req);
+    let (mut parts, body) = req.into_parts();
+
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|v| v.as_str())
+        .unwrap_or("/");
+    let target = config.pass.as_deref().unwrap_or_default();
+    if let Ok(uri) = format!("http://{}{}", target, path_and_query).parse() {
+        parts.uri = uri;
+    }
+    parts.version = Version::HTTP_2;
+
+    http1::remove_hop_by_hop_headers(&mut parts.headers, false);
+    parts.headers.remove(header::HOST);
+    parts
+        .headers
+        .insert(header::TE, HeaderValue::from_static("trailers"));
+    proxy::apply_headers(&mut parts.headers, set_header);
+
+    Request::from_parts(parts, body)
+}
+
+async fn send(
+    upstream: &Upstream,
+    address: &str,
+    config: &GrpcConfig,
+    req: Request,
+) -> io::Result<Response> {
+    let (parts, body) = build_request(config, req).into_parts();
+    let end_of_stream = body.as_bytes().map(|v| v.is_empty()).unwrap_or(false);
+
+    let mut conn = ready(upstream, address, config).await?;
+    let (res, mut stream) = conn
+        .send_request(http::Request::from_parts(parts, ()), end_of_stream)
+        .map_err(from_h2)?;
+
+    if !end_of_stream {
+        let send_timeout = config.send_timeout;
+        tokio::spawn(async move {
+            if let Err(e) = send_body(&mut stream, body, send_timeout, None).await {
+                log::error!("failed to send grpc request body: {:?}", e);
+                stream.send_reset(h2::Reason::CANCEL);
+            }
+        });
+    }
+
+    let res = timeout(config.read_timeout, res)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+        .map_err(from_h2)?;
+
+    let (mut parts, recv) = res.into_parts();
+    parts.version = Version::HTTP_11;
+    http1::remove_hop_by_hop_headers(&mut parts.headers, false);
+
+    let (tx, trailers_tx, body) = Body::channel_with_trailers();
+    let read_timeout = config.read_timeout;
+    tokio::spawn(async move {
+        if let Err(e) = receive_body(recv, &tx, trailers_tx, read_timeout).await {
+            log::error!("failed to read grpc response: {:?}", e);
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+
+    Ok(Response::from_parts(parts, body))
+}
+
+/// Returns a connection to `address` that can take a new stream, opening
+/// one when there is none or the previous one was closed.
+async fn ready(
+    upstream: &Upstream,
+    address: &str,
+    config: &GrpcConfig,
+) -> io::Result<SendRequest<Bytes>> {
+    if let Some(conn) = upstream.h2.get(address) {
+        match conn.ready().await {
+            Ok(conn) => return Ok(conn),
+            Err(e) => {
+                log::debug!("http2 connection to {} closed: {:?}", address, e);
+                upstream.h2.remove(address);
+            }
+        }
+    }
+
+    let connect = async {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        client::handshake(stream).await.map_err(from_h2)
+    };
+    let (conn, connection) = timeout(config.connect_timeout, connect)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    let name = address.to_owned();
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::debug!("http2 connection to {} failed: {:?}", name, e);
+        }
+    });
+
+    upstream.h2.put(address, conn.clone());
+    conn.ready().await.map_err(from_h2)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processor::grpc::{error_response, percent_encode};
+
+    #[test]
+    fn test_error_response() {
+        let res = error_response(14, "connection refused");
+        assert_eq!(res.status().as_u16(), 200);
+        assert_eq!(res.headers()["content-type"], "application/grpc");
+        assert_eq!(res.headers()["grpc-status"], "14");
+        assert_eq!(res.headers()["grpc-message"], "connection refused");
+
+        assert_eq!(percent_encode("100% ok\n"), "100%25 ok%0A");
+    }
+}
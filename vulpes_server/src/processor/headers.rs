@@ -0,0 +1,211 @@
+use crate::{
+    config::headers::{HeaderAction, HeaderRule, HeadersConfig},
+    processor::{proxy, variable, Request, Response},
+};
+use http::{header, HeaderMap, HeaderName, HeaderValue};
+
+/// Status codes of the responses `add_header` applies to without `always`.
+const ADD_HEADER_STATUS: [u16; 10] = [200, 201, 204, 206, 301, 302, 303, 304, 307, 308];
+
+/// Applies `more_set_headers`, `more_clear_headers` and then `add_header` to
+/// a response, evaluating the variables against `vars`.
+pub fn apply_output(headers: &HeadersConfig, vars: &Request, res: &mut Response) {
+    let status = res.status().as_u16();
+    for rule in &headers.output {
+        if !rule.status.is_empty() && !rule.status.contains(&status) {
+            continue;
+        }
+        if !matches_type(rule, res.headers()) {
+            continue;
+        }
+        match &rule.action {
+            HeaderAction::Set(values) => {
+                let values = proxy::evaluate_headers(values, vars);
+                proxy::apply_headers(res.headers_mut(), values);
+            }
+            HeaderAction::Clear(names) => clear(res.headers_mut(), names),
+        }
+    }
+
+    let success = ADD_HEADER_STATUS.contains(&status);
+    for h in headers.add_header.iter().filter(|h| h.always || success) {
+        // a header whose value evaluates to an empty string isn't added
+        let value = variable::evaluate(&h.value, vars);
+        if value.is_empty() {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(h.name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            res.headers_mut().append(name, value);
+        }
+    }
+}
+
+/// Applies `more_set_input_headers` and `more_clear_input_headers` to a
+/// request, so that they reach the upstream and the variables.
+pub fn apply_input(rules: &[HeaderRule], req: &mut Request) {
+    for rule in rules {
+        if !matches_type(rule, req.headers()) {
+            continue;
+        }
+        match &rule.action {
+            HeaderAction::Set(values) => {
+                let mut values = proxy::evaluate_headers(values, req);
+                if rule.replace {
+                    values.retain(|(name, _)| req.headers().contains_key(name));
+                }
+                proxy::apply_headers(req.headers_mut(), values);
+            }
+            HeaderAction::Clear(names) => clear(req.headers_mut(), names),
+        }
+    }
+}
+
+/// Removes the headers of `names`, where a name ending with `*` removes
+/// all the headers starting with the rest of it.
+pub fn clear(headers: &mut HeaderMap, names: &[String]) {
+    for name in names {
+        match name.strip_suffix('*') {
+            Some(prefix) => {
+                let prefix = prefix.to_ascii_lowercase();
+                let matched: Vec<HeaderName> = headers
+                    .keys()
+                    .filter(|k| k.as_str().starts_with(&prefix))
+                    .cloned()
+                    .collect();
+                for k in matched {
+                    headers.remove(k);
+                }
+            }
+            None => {
+                if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+                    headers.remove(name);
+                }
+            }
+        }
+    }
+}
+
+/// Returns whether the `Content-Type` is one of the `-t` types of a rule.
+fn matches_type(rule: &HeaderRule, headers: &HeaderMap) -> bool {
+    if rule.types.is_empty() {
+        return true;
+    }
+    let media_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase());
+    match media_type {
+        Some(v) => rule.types.contains(&v),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::headers::HeadersConfig,
+        processor::{
+            body::Body,
+            headers::{apply_input, apply_output},
+            Request, Response,
+        },
+    };
+
+    fn headers(config: &str) -> HeadersConfig {
+        let (_, parsed) = vulpes_parser::parse(config.as_bytes()).unwrap();
+        let mut c = HeadersConfig::default();
+        for v in parsed {
+            c.set(&v.label, v.value).unwrap();
+        }
+        c
+    }
+
+    fn request() -> Request {
+        http::Request::builder()
+            .uri("/a")
+            .header("Content-Type", "application/json")
+            .header("Cookie", "a=1")
+            .header("X-Forwarded-For", "10.0.0.1")
+            .body(Body::default())
+            .unwrap()
+    }
+
+    fn response(status: u16) -> Response {
+        http::Response::builder()
+            .status(status)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Server", "backend")
+            .header("X-Powered-By", "php")
+            .header("X-Powered-Version", "8")
+            .body(Body::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_apply_output() {
+        let c = headers(
+            r#"
+            add_header X-Uri $uri;
+            add_header X-Always 1 always;
+            add_header X-Empty $unknown;
+            more_set_headers "Server: vulpes" "X-Uri: set";
+            more_set_headers -s 404 "X-Missing: $uri";
+            more_set_headers -t text/plain "X-Text: 1";
+            more_clear_headers "X-Powered-*";
+            "#,
+        );
+        let req = request();
+
+        let mut res = response(200);
+        apply_output(&c, &req, &mut res);
+        let h = res.headers();
+        assert_eq!(h["server"], "vulpes");
+        // `add_header` appends to the header set by `more_set_headers`
+        assert_eq!(h.get_all("x-uri").iter().collect::<Vec<_>>(), ["set", "/a"]);
+        assert_eq!(h["x-always"], "1");
+        for name in [
+            "x-empty",
+            "x-missing",
+            "x-text",
+            "x-powered-by",
+            "x-powered-version",
+        ] {
+            assert!(!h.contains_key(name), "{}", name);
+        }
+
+        let mut res = response(404);
+        apply_output(&c, &req, &mut res);
+        let h = res.headers();
+        assert_eq!(h["x-missing"], "/a");
+        assert_eq!(h["x-uri"], "set");
+        assert_eq!(h["x-always"], "1");
+    }
+
+    #[test]
+    fn test_apply_input() {
+        let c = headers(
+            r#"
+            more_set_input_headers "X-Real-Uri: $uri" "X-Forwarded-For:";
+            more_set_input_headers -r "X-Missing: 1" "Cookie: b=2";
+            more_set_input_headers -t text/plain "X-Text: 1";
+            more_clear_input_headers "X-*";
+            more_set_input_headers "X-Added: $uri";
+            "#,
+        );
+        let mut req = request();
+        apply_input(&c.input, &mut req);
+
+        let h = req.headers();
+        assert_eq!(h["cookie"], "b=2");
+        assert_eq!(h["content-type"], "application/json");
+        assert!(!h.contains_key("x-real-uri"));
+        assert!(!h.contains_key("x-forwarded-for"));
+        assert!(!h.contains_key("x-missing"));
+        assert!(!h.contains_key("x-text"));
+        assert_eq!(h["x-added"], "/a");
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{
+    config::upstream::{HealthCheckConfig, HealthCheckType},
+    processor::{
+        body::Body,
+        http1,
+        upstream::{Upstream, Upstreams},
+        Response,
+    },
+};
+use http::{header, StatusCode};
+use std::{fmt::Write, sync::Arc};
+use tokio::net::TcpStream;
+
+/// Starts the active health checks of every upstream that has one.
+pub fn spawn(upstreams: &Upstreams) {
+    for upstream in upstreams.values() {
+        if upstream.health_check.is_some() {
+            for id in 0..upstream.peers().len() {
+                tokio::spawn(run(upstream.clone(), id));
+            }
+        }
+    }
+}
+
+async fn run(upstream: Arc<Upstream>, id: usize) {
+    let c = match &upstream.health_check {
+        Some(c) => c.clone(),
+        None => return,
+    };
+    let address = upstream.peer(id).address.clone();
+
+    let mut interval = tokio::time::interval(c.interval);
+    loop {
+        interval.tick().await;
+
+        let result = match tokio::time::timeout(c.timeout, check(&address, &c)).await {
+            Ok(result) => result,
+            Err(_) => Err("timed out".to_owned()),
+        };
+        upstream.report_health(id, result);
+    }
+}
+
+async fn check(address: &str, c: &HealthCheckConfig) -> Result<(), String> {
+    let stream = TcpStream::connect(address)
+        .await
+        .map_err(|e| e.to_string())?;
+    if c.kind == HealthCheckType::Tcp {
+        return Ok(());
+    }
+
+    let req = http::Request::builder()
+        .uri(c.uri.as_str())
+        .header(header::HOST, address)
+        .header(header::CONNECTION, "close")
+        .body(Body::default())
+        .map_err(|e| e.to_string())?;
+
+    let mut conn = http1::Connection::new(stream);
+    conn.write_request(&req).await.map_err(|e| e.to_string())?;
+
+    let head = conn.read_response_head().await.map_err(|e| e.to_string())?;
+    if !c.status.iter().any(|s| status_matches(head.status, s)) {
+        return Err(format!("unexpected status {}", head.status));
+    }
+
+    if let Some(expected) = &c.body {
+        let length = http1::response_body_length(req.method(), head.status, &head.headers)
+            .map_err(|e| e.to_string())?;
+        let body = conn.read_body(length).await.map_err(|e| e.to_string())?;
+        if !String::from_utf8_lossy(&body).contains(expected.as_str()) {
+            return Err("unexpected body".to_owned());
+        }
+    }
+
+    Ok(())
+}
+
+fn status_matches(status: StatusCode, pattern: &str) -> bool {
+    match pattern.strip_suffix("xx") {
+        Some(class) => status.as_str().starts_with(class),
+        None => status.as_str() == pattern,
+    }
+}
+
+/// Renders the state of every upstream peer as plain text, one peer per
+/// line.
+pub fn status(upstreams: &Upstreams) -> Response {
+    let mut names = upstreams.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut body = String::new();
+    for name in names {
+        let upstream = &upstreams[name];
+        for (id, peer) in upstream.peers().iter().enumerate() {
+            let s = upstream.peer_status(id);
+            let _ = writeln!(
+                body,
+                "{} {} {}{} fails={} conns={}",
+                name,
+                peer.address,
+                s.state,
+                if s.backup { " backup" } else { "" },
+                s.fails,
+                s.conns
+            );
+        }
+    }
+
+    let mut res = Response::new(body.into_bytes().into());
+    http1::set_header(res.headers_mut(), header::CONTENT_TYPE, "text/plain");
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processor::health::status_matches;
+    use http::StatusCode;
+
+    #[test]
+    fn test_status_matches() {
+        assert!(status_matches(StatusCode::OK, "200"));
+        assert!(status_matches(StatusCode::NO_CONTENT, "2xx"));
+        assert!(!status_matches(StatusCode::NOT_FOUND, "2xx"));
+    }
+}
@@ -0,0 +1,724 @@
+use crate::processor::body::{Body, BodySender};
+use http::{header, HeaderMap, HeaderValue, Method, StatusCode, Version};
+use std::{future::Future, io, time::Duration};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const MAX_HEADERS: usize = 100;
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Headers that only apply to a single connection and must not be forwarded.
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+];
+
+/// Progress of a body read with [`Connection::read_body_chunk`].
+#[derive(Debug, PartialEq)]
+pub enum BodyState {
+    /// Bytes left to read.
+    Length(u64),
+    /// Bytes left in the current chunk.
+    Chunked(u64),
+    Close,
+    Done,
+}
+
+impl BodyState {
+    pub fn new(length: BodyLength) -> BodyState {
+        match length {
+            BodyLength::Empty => BodyState::Done,
+            BodyLength::Length(n) => BodyState::Length(n),
+            BodyLength::Chunked => BodyState::Chunked(0),
+            BodyLength::Close => BodyState::Close,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BodyLength {
+    Empty,
+    Length(u64),
+    Chunked,
+    Close,
+}
+
+/// A buffered HTTP/1.x connection, used for both the client side of the
+/// listener and the connections to upstream servers.
+pub struct Connection<S> {
+    io: S,
+    buf: Vec<u8>,
+    /// Number of bytes received from the peer.
+    received: u64,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(io: S) -> Connection<S> {
+        Connection {
+            io,
+            buf: Vec::with_capacity(8192),
+            received: 0,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
+    /// Limits how long a single read or write may wait for the peer. An
+    /// expired timeout fails the operation with `TimedOut`.
+    pub fn set_timeouts(&mut self, read: Option<Duration>, write: Option<Duration>) {
+        self.read_timeout = read;
+        self.write_timeout = write;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.io
+    }
+
+    /// Returns the underlying stream and the bytes received but not
+    /// consumed yet, e.g. to tunnel an upgraded connection.
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.io, self.buf)
+    }
+
+    /// Offset in the stream of the next byte to be consumed.
+    pub fn position(&self) -> u64 {
+        self.received - self.buf.len() as u64
+    }
+
+    /// Whether bytes were received that have not been consumed yet.
+    pub fn has_buffered(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    /// Whether the next bytes from the peer are `prefix`, reading only as
+    /// much as needed to tell. Nothing is consumed.
+    pub async fn starts_with(&mut self, prefix: &[u8]) -> io::Result<bool> {
+        loop {
+            let n = self.buf.len().min(prefix.len());
+            if self.buf[..n] != prefix[..n] {
+                return Ok(false);
+            }
+            if n == prefix.len() {
+                return Ok(true);
+            }
+            if self.fill().await? == 0 {
+                return Ok(false);
+            }
+        }
+    }
+
+    async fn fill(&mut self) -> io::Result<usize> {
+        self.buf.reserve(8192);
+        let n = with_timeout(self.read_timeout, self.io.read_buf(&mut self.buf)).await?;
+        self.received += n as u64;
+        Ok(n)
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let io = &mut self.io;
+        with_timeout(self.write_timeout, async move {
+            io.write_all(buf).await?;
+            io.flush().await
+        })
+        .await
+    }
+
+    /// Reads a request head. Returns `None` when the peer closed the
+    /// connection before sending anything.
+    pub async fn read_request_head(&mut self) -> io::Result<Option<http::request::Parts>> {
+        loop {
+            if !self.buf.is_empty() {
+                let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+                let mut req = httparse::Request::new(&mut headers);
+                match req.parse(&self.buf) {
+                    Ok(httparse::Status::Complete(n)) => {
+                        let mut builder = http::Request::builder()
+                            .method(req.method.unwrap_or_default())
+                            .uri(req.path.unwrap_or_default())
+                            .version(to_version(req.version));
+                        for h in req.headers.iter() {
+                            builder = builder.header(h.name, h.value);
+                        }
+                        let (parts, _) = builder.body(()).map_err(invalid_data)?.into_parts();
+
+                        self.buf.drain(..n);
+                        return Ok(Some(parts));
+                    }
+                    Ok(httparse::Status::Partial) => {}
+                    Err(e) => return Err(invalid_data(e)),
+                }
+            }
+
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(invalid_data("request header too large"));
+            }
+
+            if self.fill().await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    /// Reads a final response head, skipping interim 1xx responses other
+    /// than 101 Switching Protocols.
+    pub async fn read_response_head(&mut self) -> io::Result<http::response::Parts> {
+        loop {
+            if !self.buf.is_empty() {
+                let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+                let mut res = httparse::Response::new(&mut headers);
+                match res.parse(&self.buf) {
+                    Ok(httparse::Status::Complete(n)) => {
+                        let mut builder = http::Response::builder()
+                            .status(res.code.unwrap_or_default())
+                            .version(to_version(res.version));
+                        for h in res.headers.iter() {
+                            builder = builder.header(h.name, h.value);
+                        }
+                        let (parts, _) = builder.body(()).map_err(invalid_data)?.into_parts();
+
+                        self.buf.drain(..n);
+                        if parts.status.is_informational()
+                            && parts.status != StatusCode::SWITCHING_PROTOCOLS
+                        {
+                            continue;
+                        }
+                        return Ok(parts);
+                    }
+                    Ok(httparse::Status::Partial) => {}
+                    Err(e) => return Err(invalid_data(e)),
+                }
+            }
+
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(invalid_data("response header too large"));
+            }
+
+            if self.fill().await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    pub async fn read_body(&mut self, length: BodyLength) -> io::Result<Vec<u8>> {
+        let mut state = BodyState::new(length);
+        let mut body = vec![];
+        while let Some(chunk) = self.read_body_chunk(&mut state).await? {
+            body.extend(chunk);
+        }
+        Ok(body)
+    }
+
+    /// Reads a body and sends it to `tx` chunk by chunk. The body is read to
+    /// the end even when the receiver is gone, so that the next message can
+    /// be read from the connection.
+    pub async fn read_body_into(&mut self, length: BodyLength, tx: BodySender) -> io::Result<()> {
+        let mut state = BodyState::new(length);
+        loop {
+            match self.read_body_chunk(&mut state).await {
+                Ok(Some(chunk)) => {
+                    let _ = tx.send(Ok(chunk)).await;
+                }
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    let _ = tx.send(Err(io::Error::new(e.kind(), e.to_string()))).await;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Returns the next part of the body described by `state`, or `None`
+    /// once it was read completely.
+    pub async fn read_body_chunk(&mut self, state: &mut BodyState) -> io::Result<Option<Vec<u8>>> {
+        match state {
+            BodyState::Done => Ok(None),
+            BodyState::Length(0) => {
+                *state = BodyState::Done;
+                Ok(None)
+            }
+            BodyState::Length(remaining) => {
+                if self.buf.is_empty() && self.fill().await? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                let n = self.buf.len().min(*remaining as usize);
+                *remaining -= n as u64;
+                Ok(Some(self.buf.drain(..n).collect()))
+            }
+            BodyState::Chunked(remaining) => {
+                if *remaining == 0 {
+                    let line = self.read_line().await?;
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    let size = parse_number(size, 16)?;
+                    if size == 0 {
+                        // trailer section ends with an empty line
+                        while !self.read_line().await?.is_empty() {}
+                        *state = BodyState::Done;
+                        return Ok(None);
+                    }
+                    *remaining = size;
+                }
+
+                if self.buf.is_empty() && self.fill().await? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                let n = self.buf.len().min(*remaining as usize);
+                *remaining -= n as u64;
+                let chunk = self.buf.drain(..n).collect();
+
+                if *remaining == 0 {
+                    // chunk data is followed by CRLF
+                    while self.buf.len() < 2 {
+                        if self.fill().await? == 0 {
+                            return Err(io::ErrorKind::UnexpectedEof.into());
+                        }
+                    }
+                    if self.buf.drain(..2).as_slice() != b"\r\n" {
+                        return Err(invalid_data("invalid chunk end"));
+                    }
+                }
+                Ok(Some(chunk))
+            }
+            BodyState::Close => {
+                if self.buf.is_empty() && self.fill().await? == 0 {
+                    *state = BodyState::Done;
+                    return Ok(None);
+                }
+                Ok(Some(std::mem::take(&mut self.buf)))
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(i) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buf[..i]).into_owned();
+                self.buf.drain(..i + 2);
+                return Ok(line);
+            }
+
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(invalid_data("line too long"));
+            }
+
+            if self.fill().await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    /// Writes a request whose body is in memory.
+    pub async fn write_request(&mut self, req: &http::Request<Body>) -> io::Result<()> {
+        let body = req
+            .body()
+            .as_bytes()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "streamed request body"))?;
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|v| v.as_str())
+            .unwrap_or("/");
+
+        let mut buf = format!("{} {} HTTP/1.1\r\n", req.method(), path).into_bytes();
+        write_headers(&mut buf, req.headers());
+        if !body.is_empty() || has_request_body(req.method()) {
+            buf.extend(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+        }
+        buf.extend(b"\r\n");
+        buf.extend(body);
+
+        self.write_all(&buf).await
+    }
+
+    pub async fn write_continue(&mut self) -> io::Result<()> {
+        self.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await
+    }
+
+    /// Writes a response. A streamed body is sent with chunked encoding on
+    /// a keep-alive connection, along with its trailers, and delimited by
+    /// closing the connection otherwise.
+    pub async fn write_response(
+        &mut self,
+        res: http::Response<Body>,
+        head_only: bool,
+        keep_alive: bool,
+    ) -> io::Result<()> {
+        let (parts, mut body) = res.into_parts();
+        let has_body = !parts.status.is_informational() && parts.status != StatusCode::NO_CONTENT;
+
+        let mut buf = format!("HTTP/1.1 {}\r\n", parts.status).into_bytes();
+        write_headers(&mut buf, &parts.headers);
+        let chunked = match body.as_bytes() {
+            Some(v) if has_body => {
+                buf.extend(format!("Content-Length: {}\r\n", v.len()).as_bytes());
+                false
+            }
+            None if has_body && keep_alive => {
+                buf.extend(b"Transfer-Encoding: chunked\r\n");
+                true
+            }
+            _ => false,
+        };
+        if !keep_alive {
+            buf.extend(b"Connection: close\r\n");
+        }
+        buf.extend(b"\r\n");
+
+        if head_only || !has_body {
+            return self.write_all(&buf).await;
+        }
+
+        if let Some(v) = body.as_bytes() {
+            buf.extend(v);
+            return self.write_all(&buf).await;
+        }
+
+        self.write_all(&buf).await?;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk?;
+            if chunk.is_empty() {
+                continue;
+            }
+            if chunked {
+                let mut buf = format!("{:x}\r\n", chunk.len()).into_bytes();
+                buf.extend(chunk);
+                buf.extend(b"\r\n");
+                self.write_all(&buf).await?;
+            } else {
+                self.write_all(&chunk).await?;
+            }
+        }
+        if chunked {
+            let mut buf = b"0\r\n".to_vec();
+            if let Some(trailers) = body.trailers().await {
+                write_headers(&mut buf, &trailers);
+            }
+            buf.extend(b"\r\n");
+            self.write_all(&buf).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    f: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout {
+        Some(t) => tokio::time::timeout(t, f)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => f.await,
+    }
+}
+
+fn write_headers(buf: &mut Vec<u8>, headers: &HeaderMap) {
+    for (name, value) in headers {
+        if name == header::CONTENT_LENGTH || name == header::TRANSFER_ENCODING {
+            continue;
+        }
+        buf.extend(name.as_str().as_bytes());
+        buf.extend(b": ");
+        buf.extend(value.as_bytes());
+        buf.extend(b"\r\n");
+    }
+}
+
+fn has_request_body(method: &Method) -> bool {
+    method == Method::POST || method == Method::PUT || method == Method::PATCH
+}
+
+fn to_version(version: Option<u8>) -> Version {
+    match version {
+        Some(0) => Version::HTTP_10,
+        _ => Version::HTTP_11,
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Whether the message has a `Transfer-Encoding` header, and whether its
+/// last coding is `chunked`.
+fn transfer_encoding(headers: &HeaderMap) -> Option<bool> {
+    let mut codings = headers
+        .get_all(header::TRANSFER_ENCODING)
+        .iter()
+        .map(|v| v.to_str().unwrap_or_default())
+        .flat_map(|v| v.split(','))
+        .peekable();
+    codings.peek()?;
+    Some(
+        codings
+            .last()
+            .map(|v| v.trim().eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false),
+    )
+}
+
+/// Returns the value of `Content-Length`. Like a value that isn't a plain
+/// number, several headers are rejected, as another server may pick a
+/// different one to frame the message.
+fn content_length(headers: &HeaderMap) -> io::Result<Option<u64>> {
+    let mut values = headers.get_all(header::CONTENT_LENGTH).iter();
+    let value = match values.next() {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    if values.next().is_some() {
+        return Err(invalid_data("duplicate content-length"));
+    }
+
+    let value = value.to_str().map_err(invalid_data)?;
+    parse_number(value.trim(), 10).map(Some)
+}
+
+/// Parses a number made of digits only, which `str::parse` doesn't ensure
+/// as it accepts a sign.
+fn parse_number(value: &str, radix: u32) -> io::Result<u64> {
+    if value.is_empty() || !value.chars().all(|c| c.is_digit(radix)) {
+        return Err(invalid_data(format!("invalid number {:?}", value)));
+    }
+    u64::from_str_radix(value, radix).map_err(invalid_data)
+}
+
+/// Returns how the body of a request is delimited. A request whose last
+/// transfer coding isn't `chunked` can't be delimited, and one with both
+/// `Transfer-Encoding` and `Content-Length` is rejected as it may be read
+/// differently by an upstream.
+pub fn request_body_length(headers: &HeaderMap) -> io::Result<BodyLength> {
+    match transfer_encoding(headers) {
+        Some(true) if headers.contains_key(header::CONTENT_LENGTH) => {
+            return Err(invalid_data("transfer-encoding with content-length"))
+        }
+        Some(true) => return Ok(BodyLength::Chunked),
+        Some(false) => return Err(invalid_data("unsupported transfer-encoding")),
+        None => {}
+    }
+
+    match content_length(headers)? {
+        Some(0) | None => Ok(BodyLength::Empty),
+        Some(n) => Ok(BodyLength::Length(n)),
+    }
+}
+
+pub fn response_body_length(
+    method: &Method,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> io::Result<BodyLength> {
+    if method == Method::HEAD
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return Ok(BodyLength::Empty);
+    }
+
+    // a response whose last coding isn't chunked ends with the connection
+    match transfer_encoding(headers) {
+        Some(true) => return Ok(BodyLength::Chunked),
+        Some(false) => return Ok(BodyLength::Close),
+        None => {}
+    }
+
+    match content_length(headers)? {
+        Some(0) => Ok(BodyLength::Empty),
+        Some(n) => Ok(BodyLength::Length(n)),
+        None => Ok(BodyLength::Close),
+    }
+}
+
+/// Whether the connection may be reused after this message, following the
+/// HTTP/1.0 and HTTP/1.1 defaults.
+pub fn is_keep_alive(version: Version, headers: &HeaderMap) -> bool {
+    let connection = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+
+    if connection.iter().any(|v| v == "close") {
+        return false;
+    }
+
+    version == Version::HTTP_11 || connection.iter().any(|v| v == "keep-alive")
+}
+
+/// Whether the request asks to switch protocols with `Upgrade`.
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE) && connection_tokens(headers).any(|v| v == "upgrade")
+}
+
+fn connection_tokens(headers: &HeaderMap) -> impl Iterator<Item = String> + '_ {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty())
+}
+
+/// Removes hop-by-hop headers, including any listed in `Connection`. For a
+/// protocol upgrade, `Upgrade` and the other headers listed in `Connection`
+/// (such as `HTTP2-Settings` for h2c) are kept and `Connection` lists only
+/// them, so the upgrade can be passed on to the next hop.
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap, upgrade: bool) {
+    let listed = connection_tokens(headers).collect::<Vec<_>>();
+
+    if upgrade {
+        let kept = listed
+            .iter()
+            .filter(|v| !HOP_BY_HOP_HEADERS.contains(&v.as_str()) && *v != "close")
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        for name in HOP_BY_HOP_HEADERS {
+            headers.remove(name);
+        }
+        set_header(headers, header::CONNECTION, &kept.join(", "));
+        return;
+    }
+
+    for name in listed.iter().map(String::as_str).chain(HOP_BY_HOP_HEADERS) {
+        headers.remove(name);
+    }
+    headers.remove(header::UPGRADE);
+}
+
+pub fn set_header(headers: &mut HeaderMap, name: header::HeaderName, value: &str) {
+    if let Ok(v) = HeaderValue::from_str(value) {
+        headers.insert(name, v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processor::http1::{BodyLength, Connection};
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let data = b"POST /a?b=c HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let mut conn = Connection::new(tokio_test_io(data));
+
+        let head = conn.read_request_head().await.unwrap().unwrap();
+        assert_eq!(head.method, http::Method::POST);
+        assert_eq!(head.uri, "/a?b=c");
+        assert_eq!(head.headers["host"], "example.com");
+
+        let length = super::request_body_length(&head.headers).unwrap();
+        assert_eq!(length, BodyLength::Chunked);
+        assert_eq!(conn.read_body(length).await.unwrap(), b"abcde");
+
+        let head = conn.read_request_head().await.unwrap().unwrap();
+        assert_eq!(head.method, http::Method::GET);
+        assert!(conn.read_request_head().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_request_body_length() {
+        let length = |headers: &[(&'static str, &str)]| {
+            let mut map = http::HeaderMap::new();
+            for (k, v) in headers {
+                let name = http::header::HeaderName::from_static(k);
+                map.append(name, v.parse().unwrap());
+            }
+            super::request_body_length(&map).map_err(|e| e.kind())
+        };
+        let invalid = Err(std::io::ErrorKind::InvalidData);
+
+        assert_eq!(length(&[]), Ok(BodyLength::Empty));
+        assert_eq!(
+            length(&[("content-length", "5")]),
+            Ok(BodyLength::Length(5))
+        );
+        assert_eq!(
+            length(&[("transfer-encoding", "gzip, chunked")]),
+            Ok(BodyLength::Chunked)
+        );
+        assert_eq!(length(&[("transfer-encoding", "chunked, gzip")]), invalid);
+        assert_eq!(length(&[("transfer-encoding", "gzip")]), invalid);
+        assert_eq!(
+            length(&[("transfer-encoding", "chunked"), ("content-length", "5")]),
+            invalid
+        );
+        assert_eq!(
+            length(&[("content-length", "5"), ("content-length", "5")]),
+            invalid
+        );
+        assert_eq!(
+            length(&[("content-length", "5"), ("content-length", "6")]),
+            invalid
+        );
+        assert_eq!(length(&[("content-length", "5, 6")]), invalid);
+        assert_eq!(length(&[("content-length", "+5")]), invalid);
+        assert_eq!(length(&[("content-length", "-5")]), invalid);
+    }
+
+    #[tokio::test]
+    async fn test_read_invalid_chunks() {
+        for data in [
+            &b"+3\r\nabc\r\n0\r\n\r\n"[..],
+            &b"3\r\nabcXY0\r\n\r\n"[..],
+            &b"x\r\n\r\n"[..],
+        ] {
+            let mut conn = Connection::new(tokio_test_io(data));
+            let e = conn.read_body(BodyLength::Chunked).await.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_remove_hop_by_hop_headers() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            "connection",
+            "Upgrade, HTTP2-Settings, keep-alive".parse().unwrap(),
+        );
+        headers.insert("upgrade", "h2c".parse().unwrap());
+        headers.insert("http2-settings", "AAMAAABkAAQAAP__".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        headers.insert("te", "trailers".parse().unwrap());
+        assert!(super::is_upgrade(&headers));
+
+        let mut upgrade = headers.clone();
+        super::remove_hop_by_hop_headers(&mut upgrade, true);
+        assert_eq!(upgrade["connection"], "upgrade, http2-settings");
+        assert_eq!(upgrade["upgrade"], "h2c");
+        assert!(upgrade.contains_key("http2-settings"));
+        assert!(!upgrade.contains_key("keep-alive"));
+        assert!(!upgrade.contains_key("te"));
+
+        super::remove_hop_by_hop_headers(&mut headers, false);
+        assert!(headers.is_empty());
+    }
+
+    fn tokio_test_io(data: &[u8]) -> tokio::io::DuplexStream {
+        let (client, mut server) = tokio::io::duplex(data.len() + 1);
+        let data = data.to_vec();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            server.write_all(&data).await.unwrap();
+        });
+        client
+    }
+}
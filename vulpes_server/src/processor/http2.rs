@@ -0,0 +1,635 @@
+use crate::{
+    config::http2::Http2Config,
+    processor::{
+        body::{Body, BodySender, TrailersSender},
+        http1, ConnectionInfo, Request, Response, Server,
+    },
+};
+use bytes::Bytes;
+use h2::{server::SendResponse, RecvStream, SendStream};
+use http::{header, HeaderMap, HeaderValue, Method};
+use std::{
+    future::poll_fn,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    sync::Notify,
+    task::JoinSet,
+    time::{sleep, timeout},
+};
+
+/// Connection preface sent first by HTTP/2 clients.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+const HEADERS: u8 = 0x1;
+const SETTINGS: u8 = 0x4;
+const END_STREAM: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+/// Default `SETTINGS_MAX_FRAME_SIZE`, which the header block of an upgraded
+/// request and the settings of the client have to fit in.
+const MAX_FRAME_SIZE: usize = 16384;
+
+/// Urgency levels of the `priority` header, 0 being the most urgent.
+const URGENCY_LEVELS: usize = 8;
+const DEFAULT_URGENCY: usize = 3;
+
+/// Serves the HTTP/2 connection `io`, whose bytes start with the client
+/// connection preface. Each stream is routed like an HTTP/1.x request.
+pub async fn serve<S>(
+    server: &Server,
+    io: S,
+    info: ConnectionInfo,
+    config: &Http2Config,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut conn = h2::server::Builder::new()
+        .max_concurrent_streams(config.max_concurrent_streams)
+        .max_header_list_size(config.max_header_size)
+        .handshake::<_, Bytes>(io)
+        .await
+        .map_err(from_h2)?;
+
+    // the idle timer only runs while no stream is open, and a connection
+    // shut down gracefully is served until its last stream completes
+    let mut streams = JoinSet::new();
+    let scheduler = Arc::new(Scheduler::default());
+    let mut closing = false;
+    loop {
+        let open = !streams.is_empty();
+        tokio::select! {
+            accepted = conn.accept() => match accepted {
+                Some(Ok((req, respond))) => {
+                    streams.spawn(handle_stream(
+                        server.clone(),
+                        req,
+                        respond,
+                        info.clone(),
+                        config.idle_timeout,
+                        scheduler.clone(),
+                    ));
+                }
+                Some(Err(e)) => return Err(from_h2(e)),
+                None => return Ok(()),
+            },
+            Some(_) = streams.join_next(), if open => {}
+            _ = sleep(config.idle_timeout), if !open => {
+                if closing {
+                    return Ok(());
+                }
+                log::debug!("close idle http2 connection");
+                conn.graceful_shutdown();
+                closing = true;
+            }
+        }
+    }
+}
+
+async fn handle_stream(
+    server: Server,
+    req: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    info: ConnectionInfo,
+    idle_timeout: Duration,
+    scheduler: Arc<Scheduler>,
+) {
+    let (mut parts, recv) = req.into_parts();
+    let urgency = parse_urgency(&parts.headers).unwrap_or(DEFAULT_URGENCY);
+
+    // HTTP/2 carries the host in the `:authority` pseudo header
+    if !parts.headers.contains_key(header::HOST) {
+        if let Some(v) = parts
+            .uri
+            .authority()
+            .and_then(|v| HeaderValue::from_str(v.as_str()).ok())
+        {
+            parts.headers.insert(header::HOST, v);
+        }
+    }
+
+    let body = if recv.is_end_stream() {
+        Body::default()
+    } else {
+        let (tx, trailers_tx, body) = Body::channel_with_trailers();
+        tokio::spawn(async move {
+            if let Err(e) = receive_body(recv, &tx, trailers_tx, idle_timeout).await {
+                let _ = tx.send(Err(e)).await;
+            }
+        });
+        body
+    };
+
+    let head_only = parts.method == Method::HEAD;
+    let mut req = Request::from_parts(parts, body);
+    req.extensions_mut().insert(info);
+
+    log::debug!("http2 request: {:?}", req);
+    let res = server.get_server(&mut req).await.handle(req).await;
+
+    // the priority of the response, from an upstream, takes precedence
+    let urgency = parse_urgency(res.headers()).unwrap_or(urgency);
+    let priority = Some((scheduler.as_ref(), urgency));
+    if let Err(e) = send_response(&mut respond, res, head_only, idle_timeout, priority).await {
+        log::debug!("failed to send http2 response: {:?}", e);
+    }
+}
+
+async fn send_response(
+    respond: &mut SendResponse<Bytes>,
+    res: Response,
+    head_only: bool,
+    send_timeout: Duration,
+    priority: Option<(&Scheduler, usize)>,
+) -> io::Result<()> {
+    let (mut parts, body) = res.into_parts();
+    parts.version = http::Version::HTTP_2;
+    http1::remove_hop_by_hop_headers(&mut parts.headers, false);
+    if let Some(v) = body.as_bytes() {
+        parts.headers.insert(header::CONTENT_LENGTH, v.len().into());
+    }
+
+    let end_of_stream = head_only || body.as_bytes().map(|v| v.is_empty()).unwrap_or(false);
+    let mut stream = respond
+        .send_response(http::Response::from_parts(parts, ()), end_of_stream)
+        .map_err(from_h2)?;
+    if end_of_stream {
+        return Ok(());
+    }
+
+    let result = send_body(&mut stream, body, send_timeout, priority).await;
+    if result.is_err() {
+        stream.send_reset(h2::Reason::INTERNAL_ERROR);
+    }
+    result
+}
+
+/// Sends `body` on `stream` as the flow control window allows, followed by
+/// its trailers. With a `priority`, each chunk waits for its turn in the
+/// scheduler of the connection.
+pub async fn send_body(
+    stream: &mut SendStream<Bytes>,
+    mut body: Body,
+    send_timeout: Duration,
+    priority: Option<(&Scheduler, usize)>,
+) -> io::Result<()> {
+    while let Some(chunk) = body.next().await {
+        let mut chunk = Bytes::from(chunk?);
+        let _turn = match priority {
+            Some((scheduler, urgency)) => Some(scheduler.turn(urgency).await),
+            None => None,
+        };
+        while !chunk.is_empty() {
+            stream.reserve_capacity(chunk.len());
+            let n = match timeout(send_timeout, poll_fn(|cx| stream.poll_capacity(cx))).await {
+                Ok(Some(n)) => n.map_err(from_h2)?,
+                Ok(None) => return Err(io::ErrorKind::BrokenPipe.into()),
+                Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+            };
+            let data = chunk.split_to(n.min(chunk.len()));
+            stream.send_data(data, false).map_err(from_h2)?;
+        }
+    }
+
+    match body.trailers().await {
+        Some(trailers) => stream.send_trailers(trailers),
+        None => stream.send_data(Bytes::new(), true),
+    }
+    .map_err(from_h2)
+}
+
+/// Responses of a connection waiting to send data, by urgency. A response
+/// only asks for flow control capacity while no more urgent response has
+/// data to send, so that the capacity of the connection goes to the most
+/// urgent ones first.
+#[derive(Default)]
+pub struct Scheduler {
+    waiting: Mutex<[usize; URGENCY_LEVELS]>,
+    notify: Notify,
+}
+
+/// Turn of a response in a [`Scheduler`], until it's dropped.
+struct Turn<'a> {
+    scheduler: &'a Scheduler,
+    urgency: usize,
+}
+
+impl Scheduler {
+    /// Waits until no response more urgent than `urgency` has data to send.
+    async fn turn(&self, urgency: usize) -> Turn<'_> {
+        self.waiting.lock().unwrap()[urgency] += 1;
+        let turn = Turn {
+            scheduler: self,
+            urgency,
+        };
+        loop {
+            let notified = self.notify.notified();
+            if self.waiting.lock().unwrap()[..urgency]
+                .iter()
+                .all(|n| *n == 0)
+            {
+                return turn;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.scheduler.waiting.lock().unwrap()[self.urgency] -= 1;
+        self.scheduler.notify.notify_waiters();
+    }
+}
+
+/// Returns the urgency of the `priority` header (RFC 9218). The
+/// `incremental` parameter isn't used, as a response is sent as its data
+/// comes anyway.
+fn parse_urgency(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get_all("priority")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| v.split(';').next()?.trim().strip_prefix("u="))
+        .filter_map(|v| v.parse::<usize>().ok())
+        .filter(|v| *v < URGENCY_LEVELS)
+        .last()
+}
+
+/// Forwards the data of `recv` and then its trailers, releasing flow control
+/// capacity as the data is consumed.
+pub async fn receive_body(
+    mut recv: RecvStream,
+    tx: &BodySender,
+    trailers_tx: TrailersSender,
+    read_timeout: Duration,
+) -> io::Result<()> {
+    loop {
+        let data = match timeout(read_timeout, recv.data()).await {
+            Ok(Some(data)) => data.map_err(from_h2)?,
+            Ok(None) => break,
+            Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+        };
+        let _ = recv.flow_control().release_capacity(data.len());
+        if tx.send(Ok(data.to_vec())).await.is_err() {
+            return Ok(());
+        }
+    }
+
+    let trailers = timeout(read_timeout, recv.trailers())
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+        .map_err(from_h2)?;
+    if let Some(trailers) = trailers {
+        let _ = trailers_tx.send(trailers);
+    }
+
+    Ok(())
+}
+
+pub fn from_h2(e: h2::Error) -> io::Error {
+    if e.is_io() {
+        return e.into_io().unwrap_or_else(|| io::ErrorKind::Other.into());
+    }
+
+    let kind = match e.reason() {
+        Some(h2::Reason::REFUSED_STREAM) => io::ErrorKind::ConnectionRefused,
+        _ if e.is_go_away() || e.is_reset() => io::ErrorKind::ConnectionReset,
+        _ => io::ErrorKind::InvalidData,
+    };
+    io::Error::new(kind, e)
+}
+
+/// Whether the request asks to switch to h2c, which is only accepted for
+/// requests without a body.
+pub fn is_h2c_upgrade(head: &http::request::Parts) -> bool {
+    let upgrade = head
+        .headers
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').any(|v| v.trim().eq_ignore_ascii_case("h2c")))
+        .unwrap_or(false);
+
+    upgrade
+        && head.version == http::Version::HTTP_11
+        && http1::is_upgrade(&head.headers)
+        && head.headers.contains_key("http2-settings")
+        && !head.headers.contains_key(header::CONTENT_LENGTH)
+        && !head.headers.contains_key(header::TRANSFER_ENCODING)
+}
+
+/// Encodes the request of an `Upgrade: h2c` as the HEADERS frame of stream
+/// 1, or returns `None` when it does not fit in a single frame.
+pub fn upgrade_frame(head: &http::request::Parts) -> Option<Vec<u8>> {
+    let authority = head
+        .headers
+        .get(header::HOST)
+        .map(|v| v.as_bytes())
+        .unwrap_or_default();
+    let path = head.uri.path_and_query().map(|v| v.as_str()).unwrap_or("/");
+
+    // literals without indexing leave the HPACK table of the client alone
+    let mut block = vec![];
+    for (name, value) in [
+        (&b":method"[..], head.method.as_str().as_bytes()),
+        (b":scheme", b"http"),
+        (b":path", path.as_bytes()),
+        (b":authority", authority),
+    ] {
+        encode_literal(&mut block, name, value);
+    }
+
+    let mut headers = head.headers.clone();
+    http1::remove_hop_by_hop_headers(&mut headers, false);
+    headers.remove(header::HOST);
+    headers.remove("http2-settings");
+    for (name, value) in &headers {
+        encode_literal(&mut block, name.as_str().as_bytes(), value.as_bytes());
+    }
+
+    if block.len() > MAX_FRAME_SIZE {
+        return None;
+    }
+
+    let mut frame = (block.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.extend([HEADERS, END_STREAM | END_HEADERS]);
+    frame.extend(1u32.to_be_bytes());
+    frame.extend(block);
+    Some(frame)
+}
+
+fn encode_literal(buf: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    buf.push(0);
+    for s in [name, value] {
+        encode_integer(buf, s.len());
+        buf.extend(s);
+    }
+}
+
+/// Encodes an HPACK integer with a 7 bit prefix and no Huffman coding.
+fn encode_integer(buf: &mut Vec<u8>, mut n: usize) {
+    if n < 0x7f {
+        buf.push(n as u8);
+        return;
+    }
+
+    buf.push(0x7f);
+    n -= 0x7f;
+    while n >= 0x80 {
+        buf.push((n % 0x80) as u8 | 0x80);
+        n /= 0x80;
+    }
+    buf.push(n as u8);
+}
+
+/// Decodes the `HTTP2-Settings` of an `Upgrade: h2c`, the base64url encoded
+/// payload of a SETTINGS frame, or returns `None` when it is invalid.
+pub fn upgrade_settings(head: &http::request::Parts) -> Option<Vec<u8>> {
+    let value = head.headers.get("http2-settings")?.as_bytes();
+    let value = value
+        .strip_suffix(b"==")
+        .or(value.strip_suffix(b"="))
+        .unwrap_or(value);
+
+    let mut payload = vec![];
+    let mut bits = 0u32;
+    let mut n = 0;
+    for c in value {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | v as u32;
+        n += 6;
+        if n >= 8 {
+            n -= 8;
+            payload.push((bits >> n) as u8);
+        }
+    }
+
+    // each setting is a 16 bit identifier and a 32 bit value
+    match payload.len() % 6 {
+        0 => Some(payload),
+        _ => None,
+    }
+}
+
+/// Reads the connection preface and first SETTINGS frame the client sends
+/// after switching to h2c, and inserts `frame` right after them so the
+/// upgraded request reaches the server as stream 1.
+///
+/// The `upgrade` settings of `HTTP2-Settings` are put first in the payload of that
+/// SETTINGS frame, so that they are applied and acknowledged with it, the
+/// values the client sends again taking precedence.
+pub async fn inject_upgrade<S>(
+    mut io: Rewind<S>,
+    upgrade: Vec<u8>,
+    frame: Vec<u8>,
+) -> io::Result<Rewind<S>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut start = vec![0u8; PREFACE.len() + FRAME_HEADER_LEN];
+    io.read_exact(&mut start).await?;
+    let settings = &start[PREFACE.len()..];
+    if &start[..PREFACE.len()] != PREFACE || settings[3] != SETTINGS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid h2c connection preface",
+        ));
+    }
+
+    let length = u32::from_be_bytes([0, settings[0], settings[1], settings[2]]) as usize;
+    let mut payload = vec![0u8; length];
+    io.read_exact(&mut payload).await?;
+
+    let length = upgrade.len() + length;
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "h2c settings too large",
+        ));
+    }
+    start[PREFACE.len()..PREFACE.len() + 3].copy_from_slice(&(length as u32).to_be_bytes()[1..]);
+    start.extend(upgrade);
+    start.extend(payload);
+    start.extend(frame);
+    io.unread(start);
+    Ok(io)
+}
+
+/// Stream that yields some bytes already read from it before reading
+/// further, e.g. the bytes buffered while looking for the HTTP/2 preface.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    io: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Vec<u8>, io: S) -> Rewind<S> {
+        Rewind { prefix, io }
+    }
+
+    fn unread(&mut self, mut data: Vec<u8>) {
+        data.append(&mut self.prefix);
+        self.prefix = data;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processor::http2::{
+        encode_integer, parse_urgency, upgrade_frame, upgrade_settings, Scheduler,
+    };
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[test]
+    fn test_encode_integer() {
+        let encoded = |n| {
+            let mut buf = vec![];
+            encode_integer(&mut buf, n);
+            buf
+        };
+        assert_eq!(encoded(10), vec![10]);
+        assert_eq!(encoded(127), vec![127, 0]);
+        assert_eq!(encoded(1337), vec![127, 0xba, 0x09]);
+    }
+
+    #[test]
+    fn test_upgrade_frame() {
+        let (head, _) = http::Request::builder()
+            .uri("/a?b")
+            .header("Host", "example.com")
+            .header("Connection", "Upgrade, HTTP2-Settings")
+            .header("Upgrade", "h2c")
+            .header("HTTP2-Settings", "AAMAAABkAAQAoAAAAAIAAAAA")
+            .header("Accept", "*/*")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let frame = upgrade_frame(&head).unwrap();
+        assert_eq!(&frame[3..9], &[1, 5, 0, 0, 0, 1]);
+
+        let mut block = vec![0, 7];
+        block.extend(b":method");
+        block.extend([3]);
+        block.extend(b"GET");
+        assert!(frame[9..].starts_with(&block));
+        assert!(frame.ends_with(b"\x00\x06accept\x03*/*"));
+        assert!(!frame.windows(7).any(|w| w == b"upgrade"));
+        assert_eq!(
+            frame.len(),
+            9 + u32::from_be_bytes([0, frame[0], frame[1], frame[2]]) as usize
+        );
+    }
+
+    #[test]
+    fn test_upgrade_settings() {
+        let settings = |v: &str| {
+            let (head, _) = http::Request::builder()
+                .header("HTTP2-Settings", v)
+                .body(())
+                .unwrap()
+                .into_parts();
+            upgrade_settings(&head)
+        };
+
+        assert_eq!(
+            settings("AAMAAABkAAQAoAAAAAIAAAAA").unwrap(),
+            [0, 3, 0, 0, 0, 100, 0, 4, 0, 160, 0, 0, 0, 2, 0, 0, 0, 0]
+        );
+        assert_eq!(settings("AAQAAAAE").unwrap(), [0, 4, 0, 0, 0, 4]);
+        assert_eq!(settings("").unwrap(), []);
+        assert_eq!(
+            settings("_-_-_-_-").unwrap(),
+            [0xff, 0xef, 0xfe, 0xff, 0xef, 0xfe]
+        );
+        assert!(settings("AAQAAA").is_none());
+        assert!(settings("AAQA+AAE").is_none());
+    }
+
+    #[test]
+    fn test_parse_urgency() {
+        let urgency = |v: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert("priority", v.parse().unwrap());
+            parse_urgency(&headers)
+        };
+        assert_eq!(urgency("u=1"), Some(1));
+        assert_eq!(urgency("u=5, i"), Some(5));
+        assert_eq!(urgency("i, u=0;x=1"), Some(0));
+        assert_eq!(urgency("u=2, u=6"), Some(6));
+        assert_eq!(urgency("u=8"), None);
+        assert_eq!(urgency("i"), None);
+        assert_eq!(parse_urgency(&http::HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn test_scheduler() {
+        let scheduler = Scheduler::default();
+        let urgent = scheduler.turn(1).await;
+        let same = scheduler.turn(1).await;
+
+        // a less urgent response waits for the more urgent ones
+        let mut less = Box::pin(scheduler.turn(3));
+        assert!(timeout(Duration::from_millis(50), &mut less).await.is_err());
+        drop(urgent);
+        assert!(timeout(Duration::from_millis(50), &mut less).await.is_err());
+        drop(same);
+        let less = timeout(Duration::from_millis(50), less).await.unwrap();
+
+        // and doesn't hold back a more urgent one
+        timeout(Duration::from_millis(50), scheduler.turn(0))
+            .await
+            .unwrap();
+        drop(less);
+    }
+}
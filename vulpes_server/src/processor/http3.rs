@@ -0,0 +1,189 @@
+use crate::processor::{
+    body::{Body, BodySender, TrailersSender},
+    http1, socket, ConnectionInfo, Request, Response, Server, HANDSHAKE_TIMEOUT,
+};
+use bytes::{Buf, Bytes};
+use h3::server::RequestStream;
+use h3_quinn::{BidiStream, RecvStream, SendStream};
+use http::{header, HeaderValue, Method};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::time::timeout;
+
+/// Serves HTTP/3 on the UDP port of `server`, using its TLS settings.
+pub async fn run(server: Server, tx: tokio::sync::oneshot::Sender<()>) -> io::Result<()> {
+    let config = match &server.tls {
+        Some(acceptor) => acceptor.quic_config()?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "quic requires ssl_certificate",
+            ))
+        }
+    };
+    let socket = socket::bind_udp(&server.listen)?;
+    let endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )?;
+    tx.send(()).unwrap();
+
+    while let Some(connecting) = endpoint.accept().await {
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(server, connecting).await {
+                log::error!("handle error: {:?}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Serves a QUIC connection, routing each request stream like an HTTP/1.x
+/// request.
+async fn serve(server: Server, connecting: quinn::Connecting) -> io::Result<()> {
+    let conn = match timeout(HANDSHAKE_TIMEOUT, connecting).await {
+        Ok(v) => v?,
+        Err(_) => return Ok(()),
+    };
+
+    let tls = match &server.tls {
+        Some(acceptor) => acceptor.quic_info(&conn)?,
+        None => return Ok(()),
+    };
+    let port = server.listen.port().unwrap_or_default();
+    let local_ip = conn.local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let info = ConnectionInfo {
+        remote_addr: conn.remote_address(),
+        local_addr: SocketAddr::new(local_ip, port),
+        unix: None,
+        tls: Some(tls),
+    };
+
+    let mut conn = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn))
+        .await
+        .map_err(from_h3)?;
+    loop {
+        match conn.accept().await {
+            Ok(Some((req, stream))) => {
+                tokio::spawn(handle_stream(server.clone(), req, stream, info.clone()));
+            }
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(from_h3(e)),
+        }
+    }
+}
+
+async fn handle_stream(
+    server: Server,
+    req: http::Request<()>,
+    stream: RequestStream<BidiStream<Bytes>, Bytes>,
+    info: ConnectionInfo,
+) {
+    let (mut parts, _) = req.into_parts();
+    parts.version = http::Version::HTTP_3;
+
+    // HTTP/3 carries the host in the `:authority` pseudo header
+    if !parts.headers.contains_key(header::HOST) {
+        if let Some(v) = parts
+            .uri
+            .authority()
+            .and_then(|v| HeaderValue::from_str(v.as_str()).ok())
+        {
+            parts.headers.insert(header::HOST, v);
+        }
+    }
+
+    // a request without a body ends its stream right after the headers
+    let (mut send, mut recv) = stream.split();
+    let body = match recv.recv_data().await {
+        Ok(Some(mut first)) => {
+            let first = first.copy_to_bytes(first.remaining()).to_vec();
+            let (tx, trailers_tx, body) = Body::channel_with_trailers();
+            tokio::spawn(async move {
+                if tx.send(Ok(first)).await.is_err() {
+                    return;
+                }
+                if let Err(e) = receive_body(recv, &tx, trailers_tx).await {
+                    let _ = tx.send(Err(e)).await;
+                }
+            });
+            body
+        }
+        Ok(None) => Body::default(),
+        Err(e) => {
+            log::debug!("failed to receive http3 request body: {:?}", e);
+            return;
+        }
+    };
+
+    let head_only = parts.method == Method::HEAD;
+    let mut req = Request::from_parts(parts, body);
+    req.extensions_mut().insert(info);
+
+    log::debug!("http3 request: {:?}", req);
+    let res = server.get_server(&mut req).await.handle(req).await;
+
+    if let Err(e) = send_response(&mut send, res, head_only).await {
+        log::debug!("failed to send http3 response: {:?}", e);
+    }
+}
+
+async fn send_response(
+    send: &mut RequestStream<SendStream<Bytes>, Bytes>,
+    res: Response,
+    head_only: bool,
+) -> io::Result<()> {
+    let (mut parts, mut body) = res.into_parts();
+    parts.version = http::Version::HTTP_3;
+    http1::remove_hop_by_hop_headers(&mut parts.headers, false);
+    if let Some(v) = body.as_bytes() {
+        parts.headers.insert(header::CONTENT_LENGTH, v.len().into());
+    }
+
+    send.send_response(http::Response::from_parts(parts, ()))
+        .await
+        .map_err(from_h3)?;
+
+    if !head_only {
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk?;
+            if !chunk.is_empty() {
+                send.send_data(Bytes::from(chunk)).await.map_err(from_h3)?;
+            }
+        }
+        if let Some(trailers) = body.trailers().await {
+            send.send_trailers(trailers).await.map_err(from_h3)?;
+        }
+    }
+
+    send.finish().await.map_err(from_h3)
+}
+
+/// Forwards the remaining data of `recv` and then its trailers.
+async fn receive_body(
+    mut recv: RequestStream<RecvStream, Bytes>,
+    tx: &BodySender,
+    trailers_tx: TrailersSender,
+) -> io::Result<()> {
+    while let Some(mut data) = recv.recv_data().await.map_err(from_h3)? {
+        let data = data.copy_to_bytes(data.remaining());
+        if tx.send(Ok(data.to_vec())).await.is_err() {
+            return Ok(());
+        }
+    }
+
+    if let Some(trailers) = recv.recv_trailers().await.map_err(from_h3)? {
+        let _ = trailers_tx.send(trailers);
+    }
+    Ok(())
+}
+
+fn from_h3(e: h3::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
@@ -0,0 +1,358 @@
+use crate::{
+    config::{
+        http::HttpConfig,
+        map::{GeoConfig, MapConfig},
+        network::{key, Family},
+        server::ServerName,
+        types::{Pattern, Template},
+    },
+    processor::{server_name::ServerNames, variable, ConnectionInfo, Request},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Variables defined by the `map` and `geo` of an `http` block, by name.
+pub type Variables = HashMap<String, Variable>;
+
+pub enum Variable {
+    Map(Map),
+    Geo(Geo),
+}
+
+pub fn build(http: &HttpConfig) -> Variables {
+    let mut variables = HashMap::new();
+    for c in &http.map {
+        variables.insert(c.variable.clone(), Variable::Map(Map::new(c)));
+    }
+    for c in &http.geo {
+        variables.insert(c.variable.clone(), Variable::Geo(Geo::new(c)));
+    }
+    variables
+}
+
+/// Values of the variables of `map` and `geo` evaluated for a request,
+/// stored in the request extensions. A variable is evaluated the first time
+/// it's used and then kept, unless its `map` is `volatile`.
+#[derive(Clone)]
+pub struct VariableCache {
+    variables: Arc<Variables>,
+    values: Arc<Mutex<HashMap<String, String>>>,
+    /// Variables being evaluated, of which a reference from their own
+    /// source evaluates to an empty string rather than recursing.
+    evaluating: Arc<Mutex<HashSet<String>>>,
+}
+
+impl VariableCache {
+    pub fn new(variables: Arc<Variables>) -> VariableCache {
+        VariableCache {
+            variables,
+            values: Arc::default(),
+            evaluating: Arc::default(),
+        }
+    }
+
+    /// Returns the value of a variable of `map` or `geo`, `None` when no
+    /// such variable is defined.
+    pub fn get(&self, name: &str, req: &Request) -> Option<String> {
+        let variable = self.variables.get(name)?;
+        if let Some(v) = self.values.lock().unwrap().get(name) {
+            return Some(v.clone());
+        }
+
+        if !self.evaluating.lock().unwrap().insert(name.to_owned()) {
+            return Some(String::new());
+        }
+        let value = match variable {
+            Variable::Map(m) => m.evaluate(req),
+            Variable::Geo(g) => g.evaluate(req),
+        };
+        self.evaluating.lock().unwrap().remove(name);
+        if !matches!(variable, Variable::Map(m) if m.volatile) {
+            let mut values = self.values.lock().unwrap();
+            values.insert(name.to_owned(), value.clone());
+        }
+        Some(value)
+    }
+}
+
+/// Compiled `map`, matching the exact keys and the wildcards of `hostnames`
+/// in hash tables before the regular expressions in order.
+pub struct Map {
+    source: Template,
+    hostnames: bool,
+    volatile: bool,
+    names: ServerNames,
+    regex: Vec<(Pattern, usize)>,
+    values: Vec<Template>,
+    default: Template,
+}
+
+impl Map {
+    fn new(c: &MapConfig) -> Map {
+        let mut names = ServerNames::default();
+        let mut regex = vec![];
+        for (i, (key, _)) in c.entries.iter().enumerate() {
+            match key {
+                ServerName::Regex(v) => regex.push((v.clone(), i)),
+                _ => names.insert(key, i),
+            }
+        }
+
+        Map {
+            source: c.source.clone(),
+            hostnames: c.hostnames,
+            volatile: c.volatile,
+            names,
+            regex,
+            values: c.entries.iter().map(|(_, v)| v.clone()).collect(),
+            default: c.default.clone(),
+        }
+    }
+
+    fn evaluate(&self, req: &Request) -> String {
+        let source = variable::evaluate(&self.source, req);
+        let key = source.to_ascii_lowercase();
+        let key = match self.hostnames {
+            true => key.trim_end_matches('.'),
+            false => &key,
+        };
+
+        if let Some((i, _)) = self.names.find(key) {
+            return variable::evaluate(&self.values[i], req);
+        }
+        // the captures of the regular expression are available in the value
+        for (pattern, i) in &self.regex {
+            if let Some(captures) = variable::Captures::new(pattern, &source) {
+                return variable::evaluate_with(&self.values[*i], req, &captures);
+            }
+        }
+        variable::evaluate(&self.default, req)
+    }
+}
+
+/// Compiled `geo`, with the networks of each address family in a radix tree.
+pub struct Geo {
+    source: Option<Template>,
+    v4: RadixTree,
+    v6: RadixTree,
+    values: Vec<String>,
+    default: String,
+}
+
+impl Geo {
+    fn new(c: &GeoConfig) -> Geo {
+        let mut geo = Geo {
+            source: c.source.clone(),
+            v4: RadixTree::default(),
+            v6: RadixTree::default(),
+            values: vec![],
+            default: c.default.clone(),
+        };
+        for (network, value) in &c.networks {
+            let i = geo.values.len();
+            geo.values.push(value.clone());
+            let (tree, bits, prefix) = match network.key() {
+                (Family::V4, bits, prefix) => (&mut geo.v4, bits, prefix),
+                (Family::V6, bits, prefix) => (&mut geo.v6, bits, prefix),
+            };
+            tree.insert(bits, prefix, i);
+        }
+        geo
+    }
+
+    fn evaluate(&self, req: &Request) -> String {
+        let addr = match &self.source {
+            Some(source) => variable::evaluate(source, req).parse::<IpAddr>().ok(),
+            None => req
+                .extensions()
+                .get::<ConnectionInfo>()
+                .filter(|v| v.unix.is_none())
+                .map(|v| v.remote_addr.ip()),
+        };
+
+        let value = addr.and_then(|addr| match key(addr) {
+            (Family::V4, bits) => self.v4.find(bits),
+            (Family::V6, bits) => self.v6.find(bits),
+        });
+        match value {
+            Some(i) => self.values[i].clone(),
+            None => self.default.clone(),
+        }
+    }
+}
+
+/// Binary radix tree of network prefixes, looked up by the longest prefix
+/// of an address.
+#[derive(Default)]
+struct RadixTree {
+    nodes: Vec<Node>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Node {
+    /// Indexes of the children for a 0 and a 1 bit, 0 when absent as the
+    /// root is never a child.
+    children: [usize; 2],
+    value: Option<usize>,
+}
+
+impl RadixTree {
+    /// Sets the value of the network of the first `prefix` bits of `bits`.
+    /// The last value of a duplicate network wins.
+    fn insert(&mut self, bits: u128, prefix: u8, value: usize) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::default());
+        }
+        let mut node = 0;
+        for n in 0..prefix {
+            let bit = ((bits >> (127 - n)) & 1) as usize;
+            node = match self.nodes[node].children[bit] {
+                0 => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = child;
+                    child
+                }
+                child => child,
+            };
+        }
+        self.nodes[node].value = Some(value);
+    }
+
+    fn find(&self, bits: u128) -> Option<usize> {
+        let mut node = self.nodes.first()?;
+        let mut result = node.value;
+        for n in 0..128 {
+            let bit = ((bits >> (127 - n)) & 1) as usize;
+            node = match node.children[bit] {
+                0 => break,
+                child => &self.nodes[child],
+            };
+            result = node.value.or(result);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::http::HttpConfig,
+        processor::{
+            body::Body,
+            map::{build, VariableCache},
+            variable, ConnectionInfo, Request,
+        },
+    };
+    use std::{net::SocketAddr, sync::Arc};
+
+    fn cache(config: &str) -> VariableCache {
+        let (_, parsed) = vulpes_parser::parse(config.as_bytes()).unwrap();
+        let http: HttpConfig = vulpes_parser::ParsedValue::Block(parsed)
+            .try_into()
+            .unwrap();
+        VariableCache::new(Arc::new(build(&http)))
+    }
+
+    /// Returns a request evaluating its variables with a new cache of the
+    /// variables of `c`.
+    fn request(c: &VariableCache, host: &str, remote_addr: &str) -> Request {
+        let mut req = http::Request::builder()
+            .uri(format!("/?ip={}", remote_addr))
+            .header("Host", host)
+            .body(Body::default())
+            .unwrap();
+        req.extensions_mut().insert(ConnectionInfo {
+            remote_addr: SocketAddr::new(remote_addr.parse().unwrap(), 5000),
+            local_addr: "127.0.0.1:80".parse().unwrap(),
+            unix: None,
+            tls: None,
+        });
+        req.extensions_mut()
+            .insert(VariableCache::new(c.variables.clone()));
+        req
+    }
+
+    #[test]
+    fn test_map() {
+        let c = cache(
+            r"map $http_host $backend {
+                hostnames;
+                default a;
+                *.foo.com b;
+                www.foo.com b2;
+                ~^api(?<n>\d*)\. c$n;
+                ~^API other;
+                ~*^web d;
+            }
+            map $backend $second {
+                default $backend-x;
+                b y;
+            }",
+        );
+        let get = |host: &str| variable::get("backend", &request(&c, host, "10.0.0.1"));
+        assert_eq!(get("x.foo.com").as_deref(), Some("b"));
+        assert_eq!(get("WWW.foo.com.").as_deref(), Some("b2"));
+        assert_eq!(get("api2.example.com").as_deref(), Some("c2"));
+        assert_eq!(get("API.example.com").as_deref(), Some("other"));
+        assert_eq!(get("WEB.example.com").as_deref(), Some("d"));
+        assert_eq!(get("example.com").as_deref(), Some("a"));
+
+        let mut req = request(&c, "x.foo.com", "10.0.0.1");
+        assert_eq!(variable::get("second", &req).as_deref(), Some("y"));
+        assert_eq!(variable::get("unknown", &req), None);
+
+        // the values are kept for the request
+        req.headers_mut()
+            .insert("host", "example.com".parse().unwrap());
+        assert_eq!(variable::get("backend", &req).as_deref(), Some("b"));
+        let req = request(&c, "example.com", "10.0.0.1");
+        assert_eq!(variable::get("second", &req).as_deref(), Some("a-x"));
+
+        let c = cache("map $cycle $cycle { default x$cycle; }");
+        assert_eq!(
+            variable::get("cycle", &request(&c, "example.com", "10.0.0.1")).as_deref(),
+            Some("x")
+        );
+        let c = cache(
+            "map $b $a { volatile; default x$b; }
+            map $a $b { volatile; default y$a; }",
+        );
+        let req = request(&c, "example.com", "10.0.0.1");
+        assert_eq!(variable::get("a", &req).as_deref(), Some("xy"));
+        assert_eq!(variable::get("b", &req).as_deref(), Some("yx"));
+    }
+
+    #[test]
+    fn test_geo() {
+        let c = cache(
+            "geo $geo {
+                default none;
+                10.0.0.0/8 a;
+                10.1.0.0/16 b;
+                10.1.2.3 c;
+                0.0.0.0/0 any;
+                2001:db8::/32 d;
+            }
+            geo $arg_ip $arg_geo {
+                10.0.0.0/8 a;
+            }",
+        );
+        let get = |name: &str, addr: &str| {
+            variable::get(name, &request(&c, "example.com", addr)).unwrap_or_default()
+        };
+        assert_eq!(get("geo", "10.2.0.1"), "a");
+        assert_eq!(get("geo", "10.1.0.1"), "b");
+        assert_eq!(get("geo", "10.1.2.3"), "c");
+        assert_eq!(get("geo", "192.168.0.1"), "any");
+        assert_eq!(get("geo", "::ffff:10.1.2.3"), "c");
+        assert_eq!(get("geo", "2001:db8::1"), "d");
+        assert_eq!(get("geo", "2001:db9::1"), "none");
+
+        assert_eq!(get("arg_geo", "10.0.0.1"), "a");
+        assert_eq!(get("arg_geo", "11.0.0.1"), "");
+    }
+}
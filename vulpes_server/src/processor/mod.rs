@@ -1,46 +1,222 @@
+pub mod access_log;
+mod body;
+mod fastcgi;
+mod gateway;
+mod grpc;
+mod headers;
+pub mod health;
+mod http1;
+mod http2;
+mod http3;
+pub mod map;
+mod pool;
+mod proxy;
+pub mod proxy_cache;
+mod rewrite;
+mod scgi;
+mod server_name;
+mod socket;
+pub mod tls;
+mod tunnel;
+pub mod upstream;
+mod uwsgi;
+mod variable;
+
 use crate::config::{
+    access_log::AccessLog,
+    headers::HeadersConfig,
+    http2::Http2Config,
+    listen::ListenConfig,
     location::{LocationConfig, LocationExp},
-    server::ServerConfig,
-    types,
+    rewrite::RewriteRule,
+    server::{ServerConfig, ServerName},
+    types::{self, ProxyPass, RedirectConfig},
 };
-use std::collections::HashMap;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
-    net::{TcpListener, TcpStream},
+use access_log::Logger;
+use body::Body;
+use fastcgi::ScriptPath;
+use gateway::Protocol;
+use http::{header, HeaderValue, Method, StatusCode};
+use map::{VariableCache, Variables};
+use proxy_cache::{CacheStatus, Caches};
+use rewrite::{OriginalUri, Outcome};
+use server_name::ServerNames;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
 };
+use tls::TlsInfo;
+use tokio::io::{AsyncRead, AsyncWrite};
+use upstream::Upstreams;
+use variable::Captures;
+
+/// How long an idle client connection is kept open between requests.
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(75);
+
+/// How long a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait before accepting again after an error.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+pub type Request = http::Request<Body>;
+pub type Response = http::Response<Body>;
+
+/// Client connection accepted on a TCP or UNIX-domain socket.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Directory set by `root` for the location of a request, stored in the
+/// request extensions.
+#[derive(Debug, Clone)]
+pub struct DocumentRoot(pub String);
+
+/// Primary name of the server of a request, its first `server_name` when
+/// it isn't a pattern, stored in the request extensions for `$host`.
+#[derive(Debug, Clone)]
+pub struct PrimaryName(pub String);
+
+/// Unique identifier of a request exposed as `$request_id`, 16 random bytes
+/// in hexadecimal, stored in the request extensions.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn generate() -> RequestId {
+        let mut bytes = [0; 16];
+        // the system random generator only fails when it isn't available
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut bytes)
+            .expect("failed to generate a request id");
+        RequestId(bytes.iter().map(|v| format!("{:02x}", v)).collect())
+    }
+}
+
+/// Addresses of the client connection a request was received on, stored in
+/// the request extensions.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub remote_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+    /// Path of the UNIX-domain socket the connection was accepted on, in
+    /// which case the addresses are unspecified.
+    pub unix: Option<String>,
+    /// Set when the connection uses TLS.
+    pub tls: Option<TlsInfo>,
+}
 
 #[derive(Clone)]
 pub struct Server {
-    listen: String,
-    http_servers: HashMap<String, HttpServer>,
+    listen: ListenConfig,
+    http_servers: Vec<HttpServer>,
+    /// Index in `http_servers` of the server of each name.
+    server_names: ServerNames,
+    /// Index of the server handling the requests whose host matches no name.
+    default_server: usize,
+    http2: Http2Config,
+    tls: Option<tls::Acceptor>,
+    /// Servers of the specific addresses of the port of a wildcard address,
+    /// which share its socket.
+    local: Arc<HashMap<SocketAddr, Server>>,
 }
 
 impl Server {
-    pub fn new(listen: String, servers: Vec<ServerConfig>) -> Server {
-        let mut http_servers = HashMap::new();
-        for s in servers {
-            let h = HttpServer::from(s);
-            http_servers.insert(h.server_name.clone().unwrap_or("".to_owned()), h);
+    /// Creates the server of a listen address, which accepts TLS connections
+    /// when `tls` is set. The unmatched hosts go to `servers[default_server]`.
+    pub fn new(
+        listen: ListenConfig,
+        servers: Vec<HttpServer>,
+        default_server: usize,
+        tls: Option<tls::Acceptor>,
+    ) -> Server {
+        let http2 = servers
+            .get(default_server)
+            .map(|h| h.http2.clone())
+            .unwrap_or_default();
+        let mut server_names = ServerNames::default();
+        for (i, h) in servers.iter().enumerate() {
+            for name in &h.server_name {
+                server_names.insert(name, i);
+            }
         }
 
         Server {
-            listen: listen,
-            http_servers: http_servers,
+            listen,
+            http_servers: servers,
+            server_names,
+            default_server,
+            http2,
+            tls,
+            local: Arc::default(),
         }
     }
 
+    /// Hands the connections accepted on `addr` to `server`, when this
+    /// server listens on the wildcard address of the port.
+    pub fn add_local(&mut self, addr: SocketAddr, server: Server) {
+        Arc::make_mut(&mut self.local).insert(addr, server);
+    }
+
     pub async fn run(self, tx: tokio::sync::oneshot::Sender<()>) -> std::io::Result<()> {
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.listen)).await?;
+        let listener = socket::listen(&self.listen)?;
         tx.send(()).unwrap();
 
         loop {
-            let (socket, _) = listener.accept().await.unwrap();
-            tokio::spawn(self.clone().process(socket));
+            let (stream, info) = match self.accept(&listener).await {
+                Ok(v) => v,
+                Err(e) => {
+                    // e.g. out of file descriptors, or aborted by the client
+                    log::error!("accept error: {:?}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            // the scope of an IPv6 address isn't part of the configuration
+            let local_addr = SocketAddr::new(info.local_addr.ip(), info.local_addr.port());
+            let server = self.local.get(&local_addr).unwrap_or(&self);
+            tokio::spawn(server.clone().process(stream, info));
         }
     }
 
-    async fn process(self, stream: TcpStream) {
-        match self.handle_tcp(stream).await {
+    /// Accepts the next connection on `listener`.
+    async fn accept(
+        &self,
+        listener: &socket::Listener,
+    ) -> std::io::Result<(Box<dyn Stream>, ConnectionInfo)> {
+        Ok(match listener {
+            socket::Listener::Tcp(l) => {
+                let (stream, remote_addr) = l.accept().await?;
+                let info = ConnectionInfo {
+                    remote_addr,
+                    local_addr: stream.local_addr()?,
+                    unix: None,
+                    tls: None,
+                };
+                (Box::new(stream) as Box<dyn Stream>, info)
+            }
+            socket::Listener::Unix(l) => {
+                let (stream, _) = l.accept().await?;
+                let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+                let info = ConnectionInfo {
+                    remote_addr: unspecified,
+                    local_addr: unspecified,
+                    unix: Some(self.listen.address.to_string()),
+                    tls: None,
+                };
+                (Box::new(stream) as Box<dyn Stream>, info)
+            }
+        })
+    }
+
+    /// Serves HTTP/3 on the UDP port instead, which requires `tls`.
+    pub async fn run_quic(self, tx: tokio::sync::oneshot::Sender<()>) -> std::io::Result<()> {
+        http3::run(self, tx).await
+    }
+
+    async fn process(self, stream: Box<dyn Stream>, info: ConnectionInfo) {
+        match self.handle_stream(stream, info).await {
             Ok(_) => {}
             Err(e) => {
                 log::error!("handle error: {:?}", e);
@@ -48,97 +224,520 @@ impl Server {
         }
     }
 
-    async fn handle_tcp(&self, stream: TcpStream) -> std::io::Result<()> {
-        let peer_addr = stream.peer_addr().unwrap();
-        let mut w = BufWriter::new(stream);
+    async fn handle_stream(
+        &self,
+        stream: Box<dyn Stream>,
+        mut info: ConnectionInfo,
+    ) -> std::io::Result<()> {
+        let acceptor = match &self.tls {
+            Some(acceptor) => acceptor,
+            None => return self.handle_connection(stream, info).await,
+        };
+        let (stream, tls, h2) =
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(v) => v?,
+                Err(_) => return Ok(()),
+            };
+        info.tls = Some(tls);
 
-        let mut buf = [0u8; 4096];
-        let n = w.read(&mut buf).await?;
+        match h2 && self.http2.enabled {
+            true => http2::serve(self, stream, info, &self.http2).await,
+            false => self.handle_connection(stream, info).await,
+        }
+    }
+
+    async fn handle_connection<S>(&self, stream: S, info: ConnectionInfo) -> std::io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        // h2c is only spoken over cleartext connections, TLS ones negotiate
+        // h2 with ALPN
+        let h2c = info.tls.is_none();
+        let mut conn = http1::Connection::new(stream);
 
-        let mut headers = [httparse::EMPTY_HEADER; 16];
-        let mut req = httparse::Request::new(&mut headers);
-        match req.parse(&buf[0..n]) {
-            Ok(httparse::Status::Complete(_)) => {}
-            _ => return Ok(()),
+        // the server of a connection with prior knowledge is yet unknown
+        if h2c && self.http2.enabled {
+            match tokio::time::timeout(KEEPALIVE_TIMEOUT, conn.starts_with(http2::PREFACE)).await {
+                Ok(Ok(true)) => {
+                    let (io, buffered) = conn.into_parts();
+                    let io = http2::Rewind::new(buffered, io);
+                    return http2::serve(self, io, info, &self.http2).await;
+                }
+                Ok(Ok(false)) => {}
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(()),
+            }
         }
 
-        log::debug!("peer_addr: {:?}, header: {:?}", peer_addr, req);
-        let s = self.get_server(&req).await;
-        s.handle(req, w).await?;
+        loop {
+            // only the requests starting in the early data may be replays
+            let early_data = info
+                .tls
+                .as_ref()
+                .map(|v| conn.position() < v.early_data_size as u64)
+                .unwrap_or(false);
+            let head = match tokio::time::timeout(KEEPALIVE_TIMEOUT, conn.read_request_head()).await
+            {
+                Ok(Ok(Some(head))) => head,
+                Ok(Ok(None)) | Err(_) => return Ok(()),
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    let res = proxy::error_response(StatusCode::BAD_REQUEST);
+                    conn.write_response(res, false, false).await?;
+                    return Err(e);
+                }
+                Ok(Err(e)) => return Err(e),
+            };
+
+            let h2c_upgrade = h2c && http2::is_h2c_upgrade(&head);
+            let mut keep_alive = http1::is_keep_alive(head.version, &head.headers);
+            let head_only = head.method == Method::HEAD;
+            let chunked = head.version == http::Version::HTTP_11;
+
+            let length = match http1::request_body_length(&head.headers) {
+                Ok(v) => v,
+                Err(e) => {
+                    let res = proxy::error_response(StatusCode::BAD_REQUEST);
+                    conn.write_response(res, false, false).await?;
+                    return Err(e);
+                }
+            };
+
+            if head
+                .headers
+                .get(header::EXPECT)
+                .map(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+                .unwrap_or(false)
+            {
+                conn.write_continue().await?;
+            }
+
+            let (tx, body) = match length {
+                http1::BodyLength::Empty => (None, Body::default()),
+                _ => {
+                    let (tx, body) = Body::channel();
+                    (Some(tx), body)
+                }
+            };
 
-        Ok(())
+            let mut req = Request::from_parts(head, body);
+            let mut req_info = info.clone();
+            if let Some(tls) = &mut req_info.tls {
+                tls.early_data = early_data;
+            }
+            req.extensions_mut().insert(req_info);
+
+            log::debug!("peer_addr: {:?}, request: {:?}", info.remote_addr, req);
+            let s = self.get_server(&mut req).await;
+
+            // the servers without HTTP/2 handle `Upgrade: h2c` like any other
+            // upgrade, e.g. passing it to a backend
+            if h2c_upgrade && s.http2.enabled {
+                let (head, body) = req.into_parts();
+                let upgrade = http2::upgrade_settings(&head).zip(http2::upgrade_frame(&head));
+                if let Some((settings, frame)) = upgrade {
+                    let mut res = Response::new(Body::default());
+                    *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+                    let headers = res.headers_mut();
+                    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+                    headers.insert(header::UPGRADE, HeaderValue::from_static("h2c"));
+                    conn.write_response(res, false, true).await?;
+
+                    let (io, buffered) = conn.into_parts();
+                    let io = http2::Rewind::new(buffered, io);
+                    let io = http2::inject_upgrade(io, settings, frame).await?;
+                    return http2::serve(self, io, info, &s.http2).await;
+                }
+                req = Request::from_parts(head, body);
+            }
+
+            // the body is read while the request is handled, so that it can
+            // be streamed to a backend
+            let (mut res, read) = match tx {
+                Some(tx) => tokio::join!(s.handle(req), conn.read_body_into(length, tx)),
+                None => (s.handle(req).await, Ok(())),
+            };
+            read?;
+
+            if let Some(upgraded) = res.extensions_mut().remove::<tunnel::Upgraded>() {
+                conn.write_response(res, false, true).await?;
+                let (io, buffered) = conn.into_parts();
+                return tunnel::run(io, buffered, upgraded).await;
+            }
+
+            // a streamed body needs chunked encoding to keep the connection
+            if res.body().as_bytes().is_none() && !chunked {
+                keep_alive = false;
+            }
+            conn.write_response(res, head_only, keep_alive).await?;
+            if !keep_alive {
+                return Ok(());
+            }
+        }
     }
 
-    async fn get_server<'a, 'b>(&self, req: &httparse::Request<'a, 'b>) -> HttpServer {
-        if let Some(host) = &req
-            .headers
-            .iter()
-            .find(|h| h.name.to_uppercase() == "HOST")
-            .map(|h| String::from_utf8_lossy(h.value))
-        {
-            if let Some(s) = self.http_servers.get(&host.to_string()) {
-                return s.clone();
+    /// Returns the server of the host of a request, and stores the captures
+    /// of its name in the request.
+    async fn get_server(&self, req: &mut Request) -> HttpServer {
+        let (index, captures) = request_host(req)
+            .and_then(|host| self.server_names.find(&host))
+            .unwrap_or((self.default_server, Captures::default()));
+        if !captures.0.is_empty() {
+            req.extensions_mut().insert(captures);
+        }
+        // the client certificate was verified for the server of the SNI name
+        let sni = req
+            .extensions()
+            .get::<ConnectionInfo>()
+            .and_then(|v| v.tls.as_ref())
+            .map(|v| v.server);
+        if let (Some(tls), Some(sni)) = (&self.tls, sni) {
+            if tls.misdirected(sni, index) {
+                req.extensions_mut().insert(tls::Misdirected);
             }
         }
+        if let Some(s) = self.http_servers.get(index) {
+            return s.clone();
+        }
 
-        return HttpServer {
-            server_name: None,
+        HttpServer {
+            server_name: vec![],
             location: HashMap::new(),
             ret: types::Return::default(),
-        };
+            redirect: RedirectConfig::default(),
+            rewrite: vec![],
+            headers: HeadersConfig::default(),
+            upstreams: Arc::new(HashMap::new()),
+            caches: Arc::new(HashMap::new()),
+            variables: Arc::new(HashMap::new()),
+            logger: Arc::default(),
+            access_log: vec![],
+            http2: Http2Config::default(),
+            alt_svc: None,
+        }
     }
 }
 
+/// Result of the rewrite phase of a request.
+enum Route<'a> {
+    /// The location handling the request.
+    Location(&'a LocationConfig),
+    /// A redirect, a `return` or an error, in a location or else in the
+    /// server.
+    Response(Option<&'a LocationConfig>, Response),
+}
+
 #[derive(Clone)]
 pub struct HttpServer {
-    server_name: Option<String>,
+    server_name: Vec<ServerName>,
     location: HashMap<String, LocationConfig>,
     ret: types::Return,
+    redirect: RedirectConfig,
+    rewrite: Vec<RewriteRule>,
+    /// Headers of the server, merged with those of the `http` block.
+    headers: HeadersConfig,
+    upstreams: Arc<Upstreams>,
+    caches: Arc<Caches>,
+    /// Variables of the `map` and `geo` of the `http` block.
+    variables: Arc<Variables>,
+    logger: Arc<Logger>,
+    /// Logs of the requests that match no location.
+    access_log: Vec<AccessLog>,
+    http2: Http2Config,
+    /// `Alt-Svc` advertising the ports the server listens on with `quic`.
+    alt_svc: Option<HeaderValue>,
 }
 
-impl From<ServerConfig> for HttpServer {
-    fn from(s: ServerConfig) -> HttpServer {
+impl HttpServer {
+    pub fn new(
+        s: ServerConfig,
+        upstreams: Arc<Upstreams>,
+        caches: Arc<Caches>,
+        variables: Arc<Variables>,
+        logger: Arc<Logger>,
+    ) -> HttpServer {
+        let alt_svc = s
+            .listen
+            .iter()
+            .filter(|l| l.quic)
+            .filter_map(|l| l.port())
+            .map(|port| format!("h3=\":{}\"; ma=86400", port))
+            .collect::<Vec<_>>()
+            .join(", ");
+
         HttpServer {
-            server_name: s.server_name.first().map(|v| v.into()),
+            server_name: s.server_name,
             location: s.location,
             ret: s.ret,
+            redirect: s.redirect,
+            rewrite: s.rewrite,
+            headers: s.headers,
+            upstreams,
+            caches,
+            variables,
+            logger,
+            access_log: s.access_log.unwrap_or_default(),
+            http2: s.http2,
+            alt_svc: HeaderValue::from_str(&alt_svc)
+                .ok()
+                .filter(|v| !v.is_empty()),
         }
     }
-}
 
-impl HttpServer {
-    pub async fn handle<'a, 'b>(
-        &self,
-        req: httparse::Request<'a, 'b>,
-        mut w: BufWriter<TcpStream>,
-    ) -> std::io::Result<()> {
-        let mut code = self.ret.code;
-        let mut body = &self.ret.text;
-        if let Some(location) = self.get_location(req.path.unwrap()) {
-            code = location.ret.code;
-            body = &location.ret.text;
+    pub async fn handle(&self, mut req: Request) -> Response {
+        req.extensions_mut().insert(RequestId::generate());
+        req.extensions_mut()
+            .insert(VariableCache::new(self.variables.clone()));
+        if let Some(name) = self.primary_name() {
+            req.extensions_mut().insert(PrimaryName(name));
+        }
+
+        // HTTP/3 is advertised to the clients of the TLS ports
+        let alt_svc = match &self.alt_svc {
+            Some(v) if req.version() != http::Version::HTTP_3 && is_tls(&req) => Some(v.clone()),
+            _ => None,
+        };
+
+        let mut res = self.handle_request(req).await;
+        if let Some(v) = alt_svc {
+            if !res.headers().contains_key(header::ALT_SVC) {
+                res.headers_mut().insert(header::ALT_SVC, v);
+            }
+        }
+        res
+    }
+
+    async fn handle_request(&self, mut req: Request) -> Response {
+        let start = Instant::now();
+        if let Some(res) = tls::client_error(&req) {
+            return match self.access_log.is_empty() {
+                true => res,
+                false => {
+                    let logs = self.access_log.clone();
+                    self.logger.log(logs, clone_request(&req), res, start)
+                }
+            };
+        }
+
+        let route = self.route(&mut req);
+        let (config, logs) = match &route {
+            Route::Location(location) | Route::Response(Some(location), _) => (
+                &location.headers,
+                location.access_log.as_deref().unwrap_or_default(),
+            ),
+            Route::Response(None, _) => (&self.headers, self.access_log.as_slice()),
+        };
+        if let Route::Location(location) = &route {
+            headers::apply_input(&location.headers.input, &mut req);
+            set_location_extensions(location, &mut req);
+        }
+        // the variables of the response headers are evaluated once the
+        // request was consumed
+        let vars = match config.add_header.is_empty() && config.output.is_empty() {
+            true => None,
+            false => Some(clone_request(&req)),
+        };
+        // as are the variables of the access log once the response was sent
+        let log = match logs.is_empty() {
+            true => None,
+            false => Some(clone_request(&req)),
+        };
+
+        let mut res = match route {
+            Route::Location(location) => self.handle_location(location, req).await,
+            Route::Response(_, res) => res,
+        };
+        if let Some(mut vars) = vars {
+            if let Some(status) = res.extensions().get::<CacheStatus>() {
+                vars.extensions_mut().insert(*status);
+            }
+            headers::apply_output(config, &vars, &mut res);
+        }
+        match log {
+            Some(mut log) => {
+                if let Some(status) = res.extensions().get::<CacheStatus>() {
+                    log.extensions_mut().insert(*status);
+                }
+                self.logger.log(logs.to_vec(), log, res, start)
+            }
+            None => res,
+        }
+    }
+
+    /// Runs the rewrite phase of the server and of the locations, and returns
+    /// the location of the request or the response ending the phase.
+    fn route(&self, req: &mut Request) -> Route<'_> {
+        // the rewrites of the server only apply once, before any location
+        match rewrite::apply(&self.rewrite, req) {
+            Outcome::Redirect(code, url) => {
+                let res = self.redirect_response(code, url, &self.redirect, req);
+                return Route::Response(None, res);
+            }
+            Outcome::Return(ret) => {
+                return Route::Response(None, self.return_response(ret, &self.redirect, req))
+            }
+            Outcome::Invalid => {
+                let res = proxy::error_response(StatusCode::INTERNAL_SERVER_ERROR);
+                return Route::Response(None, res);
+            }
+            _ => {}
+        }
+
+        let mut searches = 0;
+        loop {
+            let location = match self.get_location(req.uri().path()) {
+                Some(location) => location,
+                None => {
+                    let res = self.return_response(&self.ret, &self.redirect, req);
+                    return Route::Response(None, res);
+                }
+            };
+            match &location.root {
+                Some(root) => {
+                    req.extensions_mut().insert(DocumentRoot(root.clone()));
+                }
+                None => {
+                    req.extensions_mut().remove::<DocumentRoot>();
+                }
+            }
+
+            let redirect = location.redirect.merge(&self.redirect);
+            let res = match rewrite::apply(&location.rewrite, req) {
+                Outcome::Unchanged | Outcome::Break => return Route::Location(location),
+                Outcome::Search => {
+                    searches += 1;
+                    if searches <= rewrite::MAX_INTERNAL_REDIRECTS {
+                        continue;
+                    }
+                    log::error!("rewrite cycle while processing {}", req.uri());
+                    proxy::error_response(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+                Outcome::Redirect(code, url) => self.redirect_response(code, url, &redirect, req),
+                Outcome::Return(ret) => self.return_response(ret, &redirect, req),
+                Outcome::Invalid => proxy::error_response(StatusCode::INTERNAL_SERVER_ERROR),
+            };
+            return Route::Response(Some(location), res);
         }
+    }
 
-        w.write_all(format!("HTTP/1.1 {}\r\n", code).as_bytes())
-            .await?;
+    async fn handle_location(&self, location: &LocationConfig, req: Request) -> Response {
+        if location.health_check_status {
+            return health::status(&self.upstreams);
+        }
 
-        w.write_all(
-            format!(
-                "Content-Length: {}\r\n",
-                body.as_ref().map(|v| v.len()).unwrap_or(0)
-            )
-            .as_bytes(),
-        )
-        .await?;
-        w.write_all(b"\r\n").await?;
+        if let Some(proxy_pass) = &location.proxy_pass {
+            if let Some(upstream) = self.upstreams.get(&proxy_pass.host) {
+                let cache = location
+                    .proxy_cache
+                    .zone
+                    .as_ref()
+                    .and_then(|v| self.caches.get(v));
+                let mut res = match cache {
+                    Some(cache) => {
+                        proxy_cache::pass(cache, upstream, proxy_pass, location, req).await
+                    }
+                    None => proxy::pass(upstream, proxy_pass, location, req).await,
+                };
+                headers::clear(res.headers_mut(), &location.proxy.hide_header);
+                return res;
+            }
+        }
 
-        if let Some(b) = body {
-            w.write_all(b.as_bytes()).await?;
+        if let Some(pass) = &location.grpc.pass {
+            if let Some(upstream) = self.upstreams.get(pass) {
+                return grpc::pass(upstream, location, req).await;
+            }
         }
 
-        w.flush().await?;
+        if let Some((protocol, gateway)) = gateway::target(location) {
+            let pass = gateway.pass.as_deref().unwrap_or_default();
+            if let Some(upstream) = self.upstreams.get(pass) {
+                return gateway::pass(protocol, gateway, upstream, req).await;
+            }
+        }
 
-        Ok(())
+        let redirect = location.redirect.merge(&self.redirect);
+        self.return_response(&location.ret, &redirect, &req)
+    }
+
+    /// Responds with the `return` of the server or of a location, whose text
+    /// is the URL of the `Location` of a redirect.
+    fn return_response(
+        &self,
+        ret: &types::Return,
+        redirect: &RedirectConfig,
+        req: &Request,
+    ) -> Response {
+        let url = match (&ret.text, ret.is_redirect()) {
+            (Some(url), true) => variable::evaluate(url, req),
+            _ => return return_response(ret, req),
+        };
+        self.redirect_response(ret.code, url, redirect, req)
+    }
+
+    /// Redirects to `url`, which is made absolute when it's a path.
+    fn redirect_response(
+        &self,
+        code: StatusCode,
+        url: String,
+        redirect: &RedirectConfig,
+        req: &Request,
+    ) -> Response {
+        let url = match url.starts_with('/') && redirect.absolute.unwrap_or(true) {
+            true => format!("{}{}", self.redirect_origin(redirect, req), url),
+            false => url,
+        };
+
+        let mut res = Response::new(status_page(code).into_bytes().into());
+        *res.status_mut() = code;
+        let headers = res.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        if let Ok(v) = HeaderValue::from_str(&url) {
+            headers.insert(header::LOCATION, v);
+        }
+        res
+    }
+
+    /// Returns the first name of the server, when it isn't a pattern.
+    fn primary_name(&self) -> Option<String> {
+        match self.server_name.first() {
+            Some(ServerName::Exact(v)) if !v.is_empty() => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns the `scheme://host[:port]` prefixed to a relative redirect.
+    fn redirect_origin(&self, redirect: &RedirectConfig, req: &Request) -> String {
+        let info = req.extensions().get::<ConnectionInfo>();
+        let tls = info.map(|v| v.tls.is_some()).unwrap_or(false);
+        let scheme = match tls {
+            true => "https",
+            false => "http",
+        };
+
+        let server_name = self.primary_name();
+        let host = match redirect.server_name.unwrap_or(false) {
+            true => server_name.or_else(|| request_host(req)),
+            false => request_host(req).or(server_name),
+        };
+        let host = match host {
+            Some(v) => v,
+            None => info
+                .filter(|v| v.unix.is_none())
+                .map(|v| match v.local_addr {
+                    SocketAddr::V4(addr) => addr.ip().to_string(),
+                    SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+                })
+                .unwrap_or_default(),
+        };
+
+        let port = info
+            .filter(|v| v.unix.is_none() && redirect.port.unwrap_or(true))
+            .map(|v| v.local_addr.port())
+            .filter(|v| *v != if tls { 443 } else { 80 });
+        match port {
+            Some(port) => format!("{}://{}:{}", scheme, host, port),
+            None => format!("{}://{}", scheme, host),
+        }
     }
 
     fn get_location(&self, path: &str) -> Option<&LocationConfig> {
@@ -149,13 +748,111 @@ impl HttpServer {
             }
         }
 
-        // Empty: prefix path
-        for (p, location) in &self.location {
-            if location.exp == LocationExp::Empty && path.starts_with(p) {
-                return Some(location);
-            }
-        }
+        // Empty: longest matching prefix path
+        self.location
+            .iter()
+            .filter(|(p, location)| location.exp == LocationExp::Empty && path.starts_with(*p))
+            .max_by_key(|(p, _)| p.len())
+            .map(|(_, location)| location)
+    }
+}
+
+fn return_response(ret: &types::Return, req: &Request) -> Response {
+    let mut res = Response::new(
+        ret.text
+            .as_ref()
+            .map(|v| variable::evaluate(v, req).into_bytes())
+            .unwrap_or_default()
+            .into(),
+    );
+    *res.status_mut() = ret.code;
+    res
+}
 
-        return None;
+/// Returns the HTML page sent with the responses of a status without a body
+/// of their own, e.g. redirects.
+fn status_page(code: StatusCode) -> String {
+    let status = format!(
+        "{} {}",
+        code.as_u16(),
+        code.canonical_reason().unwrap_or_default()
+    );
+    format!(
+        "<html>\r\n<head><title>{}</title></head>\r\n<body>\r\n<center><h1>{}</h1></center>\r\n<hr><center>vulpes</center>\r\n</body>\r\n</html>\r\n",
+        status, status
+    )
+}
+
+/// Copies the head of a request along with the extensions variables read,
+/// e.g. to evaluate them after the request itself was consumed.
+fn clone_request(req: &Request) -> Request {
+    let mut clone = Request::new(Body::default());
+    *clone.method_mut() = req.method().clone();
+    *clone.uri_mut() = req.uri().clone();
+    *clone.version_mut() = req.version();
+    *clone.headers_mut() = req.headers().clone();
+    if let Some(info) = req.extensions().get::<ConnectionInfo>() {
+        clone.extensions_mut().insert(info.clone());
+    }
+    if let Some(captures) = req.extensions().get::<Captures>() {
+        clone.extensions_mut().insert(captures.clone());
+    }
+    if let Some(id) = req.extensions().get::<RequestId>() {
+        clone.extensions_mut().insert(id.clone());
+    }
+    if let Some(name) = req.extensions().get::<PrimaryName>() {
+        clone.extensions_mut().insert(name.clone());
+    }
+    if let Some(uri) = req.extensions().get::<OriginalUri>() {
+        clone.extensions_mut().insert(uri.clone());
+    }
+    if let Some(cache) = req.extensions().get::<VariableCache>() {
+        clone.extensions_mut().insert(cache.clone());
+    }
+    if let Some(root) = req.extensions().get::<DocumentRoot>() {
+        clone.extensions_mut().insert(root.clone());
+    }
+    if let Some(path) = req.extensions().get::<ScriptPath>() {
+        clone.extensions_mut().insert(path.clone());
+    }
+    if let Some(proxy_pass) = req.extensions().get::<ProxyPass>() {
+        clone.extensions_mut().insert(proxy_pass.clone());
     }
+    clone
+}
+
+/// Stores the `proxy_pass` and the script path of the location handling a
+/// request in its extensions, for the variables of the upstream and of the
+/// response headers.
+fn set_location_extensions(location: &LocationConfig, req: &mut Request) {
+    if let Some(proxy_pass) = &location.proxy_pass {
+        req.extensions_mut().insert(proxy_pass.clone());
+    }
+    if let Some((Protocol::Fastcgi, _)) = gateway::target(location) {
+        let path = fastcgi::split_path(req.uri().path(), &location.fastcgi);
+        req.extensions_mut().insert(path);
+    }
+}
+
+/// Returns the lowercased host of the `Host` header, or of the URI of an
+/// HTTP/2 request without it, with the port and the trailing dot removed.
+fn request_host(req: &Request) -> Option<String> {
+    let host = match req.headers().get(header::HOST) {
+        Some(v) => v.to_str().ok()?,
+        None => req.uri().host()?,
+    };
+
+    let host = match host.strip_prefix('[') {
+        // an IPv6 literal keeps its brackets as in `server_name [::1]`
+        Some(rest) => &host[..rest.find(']')? + 2],
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+fn is_tls(req: &Request) -> bool {
+    req.extensions()
+        .get::<ConnectionInfo>()
+        .map(|v| v.tls.is_some())
+        .unwrap_or(false)
 }
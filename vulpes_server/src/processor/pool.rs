@@ -0,0 +1,117 @@
+use crate::processor::http1::Connection;
+use bytes::Bytes;
+use h2::client::SendRequest;
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::net::TcpStream;
+
+/// Idle connection to an upstream server that can be reused for another
+/// request.
+pub struct PooledConnection {
+    pub conn: Connection<TcpStream>,
+    /// Number of requests already sent on the connection.
+    pub requests: usize,
+}
+
+struct Idle {
+    address: String,
+    conn: PooledConnection,
+    since: Instant,
+}
+
+/// Per-upstream cache of idle keepalive connections. The most recently used
+/// connections are kept and the least recently used ones are closed when the
+/// pool is full.
+pub struct Pool {
+    max: usize,
+    timeout: Duration,
+    max_requests: usize,
+    idle: Mutex<VecDeque<Idle>>,
+}
+
+impl Pool {
+    pub fn new(max: usize, timeout: Duration, max_requests: usize) -> Pool {
+        Pool {
+            max,
+            timeout,
+            max_requests,
+            idle: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max > 0
+    }
+
+    /// Takes an idle connection to `address`, dropping the ones that expired
+    /// or were closed by the server while sitting in the pool.
+    pub fn get(&self, address: &str) -> Option<PooledConnection> {
+        let mut idle = self.idle.lock().unwrap();
+        let now = Instant::now();
+
+        idle.retain(|v| now.duration_since(v.since) < self.timeout);
+
+        while let Some(i) = idle.iter().position(|v| v.address == address) {
+            let v = idle.remove(i)?;
+            if is_open(&v.conn.conn) {
+                return Some(v.conn);
+            }
+            log::debug!("drop keepalive connection closed by {}", address);
+        }
+
+        None
+    }
+
+    pub fn put(&self, address: &str, conn: PooledConnection) {
+        if !self.is_enabled() || conn.requests >= self.max_requests {
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        idle.push_front(Idle {
+            address: address.to_owned(),
+            conn,
+            since: Instant::now(),
+        });
+        idle.truncate(self.max);
+    }
+}
+
+/// HTTP/2 connections to the peers of an upstream, each shared by all the
+/// requests sent to its peer.
+#[derive(Default)]
+pub struct H2Pool {
+    conns: Mutex<HashMap<String, SendRequest<Bytes>>>,
+}
+
+impl H2Pool {
+    pub fn get(&self, address: &str) -> Option<SendRequest<Bytes>> {
+        self.conns.lock().unwrap().get(address).cloned()
+    }
+
+    pub fn put(&self, address: &str, conn: SendRequest<Bytes>) {
+        self.conns.lock().unwrap().insert(address.to_owned(), conn);
+    }
+
+    pub fn remove(&self, address: &str) {
+        self.conns.lock().unwrap().remove(address);
+    }
+}
+
+/// Checks that the server neither closed the connection nor sent anything
+/// unexpected while it was idle.
+fn is_open(conn: &Connection<TcpStream>) -> bool {
+    if conn.has_buffered() {
+        return false;
+    }
+
+    let mut buf = [0u8; 1];
+    match conn.get_ref().try_read(&mut buf) {
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        Ok(_) => false,
+    }
+}
@@ -0,0 +1,317 @@
+use crate::{
+    config::{
+        location::LocationConfig,
+        proxy::ProxyConfig,
+        types::{ProxyPass, Template},
+    },
+    processor::{
+        body::Body, http1, pool::PooledConnection, tunnel::Upgraded, upstream::Upstream, variable,
+        Request, Response,
+    },
+};
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use std::{io, sync::Arc, time::Instant};
+use tokio::{net::TcpStream, time::timeout};
+
+/// Forwards `req` to a peer of `upstream` and returns its response, or an
+/// error response when no peer could serve it.
+pub async fn pass(
+    upstream: &Arc<Upstream>,
+    proxy_pass: &ProxyPass,
+    location: &LocationConfig,
+    req: Request,
+) -> Response {
+    forward(upstream, proxy_pass, location, req)
+        .await
+        .unwrap_or_else(error_response)
+}
+
+/// Forwards `req` to a peer of `upstream`. When the attempt fails in a way
+/// listed by `proxy_next_upstream`, the request is passed to the next peer
+/// until the tries or the time budget run out. Fails with 504 when the last
+/// peer timed out and 502 when it could not be reached.
+pub async fn forward(
+    upstream: &Arc<Upstream>,
+    proxy_pass: &ProxyPass,
+    location: &LocationConfig,
+    req: Request,
+) -> Result<Response, StatusCode> {
+    // the body is kept in memory so the request can be sent to another peer
+    let (parts, body) = req.into_parts();
+    let body = body.collect().await.map_err(|e| {
+        log::error!("failed to read request body: {:?}", e);
+        StatusCode::BAD_REQUEST
+    })?;
+    let req = build_request(
+        upstream,
+        proxy_pass,
+        location,
+        Request::from_parts(parts, body.into()),
+    );
+    let config = &location.proxy;
+    let next_upstream = &config.next_upstream;
+
+    let start = Instant::now();
+    let mut tried = vec![];
+    let mut last = None;
+
+    loop {
+        let id = match upstream.get_peer(&req, &tried) {
+            Some(id) => id,
+            None => return last.unwrap_or(Err(StatusCode::BAD_GATEWAY)),
+        };
+        tried.push(id);
+        let peer = upstream.peer(id);
+
+        let (res, next, sent) = match send(upstream, &peer.address, &req, config).await {
+            Ok(res) => {
+                let status = res.status();
+                let next = next_upstream.status.contains(&status);
+                // 403 and 404 are valid answers of a healthy server
+                let failed =
+                    next && status != StatusCode::FORBIDDEN && status != StatusCode::NOT_FOUND;
+                upstream.free_peer(id, failed);
+                (Ok(res), next, true)
+            }
+            Err(e) => {
+                log::error!(
+                    "upstream {} ({}) error: {:?}",
+                    upstream.name,
+                    peer.address,
+                    e.source
+                );
+                upstream.free_peer(id, true);
+
+                let (code, next) = match e.source.kind() {
+                    io::ErrorKind::TimedOut => (StatusCode::GATEWAY_TIMEOUT, next_upstream.timeout),
+                    io::ErrorKind::InvalidData => {
+                        (StatusCode::BAD_GATEWAY, next_upstream.invalid_header)
+                    }
+                    _ => (StatusCode::BAD_GATEWAY, next_upstream.error),
+                };
+                (Err(code), next, e.sent)
+            }
+        };
+
+        let retry = next
+            && (!sent || is_idempotent(req.method()) || next_upstream.non_idempotent)
+            && (config.next_upstream_tries == 0 || tried.len() < config.next_upstream_tries)
+            && (config.next_upstream_timeout.is_zero()
+                || start.elapsed() < config.next_upstream_timeout);
+        if !retry {
+            return res;
+        }
+        last = Some(res);
+    }
+}
+
+/// Failed attempt to get a response from a peer.
+#[derive(Debug)]
+struct UpstreamError {
+    source: io::Error,
+    /// Whether the request may have reached the peer.
+    sent: bool,
+}
+
+impl From<io::Error> for UpstreamError {
+    fn from(source: io::Error) -> Self {
+        UpstreamError { source, sent: true }
+    }
+}
+
+fn build_request(
+    upstream: &Upstream,
+    proxy_pass: &ProxyPass,
+    location: &LocationConfig,
+    req: Request,
+) -> Request {
+    let set_header = evaluate_headers(&location.proxy.set_header, &req);
+    let (mut parts, body) = req.into_parts();
+
+    if let Some(uri) = &proxy_pass.uri {
+        let path_and_query = parts
+            .uri
+            .path_and_query()
+            .map(|v| v.as_str())
+            .unwrap_or("/");
+        let rest = path_and_query
+            .strip_prefix(location.path.as_str())
+            .unwrap_or(path_and_query);
+        if let Ok(v) = format!("{}{}", uri, rest).parse() {
+            parts.uri = v;
+        }
+    }
+
+    let upgrade = http1::is_upgrade(&parts.headers);
+    http1::remove_hop_by_hop_headers(&mut parts.headers, upgrade);
+    http1::set_header(&mut parts.headers, header::HOST, &proxy_pass.host);
+    if !upgrade && !upstream.pool.is_enabled() {
+        http1::set_header(&mut parts.headers, header::CONNECTION, "close");
+    }
+    apply_headers(&mut parts.headers, set_header);
+
+    Request::from_parts(parts, body)
+}
+
+/// Evaluates the values of `*_set_header` directives against `req`, before
+/// it is rewritten for the upstream.
+pub fn evaluate_headers(
+    set_header: &[(String, Template)],
+    req: &Request,
+) -> Vec<(HeaderName, String)> {
+    set_header
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            Some((name, variable::evaluate(value, req)))
+        })
+        .collect()
+}
+
+/// Sets headers evaluated by [`evaluate_headers`], an empty value removes
+/// the header.
+pub fn apply_headers(headers: &mut HeaderMap, values: Vec<(HeaderName, String)>) {
+    for (name, value) in values {
+        if value.is_empty() {
+            headers.remove(name);
+        } else if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+async fn send(
+    upstream: &Arc<Upstream>,
+    address: &str,
+    req: &Request,
+    config: &ProxyConfig,
+) -> Result<Response, UpstreamError> {
+    if let Some(conn) = upstream.pool.get(address) {
+        match send_on(upstream, address, conn, req, config).await {
+            Ok(res) => return Ok(res),
+            // the server may close an idle connection at any time, so a
+            // request that is safe to repeat gets another try on a new one
+            Err(e) if is_idempotent(req.method()) && is_closed_error(&e.source) => {
+                log::debug!("keepalive connection to {} failed: {:?}", address, e.source);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let stream = match timeout(config.connect_timeout, TcpStream::connect(address)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(source)) => {
+            return Err(UpstreamError {
+                source,
+                sent: false,
+            })
+        }
+        Err(_) => {
+            return Err(UpstreamError {
+                source: io::ErrorKind::TimedOut.into(),
+                sent: false,
+            })
+        }
+    };
+    let conn = PooledConnection {
+        conn: http1::Connection::new(stream),
+        requests: 0,
+    };
+    send_on(upstream, address, conn, req, config).await
+}
+
+async fn send_on(
+    upstream: &Arc<Upstream>,
+    address: &str,
+    mut conn: PooledConnection,
+    req: &Request,
+    config: &ProxyConfig,
+) -> Result<Response, UpstreamError> {
+    conn.conn
+        .set_timeouts(Some(config.read_timeout), Some(config.send_timeout));
+    conn.conn.write_request(req).await?;
+    conn.requests += 1;
+
+    let mut head = conn.conn.read_response_head().await?;
+    if head.status == StatusCode::SWITCHING_PROTOCOLS {
+        if !http1::is_upgrade(req.headers()) {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "unexpected 101 response").into(),
+            );
+        }
+
+        http1::remove_hop_by_hop_headers(&mut head.headers, true);
+        let (io, buffered) = conn.conn.into_parts();
+        let mut res = Response::from_parts(head, Body::default());
+        res.extensions_mut().insert(Upgraded {
+            io,
+            buffered,
+            idle_timeout: config.read_timeout,
+        });
+        return Ok(res);
+    }
+
+    let length = http1::response_body_length(req.method(), head.status, &head.headers)?;
+    let reusable =
+        length != http1::BodyLength::Close && http1::is_keep_alive(head.version, &head.headers);
+    http1::remove_hop_by_hop_headers(&mut head.headers, false);
+
+    if length == http1::BodyLength::Empty {
+        if reusable {
+            upstream.pool.put(address, conn);
+        }
+        return Ok(Response::from_parts(head, Body::default()));
+    }
+
+    // the body is passed on as it arrives, and the connection goes back to
+    // the pool once it was read to the end
+    let (tx, body) = Body::channel();
+    let (upstream, address) = (upstream.clone(), address.to_owned());
+    tokio::spawn(async move {
+        let mut state = http1::BodyState::new(length);
+        loop {
+            match conn.conn.read_body_chunk(&mut state).await {
+                Ok(Some(chunk)) => {
+                    // the rest of the body is dropped with the connection
+                    if tx.send(Ok(chunk)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("upstream {} ({}) error: {:?}", upstream.name, address, e);
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            }
+        }
+        if reusable {
+            upstream.pool.put(&address, conn);
+        }
+    });
+
+    Ok(Response::from_parts(head, body))
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    )
+}
+
+fn is_closed_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+pub fn error_response(code: StatusCode) -> Response {
+    let mut res = Response::new(Body::default());
+    *res.status_mut() = code;
+    res
+}
@@ -0,0 +1,445 @@
+use crate::{
+    cache::{Cache, Entry},
+    config::{cache::CacheValid, http::HttpConfig, location::LocationConfig, types::ProxyPass},
+    processor::{
+        body::Body, proxy, upstream::Upstream, variable, ConnectionInfo, Request, Response,
+    },
+};
+use http::{header, HeaderMap, Method, StatusCode};
+use std::{
+    collections::HashMap,
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// How often inactive entries are removed from the caches.
+const MANAGER_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Caches of an `http` block by the name of their `keys_zone`.
+pub type Caches = HashMap<String, Arc<Cache>>;
+
+/// Outcome of a cache lookup, exposed as `$upstream_cache_status`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheStatus {
+    Miss,
+    Expired,
+    Stale,
+    Updating,
+    Hit,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Expired => "EXPIRED",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Updating => "UPDATING",
+            CacheStatus::Hit => "HIT",
+        }
+    }
+}
+
+/// Opens the caches declared by `proxy_cache_path` and checks that every
+/// `proxy_cache` refers to one of them.
+pub fn build(http: &HttpConfig) -> io::Result<Caches> {
+    let mut caches = Caches::new();
+    for c in &http.proxy_cache_path {
+        caches.insert(c.zone.clone(), Arc::new(Cache::open(c)?));
+    }
+
+    for location in http.server.iter().flat_map(|s| s.location.values()) {
+        if let Some(zone) = &location.proxy_cache.zone {
+            if !caches.contains_key(zone) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown proxy_cache zone {}", zone),
+                ));
+            }
+        }
+    }
+
+    Ok(caches)
+}
+
+/// Starts the task that removes inactive entries.
+pub fn spawn(caches: &Caches) {
+    for cache in caches.values() {
+        let cache = cache.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MANAGER_INTERVAL);
+            loop {
+                interval.tick().await;
+                cache.expire_inactive().await;
+            }
+        });
+    }
+}
+
+/// Serves `req` from `cache` when possible, and passes it to `upstream`
+/// otherwise, storing the response when it may be cached.
+pub async fn pass(
+    cache: &Arc<Cache>,
+    upstream: &Arc<Upstream>,
+    proxy_pass: &ProxyPass,
+    location: &LocationConfig,
+    mut req: Request,
+) -> Response {
+    let config = &location.proxy_cache;
+    if config.purge.enabled && req.method().as_str() == "PURGE" {
+        let addr = req
+            .extensions()
+            .get::<ConnectionInfo>()
+            .filter(|v| v.unix.is_none())
+            .map(|v| v.remote_addr.ip());
+        if !config.purge.allows(addr) {
+            return proxy::error_response(StatusCode::FORBIDDEN);
+        }
+
+        let key = variable::evaluate(&config.key, &req);
+        let purged = cache.purge(&key).await;
+        log::info!("purge {}: {} entries", key, purged);
+
+        let mut res = Response::new(Body::default());
+        if purged == 0 {
+            *res.status_mut() = StatusCode::NOT_FOUND;
+        }
+        return res;
+    }
+
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return proxy::pass(upstream, proxy_pass, location, req).await;
+    }
+
+    let key = variable::evaluate(&config.key, &req);
+    let stale = match cache.lookup(&key, req.headers()).await {
+        Some(entry) if entry.is_fresh() => return respond(entry, CacheStatus::Hit),
+        entry => entry,
+    };
+    let updating = stale.is_some() && config.use_stale.updating;
+
+    let mut lock = KeyLock::new(cache, &key);
+    if (config.lock || updating) && !lock.try_lock() {
+        if updating {
+            return respond(stale.unwrap(), CacheStatus::Updating);
+        }
+        // as in nginx, the response is not cached after a lock timeout
+        if tokio::time::timeout(config.lock_timeout, lock.lock())
+            .await
+            .is_ok()
+        {
+            // the request holding the lock may have stored the response
+            if let Some(entry) = cache.lookup(&key, req.headers()).await {
+                if entry.is_fresh() {
+                    return respond(entry, CacheStatus::Hit);
+                }
+            }
+        }
+    }
+    let cacheable = lock.is_locked() || !config.lock;
+
+    // the response to a HEAD request is cached for GET requests as well
+    *req.method_mut() = Method::GET;
+    let headers = req.headers().clone();
+
+    // `updating` means the lock was taken above, so this request refreshes
+    // the entry while the stale one is served
+    if updating && config.background_update {
+        let (cache, upstream, proxy_pass, location) = (
+            cache.clone(),
+            upstream.clone(),
+            proxy_pass.clone(),
+            location.clone(),
+        );
+        tokio::spawn(async move {
+            if let Ok(res) = proxy::forward(&upstream, &proxy_pass, &location, req).await {
+                let valid = &location.proxy_cache.valid;
+                let res = store(&cache, &key, &headers, res, valid, lock).await;
+                // nobody reads the body, so it is drained for the entry to be stored
+                let mut body = res.into_body();
+                while let Some(Ok(_)) = body.next().await {}
+            }
+        });
+
+        return respond(stale.unwrap(), CacheStatus::Updating);
+    }
+
+    let status = match stale {
+        Some(_) => CacheStatus::Expired,
+        None => CacheStatus::Miss,
+    };
+    let use_stale = &config.use_stale;
+
+    match (
+        proxy::forward(upstream, proxy_pass, location, req).await,
+        stale,
+    ) {
+        (Ok(res), Some(entry)) if use_stale.status.contains(&res.status()) => {
+            respond(entry, CacheStatus::Stale)
+        }
+        (Err(code), Some(entry))
+            if (code == StatusCode::GATEWAY_TIMEOUT && use_stale.timeout)
+                || (code == StatusCode::BAD_GATEWAY && use_stale.error) =>
+        {
+            respond(entry, CacheStatus::Stale)
+        }
+        (Ok(res), _) if cacheable => with_status(
+            store(cache, &key, &headers, res, &config.valid, lock).await,
+            status,
+        ),
+        (Ok(res), _) => with_status(res, status),
+        (Err(code), _) => with_status(proxy::error_response(code), status),
+    }
+}
+
+/// Lock serializing the requests populating a key of a cache, handed back to
+/// the cache when dropped.
+struct KeyLock {
+    cache: Arc<Cache>,
+    key: String,
+    lock: Arc<Mutex<()>>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl KeyLock {
+    fn new(cache: &Arc<Cache>, key: &str) -> KeyLock {
+        KeyLock {
+            cache: cache.clone(),
+            key: key.to_owned(),
+            lock: cache.lock(key),
+            guard: None,
+        }
+    }
+
+    fn try_lock(&mut self) -> bool {
+        self.guard = self.lock.clone().try_lock_owned().ok();
+        self.guard.is_some()
+    }
+
+    async fn lock(&mut self) {
+        self.guard = Some(self.lock.clone().lock_owned().await);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.guard.is_some()
+    }
+}
+
+impl Drop for KeyLock {
+    fn drop(&mut self) {
+        // the guard owns the lock as well
+        self.guard = None;
+        self.cache.release(&self.key, &self.lock);
+    }
+}
+
+fn respond(entry: Entry, status: CacheStatus) -> Response {
+    with_status(entry.response.map(Body::from), status)
+}
+
+fn with_status(mut res: Response, status: CacheStatus) -> Response {
+    res.extensions_mut().insert(status);
+    res
+}
+
+/// Returns `res`, storing it in the cache as its body is streamed when it
+/// may be cached. `lock` is held until the entry is stored. An error while
+/// reading the body is passed on to the client and discards the entry.
+async fn store(
+    cache: &Arc<Cache>,
+    key: &str,
+    headers: &HeaderMap,
+    res: Response,
+    valid: &[CacheValid],
+    lock: KeyLock,
+) -> Response {
+    let expires = match expires(res.status(), res.headers(), valid) {
+        Some(v) => v,
+        None => return res,
+    };
+    let too_large = res
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+        .map(|v| v > cache.max_entry_size())
+        .unwrap_or(false);
+    if too_large {
+        return res;
+    }
+
+    let (parts, mut body) = res.into_parts();
+    let writer = match cache
+        .writer(key, headers, parts.status, &parts.headers, expires)
+        .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("failed to store cache entry {}: {:?}", key, e);
+            return http::Response::from_parts(parts, body);
+        }
+    };
+
+    let (tx, client_body) = Body::channel();
+    let key = key.to_owned();
+    tokio::spawn(async move {
+        let mut writer = Some(writer);
+        let mut client = true;
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("failed to read response for cache entry {}: {:?}", key, e);
+                    if let Some(writer) = writer.take() {
+                        writer.abort().await;
+                    }
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+
+            if let Some(w) = &mut writer {
+                if let Err(e) = w.write(&chunk).await {
+                    log::error!("failed to store cache entry {}: {:?}", key, e);
+                    writer.take().unwrap().abort().await;
+                }
+            }
+            // the entry is still completed when the client goes away
+            client = client && tx.send(Ok(chunk)).await.is_ok();
+            if !client && writer.is_none() {
+                return;
+            }
+        }
+
+        // the entry is committed before the body ends, so that the next
+        // request of the client finds it
+        if let Some(writer) = writer {
+            if let Err(e) = writer.commit().await {
+                log::error!("failed to store cache entry {}: {:?}", key, e);
+            }
+        }
+        drop(lock);
+        drop(tx);
+    });
+
+    http::Response::from_parts(parts, client_body)
+}
+
+/// Returns until when a response with `status` and `headers` may be served
+/// from the cache, or `None` when it must not be cached. `Cache-Control` and
+/// `Expires` take precedence over `proxy_cache_valid`.
+fn expires(status: StatusCode, headers: &HeaderMap, valid: &[CacheValid]) -> Option<SystemTime> {
+    if status.is_informational() || headers.contains_key(header::SET_COOKIE) {
+        return None;
+    }
+
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_ascii_lowercase())
+            .collect::<Vec<_>>()
+    };
+
+    if values(header::VARY).iter().any(|v| v == "*") {
+        return None;
+    }
+
+    let now = SystemTime::now();
+
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for v in values(header::CACHE_CONTROL) {
+        match v.split_once('=') {
+            Some(("max-age", n)) => max_age = n.trim_matches('"').parse::<u64>().ok(),
+            Some(("s-maxage", n)) => s_maxage = n.trim_matches('"').parse::<u64>().ok(),
+            None if v == "no-store" || v == "no-cache" || v == "private" => return None,
+            _ => {}
+        }
+    }
+    if let Some(age) = s_maxage.or(max_age) {
+        return (age > 0).then(|| now + Duration::from_secs(age));
+    }
+
+    if let Some(v) = headers.get(header::EXPIRES) {
+        let date = |v: &http::HeaderValue| httpdate::parse_http_date(v.to_str().ok()?).ok();
+        let expires = date(v)?;
+        let origin = headers.get(header::DATE).and_then(date).unwrap_or(now);
+        return expires
+            .duration_since(origin)
+            .ok()
+            .filter(|v| !v.is_zero())
+            .map(|v| now + v);
+    }
+
+    valid
+        .iter()
+        .find(|v| v.status.is_empty() || v.status.contains(&status))
+        .filter(|v| !v.time.is_zero())
+        .map(|v| now + v.time)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::cache::CacheValid, processor::proxy_cache::expires};
+    use http::{HeaderMap, StatusCode};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_expires() {
+        let valid = vec![
+            CacheValid {
+                status: vec![StatusCode::OK],
+                time: Duration::from_secs(600),
+            },
+            CacheValid {
+                status: vec![],
+                time: Duration::from_secs(60),
+            },
+        ];
+        let res = |status: u16, headers: &[(&str, &str)]| {
+            let mut res = http::Response::builder().status(status);
+            for (k, v) in headers {
+                res = res.header(*k, *v);
+            }
+            res.body(vec![]).unwrap()
+        };
+        let ttl = |res: http::Response<Vec<u8>>| {
+            expires(res.status(), res.headers(), &valid).map(|v| {
+                let ttl = v.duration_since(SystemTime::now()).unwrap().as_secs();
+                // allow for the time spent between the two now() calls
+                ttl + 1
+            })
+        };
+
+        assert_eq!(ttl(res(200, &[])), Some(600));
+        assert_eq!(ttl(res(404, &[])), Some(60));
+        assert_eq!(
+            ttl(res(200, &[("cache-control", "public, max-age=30")])),
+            Some(30)
+        );
+        assert_eq!(
+            ttl(res(200, &[("cache-control", "max-age=30, s-maxage=5")])),
+            Some(5)
+        );
+        assert_eq!(
+            ttl(res(
+                200,
+                &[
+                    ("date", "Mon, 19 Oct 2026 00:00:00 GMT"),
+                    ("expires", "Mon, 19 Oct 2026 00:02:00 GMT"),
+                ]
+            )),
+            Some(120)
+        );
+        assert_eq!(ttl(res(200, &[("expires", "0")])), None);
+        assert_eq!(ttl(res(200, &[("cache-control", "no-store")])), None);
+        assert_eq!(ttl(res(200, &[("cache-control", "max-age=0")])), None);
+        assert_eq!(ttl(res(200, &[("set-cookie", "a=b")])), None);
+        assert_eq!(ttl(res(200, &[("vary", "*")])), None);
+        assert_eq!(expires(StatusCode::OK, &HeaderMap::new(), &[]), None);
+    }
+}
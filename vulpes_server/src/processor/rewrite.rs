@@ -0,0 +1,337 @@
+use crate::{
+    config::{
+        rewrite::{Condition, FileTest, Rewrite, RewriteFlag, RewriteRule},
+        types::Return,
+    },
+    processor::{
+        variable::{self, Captures},
+        Request,
+    },
+};
+use http::{uri::PathAndQuery, StatusCode, Uri};
+
+/// Maximum number of times the location of a request is searched again
+/// after a `rewrite`, as in nginx.
+pub const MAX_INTERNAL_REDIRECTS: usize = 10;
+
+/// URI of a request before it was rewritten, exposed as `$request_uri` and
+/// stored in the request extensions.
+#[derive(Debug, Clone)]
+pub struct OriginalUri(pub Uri);
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome<'a> {
+    /// No rewrite matched.
+    Unchanged,
+    /// The URI changed and its location is to be searched.
+    Search,
+    /// The URI changed and is handled by the current location.
+    Break,
+    Redirect(StatusCode, String),
+    /// A `return` in an `if` block.
+    Return(&'a Return),
+    /// The replacement isn't a valid URI.
+    Invalid,
+}
+
+/// Applies the rewrite phase of a block to the URI of `req`.
+pub fn apply<'a>(rules: &'a [RewriteRule], req: &mut Request) -> Outcome<'a> {
+    let mut changed = false;
+    match run(rules, req, &mut changed) {
+        Some(outcome) => outcome,
+        None if changed => Outcome::Search,
+        None => Outcome::Unchanged,
+    }
+}
+
+/// Runs `rules` until one of them ends the phase with its outcome.
+fn run<'a>(rules: &'a [RewriteRule], req: &mut Request, changed: &mut bool) -> Option<Outcome<'a>> {
+    for rule in rules {
+        match rule {
+            RewriteRule::Rewrite(rewrite) => {
+                if let Some(outcome) = rewrite_uri(rewrite, req, changed) {
+                    return Some(outcome);
+                }
+            }
+            RewriteRule::If(block) => {
+                if evaluate(&block.condition, req) {
+                    if let Some(outcome) = run(&block.rules, req, changed) {
+                        return Some(outcome);
+                    }
+                }
+            }
+            RewriteRule::Return(ret) => return Some(Outcome::Return(ret)),
+            RewriteRule::Break => {
+                return Some(match changed {
+                    true => Outcome::Break,
+                    false => Outcome::Unchanged,
+                })
+            }
+        }
+    }
+    None
+}
+
+/// Returns whether the condition of an `if` holds for `req`.
+fn evaluate(condition: &Condition, req: &mut Request) -> bool {
+    match condition {
+        Condition::Variable(value) => {
+            let value = variable::evaluate(value, req);
+            !value.is_empty() && value != "0"
+        }
+        Condition::Equal {
+            value,
+            other,
+            negate,
+        } => (variable::evaluate(value, req) == variable::evaluate(other, req)) != *negate,
+        Condition::Match {
+            value,
+            pattern,
+            negate,
+        } => {
+            let captures = Captures::new(pattern, &variable::evaluate(value, req));
+            let matched = captures.is_some();
+            if let Some(captures) = captures {
+                set_captures(req, captures);
+            }
+            matched != *negate
+        }
+        Condition::File { test, path, negate } => {
+            let path = variable::evaluate(path, req);
+            let result = match test {
+                FileTest::Exists => std::fs::symlink_metadata(&path).is_ok(),
+                _ => match std::fs::metadata(&path) {
+                    Ok(meta) => match test {
+                        FileTest::File => meta.is_file(),
+                        FileTest::Directory => meta.is_dir(),
+                        _ => {
+                            std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o111
+                                != 0
+                        }
+                    },
+                    Err(_) => false,
+                },
+            };
+            result != *negate
+        }
+    }
+}
+
+fn set_captures(req: &mut Request, captures: Captures) {
+    match req.extensions_mut().get_mut::<Captures>() {
+        Some(v) => v.update(captures),
+        None => {
+            req.extensions_mut().insert(captures);
+        }
+    }
+}
+
+/// Applies a `rewrite` to the URI of `req`, and returns the outcome when it
+/// ends the phase. The arguments of the request are appended to those of
+/// the replacement, unless it ends with `?`.
+fn rewrite_uri<'a>(
+    rewrite: &Rewrite,
+    req: &mut Request,
+    changed: &mut bool,
+) -> Option<Outcome<'a>> {
+    let captures = Captures::new(&rewrite.pattern, req.uri().path())?;
+    set_captures(req, captures);
+
+    let replacement = variable::evaluate(&rewrite.replacement, req);
+    let (path, args) = match replacement.strip_suffix('?') {
+        Some(v) => (v, None),
+        None => (replacement.as_str(), req.uri().query()),
+    };
+    let url = match (path.contains('?'), args) {
+        (true, Some(args)) => format!("{}&{}", path, args),
+        (false, Some(args)) => format!("{}?{}", path, args),
+        (_, None) => path.to_owned(),
+    };
+
+    // a replacement with a scheme always redirects
+    let absolute = url.starts_with("http://") || url.starts_with("https://");
+    match rewrite.flag {
+        RewriteFlag::Permanent => {
+            return Some(Outcome::Redirect(StatusCode::MOVED_PERMANENTLY, url))
+        }
+        RewriteFlag::Redirect => return Some(Outcome::Redirect(StatusCode::FOUND, url)),
+        _ if absolute => return Some(Outcome::Redirect(StatusCode::FOUND, url)),
+        _ => {}
+    }
+
+    if !set_uri(req, &url) {
+        return Some(Outcome::Invalid);
+    }
+    *changed = true;
+    match rewrite.flag {
+        RewriteFlag::Last => Some(Outcome::Search),
+        RewriteFlag::Break => Some(Outcome::Break),
+        _ => None,
+    }
+}
+
+/// Replaces the path and query of the URI of `req`, keeping the original URI
+/// for `$request_uri`.
+fn set_uri(req: &mut Request, url: &str) -> bool {
+    let path_and_query = match url.parse::<PathAndQuery>() {
+        Ok(v) if url.starts_with('/') => v,
+        _ => return false,
+    };
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query);
+    let uri = match Uri::from_parts(parts) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+
+    if req.extensions().get::<OriginalUri>().is_none() {
+        let original = OriginalUri(req.uri().clone());
+        req.extensions_mut().insert(original);
+    }
+    *req.uri_mut() = uri;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{
+            rewrite::{IfBlock, RewriteRule},
+            types::Return,
+        },
+        processor::{
+            body::Body,
+            rewrite::{apply, OriginalUri, Outcome},
+            variable, Request,
+        },
+    };
+    use http::StatusCode;
+    use vulpes_parser::ParsedValue;
+
+    fn rewrites(values: &[&str]) -> Vec<RewriteRule> {
+        values
+            .iter()
+            .map(|v| {
+                RewriteRule::Rewrite(
+                    ParsedValue::Value(
+                        v.split(' ')
+                            .map(|v| ParsedValue::String(v.to_owned()))
+                            .collect(),
+                    )
+                    .try_into()
+                    .unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn request(uri: &str) -> Request {
+        http::Request::builder()
+            .uri(uri)
+            .body(Body::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_apply() {
+        let r = rewrites(&[
+            r"^/old/(?<name>.*)$ /new/$name?x=$1",
+            "^/new/(.*)$ /b/$1 break",
+        ]);
+        let mut req = request("/old/a?y=1");
+        assert_eq!(apply(&r, &mut req), Outcome::Break);
+        assert_eq!(req.uri(), "/b/a?x=a&y=1");
+        assert_eq!(
+            req.extensions().get::<OriginalUri>().unwrap().0,
+            "/old/a?y=1"
+        );
+        assert_eq!(variable::evaluate(&"$1 $name".into(), &req), "a a");
+
+        // a trailing `?` drops the arguments
+        let r = rewrites(&["^/a /b? last", "^/b /c"]);
+        let mut req = request("/a?y=1");
+        assert_eq!(apply(&r, &mut req), Outcome::Search);
+        assert_eq!(req.uri(), "/b");
+
+        let r = rewrites(&["^/x /y", "^/a /b"]);
+        let mut req = request("/c");
+        assert_eq!(apply(&r, &mut req), Outcome::Unchanged);
+        let mut req = request("/a");
+        assert_eq!(apply(&r, &mut req), Outcome::Search);
+
+        let r = rewrites(&["^/(.*) /new/$1 permanent", "^ https://example.com/"]);
+        let mut req = request("/a?y=1");
+        assert_eq!(
+            apply(&r, &mut req),
+            Outcome::Redirect(StatusCode::MOVED_PERMANENTLY, "/new/a?y=1".to_owned())
+        );
+        assert_eq!(
+            apply(&r[1..], &mut req),
+            Outcome::Redirect(StatusCode::FOUND, "https://example.com/?y=1".to_owned())
+        );
+
+        let r = rewrites(&["^ relative"]);
+        assert_eq!(apply(&r, &mut request("/a")), Outcome::Invalid);
+    }
+
+    fn if_block(condition: &str, rules: Vec<RewriteRule>) -> RewriteRule {
+        RewriteRule::If(IfBlock {
+            condition: condition
+                .split(' ')
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap(),
+            rules,
+        })
+    }
+
+    #[test]
+    fn test_apply_if() {
+        let forbidden: Return = ParsedValue::Value(vec![ParsedValue::String("403".to_owned())])
+            .try_into()
+            .unwrap();
+        let r = vec![
+            if_block(
+                r"($http_user_agent ~* ^(?<bot>\w+)bot)",
+                vec![RewriteRule::Return(forbidden.clone())],
+            ),
+            if_block("($arg_a = 1)", rewrites(&["^ /one"])),
+            if_block("($arg_b)", vec![RewriteRule::Break]),
+            rewrites(&["^ /other"]).remove(0),
+        ];
+
+        let mut req = request("/");
+        req.headers_mut()
+            .insert("user-agent", "GoogleBot/2.1".parse().unwrap());
+        assert_eq!(apply(&r, &mut req), Outcome::Return(&forbidden));
+        assert_eq!(variable::evaluate(&"$bot".into(), &req), "Google");
+
+        let mut req = request("/?a=1&b=0");
+        assert_eq!(apply(&r, &mut req), Outcome::Search);
+        assert_eq!(req.uri().path(), "/other");
+
+        let mut req = request("/?a=1&b=1");
+        assert_eq!(apply(&r, &mut req), Outcome::Break);
+        assert_eq!(req.uri().path(), "/one");
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let exists = |condition: &str, path: &std::path::Path| {
+            let r = vec![if_block(condition, vec![RewriteRule::Break])];
+            let mut req = request("/");
+            req.headers_mut()
+                .insert("x-path", path.to_str().unwrap().parse().unwrap());
+            apply(&r, &mut req) == Outcome::Unchanged
+                && apply(&[if_block(condition, rewrites(&["^ /a"]))], &mut req) == Outcome::Search
+        };
+        assert!(exists("(-f $http_x_path)", &file));
+        assert!(!exists("(-f $http_x_path)", dir.path()));
+        assert!(exists("(-d $http_x_path)", dir.path()));
+        assert!(exists("(-e $http_x_path)", &file));
+        assert!(exists("(!-e $http_x_path)", &dir.path().join("none")));
+        assert!(!exists("(-x $http_x_path)", &file));
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{
+    config::gateway::GatewayConfig,
+    processor::{
+        gateway::{self, Stream},
+        Request, Response,
+    },
+};
+use std::io;
+
+/// Sends `req` as an SCGI request and reads the CGI response, which lasts
+/// until the server closes the connection.
+pub async fn send(
+    mut stream: Box<dyn Stream>,
+    config: &GatewayConfig,
+    req: Request,
+) -> io::Result<Response> {
+    let (params, body) = gateway::read_request(config, req).await?;
+
+    let mut buf = encode_headers(&params);
+    buf.extend(body);
+    gateway::write(&mut stream, &buf, config.send_timeout).await?;
+
+    gateway::read_response(stream, config, gateway::parse_cgi_head).await
+}
+
+/// Encodes the headers as a netstring of NUL terminated names and values.
+/// `CONTENT_LENGTH` must be the first header, followed by `SCGI`.
+fn encode_headers(params: &[(String, String)]) -> Vec<u8> {
+    let mut headers = vec![];
+    let mut push = |name: &str, value: &str| {
+        headers.extend(name.as_bytes());
+        headers.push(0);
+        headers.extend(value.as_bytes());
+        headers.push(0);
+    };
+
+    for (i, (name, value)) in params.iter().enumerate() {
+        push(name, value);
+        if i == 0 {
+            push("SCGI", "1");
+        }
+    }
+
+    let mut buf = format!("{}:", headers.len()).into_bytes();
+    buf.extend(headers);
+    buf.push(b',');
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processor::scgi::encode_headers;
+
+    #[test]
+    fn test_encode_headers() {
+        assert_eq!(
+            encode_headers(&[
+                ("CONTENT_LENGTH".to_owned(), "27".to_owned()),
+                ("REQUEST_METHOD".to_owned(), "POST".to_owned()),
+            ]),
+            b"45:CONTENT_LENGTH\x0027\x00SCGI\x001\x00REQUEST_METHOD\x00POST\x00,"
+        );
+    }
+}
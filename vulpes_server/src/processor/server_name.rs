@@ -0,0 +1,112 @@
+use crate::{
+    config::{server::ServerName, types::Pattern},
+    processor::variable::Captures,
+};
+use std::collections::HashMap;
+
+/// Lookup tables of the `server_name` of several servers, matched in the
+/// order of nginx: the exact name, the longest name with a leading wildcard,
+/// the longest name with a trailing wildcard and then the first matching
+/// regular expression.
+#[derive(Debug, Clone, Default)]
+pub struct ServerNames {
+    exact: HashMap<String, usize>,
+    /// Keyed by `.example.com` for the subdomains of `example.com`, and by
+    /// `example.com` for the domain itself when named `.example.com`.
+    leading: HashMap<String, usize>,
+    /// Keyed by `www.example.`.
+    trailing: HashMap<String, usize>,
+    regex: Vec<(Pattern, usize)>,
+}
+
+impl ServerNames {
+    /// Adds a name of the server `index`. The first server of a duplicate
+    /// name wins.
+    pub fn insert(&mut self, name: &ServerName, index: usize) {
+        match name {
+            ServerName::Exact(v) => {
+                self.exact.entry(v.clone()).or_insert(index);
+            }
+            ServerName::Subdomains(v) => {
+                self.leading.entry(format!(".{}", v)).or_insert(index);
+            }
+            ServerName::Domain(v) => {
+                self.leading.entry(format!(".{}", v)).or_insert(index);
+                self.leading.entry(v.clone()).or_insert(index);
+            }
+            ServerName::Prefix(v) => {
+                self.trailing.entry(v.clone()).or_insert(index);
+            }
+            ServerName::Regex(v) => self.regex.push((v.clone(), index)),
+        }
+    }
+
+    /// Returns the server of a lowercased host, along with the captures when
+    /// it matched a regular expression.
+    pub fn find(&self, host: &str) -> Option<(usize, Captures)> {
+        if let Some(i) = self.exact.get(host) {
+            return Some((*i, Captures::default()));
+        }
+
+        // the suffixes and prefixes are tried from the longest
+        let suffixes =
+            std::iter::once(host).chain(host.match_indices('.').map(|(i, _)| &host[i..]));
+        if let Some(i) = suffixes.filter_map(|v| self.leading.get(v)).next() {
+            return Some((*i, Captures::default()));
+        }
+
+        let prefixes = host.rmatch_indices('.').map(|(i, _)| &host[..=i]);
+        if let Some(i) = prefixes.filter_map(|v| self.trailing.get(v)).next() {
+            return Some((*i, Captures::default()));
+        }
+
+        self.regex
+            .iter()
+            .find_map(|(pattern, i)| Some((*i, Captures::new(pattern, host)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::server::ServerName, processor::server_name::ServerNames};
+
+    #[test]
+    fn test_find() {
+        let mut names = ServerNames::default();
+        for (i, name) in [
+            "*.example.com",
+            "www.example.com",
+            ".a.example.com",
+            "www.example.*",
+            "www.*",
+            r"~^(?<sub>[a-z]+)\.example\.(org|net)$",
+            "~.",
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            names.insert(&ServerName::try_from(name.to_owned()).unwrap(), i);
+        }
+
+        let find = |host| names.find(host).map(|(i, _)| i);
+        assert_eq!(find("www.example.com"), Some(1));
+        assert_eq!(find("mail.example.com"), Some(0));
+        assert_eq!(find("a.example.com"), Some(2));
+        assert_eq!(find("b.a.example.com"), Some(2));
+        assert_eq!(find("example.com"), Some(6));
+        assert_eq!(find("www.example.org"), Some(3));
+        assert_eq!(find("www.example2.org"), Some(4));
+        assert_eq!(find(""), None);
+
+        let (i, captures) = names.find("mail.example.net").unwrap();
+        assert_eq!(i, 5);
+        assert_eq!(
+            captures.0,
+            vec![
+                ("1".to_owned(), "mail".to_owned()),
+                ("sub".to_owned(), "mail".to_owned()),
+                ("2".to_owned(), "net".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use crate::config::listen::{ListenAddress, ListenConfig};
+use socket2::{Domain, Protocol, SockAddr, Socket, TcpKeepalive, Type};
+use std::{io, os::unix::fs::FileTypeExt};
+use tokio::net::{TcpListener, UnixListener};
+
+/// Listening socket of a `listen` address.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Binds the TCP or UNIX-domain socket of `listen`, with its parameters
+/// applied before `listen(2)`.
+pub fn listen(listen: &ListenConfig) -> io::Result<Listener> {
+    let socket = match &listen.address {
+        ListenAddress::Inet(addr) => {
+            let socket = Socket::new(
+                Domain::for_address(*addr),
+                Type::STREAM,
+                Some(Protocol::TCP),
+            )?;
+            // as std and tokio do, so that a restarted server can bind again
+            socket.set_reuse_address(true)?;
+            if addr.is_ipv6() {
+                socket.set_only_v6(listen.ipv6only)?;
+            }
+            set_keepalive(&socket, listen)?;
+            configure(&socket, listen)?;
+            socket.bind(&(*addr).into())?;
+            socket
+        }
+        ListenAddress::Unix(path) => {
+            // a socket file left by a previous run would make bind fail
+            if let Ok(meta) = std::fs::symlink_metadata(path) {
+                if meta.file_type().is_socket() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+            let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+            configure(&socket, listen)?;
+            socket.bind(&SockAddr::unix(path)?)?;
+            socket
+        }
+    };
+    socket.listen(listen.backlog)?;
+
+    match listen.address {
+        ListenAddress::Inet(_) => Ok(Listener::Tcp(TcpListener::from_std(socket.into())?)),
+        ListenAddress::Unix(_) => Ok(Listener::Unix(UnixListener::from_std(socket.into())?)),
+    }
+}
+
+/// Binds the UDP socket of a `listen ... quic` address.
+pub fn bind_udp(listen: &ListenConfig) -> io::Result<std::net::UdpSocket> {
+    let addr = match &listen.address {
+        ListenAddress::Inet(addr) => *addr,
+        ListenAddress::Unix(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "quic requires an inet address",
+            ))
+        }
+    };
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(listen.ipv6only)?;
+    }
+    configure(&socket, listen)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+fn configure(socket: &Socket, listen: &ListenConfig) -> io::Result<()> {
+    if listen.reuseport {
+        socket.set_reuse_port(true)?;
+    }
+    socket.set_nonblocking(true)
+}
+
+/// Accepted connections inherit the keepalive settings of the listening
+/// socket.
+fn set_keepalive(socket: &Socket, listen: &ListenConfig) -> io::Result<()> {
+    let k = match &listen.so_keepalive {
+        Some(k) => k,
+        None => return Ok(()),
+    };
+
+    socket.set_keepalive(k.enabled)?;
+    if !k.enabled {
+        return Ok(());
+    }
+
+    let mut keepalive = TcpKeepalive::new();
+    if let Some(v) = k.idle {
+        keepalive = keepalive.with_time(v);
+    }
+    if let Some(v) = k.interval {
+        keepalive = keepalive.with_interval(v);
+    }
+    if let Some(v) = k.count {
+        keepalive = keepalive.with_retries(v);
+    }
+    socket.set_tcp_keepalive(&keepalive)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::listen::{ListenAddress, ListenConfig, SoKeepalive},
+        processor::socket::{listen, Listener},
+    };
+    use socket2::SockRef;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_listen_options() {
+        let config = ListenConfig {
+            address: ListenAddress::Inet("[::]:0".parse().unwrap()),
+            reuseport: true,
+            ipv6only: false,
+            so_keepalive: Some(SoKeepalive {
+                enabled: true,
+                idle: Some(Duration::from_secs(600)),
+                interval: None,
+                count: Some(4),
+            }),
+            ..Default::default()
+        };
+        let listener = match listen(&config) {
+            Ok(Listener::Tcp(v)) => v,
+            Ok(Listener::Unix(_)) => unreachable!(),
+            // the sandbox may lack IPv6
+            Err(_) => return,
+        };
+
+        let socket = SockRef::from(&listener);
+        assert!(socket.reuse_port().unwrap());
+        assert!(!socket.only_v6().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(600));
+        assert_eq!(socket.keepalive_retries().unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_listen_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vulpes.sock");
+        let config = ListenConfig {
+            address: ListenAddress::Unix(path.clone()),
+            ..Default::default()
+        };
+
+        // the socket file of the first listener is replaced
+        drop(listen(&config).unwrap());
+        let listener = match listen(&config).unwrap() {
+            Listener::Unix(v) => v,
+            Listener::Tcp(_) => unreachable!(),
+        };
+
+        let client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        assert_eq!(
+            server.local_addr().unwrap().as_pathname(),
+            client.peer_addr().unwrap().as_pathname()
+        );
+    }
+}
@@ -0,0 +1,1130 @@
+use crate::{
+    config::{
+        server::ServerConfig,
+        ssl::{SessionCache, SslConfig, VerifyClient},
+    },
+    processor::{
+        http1, http2::Rewind, server_name::ServerNames, ConnectionInfo, Request, Response,
+    },
+};
+use http::{header, StatusCode};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use rustls::{
+    server::{
+        AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier, ClientHello,
+        NoServerSessionStorage, ProducesTickets, ResolvesServerCert, StoresServerSessions,
+    },
+    sign::{self, CertifiedKey},
+    Certificate, DistinguishedName, PrivateKey, RootCertStore, SupportedCipherSuite,
+    SupportedProtocolVersion,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    io::{BufReader, Read},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{server::TlsStream, LazyConfigAcceptor};
+use x509_parser::{
+    objects::{oid2abbrev, oid_registry},
+    x509::X509Name,
+};
+
+/// TLS parameters of a client connection, stored in its
+/// [`ConnectionInfo`](crate::processor::ConnectionInfo).
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    /// Protocol version, e.g. `TLSv1.3`.
+    pub protocol: String,
+    /// IANA name of the cipher suite.
+    pub cipher: String,
+    /// Host name requested with SNI.
+    pub server_name: Option<String>,
+    pub client_cert: Option<ClientCert>,
+    /// Result of the client certificate verification, `SUCCESS`, `NONE` or
+    /// `FAILED:<reason>`.
+    pub client_verify: String,
+    /// Set when the requests of the connection are rejected because of the
+    /// client certificate.
+    pub client_error: Option<&'static str>,
+    /// The request was sent as TLS 1.3 early data, which may be replayed.
+    /// Over HTTP/2 the streams aren't told apart, and every request of a
+    /// connection that sent early data is marked.
+    pub early_data: bool,
+    /// Number of bytes the client sent as early data.
+    pub early_data_size: usize,
+    /// Index of the server selected by SNI, whose settings verified the
+    /// client certificate.
+    pub server: usize,
+}
+
+/// Fields of the certificate a client sent.
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    /// Subject DN in the RFC 2253 format.
+    pub subject: String,
+    /// Issuer DN in the RFC 2253 format.
+    pub issuer: String,
+    /// Serial number in hexadecimal.
+    pub serial: String,
+    /// SHA-1 of the certificate in hexadecimal.
+    pub fingerprint: String,
+}
+
+/// Accepts the TLS connections of a port.
+#[derive(Clone)]
+pub struct Acceptor {
+    /// Configuration of the connections of each server, which differ in
+    /// the request of a client certificate.
+    configs: Vec<Arc<rustls::ServerConfig>>,
+    /// Index of the server of each name, which is selected by SNI.
+    names: Arc<ServerNames>,
+    default_server: usize,
+    /// Configuration of QUIC connections, only set with TLS 1.3.
+    quic: Option<Arc<rustls::ServerConfig>>,
+    /// Verification of the client certificates of each server.
+    client_auth: Vec<Option<Arc<ClientAuth>>>,
+    certs: Vec<Arc<CertSource>>,
+    ticketer: Option<Arc<Ticketer>>,
+}
+
+/// Marks a request whose host is that of another server than the one
+/// selected by SNI, when the servers verify client certificates differently.
+pub struct Misdirected;
+
+/// Session caches of `ssl_session_cache` by name, shared by the ports.
+pub type SessionCaches = HashMap<String, Arc<SessionStore>>;
+
+/// Builds the TLS configuration of a port from the servers listening on it.
+/// The certificate is selected by SNI among the servers that have one, and
+/// that of `servers[default_server]` is used when no name matches, or the
+/// first one when it has none. Protocols and cipher suites, as well as
+/// session resumption, are those of the default server. The client
+/// certificate is requested and verified by the settings of the server
+/// selected by SNI.
+pub fn acceptor(
+    servers: &[ServerConfig],
+    default_server: usize,
+    http2: bool,
+    caches: &mut SessionCaches,
+) -> io::Result<Acceptor> {
+    let mut resolver = CertResolver {
+        names: ServerNames::default(),
+        certs: vec![],
+        default: 0,
+    };
+    for (i, s) in servers.iter().enumerate() {
+        if s.ssl.certificate.is_none() {
+            continue;
+        }
+        if i == default_server {
+            resolver.default = resolver.certs.len();
+        }
+        for name in &s.server_name {
+            resolver.names.insert(name, resolver.certs.len());
+        }
+        resolver.certs.push(Arc::new(CertSource::new(&s.ssl)?));
+    }
+    if resolver.certs.is_empty() {
+        return Err(invalid_input("no ssl_certificate for a port with ssl"));
+    }
+    let certs = resolver.certs.clone();
+
+    let ssl = &servers[default_server].ssl;
+    let versions = protocol_versions(&ssl.protocols);
+    let builder = rustls::ServerConfig::builder()
+        .with_cipher_suites(&cipher_suites(&ssl.ciphers, &versions)?)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&versions)
+        .map_err(invalid_input)?;
+
+    let resolver = Arc::new(resolver);
+    let mut config = builder
+        .clone()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+
+    config.alpn_protocols = match http2 {
+        true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        false => vec![b"http/1.1".to_vec()],
+    };
+
+    config.session_storage = match &ssl.session_cache {
+        SessionCache::Shared { name, size } => {
+            let store = caches
+                .entry(name.clone())
+                .or_insert_with(|| Arc::new(SessionStore::new(*size)));
+            Arc::new(SessionCacheHandle {
+                store: store.clone(),
+                timeout: ssl.session_timeout,
+            })
+        }
+        SessionCache::Off | SessionCache::None => Arc::new(NoServerSessionStorage {}),
+    };
+    let ticketer = match ssl.session_tickets {
+        true => Some(Arc::new(Ticketer::new(ssl)?)),
+        false => None,
+    };
+    if let Some(ticketer) = &ticketer {
+        config.ticketer = ticketer.clone();
+    }
+
+    if ssl.early_data {
+        // rustls only accepts early data for sessions resumed from the
+        // cache, where each session can be used once
+        if ticketer.is_some() || !matches!(ssl.session_cache, SessionCache::Shared { .. }) {
+            return Err(invalid_input(
+                "ssl_early_data requires ssl_session_tickets off and a shared ssl_session_cache",
+            ));
+        }
+        config.max_early_data_size = MAX_EARLY_DATA_SIZE;
+    }
+
+    // the servers share the settings of the default one, except for the
+    // verifier requesting the client certificate
+    let mut names = ServerNames::default();
+    let mut client_auth = vec![];
+    let mut configs = vec![];
+    let config = Arc::new(config);
+    for (i, s) in servers.iter().enumerate() {
+        for name in &s.server_name {
+            names.insert(name, i);
+        }
+        let auth = match s.ssl.verify_client {
+            VerifyClient::Off => None,
+            _ => Some(Arc::new(ClientAuth::new(&s.ssl)?)),
+        };
+        configs.push(match &auth {
+            Some(auth) => {
+                let mut c = builder
+                    .clone()
+                    .with_client_cert_verifier(auth.clone())
+                    .with_cert_resolver(resolver.clone());
+                c.alpn_protocols = config.alpn_protocols.clone();
+                c.session_storage = config.session_storage.clone();
+                c.ticketer = config.ticketer.clone();
+                c.max_early_data_size = config.max_early_data_size;
+                Arc::new(c)
+            }
+            None => config.clone(),
+        });
+        client_auth.push(auth);
+    }
+
+    // QUIC only negotiates h3, and rustls only allows early data of
+    // unlimited size on it, which isn't accepted
+    let quic = versions.contains(&&rustls::version::TLS13).then(|| {
+        let mut quic = configs[default_server].as_ref().clone();
+        quic.alpn_protocols = vec![b"h3".to_vec()];
+        quic.max_early_data_size = 0;
+        Arc::new(quic)
+    });
+
+    Ok(Acceptor {
+        configs,
+        names: Arc::new(names),
+        default_server,
+        quic,
+        client_auth,
+        certs,
+        ticketer,
+    })
+}
+
+/// Starts the task that reloads the certificates, OCSP responses and
+/// session ticket keys of `acceptor` when their files change. Handshakes in
+/// progress and established connections keep the previous certificate.
+pub fn spawn(acceptor: &Acceptor) {
+    let certs = acceptor.certs.clone();
+    let ticketer = acceptor.ticketer.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            for cert in &certs {
+                cert.reload();
+            }
+            if let Some(ticketer) = &ticketer {
+                ticketer.reload();
+            }
+        }
+    });
+}
+
+impl Acceptor {
+    /// Performs the handshake of a client connection, and returns whether
+    /// `h2` was negotiated with ALPN. Early data sent by the client is read
+    /// again from the returned stream.
+    pub async fn accept<S>(&self, stream: S) -> io::Result<(Rewind<TlsStream<S>>, TlsInfo, bool)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let start = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), stream).await?;
+        let server = self.server(start.client_hello().server_name());
+        let mut stream = start.into_stream(self.configs[server].clone()).await?;
+
+        let mut early_data = vec![];
+        if let Some(mut reader) = stream.get_mut().1.early_data() {
+            reader.read_to_end(&mut early_data)?;
+        }
+
+        let (_, conn) = stream.get_ref();
+        let protocol = match conn.protocol_version() {
+            Some(rustls::ProtocolVersion::TLSv1_2) => "TLSv1.2",
+            Some(rustls::ProtocolVersion::TLSv1_3) => "TLSv1.3",
+            _ => "",
+        };
+        let mut info = TlsInfo {
+            protocol: protocol.to_owned(),
+            cipher: conn
+                .negotiated_cipher_suite()
+                .map(|v| format!("{:?}", v.suite()))
+                .unwrap_or_default(),
+            server_name: conn.server_name().map(str::to_owned),
+            client_cert: None,
+            client_verify: "NONE".to_owned(),
+            client_error: None,
+            early_data: !early_data.is_empty(),
+            early_data_size: early_data.len(),
+            server,
+        };
+
+        self.verify_client(conn.peer_certificates(), &mut info)?;
+
+        let h2 = conn.alpn_protocol() == Some(b"h2");
+        Ok((Rewind::new(early_data, stream), info, h2))
+    }
+
+    /// Returns the configuration of the QUIC endpoint of the port, which
+    /// shares the certificates and settings of TCP connections. As it can't
+    /// request a client certificate by SNI, the servers of the port have to
+    /// verify client certificates the same way.
+    pub fn quic_config(&self) -> io::Result<quinn::ServerConfig> {
+        let config = self
+            .quic
+            .clone()
+            .ok_or_else(|| invalid_input("quic requires TLSv1.3 in ssl_protocols"))?;
+        if (0..self.configs.len()).any(|i| self.misdirected(self.default_server, i)) {
+            return Err(invalid_input(
+                "quic requires the same ssl_verify_client, ssl_client_certificate and ssl_verify_depth in the servers of a port",
+            ));
+        }
+        Ok(quinn::ServerConfig::with_crypto(config))
+    }
+
+    /// Returns the index of the server of the name requested with SNI.
+    fn server(&self, server_name: Option<&str>) -> usize {
+        server_name
+            .and_then(|v| self.names.find(&v.to_ascii_lowercase()))
+            .map(|(i, _)| i)
+            .unwrap_or(self.default_server)
+    }
+
+    /// Whether a request of a connection whose client certificate was
+    /// verified by the settings of server `sni` can't be handled by server
+    /// `host`, as they verify client certificates differently.
+    pub fn misdirected(&self, sni: usize, host: usize) -> bool {
+        let auth = |i: usize| self.client_auth.get(i).cloned().flatten();
+        match (auth(sni), auth(host)) {
+            (None, None) => false,
+            (Some(a), Some(b)) => !a.same(&b),
+            _ => true,
+        }
+    }
+
+    /// Returns the TLS parameters of an established QUIC connection. The
+    /// cipher suite isn't exposed by quinn and is left empty.
+    pub fn quic_info(&self, conn: &quinn::Connection) -> io::Result<TlsInfo> {
+        let server_name = conn
+            .handshake_data()
+            .and_then(|v| v.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+            .and_then(|v| v.server_name);
+        let server = self.server(server_name.as_deref());
+        let mut info = TlsInfo {
+            protocol: "TLSv1.3".to_owned(),
+            cipher: String::new(),
+            server_name,
+            client_cert: None,
+            client_verify: "NONE".to_owned(),
+            client_error: None,
+            early_data: false,
+            early_data_size: 0,
+            server,
+        };
+
+        let chain = conn
+            .peer_identity()
+            .and_then(|v| v.downcast::<Vec<Certificate>>().ok());
+        self.verify_client(chain.as_deref().map(Vec::as_slice), &mut info)?;
+        Ok(info)
+    }
+
+    /// Verifies the certificate chain sent by the client, if requested by
+    /// the server of the connection.
+    fn verify_client(&self, chain: Option<&[Certificate]>, info: &mut TlsInfo) -> io::Result<()> {
+        match (&self.client_auth[info.server], chain) {
+            (Some(auth), Some(chain)) if !chain.is_empty() => {
+                info.client_cert = Some(client_cert(&chain[0])?);
+                match auth.verify(chain) {
+                    Ok(_) => info.client_verify = "SUCCESS".to_owned(),
+                    Err(reason) => {
+                        info.client_verify = format!("FAILED:{}", reason);
+                        if auth.mode != VerifyClient::OptionalNoCa {
+                            info.client_error = Some("The SSL certificate error");
+                        }
+                    }
+                }
+            }
+            (Some(auth), _) if auth.mode == VerifyClient::On => {
+                info.client_error = Some("No required SSL certificate was sent");
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Returns the 400 response for a request on a connection whose client
+/// certificate was rejected, or the 421 response for a [`Misdirected`]
+/// request.
+pub fn client_error(req: &Request) -> Option<Response> {
+    let (status, message) = match req.extensions().get::<Misdirected>() {
+        Some(_) => (StatusCode::MISDIRECTED_REQUEST, "Misdirected Request"),
+        None => {
+            let message = req
+                .extensions()
+                .get::<ConnectionInfo>()
+                .and_then(|v| v.tls.as_ref())
+                .and_then(|v| v.client_error)?;
+            (StatusCode::BAD_REQUEST, message)
+        }
+    };
+
+    let mut res = Response::new(message.as_bytes().to_vec().into());
+    *res.status_mut() = status;
+    http1::set_header(res.headers_mut(), header::CONTENT_TYPE, "text/plain");
+    Some(res)
+}
+
+/// Requests a certificate from clients without failing the handshake, the
+/// certificate is verified once the handshake is done so that a failure can
+/// be answered with an error response.
+struct ClientAuth {
+    mode: VerifyClient,
+    /// Verifier of the trusted CAs, unless only `optional_no_ca` is used.
+    verifier: Option<AllowAnyAuthenticatedClient>,
+    roots: Vec<Certificate>,
+    subjects: Vec<DistinguishedName>,
+    depth: usize,
+}
+
+impl ClientAuth {
+    fn new(ssl: &SslConfig) -> io::Result<ClientAuth> {
+        let roots = match &ssl.client_certificate {
+            Some(path) => rustls_pemfile::certs(&mut open(path)?)?
+                .into_iter()
+                .map(Certificate)
+                .collect(),
+            None if ssl.verify_client == VerifyClient::OptionalNoCa => vec![],
+            None => {
+                return Err(invalid_input(
+                    "ssl_verify_client requires ssl_client_certificate",
+                ))
+            }
+        };
+
+        let verifier = match roots.is_empty() {
+            true => None,
+            false => {
+                let mut store = RootCertStore::empty();
+                for cert in &roots {
+                    store.add(cert).map_err(invalid_input)?;
+                }
+                Some(AllowAnyAuthenticatedClient::new(store))
+            }
+        };
+        let subjects = verifier
+            .as_ref()
+            .map(|v| v.client_auth_root_subjects().to_vec())
+            .unwrap_or_default();
+
+        Ok(ClientAuth {
+            mode: ssl.verify_client,
+            verifier,
+            roots,
+            subjects,
+            depth: ssl.verify_depth,
+        })
+    }
+
+    /// Whether `other` verifies client certificates the same way.
+    fn same(&self, other: &ClientAuth) -> bool {
+        self.mode == other.mode && self.roots == other.roots && self.depth == other.depth
+    }
+
+    fn verify(&self, chain: &[Certificate]) -> Result<(), String> {
+        let verifier = self.verifier.as_ref().ok_or("no trusted CA")?;
+
+        // CAs sent along with the certificate do not count in the depth
+        let (end_entity, rest) = chain.split_first().ok_or("no certificate")?;
+        let intermediates: Vec<Certificate> = rest
+            .iter()
+            .filter(|v| !self.roots.contains(v))
+            .cloned()
+            .collect();
+        if intermediates.len() >= self.depth {
+            return Err("certificate chain too long".to_owned());
+        }
+
+        verifier
+            .verify_client_cert(end_entity, &intermediates, SystemTime::now())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl ClientCertVerifier for ClientAuth {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &self.subjects
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        // the signature of the handshake is still checked by rustls
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+fn client_cert(cert: &Certificate) -> io::Result<ClientCert> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    let digest = ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, &cert.0);
+    Ok(ClientCert {
+        subject: format_name(parsed.subject()),
+        issuer: format_name(parsed.issuer()),
+        serial: parsed
+            .raw_serial()
+            .iter()
+            .map(|v| format!("{:02X}", v))
+            .collect(),
+        fingerprint: digest
+            .as_ref()
+            .iter()
+            .map(|v| format!("{:02x}", v))
+            .collect(),
+    })
+}
+
+/// Formats a distinguished name as in RFC 2253, most specific part first.
+fn format_name(name: &X509Name) -> String {
+    let mut rdns: Vec<String> = name
+        .iter()
+        .map(|rdn| {
+            rdn.iter()
+                .map(|attr| {
+                    let key = oid2abbrev(attr.attr_type(), oid_registry())
+                        .map(str::to_owned)
+                        .unwrap_or_else(|_| attr.attr_type().to_id_string());
+                    let value = attr.as_str().unwrap_or_default();
+                    format!("{}={}", key, escape_dn_value(value))
+                })
+                .collect::<Vec<_>>()
+                .join("+")
+        })
+        .collect();
+    rdns.reverse();
+    rdns.join(",")
+}
+
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let count = value.chars().count();
+    for (i, c) in value.chars().enumerate() {
+        let leading = i == 0 && (c == ' ' || c == '#');
+        let trailing = i + 1 == count && c == ' ';
+        if leading || trailing || ",+\"<>;\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Selects the certificate of the server named with SNI.
+struct CertResolver {
+    /// Index in `certs` of the certificate of each server name.
+    names: ServerNames,
+    certs: Vec<Arc<CertSource>>,
+    /// Index in `certs` of the certificate used without SNI or when the
+    /// name is unknown.
+    default: usize,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let index = hello
+            .server_name()
+            .and_then(|v| self.names.find(&v.to_ascii_lowercase()))
+            .map(|(i, _)| i)
+            .unwrap_or(self.default);
+        self.certs.get(index).map(|v| v.current())
+    }
+}
+
+/// Interval at which the files of the certificates are checked.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum size of TLS 1.3 early data accepted on a connection.
+const MAX_EARLY_DATA_SIZE: u32 = 16384;
+
+/// Modification time and length of a file, `None` when it can't be read.
+type Stamp = Option<(SystemTime, u64)>;
+
+/// Detects changes of a set of files by their stamps.
+struct FileWatch {
+    paths: Vec<String>,
+    /// Stamps of the files when they were last loaded, and those seen by the
+    /// last check.
+    stamps: Mutex<(Vec<Stamp>, Vec<Stamp>)>,
+}
+
+impl FileWatch {
+    fn new(paths: Vec<String>) -> FileWatch {
+        let stamps = file_stamps(&paths);
+        FileWatch {
+            paths,
+            stamps: Mutex::new((stamps.clone(), stamps)),
+        }
+    }
+
+    /// Loads the files again with `load` once they have changed and were
+    /// left unchanged for an interval, so that e.g. a certificate isn't
+    /// paired with the key of the previous one while both are being
+    /// replaced. Files that fail to load are retried at the next check.
+    fn reload<F>(&self, load: F)
+    where
+        F: FnOnce() -> io::Result<()>,
+    {
+        let stamps = file_stamps(&self.paths);
+        let mut guard = self.stamps.lock().unwrap();
+        let (loaded, seen) = &mut *guard;
+        let settled = stamps == *seen;
+        *seen = stamps.clone();
+        if !settled || stamps == *loaded {
+            return;
+        }
+
+        match load() {
+            Ok(_) => {
+                log::info!("reload {}", self.paths.join(", "));
+                *loaded = stamps;
+            }
+            Err(e) => log::error!("failed to reload {}: {}", self.paths.join(", "), e),
+        }
+    }
+}
+
+fn file_stamps(paths: &[String]) -> Vec<Stamp> {
+    paths
+        .iter()
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|v| Ok((v.modified()?, v.len())))
+                .ok()
+        })
+        .collect()
+}
+
+/// Certificate of a server, loaded again when one of its files changes.
+struct CertSource {
+    ssl: SslConfig,
+    key: RwLock<Arc<CertifiedKey>>,
+    watch: FileWatch,
+}
+
+impl CertSource {
+    fn new(ssl: &SslConfig) -> io::Result<CertSource> {
+        if ssl.stapling && ssl.stapling_file.is_none() {
+            log::warn!("ssl_stapling is ignored without ssl_stapling_file");
+        }
+
+        let paths = [&ssl.certificate, &ssl.certificate_key, &ssl.stapling_file]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        let watch = FileWatch::new(paths);
+        let key = load_certified_key(ssl)?;
+        Ok(CertSource {
+            ssl: ssl.clone(),
+            key: RwLock::new(Arc::new(key)),
+            watch,
+        })
+    }
+
+    fn current(&self) -> Arc<CertifiedKey> {
+        self.key.read().unwrap().clone()
+    }
+
+    /// Replaces the certificate when its files have changed, keeping the
+    /// previous one when the new files fail to load.
+    fn reload(&self) {
+        self.watch.reload(|| {
+            let key = load_certified_key(&self.ssl)?;
+            *self.key.write().unwrap() = Arc::new(key);
+            Ok(())
+        });
+    }
+}
+
+fn load_certified_key(ssl: &SslConfig) -> io::Result<CertifiedKey> {
+    let cert_path = ssl.certificate.as_deref().unwrap_or_default();
+    let key_path = ssl.certificate_key.as_deref().unwrap_or(cert_path);
+
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut open(cert_path)?)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(invalid_input(format!("no certificate in {}", cert_path)));
+    }
+
+    let key = rustls_pemfile::read_all(&mut open(key_path)?)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(v)
+            | rustls_pemfile::Item::RSAKey(v)
+            | rustls_pemfile::Item::ECKey(v) => Some(PrivateKey(v)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_input(format!("no private key in {}", key_path)))?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|e| invalid_input(format!("{}: {}", key_path, e)))?;
+
+    let mut certified = CertifiedKey::new(certs, key);
+    if let (true, Some(path)) = (ssl.stapling, &ssl.stapling_file) {
+        let ocsp = std::fs::read(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+        certified.ocsp = Some(ocsp);
+    }
+    Ok(certified)
+}
+
+/// Encrypts session tickets with AES-GCM under the keys of
+/// `ssl_session_ticket_key`, each ticket starting with the 16 bytes name of
+/// its key.
+struct Ticketer {
+    keys: RwLock<Vec<TicketKey>>,
+    watch: FileWatch,
+    lifetime: u32,
+}
+
+struct TicketKey {
+    name: [u8; 16],
+    key: LessSafeKey,
+}
+
+impl Ticketer {
+    fn new(ssl: &SslConfig) -> io::Result<Ticketer> {
+        let keys = match ssl.session_ticket_key.is_empty() {
+            true => {
+                let mut bytes = [0u8; 48];
+                SystemRandom::new()
+                    .fill(&mut bytes)
+                    .map_err(|_| invalid_input("failed to generate a ticket key"))?;
+                vec![TicketKey::new(&bytes, "random")?]
+            }
+            false => load_ticket_keys(&ssl.session_ticket_key)?,
+        };
+
+        Ok(Ticketer {
+            keys: RwLock::new(keys),
+            watch: FileWatch::new(ssl.session_ticket_key.clone()),
+            lifetime: ssl.session_timeout.as_secs().try_into().unwrap_or(u32::MAX),
+        })
+    }
+
+    /// Replaces the keys when one of their files has changed.
+    fn reload(&self) {
+        if self.watch.paths.is_empty() {
+            return;
+        }
+        self.watch.reload(|| {
+            *self.keys.write().unwrap() = load_ticket_keys(&self.watch.paths)?;
+            Ok(())
+        });
+    }
+}
+
+impl TicketKey {
+    /// Uses the layout of nginx key files, the name followed by the HMAC and
+    /// AES keys of 16 bytes each, or of 32 bytes for a file of 80 bytes.
+    fn new(bytes: &[u8], path: &str) -> io::Result<TicketKey> {
+        let (algorithm, aes) = match bytes.len() {
+            48 => (&AES_128_GCM, &bytes[32..]),
+            80 => (&AES_256_GCM, &bytes[48..]),
+            n => {
+                return Err(invalid_input(format!(
+                    "{}: ticket key must be 48 or 80 bytes, not {}",
+                    path, n
+                )))
+            }
+        };
+
+        Ok(TicketKey {
+            name: bytes[..16].try_into().unwrap(),
+            key: LessSafeKey::new(
+                UnboundKey::new(algorithm, aes)
+                    .map_err(|_| invalid_input(format!("{}: invalid ticket key", path)))?,
+            ),
+        })
+    }
+}
+
+fn load_ticket_keys(paths: &[String]) -> io::Result<Vec<TicketKey>> {
+    paths
+        .iter()
+        .map(|path| {
+            let bytes = std::fs::read(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+            TicketKey::new(&bytes, path)
+        })
+        .collect()
+}
+
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys.read().unwrap();
+        let current = keys.first()?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+        let mut data = plain.to_vec();
+        current
+            .key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(current.name),
+                &mut data,
+            )
+            .ok()?;
+
+        let mut ticket = Vec::with_capacity(16 + NONCE_LEN + data.len());
+        ticket.extend_from_slice(&current.name);
+        ticket.extend_from_slice(&nonce);
+        ticket.append(&mut data);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < 16 + NONCE_LEN {
+            return None;
+        }
+        let (name, rest) = cipher.split_at(16);
+        let (nonce, data) = rest.split_at(NONCE_LEN);
+
+        let keys = self.keys.read().unwrap();
+        let key = keys.iter().find(|v| v.name == name)?;
+        let mut data = data.to_vec();
+        let plain = key
+            .key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(key.name),
+                &mut data,
+            )
+            .ok()?;
+        Some(plain.to_vec())
+    }
+}
+
+/// Encoded sessions by their ids, with the time they expire.
+type Sessions = HashMap<Vec<u8>, (Vec<u8>, Instant)>;
+
+/// Sessions stored by `ssl_session_cache`, the oldest being evicted when
+/// the cache is full.
+pub struct SessionStore {
+    sessions: Mutex<Sessions>,
+    capacity: usize,
+}
+
+impl SessionStore {
+    fn new(size: u64) -> SessionStore {
+        SessionStore {
+            sessions: Mutex::new(HashMap::new()),
+            // about 256 bytes per session, as in nginx
+            capacity: (size / 256).max(1) as usize,
+        }
+    }
+
+    fn put(&self, key: Vec<u8>, value: Vec<u8>, expires: Instant) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= self.capacity && !sessions.contains_key(&key) {
+            let now = Instant::now();
+            sessions.retain(|_, (_, expires)| *expires > now);
+        }
+        if sessions.len() >= self.capacity && !sessions.contains_key(&key) {
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, (_, expires))| *expires)
+                .map(|(k, _)| k.clone());
+            if let Some(k) = oldest {
+                sessions.remove(&k);
+            }
+        }
+        sessions.insert(key, (value, expires));
+    }
+
+    fn get(&self, key: &[u8], take: bool) -> Option<Vec<u8>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let expired = sessions
+            .get(key)
+            .map(|(_, expires)| *expires <= Instant::now())?;
+        if expired || take {
+            let (value, _) = sessions.remove(key)?;
+            return (!expired).then_some(value);
+        }
+        sessions.get(key).map(|(value, _)| value.clone())
+    }
+}
+
+/// Session cache of a port, storing sessions for its `ssl_session_timeout`.
+struct SessionCacheHandle {
+    store: Arc<SessionStore>,
+    timeout: Duration,
+}
+
+impl StoresServerSessions for SessionCacheHandle {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.store.put(key, value, Instant::now() + self.timeout);
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.store.get(key, false)
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.store.get(key, true)
+    }
+
+    fn can_cache(&self) -> bool {
+        true
+    }
+}
+
+fn open(path: &str) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+fn protocol_versions(names: &[String]) -> Vec<&'static SupportedProtocolVersion> {
+    let mut versions = vec![];
+    if names.iter().any(|v| v == "TLSv1.3") {
+        versions.push(&rustls::version::TLS13);
+    }
+    if names.iter().any(|v| v == "TLSv1.2") {
+        versions.push(&rustls::version::TLS12);
+    }
+    versions
+}
+
+/// OpenSSL and IANA names of the suites supported by rustls, which
+/// `ssl_ciphers` may use instead of the rustls names.
+const CIPHER_NAMES: &[(&str, &str)] = &[
+    ("TLS_AES_256_GCM_SHA384", "TLS13_AES_256_GCM_SHA384"),
+    ("TLS_AES_128_GCM_SHA256", "TLS13_AES_128_GCM_SHA256"),
+    (
+        "TLS_CHACHA20_POLY1305_SHA256",
+        "TLS13_CHACHA20_POLY1305_SHA256",
+    ),
+    (
+        "ECDHE-ECDSA-AES256-GCM-SHA384",
+        "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+    ),
+    (
+        "ECDHE-ECDSA-AES128-GCM-SHA256",
+        "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+    ),
+    (
+        "ECDHE-ECDSA-CHACHA20-POLY1305",
+        "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+    ),
+    (
+        "ECDHE-RSA-AES256-GCM-SHA384",
+        "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+    ),
+    (
+        "ECDHE-RSA-AES128-GCM-SHA256",
+        "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+    ),
+    (
+        "ECDHE-RSA-CHACHA20-POLY1305",
+        "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+    ),
+];
+
+/// Returns the suites named in `names`, or the defaults, keeping those
+/// usable with one of `versions`. OpenSSL keywords such as `HIGH` or
+/// `!aNULL` select nothing in particular with rustls and are ignored.
+fn cipher_suites(
+    names: &[String],
+    versions: &[&'static SupportedProtocolVersion],
+) -> io::Result<Vec<SupportedCipherSuite>> {
+    let mut suites = vec![];
+    for name in names {
+        // suite names have dashes or underscores, keywords and their
+        // combinations like `ECDHE+AESGCM` don't, and `!`, `-`, `+` or `@`
+        // prefixes only make sense with OpenSSL's ordering rules
+        let keyword = name.starts_with(|c| "!-+@".contains(c))
+            || name.contains('+')
+            || !name.contains(|c| c == '-' || c == '_');
+        if keyword {
+            log::warn!("ignore ssl cipher keyword {}", name);
+            continue;
+        }
+
+        let name = CIPHER_NAMES
+            .iter()
+            .find(|(alias, _)| alias == name)
+            .map(|(_, v)| *v)
+            .unwrap_or(name);
+        let suite = rustls::ALL_CIPHER_SUITES
+            .iter()
+            .find(|v| format!("{:?}", v.suite()) == name)
+            .ok_or_else(|| invalid_input(format!("unknown ssl cipher {}", name)))?;
+        suites.push(*suite);
+    }
+    if suites.is_empty() {
+        suites = rustls::DEFAULT_CIPHER_SUITES.to_vec();
+    }
+
+    Ok(suites
+        .into_iter()
+        .filter(|v| versions.contains(&v.version()))
+        .collect())
+}
+
+fn invalid_input<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processor::tls::{
+        cipher_suites, escape_dn_value, protocol_versions, SessionStore, TicketKey, Ticketer,
+    };
+    use rustls::server::ProducesTickets;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_cipher_suites() {
+        let versions = protocol_versions(&["TLSv1.3".to_owned()]);
+        assert_eq!(versions, vec![&rustls::version::TLS13]);
+
+        let suites = cipher_suites(
+            &[
+                "TLS13_AES_128_GCM_SHA256".to_owned(),
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_owned(),
+            ],
+            &versions,
+        )
+        .unwrap();
+        assert_eq!(suites, vec![rustls::cipher_suite::TLS13_AES_128_GCM_SHA256]);
+
+        assert_eq!(cipher_suites(&[], &versions).unwrap().len(), 3);
+        assert!(cipher_suites(&["RC4-MD5".to_owned()], &versions).is_err());
+
+        // OpenSSL names
+        let versions = protocol_versions(&["TLSv1.2".to_owned(), "TLSv1.3".to_owned()]);
+        let names = ["ECDHE-RSA-AES128-GCM-SHA256", "TLS_AES_256_GCM_SHA384"];
+        assert_eq!(
+            cipher_suites(&names.map(str::to_owned), &versions).unwrap(),
+            vec![
+                rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
+            ]
+        );
+
+        // keywords are ignored, leaving the defaults when nothing else is named
+        let names = ["HIGH", "!aNULL", "-RC4-MD5", "ECDHE+AESGCM", "@STRENGTH"];
+        assert_eq!(
+            cipher_suites(&names.map(str::to_owned), &versions).unwrap(),
+            rustls::DEFAULT_CIPHER_SUITES.to_vec()
+        );
+    }
+
+    #[test]
+    fn test_escape_dn_value() {
+        assert_eq!(escape_dn_value("client"), "client");
+        assert_eq!(escape_dn_value("Acme, Inc."), "Acme\\, Inc.");
+        assert_eq!(escape_dn_value(" a+b "), "\\ a\\+b\\ ");
+    }
+
+    #[test]
+    fn test_ticketer() {
+        let ticketer = Ticketer::new(&Default::default()).unwrap();
+        let ticket = ticketer.encrypt(b"session").unwrap();
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session");
+        assert_ne!(ticketer.encrypt(b"session").unwrap(), ticket);
+
+        // tickets of the previous key are still accepted after a rotation
+        let previous = ticketer.keys.write().unwrap().remove(0);
+        let current = TicketKey::new(&[1; 80], "current").unwrap();
+        *ticketer.keys.write().unwrap() = vec![current];
+        assert!(ticketer.decrypt(&ticket).is_none());
+        ticketer.keys.write().unwrap().push(previous);
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session");
+        assert_eq!(&ticketer.encrypt(b"session").unwrap()[..16], &[1; 16]);
+
+        let mut tampered = ticket;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(ticketer.decrypt(&tampered).is_none());
+        assert!(TicketKey::new(&[0; 32], "short").is_err());
+    }
+
+    #[test]
+    fn test_session_store() {
+        let store = SessionStore::new(512);
+        let later = Instant::now() + Duration::from_secs(60);
+        store.put(b"a".to_vec(), b"1".to_vec(), later);
+        store.put(b"b".to_vec(), b"2".to_vec(), later + Duration::from_secs(1));
+        assert_eq!(store.get(b"a", false).unwrap(), b"1");
+
+        // the session that expires first is evicted when full
+        store.put(b"c".to_vec(), b"3".to_vec(), later + Duration::from_secs(2));
+        assert!(store.get(b"a", false).is_none());
+        assert_eq!(store.get(b"b", true).unwrap(), b"2");
+        assert!(store.get(b"b", false).is_none());
+
+        store.put(b"d".to_vec(), b"4".to_vec(), Instant::now());
+        assert!(store.get(b"d", false).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Upstream connection that switched protocols, carried in the extensions of
+/// a 101 response so the client connection can be tunneled to it.
+pub struct Upgraded {
+    pub io: TcpStream,
+    /// Bytes the upstream sent right after its response head.
+    pub buffered: Vec<u8>,
+    /// The tunnel is closed when neither side sends anything for this long.
+    pub idle_timeout: Duration,
+}
+
+/// Copies bytes in both directions between the client and the upgraded
+/// upstream connection until both sides are closed or the tunnel is idle.
+pub async fn run<S>(client: S, client_buffered: Vec<u8>, upgraded: Upgraded) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = upgraded.io.into_split();
+
+    upstream_write.write_all(&client_buffered).await?;
+    client_write.write_all(&upgraded.buffered).await?;
+
+    let mut client_buf = vec![0u8; 16 * 1024];
+    let mut upstream_buf = vec![0u8; 16 * 1024];
+    let mut client_open = true;
+    let mut upstream_open = true;
+
+    while client_open || upstream_open {
+        tokio::select! {
+            n = client_read.read(&mut client_buf), if client_open => match n? {
+                0 => {
+                    client_open = false;
+                    upstream_write.shutdown().await?;
+                }
+                n => upstream_write.write_all(&client_buf[..n]).await?,
+            },
+            n = upstream_read.read(&mut upstream_buf), if upstream_open => match n? {
+                0 => {
+                    upstream_open = false;
+                    client_write.shutdown().await?;
+                }
+                n => {
+                    client_write.write_all(&upstream_buf[..n]).await?;
+                    client_write.flush().await?;
+                }
+            },
+            _ = tokio::time::sleep(upgraded.idle_timeout) => {
+                log::debug!("close idle tunnel");
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,589 @@
+use crate::{
+    config::{
+        http::HttpConfig,
+        upstream::{HealthCheckConfig, LoadBalance, UpstreamConfig, UpstreamServerConfig},
+    },
+    processor::{
+        pool::{H2Pool, Pool},
+        variable, ConnectionInfo, Request,
+    },
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Number of points each unit of weight gets on the consistent hash ring.
+const CONSISTENT_POINTS: usize = 160;
+
+/// Number of attempts the hash methods make before falling back to round
+/// robin when the hashed peer is unavailable.
+const HASH_TRIES: usize = 20;
+
+pub type Upstreams = HashMap<String, Arc<Upstream>>;
+
+/// Builds the upstreams of an `http` block. A `proxy_pass` target that does
+/// not name an `upstream` block gets an implicit single server upstream,
+/// and so does a `grpc_pass`, `fastcgi_pass`, `uwsgi_pass` or `scgi_pass`
+/// target.
+pub fn build(http: &HttpConfig) -> Upstreams {
+    let mut upstreams: Upstreams = http
+        .upstream
+        .iter()
+        .map(|(name, c)| (name.clone(), Arc::new(Upstream::new(c.clone()))))
+        .collect();
+
+    let targets = http
+        .server
+        .iter()
+        .flat_map(|s| s.location.values())
+        .flat_map(|l| {
+            [
+                l.proxy_pass.as_ref().map(|v| &v.host),
+                l.fastcgi.gateway.pass.as_ref(),
+                l.uwsgi.pass.as_ref(),
+                l.scgi.pass.as_ref(),
+                l.grpc.pass.as_ref(),
+            ]
+        })
+        .flatten();
+    for target in targets {
+        upstreams.entry(target.clone()).or_insert_with(|| {
+            Arc::new(Upstream::new(UpstreamConfig {
+                name: target.clone(),
+                server: vec![UpstreamServerConfig {
+                    address: target.clone(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }))
+        });
+    }
+
+    upstreams
+}
+
+#[derive(Debug)]
+pub struct Peer {
+    pub address: String,
+    weight: usize,
+    max_fails: usize,
+    fail_timeout: Duration,
+    down: bool,
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    current_weight: isize,
+    fails: usize,
+    checked: Option<Instant>,
+    conns: usize,
+    /// Set by active health checks, see [`Upstream::report_health`].
+    unhealthy: bool,
+    /// Consecutive health check successes or failures, depending on whether
+    /// the peer is currently unhealthy.
+    streak: usize,
+}
+
+/// Snapshot of a peer used by the upstream status page.
+#[derive(Debug)]
+pub struct PeerStatus {
+    pub state: &'static str,
+    pub backup: bool,
+    pub fails: usize,
+    pub conns: usize,
+}
+
+pub struct Upstream {
+    pub name: String,
+    pub health_check: Option<HealthCheckConfig>,
+    pub pool: Pool,
+    /// Connections used by `grpc_pass`.
+    pub h2: H2Pool,
+    method: LoadBalance,
+    /// Primary peers followed by backup peers.
+    peers: Vec<Peer>,
+    backup_start: usize,
+    /// Sorted consistent hash ring of `(point, peer index)`.
+    ring: Vec<(u32, usize)>,
+    state: Mutex<Vec<PeerState>>,
+}
+
+impl Upstream {
+    pub fn new(c: UpstreamConfig) -> Upstream {
+        let (primary, backup): (Vec<_>, Vec<_>) = c.server.into_iter().partition(|s| !s.backup);
+        let backup_start = primary.len();
+
+        let peers: Vec<Peer> = primary
+            .into_iter()
+            .chain(backup)
+            .map(|s| Peer {
+                address: with_default_port(s.address),
+                weight: s.weight,
+                max_fails: s.max_fails,
+                fail_timeout: s.fail_timeout,
+                down: s.down,
+            })
+            .collect();
+
+        let mut ring = vec![];
+        if let LoadBalance::Hash {
+            consistent: true, ..
+        } = c.method
+        {
+            for (i, peer) in peers[..backup_start].iter().enumerate() {
+                for n in 0..peer.weight * CONSISTENT_POINTS {
+                    let point = crc32fast::hash(format!("{}-{}", peer.address, n).as_bytes());
+                    ring.push((point, i));
+                }
+            }
+            ring.sort_unstable();
+        }
+
+        let state = Mutex::new(peers.iter().map(|_| PeerState::default()).collect());
+
+        Upstream {
+            name: c.name,
+            health_check: c.health_check,
+            pool: Pool::new(c.keepalive, c.keepalive_timeout, c.keepalive_requests),
+            h2: H2Pool::default(),
+            method: c.method,
+            peers,
+            backup_start,
+            ring,
+            state,
+        }
+    }
+
+    pub fn peer(&self, id: usize) -> &Peer {
+        &self.peers[id]
+    }
+
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    pub fn peer_status(&self, id: usize) -> PeerStatus {
+        let state = self.state.lock().unwrap();
+        let s = &state[id];
+
+        PeerStatus {
+            state: if self.peers[id].down {
+                "down"
+            } else if s.unhealthy {
+                "unhealthy"
+            } else if !self.is_available(id, &state, Instant::now()) {
+                "unavailable"
+            } else {
+                "up"
+            },
+            backup: id >= self.backup_start,
+            fails: s.fails,
+            conns: s.conns,
+        }
+    }
+
+    /// Records the result of an active health check. A peer is taken out of
+    /// rotation after `fall` consecutive failures and put back after `rise`
+    /// consecutive successes.
+    pub fn report_health(&self, id: usize, result: Result<(), String>) {
+        let (rise, fall) = match &self.health_check {
+            Some(c) => (c.rise, c.fall),
+            None => return,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let s = &mut state[id];
+        let address = &self.peers[id].address;
+
+        match (s.unhealthy, result) {
+            (false, Ok(_)) | (true, Err(_)) => s.streak = 0,
+            (false, Err(e)) => {
+                s.streak += 1;
+                log::debug!(
+                    "health check failed for {} in upstream {}: {}",
+                    address,
+                    self.name,
+                    e
+                );
+                if s.streak >= fall {
+                    log::warn!(
+                        "upstream server {} in upstream {} is unhealthy: {}",
+                        address,
+                        self.name,
+                        e
+                    );
+                    s.unhealthy = true;
+                    s.streak = 0;
+                }
+            }
+            (true, Ok(_)) => {
+                s.streak += 1;
+                if s.streak >= rise {
+                    log::info!(
+                        "upstream server {} in upstream {} is healthy",
+                        address,
+                        self.name
+                    );
+                    s.unhealthy = false;
+                    s.streak = 0;
+                    s.fails = 0;
+                }
+            }
+        }
+    }
+
+    /// Selects a peer for `req`, skipping the peers in `tried`. The returned
+    /// peer must be released with [`Upstream::free_peer`].
+    pub fn get_peer(&self, req: &Request, tried: &[usize]) -> Option<usize> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        let candidates = |state: &[PeerState], range: std::ops::Range<usize>| {
+            range
+                .filter(|i| !tried.contains(i) && self.is_available(*i, state, now))
+                .collect::<Vec<_>>()
+        };
+
+        let primary = candidates(&state, 0..self.backup_start);
+        let mut selected = if primary.is_empty() {
+            None
+        } else {
+            match &self.method {
+                LoadBalance::RoundRobin => self.round_robin(&mut state, &primary),
+                LoadBalance::LeastConn => self.least_conn(&mut state, &primary),
+                LoadBalance::IpHash => {
+                    let key = match req.extensions().get::<ConnectionInfo>() {
+                        Some(info) => match info.remote_addr.ip() {
+                            IpAddr::V4(ip) => ip.octets()[..3].to_vec(),
+                            IpAddr::V6(ip) => ip.octets().to_vec(),
+                        },
+                        None => vec![],
+                    };
+                    self.ip_hash(&key, &primary)
+                        .or_else(|| self.round_robin(&mut state, &primary))
+                }
+                LoadBalance::Hash { key, consistent } => {
+                    let key = variable::evaluate(key, req);
+                    let hashed = if *consistent {
+                        self.consistent_hash(key.as_bytes(), &primary)
+                    } else {
+                        self.hash(key.as_bytes(), &primary)
+                    };
+                    hashed.or_else(|| self.round_robin(&mut state, &primary))
+                }
+            }
+        };
+
+        if selected.is_none() {
+            let backup = candidates(&state, self.backup_start..self.peers.len());
+            selected = self.round_robin(&mut state, &backup);
+        }
+
+        match selected {
+            Some(i) => {
+                state[i].conns += 1;
+                Some(i)
+            }
+            // the other peers were already tried for this request
+            None if !tried.is_empty() => None,
+            None => {
+                log::error!(
+                    "no live upstreams while connecting to upstream {}",
+                    self.name
+                );
+                // all peers failed, mark them as live for quick recovery
+                for s in state.iter_mut() {
+                    s.fails = 0;
+                }
+                None
+            }
+        }
+    }
+
+    /// Releases a peer returned by [`Upstream::get_peer`], recording whether
+    /// the attempt failed for passive health tracking.
+    pub fn free_peer(&self, id: usize, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        let s = &mut state[id];
+        s.conns = s.conns.saturating_sub(1);
+
+        if !failed {
+            s.fails = 0;
+            return;
+        }
+
+        let peer = &self.peers[id];
+        s.fails += 1;
+        s.checked = Some(Instant::now());
+        if peer.max_fails > 0 && s.fails >= peer.max_fails {
+            log::warn!(
+                "upstream server temporarily disabled: {} in upstream {}",
+                peer.address,
+                self.name
+            );
+        }
+    }
+
+    fn is_available(&self, id: usize, state: &[PeerState], now: Instant) -> bool {
+        let peer = &self.peers[id];
+        if peer.down || state[id].unhealthy {
+            return false;
+        }
+
+        // like nginx, a lone server is never considered unavailable
+        if self.peers.len() == 1 || peer.max_fails == 0 || state[id].fails < peer.max_fails {
+            return true;
+        }
+
+        match state[id].checked {
+            Some(checked) => now.duration_since(checked) > peer.fail_timeout,
+            None => true,
+        }
+    }
+
+    /// Smooth weighted round robin over `candidates`.
+    fn round_robin(&self, state: &mut [PeerState], candidates: &[usize]) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        let mut total = 0;
+
+        for &i in candidates {
+            let weight = self.peers[i].weight as isize;
+            state[i].current_weight += weight;
+            total += weight;
+
+            if best.map_or(true, |b| state[i].current_weight > state[b].current_weight) {
+                best = Some(i);
+            }
+        }
+
+        if let Some(b) = best {
+            state[b].current_weight -= total;
+        }
+
+        best
+    }
+
+    fn least_conn(&self, state: &mut [PeerState], candidates: &[usize]) -> Option<usize> {
+        // compare conns / weight without dividing
+        let load = |i: usize, j: usize, state: &[PeerState]| {
+            (state[i].conns * self.peers[j].weight).cmp(&(state[j].conns * self.peers[i].weight))
+        };
+
+        let best = *candidates.iter().min_by(|&&i, &&j| load(i, j, state))?;
+        let tied = candidates
+            .iter()
+            .copied()
+            .filter(|&i| load(i, best, state).is_eq())
+            .collect::<Vec<_>>();
+
+        self.round_robin(state, &tied)
+    }
+
+    fn ip_hash(&self, key: &[u8], candidates: &[usize]) -> Option<usize> {
+        let total = self.total_weight();
+        let mut hash: usize = 89;
+
+        for _ in 0..HASH_TRIES {
+            for b in key {
+                hash = (hash * 113 + *b as usize) % 6271;
+            }
+
+            let i = self.peer_by_weight(hash % total);
+            if candidates.contains(&i) {
+                return Some(i);
+            }
+        }
+
+        None
+    }
+
+    fn hash(&self, key: &[u8], candidates: &[usize]) -> Option<usize> {
+        let total = self.total_weight();
+        let mut hash = crc32fast::hash(key);
+
+        for n in 0..HASH_TRIES {
+            let i = self.peer_by_weight(hash as usize % total);
+            if candidates.contains(&i) {
+                return Some(i);
+            }
+
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(n.to_string().as_bytes());
+            hasher.update(key);
+            hash = hasher.finalize();
+        }
+
+        None
+    }
+
+    fn consistent_hash(&self, key: &[u8], candidates: &[usize]) -> Option<usize> {
+        let hash = crc32fast::hash(key);
+        let start = self.ring.partition_point(|(point, _)| *point < hash);
+
+        (0..self.ring.len())
+            .map(|n| self.ring[(start + n) % self.ring.len()].1)
+            .find(|i| candidates.contains(i))
+    }
+
+    fn total_weight(&self) -> usize {
+        self.peers[..self.backup_start]
+            .iter()
+            .map(|p| p.weight)
+            .sum::<usize>()
+            .max(1)
+    }
+
+    fn peer_by_weight(&self, mut w: usize) -> usize {
+        for (i, peer) in self.peers[..self.backup_start].iter().enumerate() {
+            if w < peer.weight {
+                return i;
+            }
+            w -= peer.weight;
+        }
+        0
+    }
+}
+
+fn with_default_port(address: String) -> String {
+    if address.starts_with("unix:") {
+        return address;
+    }
+
+    match address.rsplit_once(':') {
+        Some((_, port)) if !port.contains(']') => address,
+        _ => format!("{}:80", address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::upstream::{HealthCheckConfig, LoadBalance, UpstreamConfig, UpstreamServerConfig},
+        processor::upstream::Upstream,
+    };
+
+    fn upstream(method: LoadBalance, servers: &[(&str, usize, bool)]) -> Upstream {
+        Upstream::new(UpstreamConfig {
+            name: "backend".to_owned(),
+            method,
+            server: servers
+                .iter()
+                .map(|(address, weight, backup)| UpstreamServerConfig {
+                    address: address.to_string(),
+                    weight: *weight,
+                    backup: *backup,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    fn request(uri: &str) -> crate::processor::Request {
+        http::Request::builder()
+            .uri(uri)
+            .body(Default::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let u = upstream(
+            LoadBalance::RoundRobin,
+            &[("a:80", 5, false), ("b:80", 1, false), ("c:80", 1, false)],
+        );
+
+        let selected = (0..7)
+            .map(|_| {
+                let i = u.get_peer(&request("/"), &[]).unwrap();
+                u.free_peer(i, false);
+                u.peer(i).address.as_str()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            selected,
+            ["a:80", "a:80", "b:80", "a:80", "c:80", "a:80", "a:80"]
+        );
+    }
+
+    #[test]
+    fn test_backup_and_max_fails() {
+        let u = upstream(
+            LoadBalance::RoundRobin,
+            &[("a:80", 1, false), ("b:80", 1, false), ("c:80", 1, true)],
+        );
+
+        for _ in 0..2 {
+            let i = u.get_peer(&request("/"), &[]).unwrap();
+            u.free_peer(i, true);
+        }
+
+        let i = u.get_peer(&request("/"), &[]).unwrap();
+        assert_eq!(u.peer(i).address, "c:80");
+        assert_eq!(u.get_peer(&request("/"), &[i]), None);
+    }
+
+    #[test]
+    fn test_report_health() {
+        let mut u = upstream(
+            LoadBalance::RoundRobin,
+            &[("a:80", 1, false), ("b:80", 1, false)],
+        );
+        u.health_check = Some(HealthCheckConfig {
+            rise: 2,
+            fall: 1,
+            ..Default::default()
+        });
+
+        u.report_health(0, Err("refused".to_owned()));
+        for _ in 0..3 {
+            assert_eq!(u.get_peer(&request("/"), &[]), Some(1));
+            u.free_peer(1, false);
+        }
+        assert_eq!(u.peer_status(0).state, "unhealthy");
+
+        u.report_health(0, Ok(()));
+        assert_eq!(u.peer_status(0).state, "unhealthy");
+        u.report_health(0, Ok(()));
+        assert_eq!(u.peer_status(0).state, "up");
+    }
+
+    #[test]
+    fn test_least_conn() {
+        let u = upstream(
+            LoadBalance::LeastConn,
+            &[("a:80", 1, false), ("b:80", 1, false)],
+        );
+
+        let first = u.get_peer(&request("/"), &[]).unwrap();
+        let second = u.get_peer(&request("/"), &[]).unwrap();
+        assert_ne!(first, second);
+
+        u.free_peer(first, false);
+        assert_eq!(u.get_peer(&request("/"), &[]), Some(first));
+    }
+
+    #[test]
+    fn test_hash() {
+        for consistent in [false, true] {
+            let u = upstream(
+                LoadBalance::Hash {
+                    key: "$request_uri".into(),
+                    consistent,
+                },
+                &[("a:80", 1, false), ("b:80", 1, false), ("c:80", 1, false)],
+            );
+
+            let first = u.get_peer(&request("/a"), &[]).unwrap();
+            for _ in 0..5 {
+                assert_eq!(u.get_peer(&request("/a"), &[]), Some(first));
+            }
+
+            let next = u.get_peer(&request("/a"), &[first]).unwrap();
+            assert_ne!(next, first);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{
+    config::gateway::GatewayConfig,
+    processor::{
+        gateway::{self, Stream},
+        Request, Response,
+    },
+};
+use std::io;
+
+/// Sends `req` as a uwsgi packet with modifier 0, i.e. a WSGI request, and
+/// reads the HTTP response, which lasts until the server closes the
+/// connection.
+pub async fn send(
+    mut stream: Box<dyn Stream>,
+    config: &GatewayConfig,
+    req: Request,
+) -> io::Result<Response> {
+    let (params, body) = gateway::read_request(config, req).await?;
+
+    let mut buf = encode_packet(&params)?;
+    buf.extend(body);
+    gateway::write(&mut stream, &buf, config.send_timeout).await?;
+
+    gateway::read_response(stream, config, gateway::parse_http_head).await
+}
+
+/// Encodes the variables as little endian length prefixed names and values,
+/// after the 4 byte packet header.
+fn encode_packet(params: &[(String, String)]) -> io::Result<Vec<u8>> {
+    let mut vars = vec![];
+    for v in params.iter().flat_map(|(name, value)| [name, value]) {
+        vars.extend(length(v.len())?);
+        vars.extend(v.as_bytes());
+    }
+
+    let mut buf = vec![0];
+    buf.extend(length(vars.len())?);
+    buf.push(0);
+    buf.extend(vars);
+    Ok(buf)
+}
+
+fn length(n: usize) -> io::Result<[u8; 2]> {
+    u16::try_from(n)
+        .map(u16::to_le_bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "uwsgi packet too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processor::uwsgi::encode_packet;
+
+    #[test]
+    fn test_encode_packet() {
+        let packet = encode_packet(&[("PATH_INFO".to_owned(), "/a".to_owned())]).unwrap();
+        assert_eq!(packet, b"\x00\x0f\x00\x00\x09\x00PATH_INFO\x02\x00/a");
+
+        let long = "v".repeat(0x10000);
+        assert!(encode_packet(&[("A".to_owned(), long)]).is_err());
+    }
+}
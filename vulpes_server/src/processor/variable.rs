@@ -0,0 +1,309 @@
+use crate::{
+    config::types::{ProxyPass, Template, TemplatePart},
+    processor::{
+        fastcgi::ScriptPath, map::VariableCache, proxy_cache::CacheStatus, request_host,
+        rewrite::OriginalUri, ConnectionInfo, DocumentRoot, PrimaryName, Request, RequestId,
+    },
+};
+use http::header;
+use std::time::SystemTime;
+
+/// Captures of the regular expressions of the server name and of `rewrite`,
+/// by number and by name, stored in the request extensions.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Captures(pub Vec<(String, String)>);
+
+impl Captures {
+    /// Returns the captures of `pattern` in `value`, `None` when it doesn't
+    /// match.
+    pub fn new(pattern: &regex::Regex, value: &str) -> Option<Captures> {
+        let captures = pattern.captures(value)?;
+        let mut result = vec![];
+        for (n, name) in pattern.capture_names().enumerate().skip(1) {
+            if let Some(m) = captures.get(n) {
+                result.push((n.to_string(), m.as_str().to_owned()));
+                if let Some(name) = name {
+                    result.push((name.to_owned(), m.as_str().to_owned()));
+                }
+            }
+        }
+        Some(Captures(result))
+    }
+
+    /// Adds the captures of a later match, which replace all the numbered
+    /// captures and the named ones of the same name.
+    pub fn update(&mut self, other: Captures) {
+        self.0.retain(|(k, _)| {
+            !k.bytes().all(|v| v.is_ascii_digit()) && !other.0.iter().any(|(n, _)| n == k)
+        });
+        self.0.extend(other.0);
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.clone())
+    }
+}
+
+/// Expands the variables of `template` against a request. Unknown variables
+/// expand to an empty string.
+pub fn evaluate(template: &Template, req: &Request) -> String {
+    expand(template, |name| get(name, req))
+}
+
+/// Expands the variables of `template` with `captures` taking precedence
+/// over those of the request, e.g. for the value of a `map` regex.
+pub fn evaluate_with(template: &Template, req: &Request, captures: &Captures) -> String {
+    expand(template, |name| {
+        captures.get(name).or_else(|| get(name, req))
+    })
+}
+
+/// Expands the variables of a `log_format`, with `values` taking precedence
+/// over those of the request. An empty value is written as `-`, and the
+/// quotes, backslashes and control characters of the values are escaped.
+pub fn evaluate_log(template: &Template, req: &Request, values: &Captures) -> String {
+    expand(template, |name| {
+        let value = values
+            .get(name)
+            .or_else(|| get(name, req))
+            .unwrap_or_default();
+        if value.is_empty() {
+            return Some("-".to_owned());
+        }
+        let mut escaped = String::new();
+        for c in value.chars() {
+            match c {
+                c if c == '"' || c == '\\' || c.is_control() => {
+                    escaped.push_str(&format!("\\x{:02X}", c as u32))
+                }
+                c => escaped.push(c),
+            }
+        }
+        Some(escaped)
+    })
+}
+
+fn expand(template: &Template, get: impl Fn(&str) -> Option<String>) -> String {
+    let mut result = String::new();
+    for part in template.parts() {
+        match part {
+            TemplatePart::Literal(v) => result.push_str(v),
+            TemplatePart::Variable(name) => {
+                if let Some(v) = get(name) {
+                    result.push_str(&v);
+                }
+            }
+        }
+    }
+    result
+}
+
+pub fn get(name: &str, req: &Request) -> Option<String> {
+    let info = req.extensions().get::<ConnectionInfo>();
+    let tls = info.and_then(|v| v.tls.as_ref());
+    let client_cert = tls.and_then(|v| v.client_cert.as_ref());
+
+    match name {
+        "host" => request_host(req)
+            .filter(|v| !v.is_empty())
+            .or_else(|| req.extensions().get::<PrimaryName>().map(|v| v.0.clone())),
+        "uri" | "document_uri" => Some(req.uri().path().to_owned()),
+        "request_uri" => match req.extensions().get::<OriginalUri>() {
+            Some(v) => v.0.path_and_query().map(|v| v.to_string()),
+            None => req.uri().path_and_query().map(|v| v.to_string()),
+        },
+        "args" | "query_string" => Some(req.uri().query().unwrap_or_default().to_owned()),
+        "request_method" => Some(req.method().to_string()),
+        "request" => Some(format!(
+            "{} {} {:?}",
+            req.method(),
+            get("request_uri", req).unwrap_or_default(),
+            req.version()
+        )),
+        // `httpdate` formats as `Sun, 06 Nov 1994 08:49:37 GMT`
+        "time_local" => match httpdate::fmt_http_date(SystemTime::now())
+            .split(' ')
+            .collect::<Vec<_>>()[..]
+        {
+            [_, day, month, year, time, _] => {
+                Some(format!("{}/{}/{}:{} +0000", day, month, year, time))
+            }
+            _ => None,
+        },
+        "request_id" => req.extensions().get::<RequestId>().map(|v| v.0.clone()),
+        // the clients of a UNIX-domain socket have no address or port
+        "remote_addr" => info.map(|v| match v.unix {
+            Some(_) => "unix:".to_owned(),
+            None => v.remote_addr.ip().to_string(),
+        }),
+        "remote_port" => info.map(|v| match v.unix {
+            Some(_) => String::new(),
+            None => v.remote_addr.port().to_string(),
+        }),
+        "server_port" => info.map(|v| match v.unix {
+            Some(_) => String::new(),
+            None => v.local_addr.port().to_string(),
+        }),
+        "server_addr" => info.map(|v| match &v.unix {
+            Some(path) => path.clone(),
+            None => v.local_addr.ip().to_string(),
+        }),
+        "server_protocol" => Some(format!("{:?}", req.version())),
+        "scheme" => Some(match tls {
+            Some(_) => "https".to_owned(),
+            None => "http".to_owned(),
+        }),
+        "https" => Some(match tls {
+            Some(_) => "on".to_owned(),
+            None => String::new(),
+        }),
+        "ssl_protocol" => tls.map(|v| v.protocol.clone()),
+        "ssl_cipher" => tls.map(|v| v.cipher.clone()),
+        "ssl_server_name" => tls.and_then(|v| v.server_name.clone()),
+        "ssl_client_verify" => tls.map(|v| v.client_verify.clone()),
+        "ssl_client_s_dn" => client_cert.map(|v| v.subject.clone()),
+        "ssl_client_i_dn" => client_cert.map(|v| v.issuer.clone()),
+        "ssl_client_serial" => client_cert.map(|v| v.serial.clone()),
+        "ssl_client_fingerprint" => client_cert.map(|v| v.fingerprint.clone()),
+        "ssl_early_data" => Some(match tls.map(|v| v.early_data) {
+            Some(true) => "1".to_owned(),
+            _ => String::new(),
+        }),
+        "content_type" => header_value(req, header::CONTENT_TYPE),
+        "content_length" => header_value(req, header::CONTENT_LENGTH),
+        "document_root" => req.extensions().get::<DocumentRoot>().map(|v| v.0.clone()),
+        "request_filename" => req
+            .extensions()
+            .get::<DocumentRoot>()
+            .map(|v| format!("{}{}", v.0.trim_end_matches('/'), req.uri().path())),
+        "fastcgi_script_name" => req
+            .extensions()
+            .get::<ScriptPath>()
+            .map(|v| v.script_name.clone()),
+        "fastcgi_path_info" => req
+            .extensions()
+            .get::<ScriptPath>()
+            .map(|v| v.path_info.clone()),
+        "proxy_host" => req.extensions().get::<ProxyPass>().map(|v| v.host.clone()),
+        "upstream_cache_status" => req
+            .extensions()
+            .get::<CacheStatus>()
+            .map(|v| v.as_str().to_owned()),
+        _ => {
+            if let Some(v) = req
+                .extensions()
+                .get::<VariableCache>()
+                .and_then(|c| c.get(name, req))
+            {
+                return Some(v);
+            }
+
+            if let Some(name) = name.strip_prefix("http_") {
+                return header_value(req, name.replace('_', "-"));
+            }
+
+            if let Some(name) = name.strip_prefix("arg_") {
+                return req.uri().query().and_then(|q| {
+                    q.split('&')
+                        .filter_map(|v| v.split_once('=').or(Some((v, ""))))
+                        .find(|(k, _)| *k == name)
+                        .map(|(_, v)| v.to_owned())
+                });
+            }
+
+            // the captures of a regular expression server name
+            req.extensions().get::<Captures>().and_then(|c| c.get(name))
+        }
+    }
+}
+
+fn header_value(req: &Request, name: impl header::AsHeaderName) -> Option<String> {
+    req.headers()
+        .get(name)
+        .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::types::{Template, TemplatePart},
+        processor::{body::Body, variable, ConnectionInfo, PrimaryName, Request, RequestId},
+    };
+
+    fn evaluate(template: &str, req: &Request) -> String {
+        variable::evaluate(&template.into(), req)
+    }
+
+    #[test]
+    fn test_template() {
+        let template = Template::from("a$b${c}d$ $");
+        assert_eq!(
+            template.parts(),
+            [
+                TemplatePart::Literal("a".to_owned()),
+                TemplatePart::Variable("b".to_owned()),
+                TemplatePart::Variable("c".to_owned()),
+                TemplatePart::Literal("d$ $".to_owned()),
+            ]
+        );
+        assert_eq!(
+            Template::from("${a").parts(),
+            [TemplatePart::Literal("${a".to_owned())]
+        );
+        assert!(Template::from("").parts().is_empty());
+
+        // a capture is a single digit
+        assert_eq!(
+            Template::from("/$1abc${12}").parts(),
+            [
+                TemplatePart::Literal("/".to_owned()),
+                TemplatePart::Variable("1".to_owned()),
+                TemplatePart::Literal("abc".to_owned()),
+                TemplatePart::Variable("12".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut req = http::Request::builder()
+            .uri("/path?a=1&b=2")
+            .header("Host", "Example.com:8080")
+            .header("User-Agent", "test")
+            .body(Body::default())
+            .unwrap();
+        req.extensions_mut().insert(ConnectionInfo {
+            remote_addr: "10.0.0.1:5000".parse().unwrap(),
+            local_addr: "127.0.0.1:80".parse().unwrap(),
+            unix: None,
+            tls: None,
+        });
+        req.extensions_mut().insert(RequestId::generate());
+
+        assert_eq!(
+            evaluate("$host$uri?$args ${arg_b}x $http_user_agent", &req),
+            "example.com/path?a=1&b=2 2x test"
+        );
+        assert_eq!(evaluate("$remote_addr $unknown$", &req), "10.0.0.1 $");
+        assert_eq!(evaluate("$scheme:$https", &req), "http:");
+
+        // the id of a request doesn't change between evaluations
+        let id = evaluate("$request_id", &req);
+        assert_eq!(id.len(), 32);
+        assert!(id.bytes().all(|v| v.is_ascii_hexdigit()));
+        assert_eq!(evaluate("$request_id", &req), id);
+
+        req.headers_mut()
+            .insert("host", "[::1]:8080".parse().unwrap());
+        assert_eq!(evaluate("$host", &req), "[::1]");
+        // without a host the primary name of the server is used
+        req.headers_mut().remove("host");
+        assert_eq!(evaluate("$host", &req), "");
+        req.extensions_mut()
+            .insert(PrimaryName("example.com".to_owned()));
+        assert_eq!(evaluate("$host", &req), "example.com");
+    }
+}
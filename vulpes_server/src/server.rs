@@ -1,31 +1,117 @@
 use crate::{
-    config::{server::ServerConfig, Config},
-    processor,
+    config::{
+        listen::{ListenAddress, ListenConfig},
+        server::ServerConfig,
+        Config,
+    },
+    processor::{self, access_log, health, map, proxy_cache, tls, upstream, HttpServer},
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
 };
-use std::{collections::HashMap, error::Error};
 use tokio::{
     signal::unix::{signal, SignalKind},
     sync::oneshot,
 };
 
+/// Servers listening on the same address.
+struct Listen {
+    /// The socket parameters of the first `listen` of the address apply.
+    config: ListenConfig,
+    servers: Vec<HttpServer>,
+    /// Index of the server whose `listen` has the `default_server` parameter.
+    default_server: Option<usize>,
+    configs: Vec<ServerConfig>,
+    /// Some `listen` of the address has the `ssl` parameter.
+    ssl: bool,
+}
+
 pub async fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let mut listen_map: HashMap<String, Vec<ServerConfig>> = HashMap::new();
+    let mut listen_map: HashMap<ListenAddress, Listen> = HashMap::new();
+    // UDP ports of `listen ... quic`, which may share the number of a TCP port
+    let mut quic_map: HashMap<ListenAddress, Listen> = HashMap::new();
     for http in config.http {
+        let upstreams = Arc::new(upstream::build(&http));
+        health::spawn(&upstreams);
+        let caches = Arc::new(proxy_cache::build(&http)?);
+        proxy_cache::spawn(&caches);
+        let variables = Arc::new(map::build(&http));
+        let logger = Arc::new(access_log::build(&http)?);
+
         for server in http.server.iter() {
             for listen in server.listen.iter() {
-                let listen_index = &listen[0];
-                if let Some(v) = listen_map.get_mut(listen_index) {
-                    v.push(server.clone());
-                } else {
-                    listen_map.insert(listen_index.clone(), vec![server.clone()]);
+                let map = match listen.quic {
+                    true => &mut quic_map,
+                    false => &mut listen_map,
+                };
+                let l = map.entry(listen.address.clone()).or_insert_with(|| Listen {
+                    config: listen.clone(),
+                    servers: vec![],
+                    default_server: None,
+                    configs: vec![],
+                    ssl: false,
+                });
+                if listen.default_server {
+                    if l.default_server.is_some() {
+                        return Err(
+                            format!("duplicate default server for {}", listen.address).into()
+                        );
+                    }
+                    l.default_server = Some(l.servers.len());
                 }
+                l.servers.push(HttpServer::new(
+                    server.clone(),
+                    upstreams.clone(),
+                    caches.clone(),
+                    variables.clone(),
+                    logger.clone(),
+                ));
+                l.configs.push(server.clone());
+                l.ssl |= listen.quic || listen.ssl;
             }
         }
     }
 
-    for (listen, servers) in listen_map {
+    let mut session_caches = tls::SessionCaches::new();
+    let mut servers = HashMap::new();
+    for (listen, l) in listen_map {
+        // the HTTP/2 settings of the default server apply to the connections
+        let default_server = l.default_server.unwrap_or(0);
+        let tls = match l.ssl {
+            true => Some(tls::acceptor(
+                &l.configs,
+                default_server,
+                l.configs[default_server].http2.enabled,
+                &mut session_caches,
+            )?),
+            false => None,
+        };
+        if let Some(acceptor) = &tls {
+            tls::spawn(acceptor);
+        }
+        let s = processor::Server::new(l.config, l.servers, default_server, tls);
+        servers.insert(listen, s);
+    }
+
+    // as in nginx, a specific address of a port that also has the wildcard
+    // address isn't bound, its connections are accepted on the wildcard one
+    let shared = servers
+        .keys()
+        .filter_map(|v| Some((v.clone(), wildcard(v)?)))
+        .filter(|(_, w)| servers.contains_key(w))
+        .collect::<Vec<_>>();
+    for (address, wildcard) in shared {
+        if let (Some(s), ListenAddress::Inet(addr)) = (servers.remove(&address), &address) {
+            log::info!("share listen on {} with {}", address, wildcard);
+            servers.get_mut(&wildcard).unwrap().add_local(*addr, s);
+        }
+    }
+
+    for (listen, s) in servers {
         log::info!("start server listen on {}", listen);
-        let s = processor::Server::new(listen, servers);
         let (tx, rx) = oneshot::channel();
         let handle = tokio::spawn(s.run(tx));
 
@@ -35,9 +121,38 @@ pub async fn run(config: Config) -> Result<(), Box<dyn Error>> {
         }
     }
 
+    for (listen, l) in quic_map {
+        log::info!("start quic server listen on {}", listen);
+        let default_server = l.default_server.unwrap_or(0);
+        let tls = tls::acceptor(&l.configs, default_server, false, &mut session_caches)?;
+        tls::spawn(&tls);
+        let s = processor::Server::new(l.config, l.servers, default_server, Some(tls));
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::spawn(s.run_quic(tx));
+
+        match rx.await {
+            Ok(_) => {}
+            Err(_) => handle.await??,
+        }
+    }
+
     let mut sig = signal(SignalKind::interrupt()).unwrap();
     sig.recv().await;
 
     log::info!("stop server");
     Ok(())
 }
+
+/// Returns the wildcard address of the port of a specific IP address.
+fn wildcard(address: &ListenAddress) -> Option<ListenAddress> {
+    match address {
+        ListenAddress::Inet(addr) if !addr.ip().is_unspecified() => {
+            let ip: IpAddr = match addr {
+                SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+            Some(ListenAddress::Inet(SocketAddr::new(ip, addr.port())))
+        }
+        _ => None,
+    }
+}